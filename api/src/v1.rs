@@ -6,14 +6,14 @@
     This file handles the JSON API version 1 of the Daemon.
 */
 
-use avrio_config::config;
 use avrio_core::{
     account::get_account,
     block::{get_block, get_block_from_raw, save_block, Block},
+    finality::{finality_status, finalized_by},
     validate::Verifiable,
 };
 use avrio_crypto::public_key_to_address;
-use avrio_database::{get_data, open_database};
+use avrio_database::store::{self, Table};
 use avrio_p2p::helper::prop_block;
 use avrio_rpc::block_announce;
 use log::*;
@@ -31,52 +31,27 @@ fn must_provide_method() -> &'static str {
 }
 #[get("/blockcount/<chain>")]
 pub fn get_blockcount_v1(chain: String) -> String {
-    let our_height_string = get_data(
-        config().db_path + &"/chains/".to_owned() + &chain + &"-chainindex".to_owned(),
-        &"blockcount".to_owned(),
-    );
-    if our_height_string == "-1" {
-        return "{ \"success\": true, ".to_owned() + "\"blockcount\": " + &0.to_string() + " }";
-    } else {
-        let try_parse = our_height_string.parse::<u64>();
-        if let Ok(our_height) = try_parse {
-            return "{ \"success\": true, ".to_owned()
+    match store::get::<u64>(&Table::ChainIndex(chain), "blockcount") {
+        Ok(our_height) => {
+            "{ \"success\": true, ".to_owned()
                 + "\"blockcount\": "
-                + &our_height.to_string()
-                + " }";
-        } else {
-            return "{ \"success\": false, ".to_owned()
-                + "\"error\": "
-                + &try_parse.unwrap_err().to_string()
-                + " }";
+                + &our_height.unwrap_or(0).to_string()
+                + " }"
         }
+        Err(e) => serde_json::json!({ "success": false, "error": e.to_string() }).to_string(),
     }
 }
 
 #[get("/transactioncount/<chain>")]
 pub fn transaction_count(chain: String) -> String {
-    let txn_count_string = get_data(
-        config().db_path + &"/chains/".to_owned() + &chain + &"-chainindex".to_owned(),
-        &"txncount".to_owned(),
-    );
-    if txn_count_string == "-1" {
-        return "{ \"success\": true, ".to_owned()
-            + "\"transaction_count\": "
-            + &0.to_string()
-            + " }";
-    } else {
-        let try_parse = txn_count_string.parse::<u64>();
-        if let Ok(txn_count) = try_parse {
-            return "{ \"success\": true, ".to_owned()
+    match store::get::<u64>(&Table::ChainIndex(chain), "txncount") {
+        Ok(txn_count) => {
+            "{ \"success\": true, ".to_owned()
                 + "\"transaction_count\": "
-                + &txn_count.to_string()
-                + " }";
-        } else {
-            return "{ \"success\": false, ".to_owned()
-                + "\"error\": "
-                + &try_parse.unwrap_err().to_string()
-                + " }";
+                + &txn_count.unwrap_or(0).to_string()
+                + " }"
         }
+        Err(e) => serde_json::json!({ "success": false, "error": e.to_string() }).to_string(),
     }
 }
 
@@ -119,6 +94,20 @@ pub fn get_block_v1(hash: String) -> String {
     "{ \"success\": false, \"response\": { \"block\": } }".to_owned()
 }
 
+/// Returns the finality status (unknown, tentative, enacted or final) of the block and the chunk which finalized it
+/// Deposits should only be credited once their block is final
+#[get("/finality/<hash>")]
+pub fn get_finality_v1(hash: String) -> String {
+    let status = finality_status(&hash);
+    "{ \"success\": true, \"hash\": \"".to_string()
+        + &hash
+        + "\", \"status\": \""
+        + status.as_str()
+        + "\", \"chunk\": \""
+        + &finalized_by(&hash).unwrap_or_default()
+        + "\" }"
+}
+
 #[get("/hash_at_height/<chain>/<height>")]
 pub fn hash_at_height(height: u64, chain: String) -> String {
     let block = get_block(&chain, height);
@@ -159,12 +148,8 @@ pub fn publickey_to_address(publickey: String) -> String {
 
 #[get("/chainlist")]
 pub fn chainlist() -> String {
-    if let Ok(db) = open_database(config().db_path + &"/chainlist".to_owned()) {
-        let mut chains: Vec<String> = vec![];
-
-        for (key, _) in db.iter() {
-            chains.push(key.to_owned());
-        }
+    if let Ok(entries) = store::entries(&Table::ChainList) {
+        let chains: Vec<String> = entries.into_iter().map(|(key, _)| key).collect();
 
         log::trace!("Our chain list: {:#?}", chains);
         if let Ok(s) = serde_json::to_string(&chains) {
@@ -294,6 +279,7 @@ pub fn get_middleware() -> Vec<Route> {
         must_provide_method,
         get_balance_v1,
         get_block_v1,
+        get_finality_v1,
         get_usernames_v1,
         submit_block_v1,
         get_blockcount_v1,
@@ -1,11 +1,28 @@
-use avrio_core::{account::get_nonce, certificate::get_fullnode_count, chunk::{BlockChunk, string_to_bls_privatkey}, commitee::{self, Comitee}, mempool};
-use avrio_crypto::raw_lyra;
-use avrio_p2p::guid::{self, form_table};
+use avrio_core::{account::get_nonce, certificate::get_fullnode_count, chunk::{BlockChunk, string_to_bls_privatkey}, commitee::{self, Comitee}, mempool, reward::epoch_rewards, lottery::select_candidates};
+use avrio_core::{
+    block::get_block_from_raw,
+    certificate::{scheduled_removals, Certificate},
+    consensus::{
+        enact_consensus_blocks, form_consensus_blocks, has_quorum, sign_consensus_block,
+        valid_consensus_signature, ConsensusSignatures,
+    },
+    epoch::salt_seed_message,
+    lottery::{lottery_threshold, lotto_seed, ticket_value},
+    participation::{fullnode_set, is_active, required_salt_seeds, voting_members},
+    probation::observers_after,
+    snapshot::Snapshot,
+    transaction::FullnodeDeltaList,
+};
+use avrio_crypto::{raw_lyra, validate_vrf};
+use avrio_database::store::{self, Table};
+use avrio_p2p::{
+    format::P2pData,
+    guid::{self, form_table},
+};
 use std::{thread::sleep, time::Duration};
 // contains functions called by the fullnode
 use crate::*;
 use avrio_core::mempool::MEMPOOL;
-use avrio_rpc::LOCAL_CALLBACKS;
 lazy_static! {
     static ref VRF_LOTTO_ENTRIES: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
     static ref COMMITEE_INDEX: Mutex<u64> = Mutex::new(1025);
@@ -154,11 +171,11 @@ pub fn propose_round_chunk() -> Result<String, Box<dyn std::error::Error>> {
             let committee = epoch.committees[committee_index as usize].clone();
             // get the round number
             let top_epoch = get_top_epoch()?;
-            let top_round_index: u64 = get_data(
-                config().db_path + "/blockchunks",
+            let top_round_index: u64 = store::get(
+                &Table::BlockChunks,
                 &(committee.index.to_string() + "-round-" + &epoch.epoch_number.to_string()),
-            )
-            .parse()?;
+            )?
+            .ok_or("could not find top round of committee")?;
             let top_chunk =
                 BlockChunk::get_by_round(top_round_index, top_epoch.epoch_number, committee.index)?;
             // check we are the proposer for this round
@@ -229,8 +246,8 @@ pub fn start_genesis_epoch() -> Result<(), Box<dyn std::error::Error>> {
     match FULLNODE_KEYS.lock() {
         /* 0 - ECDSA pub, 1 - ECDSA priv, 2 - BLS pub, 3 - BLS priv, 4 - secp2561k pub, 5 - secp2561k priv*/
         Ok(lock) => {
-            let (proof, _) = get_vrf(lock[5].clone(), String::from("genesis"))?;
-            if !avrio_crypto::validate_vrf(lock[4].clone(), proof.clone(), String::from("genesis"))
+            let (proof, _) = get_vrf(lock[5].clone(), salt_seed_message(0))?;
+            if !avrio_crypto::validate_vrf(lock[4].clone(), proof.clone(), salt_seed_message(0))
             {
                 error!("Created salt seed invalid");
                 return Err("epoch salt seed invalid".into());
@@ -289,127 +306,7 @@ pub fn start_genesis_epoch() -> Result<(), Box<dyn std::error::Error>> {
                     return Err(format!("Failed to prop epoch seed block {}", block.hash).into());
                 }
             }
-            // Now we wait for VRF tickets to come in
-            // register with the announcement system, to collect all VRF lotto tickets
-            let mut block_callbacks = LOCAL_CALLBACKS.lock()?;
-            block_callbacks.push(Caller {
-                callback: Box::new(|ann| {
-                    if ann.m_type == "block" {
-                        let block: Block = serde_json::from_str(&ann.content).unwrap();
-                        for txn in block.txns {
-                            if txn.flag == 'v' {
-                                handle_vrf_submitted(txn);
-                            }
-                        }
-                    }
-                }),
-            });
-            drop(block_callbacks);
-            sleep(Duration::from_millis(60000)); // wait a single minute
-                                                 // now we can proceed
-            let mut blocks: Vec<Block> = vec![];
-
-            // create the shuffle bits
-            let top_epoch = get_top_epoch()?;
-            let new_epoch = Epoch::get(top_epoch.epoch_number + 1)?;
-            let (shuffle_proof, _) = get_vrf(
-                lock[5].clone(),
-                raw_lyra(
-                    &(new_epoch.salt.to_string() + &new_epoch.epoch_number.to_string() + &lock[0]),
-                ),
-            )?;
-
-            let mut transaction = Transaction {
-                hash: String::from(""),
-                amount: 0,
-                extra: bs58::encode(shuffle_proof).into_string(),
-                flag: 'z',
-                sender_key: lock[0].clone(),
-                receive_key: String::from("0"),
-                access_key: String::from(""),
-                unlock_time: 0,
-                gas_price: 1,
-                max_gas: u64::MAX,
-                nonce: get_nonce(lock[0].clone()),
-                timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
-            };
-            transaction.hash();
-            let shuffle_bits_block = Block::new(vec![transaction], lock[1].clone(), None);
-            let mut shuffle_bits_block_rec =
-                shuffle_bits_block.form_receive_block(Some(String::from("0")))?;
-            let _ = shuffle_bits_block_rec.sign(&lock[1]).unwrap();
-
-            if let Err(shuffle_bits_error) = shuffle_bits_block
-                .valid()
-                .and_then(|_| shuffle_bits_block.save())
-                .and_then(|_| shuffle_bits_block.enact())
-                .and_then(|_| shuffle_bits_block_rec.valid())
-                .and_then(|_| shuffle_bits_block_rec.save())
-                .and_then(|_| shuffle_bits_block_rec.enact())
-            {
-                error!(
-                    "Failed to broadcast shuffle bits in block, got error={}",
-                    shuffle_bits_error
-                );
-                return Err(format!(
-                    "Failed to broadcast shuffle bits in block, got error={}",
-                    shuffle_bits_error
-                )
-                .into());
-            }
-            blocks.push(shuffle_bits_block);
-            blocks.push(shuffle_bits_block_rec);
-
-            // create an empty fullnode delta list txn
-            let delta_list: ((String, String), Vec<(String, u8, String)>) = (
-                (raw_lyra(&lock[0]), raw_lyra(&top_epoch.committees[0].hash)),
-                vec![],
-            );
-
-            let mut transaction = Transaction {
-                hash: String::from(""),
-                amount: 0,
-                extra: bs58::encode(serde_json::to_string(&delta_list)?).into_string(),
-                flag: 'y',
-                sender_key: lock[0].clone(),
-                receive_key: String::from("0"),
-                access_key: String::from(""),
-                unlock_time: 0,
-                gas_price: 1,
-                max_gas: u64::MAX,
-                nonce: get_nonce(lock[0].clone()),
-                timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
-            };
-            transaction.hash();
-            let delta_list_block = Block::new(vec![transaction], lock[1].clone(), None);
-            let mut delta_list_block_rec =
-                delta_list_block.form_receive_block(Some(String::from("0")))?;
-            let _ = delta_list_block_rec.sign(&lock[1]).unwrap();
-
-            if let Err(delta_list_error) = delta_list_block
-                .valid()
-                .and_then(|_| delta_list_block.save())
-                .and_then(|_| delta_list_block.enact())
-                .and_then(|_| delta_list_block_rec.valid())
-                .and_then(|_| delta_list_block_rec.save())
-                .and_then(|_| delta_list_block_rec.enact())
-            {
-                error!(
-                    "Failed to broadcast delta_list in block, got error={}",
-                    delta_list_error
-                );
-                return Err(format!(
-                    "Failed to broadcast delta_list in block, got error={}",
-                    delta_list_error
-                )
-                .into());
-            }
-            blocks.push(delta_list_block);
-            blocks.push(delta_list_block_rec);
-            // send blocks to network
-            for block in &blocks {
-                let _ = prop_block(block)?;
-            }
+            // the consensus round leader (us) closes the VRF lottery and starts the next epoch once the VrfLotto stage is over, see handle_vrf_lotto_end
             return Ok(());
         }
         Err(lock_error) => {
@@ -422,6 +319,28 @@ pub fn start_genesis_epoch() -> Result<(), Box<dyn std::error::Error>> {
     };
 }
 
+/// How long we wait before relaying an enacted VRF lottery ticket to the round leader, giving the block containing it time to finish enacting
+const TICKET_RELAY_DELAY: u64 = 1000;
+
+/// Called when a VRF lottery ticket is enacted, handles it in a new thread (as it may be enacted while FULLNODE_KEYS is locked)
+pub fn handle_vrf_ticket(txn: Transaction) {
+    create_timer(
+        Duration::from_millis(TICKET_RELAY_DELAY),
+        Box::new(handle_vrf_submitted),
+        txn,
+    );
+}
+
+/// Sends the block containing an enacted VRF lottery ticket to our GUID peers, only the round leader processes it
+fn relay_vrf_ticket(txn: &Transaction) -> Result<(), Box<dyn std::error::Error>> {
+    let block_hash = store::get_raw(&Table::Transactions, &txn.hash)?.ok_or_else(|| {
+        format!("could not find block containing ticket txn {}", txn.hash)
+    })?;
+    let block = get_block_from_raw(block_hash);
+    guid::send_to_all(serde_json::to_string(&block)?, 0x66, false, false)?;
+    Ok(())
+}
+
 pub fn handle_vrf_submitted(txn: Transaction) {
     match FULLNODE_KEYS.lock() {
         Ok(lock) => {
@@ -438,19 +357,27 @@ pub fn handle_vrf_submitted(txn: Transaction) {
                     ticket_hash
                 );
                 // decode VRF's proof into value
-                let vrf_hash = proof_to_hash(&txn.extra).unwrap_or_default();
-                // turn VRF hash into bigint
-                let vrf_ticket_bigint = vrf_hash_to_integer(vrf_hash);
+                let ticket = ticket_value(&txn.extra).unwrap_or(u64::MAX);
+                let (_, threshold) = lottery_threshold(&top_epoch);
                 debug!(
                     "VRF lotto ticket={} sent by={} value={}, viable={}",
                     ticket_hash,
                     txn.sender_key,
-                    vrf_ticket_bigint,
-                    vrf_ticket_bigint < BigDecimal::from(1)
+                    ticket,
+                    ticket <= threshold
                 );
                 match VRF_LOTTO_ENTRIES.lock() {
                     Ok(mut lock) => {
-                        lock.push((txn.sender_key.clone(), txn.hash));
+                        // we may see a ticket both enacted locally and relayed by a committee member
+                        if lock.iter().any(|(sender, _)| sender == &txn.sender_key) {
+                            trace!(
+                                "Already have VRF lotto ticket from {}, ignoring ticket={}",
+                                txn.sender_key,
+                                ticket_hash
+                            );
+                        } else {
+                            lock.push((txn.sender_key.clone(), txn.hash));
+                        }
                     }
                     Err(lock_error) => {
                         error!(
@@ -466,7 +393,12 @@ pub fn handle_vrf_submitted(txn: Transaction) {
                     ticket_hash,
                     round_leader
                 );
-                // TODO: Send the ticket to the round leader
+                if let Err(e) = relay_vrf_ticket(&txn) {
+                    error!(
+                        "Failed to relay VRF ticket={} to round leader, error={}",
+                        ticket_hash, e
+                    );
+                }
             } else {
                 debug!(
                     "Not in consensus commitee, ignoring VRF lotto ticket={}",
@@ -480,12 +412,250 @@ pub fn handle_vrf_submitted(txn: Transaction) {
         ),
     }
 }
+
+/// Called when the VRF lottery starts, if we are the consensus round leader we close the lottery once the VrfLotto stage is over
+pub fn handle_vrf_lotto_end() {
+    let top_epoch = get_top_epoch().unwrap_or_default();
+    if top_epoch.committees.is_empty()
+        || top_epoch.committees[0]
+            .get_round_leader()
+            .unwrap_or_default()
+            != config().chain_key
+    {
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64;
+    let time_till_close = top_epoch.time_till_deadline(now).unwrap_or(0);
+    debug!("Closing VRF lotto in {} ms", time_till_close);
+    create_timer(
+        Duration::from_millis(time_till_close),
+        Box::new(close_vrf_lotto),
+        (),
+    );
+}
+
+pub fn close_vrf_lotto(_null: ()) {
+    if let Err(e) = form_next_epoch() {
+        error!("Failed to close VRF lotto and start next epoch, error={}", e);
+    }
+}
+
+/// # Form next epoch
+/// Called by the consensus round leader once the VrfLotto stage is over
+/// Announces the shuffle bits ('z') then the fullnode delta list ('y'), which contains the candidates selected by the VRF lottery and starts the next epoch
+fn form_next_epoch() -> Result<(), Box<dyn std::error::Error>> {
+    let keys = FULLNODE_KEYS.lock()?.clone(); // enacting the delta list calls the epoch started callbacks, which lock FULLNODE_KEYS
+    /* 0 - ECDSA pub, 1 - ECDSA priv, 2 - BLS pub, 3 - BLS priv, 4 - secp2561k pub, 5 - secp2561k priv*/
+    if keys.len() != 6 {
+        return Err("Keys not loaded".into());
+    }
+
+    // create the shuffle bits
+    let top_epoch = get_top_epoch()?;
+    let new_epoch = Epoch::get(top_epoch.epoch_number + 1)?;
+    let (shuffle_proof, _) = get_vrf(
+        keys[5].clone(),
+        raw_lyra(&(new_epoch.salt.to_string() + &new_epoch.epoch_number.to_string() + &keys[0])),
+    )?;
+
+    let mut transaction = Transaction {
+        hash: String::from(""),
+        amount: 0,
+        extra: bs58::encode(shuffle_proof).into_string(),
+        flag: 'z',
+        sender_key: keys[0].clone(),
+        receive_key: String::from("0"),
+        access_key: String::from(""),
+        unlock_time: 0,
+        gas_price: 1,
+        max_gas: u64::MAX,
+        nonce: get_nonce(keys[0].clone()),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+    };
+    transaction.hash();
+    if let Err(shuffle_bits_error) = produce_consensus_blocks(&keys, vec![transaction]) {
+        error!(
+            "Failed to broadcast shuffle bits in block, got error={}",
+            shuffle_bits_error
+        );
+        return Err(format!(
+            "Failed to broadcast shuffle bits in block, got error={}",
+            shuffle_bits_error
+        )
+        .into());
+    }
+
+    // eclose the candidates selected by the VRF lottery
+    let selected = select_candidates(&top_epoch);
+    let collected = VRF_LOTTO_ENTRIES.lock()?.drain(..).count();
+    info!(
+        "Closing VRF lotto, collected {} tickets, eclosing {} candidates",
+        collected,
+        selected.len()
+    );
+    // remove fullnodes which exited, whose certificates expire or who failed probation
+    let mut deltas: Vec<(String, u8, String)> = scheduled_removals(&top_epoch);
+    let mut fullnodes: Vec<String> = fullnode_set(&top_epoch)
+        .into_iter()
+        .filter(|fullnode| is_active(fullnode) && !deltas.iter().any(|delta| &delta.0 == fullnode))
+        .collect();
+    info!("Removing {} fullnodes at end of epoch", deltas.len());
+    let mut eclosed: Vec<String> = vec![];
+    for (candidate, ticket_txn) in selected {
+        fullnodes.push(candidate.clone());
+        eclosed.push(candidate.clone());
+        deltas.push((candidate, 0, ticket_txn));
+    }
+    fullnodes.sort();
+    let preshuffle = commitee::preshuffle_hash(&fullnodes);
+    let next_epoch = Epoch::get(top_epoch.epoch_number + 1)?; // reload, now with the shuffle bits set
+    commitee::sort_full_list(&mut fullnodes, next_epoch.salt, next_epoch.shuffle_bits);
+    let committees = Comitee::form_comitees_with_observers(
+        &fullnodes,
+        &observers_after(&top_epoch, &eclosed),
+    );
+    // snapshot the state the next epoch starts from, the committee agrees on its hash by signing the delta list
+    let snapshot = Snapshot::agree(next_epoch.epoch_number, None)?;
+    let delta_list: FullnodeDeltaList = (
+        (preshuffle, commitee::postshuffle_hash(&committees)),
+        deltas,
+        snapshot.hash,
+    );
+
+    let mut transaction = Transaction {
+        hash: String::from(""),
+        amount: 0,
+        extra: bs58::encode(serde_json::to_string(&delta_list)?).into_string(),
+        flag: 'y',
+        sender_key: keys[0].clone(),
+        receive_key: String::from("0"),
+        access_key: String::from(""),
+        unlock_time: 0,
+        gas_price: 1,
+        max_gas: u64::MAX,
+        nonce: get_nonce(keys[0].clone()),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+    };
+    transaction.hash();
+    if let Err(delta_list_error) = produce_consensus_blocks(&keys, vec![transaction]) {
+        error!(
+            "Failed to broadcast delta_list in block, got error={}",
+            delta_list_error
+        );
+        return Err(format!(
+            "Failed to broadcast delta_list in block, got error={}",
+            delta_list_error
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// # Produce consensus blocks
+/// Called by the consensus round leader: puts the consensus txns in a block on the "0" chain and asks the consensus committee to sign it
+/// Once a quorum of the committee has signed, enacts the blocks and sends them and the aggregated signatures (which finalize the block) to the network
+fn produce_consensus_blocks(
+    keys: &[String],
+    txns: Vec<Transaction>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let top_epoch = get_top_epoch()?;
+    let committee = top_epoch
+        .committees
+        .first()
+        .cloned()
+        .ok_or("No consensus committee")?;
+    let (send_block, recieve_block) = form_consensus_blocks(txns, &keys[1])?;
+    let mut signatures = vec![sign_consensus_block(&send_block, &keys[3])?];
+    match guid::send_to_all(serde_json::to_string(&send_block)?, 0x67, true, false) {
+        Ok(responses) => {
+            for response in responses {
+                if response.message_type != 0x68 || response.message.is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<(String, String)>(&response.message) {
+                    Ok(signature) => {
+                        if valid_consensus_signature(&send_block.hash, &committee.members, &signature) {
+                            signatures.push(signature);
+                        } else {
+                            error!(
+                                "Got invalid signature on consensus block {} from {}",
+                                send_block.hash, signature.0
+                            );
+                        }
+                    }
+                    Err(e) => error!(
+                        "Failed to decode consensus block signature {}, error={}",
+                        response.message, e
+                    ),
+                }
+            }
+        }
+        Err(e) => error!("Failed to ask GUID peers to sign consensus block, error={}", e),
+    }
+    let aggregated =
+        ConsensusSignatures::aggregate(&send_block.hash, top_epoch.epoch_number, &signatures)?;
+    if !has_quorum(
+        &aggregated.ecdsa_signers()?,
+        &voting_members(top_epoch.epoch_number, &committee),
+    ) {
+        error!(
+            "Only got {}/{} signatures on consensus block {}, not enacting",
+            aggregated.signers.len(),
+            committee.members.len(),
+            send_block.hash
+        );
+        return Err("Consensus block not signed by a quorum".into());
+    }
+    // the blocks are only valid once the signatures are saved, so save and send them first
+    aggregated.valid()?;
+    aggregated.save()?;
+    let blocks = [send_block, recieve_block];
+    enact_consensus_blocks(&blocks)?;
+    aggregated.enact()?;
+    guid::send_to_all(serde_json::to_string(&aggregated)?, 0x69, false, false)?;
+    for block in &blocks {
+        prop_block(block)?;
+        debug!("Sent consensus block {} to peers", block.hash);
+    }
+    info!(
+        "Produced consensus block {} signed by {} consensus committee members",
+        blocks[0].hash,
+        aggregated.signers.len()
+    );
+    Ok(())
+}
+
+/// Called when the consensus round leader asks us to sign a consensus block, returns our BLS publickey and signature
+pub fn sign_proposed_consensus_block(
+    block: Block,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let keys = FULLNODE_KEYS.lock()?.clone();
+    /* 0 - ECDSA pub, 1 - ECDSA priv, 2 - BLS pub, 3 - BLS priv, 4 - secp2561k pub, 5 - secp2561k priv*/
+    if keys.len() != 6 {
+        return Err("Keys not loaded".into());
+    }
+    let top_epoch = get_top_epoch()?;
+    if top_epoch.committees.is_empty() || !top_epoch.committees[0].members.contains(&keys[0]) {
+        return Err("Not in consensus committee".into());
+    }
+    sign_consensus_block(&block, &keys[3])
+}
+
 /// Starts the next epoch
 // Returns a result, if we are in a committee this epoch and we sucsessfully started this epoch Ok(true), if we are excluded this epoch Ok(false)
 /// Otherwise if there was an error return it
 pub fn handle_new_epoch() -> Result<bool, Box<dyn std::error::Error>> {
+    // start the VRF lotto once the main stage of this epoch is over
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+    let time_till_lotto = get_top_epoch()?
+        .time_till_deadline(now)
+        .unwrap_or(config().target_epoch_length);
+    debug!("Starting VRF lotto in {} ms", time_till_lotto);
     create_timer(
-        Duration::from_millis(config().target_epoch_length),
+        Duration::from_millis(time_till_lotto),
         Box::new(start_vrf_lotto),
         (),
     );
@@ -533,17 +703,220 @@ pub fn handle_new_epoch() -> Result<bool, Box<dyn std::error::Error>> {
     Ok(true)
 }
 
+/// Called when an epoch moves to a new stage, if the stage has a deadline we check the epoch has left it once its grace period is over
+pub fn handle_epoch_stage(epoch_number: u64, _from: EpochStage, to: EpochStage) {
+    if let (Some(length), Some(grace_period)) = (to.length(), to.grace_period()) {
+        create_timer(
+            Duration::from_millis(length + grace_period + 1),
+            Box::new(fail_stalled_epoch),
+            (epoch_number, to),
+        );
+    }
+}
+
+/// Moves the epoch to the Failed stage if it is still the top epoch and is stuck in the stage past its deadline
+fn fail_stalled_epoch((epoch_number, stage): (u64, EpochStage)) {
+    let mut top_epoch = get_top_epoch().unwrap_or_default();
+    if top_epoch.epoch_number != epoch_number || top_epoch.stage != stage {
+        return;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64;
+    if top_epoch.overdue(now) {
+        warn!(
+            "Epoch {} stalled in stage {:?}, marking as failed",
+            epoch_number, stage
+        );
+        if let Err(e) = top_epoch.transition(EpochStage::Failed, now) {
+            error!("Failed to mark epoch {} as failed, error={}", epoch_number, e);
+        }
+    }
+}
+
+/// Resumes the top epoch after a restart, from the stage saved on disk
+pub fn resume_epoch() -> Result<(), Box<dyn std::error::Error>> {
+    let mut top_epoch = get_top_epoch()?;
+    info!(
+        "Resuming epoch {} in stage {:?}",
+        top_epoch.epoch_number, top_epoch.stage
+    );
+    if top_epoch.stage_started == 0 {
+        // we do not know when the stage started, count it as starting now rather than long ago
+        top_epoch.stage_started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        top_epoch.save()?;
+    }
+    if top_epoch.overdue(SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64) {
+        fail_stalled_epoch((top_epoch.epoch_number, top_epoch.stage));
+        return Ok(());
+    }
+    handle_epoch_stage(top_epoch.epoch_number, top_epoch.stage, top_epoch.stage);
+    match top_epoch.stage {
+        EpochStage::Reorg | EpochStage::Main => {
+            handle_new_epoch()?;
+        }
+        EpochStage::VrfLotto => {
+            info!("Waiting for the fullnode delta list to start the next epoch");
+        }
+        EpochStage::Failed | EpochStage::Final => {
+            debug!("Epoch {} not running", top_epoch.epoch_number);
+        }
+    }
+    Ok(())
+}
+
+/// How long the consensus round leader waits before asking members who did not send an epoch salt seed again
+const SALT_SEED_RETRY_DELAY: u64 = 5000;
+
+/// Creates our epoch salt seed (for the epoch after epoch_number), returns our publickey and the VRF proof
+pub fn create_epoch_salt_seed(
+    epoch_number: u64,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let lock = FULLNODE_KEYS.lock()?;
+    /* 0 - ECDSA pub, 1 - ECDSA priv, 2 - BLS pub, 3 - BLS priv, 4 - secp2561k pub, 5 - secp2561k priv*/
+    if lock.len() != 6 {
+        return Err("Keys not loaded".into());
+    }
+    let (proof, _) = get_vrf(lock[5].clone(), salt_seed_message(epoch_number))?;
+    Ok((lock[0].clone(), proof))
+}
+
+/// Checks an epoch salt seed sent by a consensus committee member and adds it to seeds if valid
+fn add_salt_seed(
+    response: P2pData,
+    members: &[String],
+    message: &str,
+    seeds: &mut Vec<(String, String)>,
+) {
+    if (response.message_type != 0x07 && response.message_type != 0x63) || response.message.is_empty() {
+        trace!(
+            "Ignoring epoch salt seed response, type={}, message={}",
+            response.message_type,
+            response.message
+        );
+        return;
+    }
+    match serde_json::from_str::<(String, String)>(&response.message) {
+        Ok((publickey, proof)) => {
+            if !members.contains(&publickey) {
+                error!("Got epoch salt seed from non member {}", publickey);
+            } else if seeds.iter().any(|(seeder, _)| seeder == &publickey) {
+                debug!("Already have epoch salt seed from {}", publickey);
+            } else {
+                match Certificate::get(publickey.clone()) {
+                    Ok(cert) => {
+                        if validate_vrf(cert.secp256k1_publickey, proof.clone(), message.to_owned()) {
+                            debug!("Got valid epoch salt seed from {}", publickey);
+                            seeds.push((publickey, proof));
+                        } else {
+                            error!("Got invalid epoch salt seed {} from {}", proof, publickey);
+                        }
+                    }
+                    Err(e) => error!(
+                        "Failed to get certificate of epoch salt seed creator {}, error={}",
+                        publickey, e
+                    ),
+                }
+            }
+        }
+        Err(e) => error!(
+            "Failed to decode epoch salt seed response {}, error={}",
+            response.message, e
+        ),
+    }
+}
+
+/// # Coordinate epoch salt
+/// Asks every member of the consensus committee for their salt seed, then announces the seeds in an announceEpochSaltSeeds ('a') txn
+/// Members who do not respond are asked again once; if fewer than min_vote percent of the participating members respond the salt is not announced (the txn would be invalid)
+fn coordinate_epoch_salt(
+    keys: &[String],
+    current_epoch: &Epoch,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let message = salt_seed_message(current_epoch.epoch_number);
+    let (own_proof, _) = get_vrf(keys[5].clone(), message.clone())?;
+    let mut seeds: Vec<(String, String)> = vec![(keys[0].clone(), own_proof)];
+    let members = &current_epoch.committees[0].members;
+    for message_type in &[0x06, 0x62] {
+        if seeds.len() >= members.len() {
+            break;
+        }
+        if *message_type == 0x62 {
+            debug!(
+                "Got {}/{} epoch salt seeds, retrying in {} ms",
+                seeds.len(),
+                members.len(),
+                SALT_SEED_RETRY_DELAY
+            );
+            sleep(Duration::from_millis(SALT_SEED_RETRY_DELAY));
+        }
+        match guid::send_to_all(
+            current_epoch.epoch_number.to_string(),
+            *message_type,
+            true,
+            false,
+        ) {
+            Ok(responses) => {
+                for response in responses {
+                    add_salt_seed(response, members, &message, &mut seeds);
+                }
+            }
+            Err(e) => error!("Failed to ask GUID peers for epoch salt seeds, error={}", e),
+        }
+    }
+    let required = required_salt_seeds(&current_epoch.committees[0]); // members who toggled off participation are not counted
+    if seeds.len() < required {
+        error!(
+            "Only got {}/{} epoch salt seeds (required={}), not announcing epoch salt",
+            seeds.len(),
+            members.len(),
+            required
+        );
+        return Err("Not enough epoch salt seeds".into());
+    }
+    seeds.sort();
+    info!("Announcing {} epoch salt seeds", seeds.len());
+    let mut transaction = Transaction {
+        hash: String::from(""),
+        amount: 0,
+        extra: bs58::encode(serde_json::to_string(&seeds)?).into_string(),
+        flag: 'a',
+        sender_key: keys[0].clone(),
+        receive_key: String::from("0"),
+        access_key: String::from(""),
+        unlock_time: 0,
+        gas_price: 1,
+        max_gas: u64::MAX,
+        nonce: get_nonce(keys[0].clone()),
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+    };
+    transaction.hash();
+    produce_consensus_blocks(keys, vec![transaction])
+}
+
 pub fn start_vrf_lotto(_null: ()) {
     match FULLNODE_KEYS.lock() {
         Ok(lock) => {
+            let keys = lock.clone();
+            drop(lock); // enacting the announceEpochSaltSeeds txn calls the VRF lottery callbacks, which lock FULLNODE_KEYS
             let current_epoch = get_top_epoch().unwrap_or_default();
+            if current_epoch.stage != EpochStage::Reorg && current_epoch.stage != EpochStage::Main {
+                debug!(
+                    "Epoch {} in stage {:?}, not starting VRF lotto",
+                    current_epoch.epoch_number, current_epoch.stage
+                );
+                return;
+            }
             if current_epoch.committees[0]
                 .get_round_leader()
                 .unwrap_or_default()
-                == lock[0]
+                == keys[0]
             {
                 info!("Consensus committee round leader, coordinating epoch salt formation");
-                // TODO: Send a generateEpochSalt p2p message to every peer in our GUID, collect the responses, then form a announceSaltSeeds txn and process
+                if let Err(e) = coordinate_epoch_salt(&keys, &current_epoch) {
+                    error!("Failed to coordinate epoch salt formation, error={}", e);
+                }
             } else {
                 debug!(
                     "Not consensus committee round leader, not coordinating epoch salt formation"
@@ -557,6 +930,84 @@ pub fn start_vrf_lotto(_null: ()) {
     }
 }
 
+/// Called when the VRF lottery starts, if we are the consensus committee round leader we pay out the rewards for the epoch
+pub fn handle_epoch_rewards() {
+    // the lottery callbacks are called while enacting the announceEpochSaltSeeds txn, so issue the rewards on a new thread
+    create_timer(Duration::from_millis(0), Box::new(issue_epoch_rewards), ());
+}
+
+pub fn issue_epoch_rewards(_null: ()) {
+    match FULLNODE_KEYS.lock() {
+        Ok(lock) => {
+            let keys = lock.clone();
+            drop(lock); // enacting the reward txn calls the block enacted callbacks, which may lock FULLNODE_KEYS
+            let current_epoch = get_top_epoch().unwrap_or_default();
+            if current_epoch.committees.is_empty()
+                || current_epoch.committees[0]
+                    .get_round_leader()
+                    .unwrap_or_default()
+                    != keys[0]
+            {
+                debug!("Not consensus committee round leader, not issuing epoch rewards");
+                return;
+            }
+            let rewards = match epoch_rewards(current_epoch.epoch_number) {
+                Ok(rewards) => rewards,
+                Err(e) => {
+                    error!(
+                        "Failed to get rewards for epoch {}, error={}",
+                        current_epoch.epoch_number, e
+                    );
+                    return;
+                }
+            };
+            if rewards.is_empty() {
+                info!(
+                    "No rewards owed for epoch {}, not issuing reward txn",
+                    current_epoch.epoch_number
+                );
+                return;
+            }
+            let total: u64 = rewards.iter().map(|(_, reward)| reward).sum();
+            info!(
+                "Consensus committee round leader, issuing rewards for epoch {} to {} fullnodes, total={}",
+                current_epoch.epoch_number,
+                rewards.len(),
+                total
+            );
+            let mut txn = Transaction {
+                hash: String::from(""),
+                amount: total,
+                extra: bs58::encode(serde_json::to_string(&rewards).unwrap_or_default())
+                    .into_string(),
+                flag: 'r',
+                sender_key: keys[0].clone(),
+                receive_key: String::from("0"),
+                access_key: String::from(""),
+                unlock_time: 0,
+                gas_price: 1,
+                max_gas: u64::MAX,
+                nonce: get_nonce(keys[0].clone()),
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_millis() as u64,
+            };
+            txn.hash();
+            if let Err(reward_error) = produce_consensus_blocks(&keys, vec![txn]) {
+                error!(
+                    "Failed to broadcast epoch rewards for epoch {}, got error={}",
+                    current_epoch.epoch_number, reward_error
+                );
+            }
+        }
+        Err(lock_error) => error!(
+            "Failed to get mutex lock on FULLNODE_KEYS lazy static, error={}",
+            lock_error
+        ),
+    }
+}
+
 pub fn handle_vrf_lottery() {
     info!(
         "VRF lottery started, participating={}",
@@ -567,23 +1018,29 @@ pub fn handle_vrf_lottery() {
     }
     let current_epoch = get_top_epoch().unwrap_or_default();
     let next_epoch = Epoch::get(current_epoch.epoch_number + 1).unwrap_or_default();
-    let vrf_seed = raw_hash(&(format!("{}{}{}", current_epoch.salt, next_epoch.salt, "-vrflotto")));
+    let vrf_seed = lotto_seed(&current_epoch, &next_epoch);
     debug!("VRF seed: {}", vrf_seed);
     match FULLNODE_KEYS.lock() {
         Ok(lock) => {
-            let vrf_proof = get_vrf(lock[1].clone(), vrf_seed).unwrap_or_default();
+            let vrf_proof = get_vrf(lock[5].clone(), vrf_seed).unwrap_or_default();
             // Now we check if the VRF fufills the criteria for eclosion
-            // for now this is not done
-            let vrf_value = vrf_hash_to_integer(vrf_proof.1);
+            let vrf_value = ticket_value(&vrf_proof.0).unwrap_or(u64::MAX);
+            let (_, threshold) = lottery_threshold(&current_epoch);
             info!(
-                "Created VRF lottery entry, ticket={:.4}, requirement={}, viable={}",
-                vrf_value, 1, true
+                "Created VRF lottery entry, ticket={}, requirement={}, viable={}",
+                vrf_value,
+                threshold,
+                vrf_value <= threshold
             );
+            if vrf_value > threshold {
+                // non viable tickets are rejected, dont waste the fee
+                return;
+            }
             // now form a txn, place in block and send to network
             let mut txn = Transaction {
                 hash: String::from(""),
                 amount: 0,
-                extra: bs58::encode(vrf_proof.0).into_string(),
+                extra: vrf_proof.0,
                 flag: 'v',
                 sender_key: lock[0].clone(),
                 receive_key: String::from("0"),
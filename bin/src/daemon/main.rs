@@ -9,10 +9,15 @@ use std::{
 };
 use std::panic;
 use avrio_core::{
-    account::to_dec,
+    account::{get_nonce, to_dec},
+    archive::{export, import},
     certificate::{generate_certificate, get_fullnode_count},
-    epoch::{get_top_epoch, Epoch},
+    epoch::{get_top_epoch, Epoch, EpochStage, EPOCH_STAGE_CALLBACKS},
+    finality::BLOCK_FINALIZED_CALLBACKS,
+    fork::FORK_CALLBACKS,
+    integrity::{check, rebuild},
     invite::{generate_invite, new_invite},
+    prune::{is_pruning, prune, prune_in_background},
     states::form_state_digest,
     timer::create_timer,
     transaction::{
@@ -30,13 +35,13 @@ use std::process;
 pub extern crate avrio_config;
 use avrio_config::config;
 use std::net::{SocketAddr, TcpStream};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 extern crate avrio_core;
 
 use avrio_p2p::{
     core::new_connection,
     core::rec_server,
-    helper::{prop_block, sync_in_order, sync_needed},
+    helper::{bootstrap_from_snapshot, prop_block, sync_in_order, sync_needed},
 };
 
 use avrio_core::{
@@ -45,7 +50,10 @@ use avrio_core::{
 };
 
 extern crate avrio_database;
-use avrio_database::{get_data, get_peerlist};
+use avrio_database::{
+    get_peerlist,
+    store::{self, Table},
+};
 #[macro_use]
 extern crate log;
 
@@ -55,10 +63,8 @@ use avrio_api::start_server;
 
 extern crate avrio_crypto;
 use avrio_crypto::{
-    generate_secp256k1_keypair, get_vrf, private_to_public_secp256k1, proof_to_hash, raw_hash,
-    vrf_hash_to_integer, Wallet,
+    generate_secp256k1_keypair, get_vrf, private_to_public_secp256k1, raw_hash, Wallet,
 };
-use bigdecimal::BigDecimal;
 use fern::colors::{Color, ColoredLevelConfig};
 
 use lazy_static::lazy_static;
@@ -73,7 +79,7 @@ pub fn safe_exit() {
 
     info!("Goodbye!");
     let _ = avrio_p2p::core::close_all();
-    avrio_database::close_flush_stream();
+    avrio_database::close_database();
     (*(MEMPOOL.lock().unwrap()))
         .as_ref()
         .unwrap()
@@ -233,21 +239,15 @@ fn generate_chains() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 fn database_present() -> bool {
-    let get_res = get_data(
-        config().db_path + &"/chains/masterchainindex".to_owned(),
-        &"digest".to_owned(),
-    );
-    !(get_res == *"-1" || get_res == *"0")
+    match store::get_raw(&Table::MasterChainIndex, "digest") {
+        Ok(Some(digest)) => digest != "0",
+        _ => false,
+    }
 }
 
 fn create_file_structure() -> std::result::Result<(), Box<dyn std::error::Error>> {
     info!("Creating datadir folder structure");
-    create_dir_all(config().db_path + &"/blocks".to_string())?;
-    create_dir_all(config().db_path + &"/chains".to_string())?;
-    create_dir_all(config().db_path + &"/wallets".to_string())?;
     create_dir_all(config().db_path + &"/keystore".to_string())?;
-    create_dir_all(config().db_path + &"/accounts".to_string())?;
-    create_dir_all(config().db_path + &"/usernames".to_string())?;
     info!("Created datadir folder structure");
     Ok(())
 }
@@ -283,6 +283,30 @@ fn main() {
         .author("Leo Cornelius")
         .subcommand(App::new("seednode").about("Runs the node as a seednode"))
         .subcommand(App::new("generate_keypair").about("Generates a fullnode keypair and exits"))
+        .subcommand(
+            App::new("export")
+                .about("Exports every epoch, block chunk and block to an archive file and exits")
+                .arg(Arg::with_name("file").required(true).index(1)),
+        )
+        .subcommand(
+            App::new("import")
+                .about("Validates and enacts the epochs, block chunks and blocks of an archive file and exits")
+                .arg(Arg::with_name("file").required(true).index(1)),
+        )
+        .subcommand(
+            App::new("check_db")
+                .about("Checks the chain indexes, transactions index, balances and digests against the stored blocks and exits")
+                .arg(
+                    Arg::with_name("rebuild")
+                        .long("rebuild")
+                        .takes_value(false)
+                        .help("Rebuilds the chain indexes, transactions index and digests from the stored blocks, then checks again"),
+                ),
+        )
+        .subcommand(
+            App::new("migrate_db")
+                .about("Upgrades the data directory to the current schema version and exits"),
+        )
         .arg(
             Arg::with_name("conf")
                 .short("c")
@@ -353,6 +377,17 @@ fn main() {
     }
     let conf = config();
     conf.create().unwrap();
+    match avrio_database::schema::upgrade() {
+        Ok(version) => debug!("Data directory schema version {}", version),
+        Err(e) => {
+            error!("Can not use data directory, error={}", e);
+            process::exit(1);
+        }
+    }
+    if matches.subcommand_matches("migrate_db").is_some() {
+        info!("Data directory is up to date");
+        process::exit(0);
+    }
     if config().node_type == 'c' {
         info!("Running as candidate, loading keys");
         let keys = open_keypair();
@@ -370,6 +405,8 @@ fn main() {
                     Ok(mut lock) => {
                         debug!("Got mutex lock on VRF_LOTTERY_CALLBACKS lazy static ");
                         lock.push(Box::new(handle_vrf_lottery));
+                        lock.push(Box::new(handle_epoch_rewards));
+                        lock.push(Box::new(handle_vrf_lotto_end));
                         debug!("Registered in VRF_LOTTERY_CALLBACKS");
                     }
                     Err(lock_error) => {
@@ -380,6 +417,45 @@ fn main() {
                         process::exit(0);
                     }
                 }
+                match VRF_TICKET_SUBMITTED.lock() {
+                    Ok(mut lock) => {
+                        lock.push(Box::new(handle_vrf_ticket));
+                        debug!("Registered in VRF_TICKET_SUBMITTED");
+                    }
+                    Err(lock_error) => {
+                        error!(
+                            "Failed to gain mutex lock on VRF_TICKET_SUBMITTED lazy static, got error={}",
+                            lock_error
+                        );
+                        process::exit(0);
+                    }
+                }
+                match avrio_p2p::handle::EPOCH_SALT_SEED_HANDLER.lock() {
+                    Ok(mut lock) => {
+                        *lock = Some(Box::new(create_epoch_salt_seed));
+                        debug!("Registered EPOCH_SALT_SEED_HANDLER");
+                    }
+                    Err(lock_error) => {
+                        error!(
+                            "Failed to gain mutex lock on EPOCH_SALT_SEED_HANDLER lazy static, got error={}",
+                            lock_error
+                        );
+                        process::exit(0);
+                    }
+                }
+                match avrio_p2p::handle::CONSENSUS_BLOCK_SIGNER.lock() {
+                    Ok(mut lock) => {
+                        *lock = Some(Box::new(sign_proposed_consensus_block));
+                        debug!("Registered CONSENSUS_BLOCK_SIGNER");
+                    }
+                    Err(lock_error) => {
+                        error!(
+                            "Failed to gain mutex lock on CONSENSUS_BLOCK_SIGNER lazy static, got error={}",
+                            lock_error
+                        );
+                        process::exit(0);
+                    }
+                }
                 match EPOCH_STARTED_CALLBACKS.lock() {
                     Ok(mut lock) => {
                         debug!("Got mutex lock on EPOCH_STARTED_CALLBACKS lazy static ");
@@ -394,6 +470,19 @@ fn main() {
                         process::exit(0);
                     }
                 }
+                match EPOCH_STAGE_CALLBACKS.lock() {
+                    Ok(mut lock) => {
+                        lock.push(Arc::new(handle_epoch_stage));
+                        debug!("Registered in EPOCH_STAGE_CALLBACKS");
+                    }
+                    Err(lock_error) => {
+                        error!(
+                            "Failed to gain mutex lock on EPOCH_STAGE_CALLBACKS lazy static, got error={}",
+                            lock_error
+                        );
+                        process::exit(0);
+                    }
+                }
                 if get_fullnode_count() == 0 {
                     let now = SystemTime::now()
                         .duration_since(UNIX_EPOCH)
@@ -466,14 +555,68 @@ fn main() {
     let _ = mempool.save_to_disk(&(config().db_path + "/mempool")); // create files
     *(MEMPOOL.lock().unwrap()) = Some(mempool);
     info!("Avrio Daemon successfully launched");
-    let mut statedigest = get_data(config().db_path + &"/chaindigest".to_owned(), "master");
-    if statedigest == *"-1" {
-        generate_chains().unwrap();
-        statedigest =
-            form_state_digest(config().db_path + &"/chaindigest".to_owned()).unwrap_or_default();
-        info!("State digest: {}", statedigest);
-    } else {
-        info!("State digest: {}", statedigest);
+    let statedigest = match store::get_raw(&Table::ChainDigest, "master") {
+        Ok(Some(statedigest)) => statedigest,
+        _ => {
+            generate_chains().unwrap();
+            form_state_digest().unwrap_or_default()
+        }
+    };
+    info!("State digest: {}", statedigest);
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        let path = export_matches.value_of("file").unwrap_or_default();
+        match export(path) {
+            Ok(_) => safe_exit(),
+            Err(e) => {
+                error!("Failed to export archive {}, error={}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(import_matches) = matches.subcommand_matches("import") {
+        let path = import_matches.value_of("file").unwrap_or_default();
+        match import(path) {
+            Ok(_) => safe_exit(),
+            Err(e) => {
+                error!("Failed to import archive {}, error={}", path, e);
+                avrio_database::close_database();
+                process::exit(1);
+            }
+        }
+    }
+    if let Some(check_matches) = matches.subcommand_matches("check_db") {
+        if check_matches.is_present("rebuild") {
+            if let Err(e) = rebuild() {
+                error!("Failed to rebuild indexes, error={}", e);
+                avrio_database::close_database();
+                process::exit(1);
+            }
+        }
+        match check() {
+            Ok(report) => {
+                for discrepancy in &report.discrepancies {
+                    warn!("{}", discrepancy);
+                }
+                info!(
+                    "Checked database, chains={}, blocks={}, transactions={}, accounts={}, pruned={}, discrepancies={}",
+                    report.chains,
+                    report.blocks,
+                    report.transactions,
+                    report.accounts,
+                    report.pruned,
+                    report.discrepancies.len()
+                );
+                if report.discrepancies.is_empty() {
+                    safe_exit();
+                }
+                if !check_matches.is_present("rebuild") {
+                    info!("Run check_db --rebuild to rebuild the indexes and digests from the stored blocks");
+                }
+            }
+            Err(e) => error!("Failed to check database, error={}", e),
+        }
+        avrio_database::close_database();
+        process::exit(1);
     }
     info!(
         "Launching P2p server on {}:{}",
@@ -528,6 +671,10 @@ fn main() {
         let _ = new_connection(&peer.to_string());
     }
     if !matches.is_present("no-sync") {
+        // start from the state snapshot of the checkpoint epoch (if one is set) rather than replaying every block before it
+        if let Err(e) = bootstrap_from_snapshot() {
+            error!("Failed to bootstrap from state snapshot, syncing every block instead. Error={}", e);
+        }
         let syncneed = sync_needed();
 
         match syncneed {
@@ -566,6 +713,64 @@ fn main() {
         launch(17785);
     });
     info!("Launched RPC server on port=17785");
+    match BLOCK_FINALIZED_CALLBACKS.lock() {
+        Ok(mut lock) => {
+            lock.push(Box::new(|block_hash, chunk_hash| {
+                if let Err(e) = finalized_announce(block_hash, chunk_hash) {
+                    error!("Failed to announce finalized block, error={}", e);
+                }
+            }));
+            debug!("Registered in BLOCK_FINALIZED_CALLBACKS");
+        }
+        Err(lock_error) => {
+            error!(
+                "Failed to gain mutex lock on BLOCK_FINALIZED_CALLBACKS lazy static, got error={}",
+                lock_error
+            );
+            process::exit(0);
+        }
+    }
+    match FORK_CALLBACKS.lock() {
+        Ok(mut lock) => {
+            lock.push(Box::new(|conflict| {
+                if let Err(e) = fork_announce(conflict) {
+                    error!("Failed to announce fork, error={}", e);
+                }
+            }));
+            debug!("Registered in FORK_CALLBACKS");
+        }
+        Err(lock_error) => {
+            error!(
+                "Failed to gain mutex lock on FORK_CALLBACKS lazy static, got error={}",
+                lock_error
+            );
+            process::exit(0);
+        }
+    }
+    if is_pruning() {
+        // catch up on the epochs which started while we were offline, then prune as each epoch starts
+        if let Err(e) = prune() {
+            error!("Failed to prune old blocks, error={}", e);
+        }
+        match EPOCH_STARTED_CALLBACKS.lock() {
+            Ok(mut lock) => {
+                lock.push(Box::new(prune_in_background));
+                debug!("Registered in EPOCH_STARTED_CALLBACKS");
+            }
+            Err(lock_error) => {
+                error!(
+                    "Failed to gain mutex lock on EPOCH_STARTED_CALLBACKS lazy static, got error={}",
+                    lock_error
+                );
+                process::exit(0);
+            }
+        }
+    }
+    if config().node_type == 'f' {
+        if let Err(e) = resume_epoch() {
+            error!("Failed to resume epoch, error={}", e);
+        }
+    }
     loop {
         // Now we loop until shutdown
         let _ = io::stdout().flush();
@@ -583,10 +788,12 @@ fn main() {
                         *param = "";
                     }
                 }
-                info!(
-                    "Got data: {}",
-                    get_data(config().db_path + "/" + params[1], params[2])
-                );
+                let table = Table::from_path(&(config().db_path + "/" + params[1]));
+                match store::get_raw(&table, params[2]) {
+                    Ok(Some(data)) => info!("Got data: {}", data),
+                    Ok(None) => info!("Got data: -1"),
+                    Err(e) => error!("Failed to read {} from {}, error={}", params[2], params[1], e),
+                }
             }
         } else if read == "address_details" {
             info!("Enter the address of the account.");
@@ -630,10 +837,9 @@ fn main() {
         } else if read == "get_transaction" {
             info!("Enter the transaction hash:");
             let hash: String = read!("{}\n");
-            let block_txn_is_in = get_data(config().db_path + &"/transactions".to_owned(), &hash);
-            if block_txn_is_in == *"-1" {
-                error!("Can not find that txn in db");
-            } else {
+            if let Some(block_txn_is_in) =
+                store::get_raw(&Table::Transactions, &hash).ok().flatten()
+            {
                 let blk: Block = get_block_from_raw(block_txn_is_in);
                 if blk == Block::default() {
                     error!("Couldnt find a block with that transaction in");
@@ -666,6 +872,8 @@ fn main() {
                         }
                     }
                 }
+            } else {
+                error!("Can not find that txn in db");
             }
         } else if read == "get_account" {
             info!("Enter the public key of the account:");
@@ -689,12 +897,10 @@ fn main() {
             info!("Enter lock commitment: (tip you get this from locking funds in the wallet!)");
             let commitment: String = read!();
             // the commitment should be a valid txn hash, check it
-            let block_txn_is_in =
-                get_data(config().db_path + &"/transactions".to_owned(), &commitment);
             let mut commitment_txn: Transaction = Transaction::default();
-            if block_txn_is_in == *"-1" {
-                error!("Can not find comitment");
-            } else {
+            if let Some(block_txn_is_in) =
+                store::get_raw(&Table::Transactions, &commitment).ok().flatten()
+            {
                 let blk: Block = get_block_from_raw(block_txn_is_in);
                 if blk == Block::default() {
                     error!("Couldnt find a block with that commitment in");
@@ -705,6 +911,8 @@ fn main() {
                         }
                     }
                 }
+            } else {
+                error!("Can not find comitment");
             }
             if commitment_txn == Transaction::default() {
                 error!("Block did not contain commitment as expected");
@@ -800,28 +1008,15 @@ pub fn register_fullnode(
             unlock_time: 0,
             gas_price: 20,
             max_gas: u64::MAX,
-            nonce: get_data(
-                config().db_path
-                    + &"/chains/".to_owned()
-                    + &wallet.public_key
-                    + &"-chainindex".to_owned(),
-                &"txncount".to_owned(),
-            )
-            .parse()
-            .unwrap_or(0),
+            nonce: get_nonce(wallet.public_key.clone()),
             timestamp: SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("time went backwards ONO")
                 .as_millis() as u64,
         };
         txn.hash();
-        let prev_block = get_block_from_raw(get_data(
-            config().db_path
-                + &"/chains/".to_owned()
-                + &wallet.public_key
-                + &"-chainindex".to_owned(),
-            "topblockhash",
-        ));
+        let prev_block =
+            get_block_from_raw(top_block_hash(&wallet.public_key).unwrap_or_default());
         let mut send_block = Block {
             header: Header {
                 version_major: 0,
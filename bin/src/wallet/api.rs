@@ -14,7 +14,7 @@ use avrio_blockchain::{
 };
 use avrio_config::config;
 use avrio_core::{account::{get_account, to_atomc}, transaction::Transaction};
-use avrio_database::{get_data, save_data};
+use avrio_database::store::{self, Table};
 use log::*;
 use rocket::{routes, Route};
 use std::io::prelude::*;
@@ -258,8 +258,8 @@ fn save_wallet(
         aead.encrypt(nonce, keypair[1].as_bytes().as_ref())
             .expect("wallet private key encryption failure!"),
     );
-    let _ = save_data(&publickey_en, &path, "pubkey".to_owned());
-    let _ = save_data(&privatekey_en, &path, "privkey".to_owned());
+    let _ = store::put_raw(&Table::Wallet(name.clone()), "pubkey", &publickey_en);
+    let _ = store::put_raw(&Table::Wallet(name.clone()), "privkey", &privatekey_en);
     info!("Saved wallet to {}", path);
     conf.chain_key = keypair[0].clone();
     conf.create()?;
@@ -273,11 +273,7 @@ pub fn create_wallet(
     privatekey: String,
     authkey: String,
 ) -> String {
-    if avrio_database::get_data(
-        config().db_path + &"/wallets/".to_owned() + &walletname,
-        "pubkey",
-    ) != "-1"
-    {
+    if store::contains(&Table::Wallet(walletname.clone()), "pubkey").unwrap_or(false) {
         error!("Wallet with name={} already exists", walletname);
         return "{\"success\": false, \"error\": \"wallet with name already exists\" }".into();
     } else {
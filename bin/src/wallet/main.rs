@@ -16,7 +16,7 @@ use avrio_core::block::{
 };
 use avrio_core::{account::*, transaction::Transaction};
 use avrio_crypto::Wallet;
-use avrio_database::*;
+use avrio_database::{store::Table, *};
 use avrio_rpc::{launch_client, Announcement, Caller};
 use clap::{App, Arg};
 use fern::colors::{Color, ColoredLevelConfig};
@@ -1452,7 +1452,7 @@ pub fn create_wallet() -> Result<Wallet, Box<dyn Error>> {
     let name: String = trim_newline(&mut read!());
     info!("Enter password:");
     let password: String = rpassword::read_password()?;
-    if get_data(config().db_path + &"/wallets/".to_owned() + &name, "pubkey") != "-1" {
+    if store::contains(&Table::Wallet(name.clone()), "pubkey").unwrap_or(false) {
         error!("Wallet already exists");
         return Err("Wallet path taken".into());
     } else {
@@ -1473,7 +1473,7 @@ fn import_wallet() -> Result<Wallet, Box<dyn Error>> {
     let private_key: String = trim_newline(&mut read!());
     info!("Please enter name of new wallet");
     let name: String = trim_newline(&mut read!());
-    if get_data(config().db_path + &"/wallets/".to_owned() + &name, "pubkey") != "-1" {
+    if store::contains(&Table::Wallet(name.clone()), "pubkey").unwrap_or(false) {
         error!("Wallet with name={} already exists", name);
         return Err("wallet with name already exists".into());
     }
@@ -1528,8 +1528,8 @@ pub fn save_wallet(
         aead.encrypt(nonce, keypair[1].as_bytes().as_ref())
             .expect("wallet private key encryption failure!"),
     );
-    let _ = save_data(&publickey_en, &path, "pubkey".to_owned());
-    let _ = save_data(&privatekey_en, &path, "privkey".to_owned());
+    let _ = store::put_raw(&Table::Wallet(name.clone()), "pubkey", &publickey_en);
+    let _ = store::put_raw(&Table::Wallet(name.clone()), "privkey", &privatekey_en);
     info!("Saved wallet to {}", path);
     Ok(())
 }
@@ -1559,10 +1559,11 @@ pub fn open_wallet(wallet_name: String, password: String) -> Wallet {
     let padded_string = String::from_utf8(padded).unwrap();
     let nonce = GenericArray::from_slice(padded_string.as_bytes()); // 96-bits; unique per message
     trace!("nonce: {}", padded_string);
-    let ciphertext = hex::decode(get_data(
-        config().db_path + &"/wallets/".to_owned() + &wallet_name,
-        &"privkey".to_owned(),
-    ))
+    let ciphertext = hex::decode(
+        store::get_raw(&Table::Wallet(wallet_name.clone()), "privkey")
+            .expect("failed to read wallet")
+            .expect("wallet private key not found"),
+    )
     .expect("failed to parse hex");
     let privkey = String::from_utf8(
         aead.decrypt(nonce, ciphertext.as_ref())
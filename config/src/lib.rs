@@ -43,6 +43,7 @@ pub struct NetworkConfig {
     pub min_suported_version: Vec<u8>,
     pub max_supported_version: Vec<u8>,
     pub target_committee_count: u64, // the ideal number of committes to have,if there is not enough fullnodes this will not be reached
+    pub min_committee_size: u64, // the smallest a committee can be, fewer committees are formed rather than go below this
 }
 
 /// This is what is saved in a file, the stuff the user can change and edit to fit their needs
@@ -67,6 +68,10 @@ pub struct ConfigSave {
     pub max_syncing_peers: u64,
     pub first_epoch_time: u64,
     pub god_account: String, // publickey of the 'god account', the first fullnode who starts the first epoch
+    #[serde(default)]
+    pub snapshot_checkpoint: String, // "<epoch number>:<epoch hash>" of a trusted epoch to bootstrap from its state snapshot, empty to replay every block
+    #[serde(default)]
+    pub prune_keep_epochs: u64, // keep the block bodies of only the last n epochs (a pruned node), 0 to keep every block
 }
 
 /// This is the entire config - this is what is passed arround in software and what you should use in anything your build
@@ -113,6 +118,10 @@ pub struct Config {
     pub max_syncing_peers: u64,
     pub first_epoch_time: u64,
     pub god_account: String,
+    pub snapshot_checkpoint: String,
+    pub prune_keep_epochs: u64,
+    pub target_committee_count: u64,
+    pub min_committee_size: u64,
 }
 
 pub fn config_read(path: &str) -> Config {
@@ -174,6 +183,8 @@ impl Default for ConfigSave {
             max_syncing_peers: 8,
             first_epoch_time: 0,
             god_account: String::from(""),
+            snapshot_checkpoint: String::from(""),
+            prune_keep_epochs: 0,
         }
     }
 }
@@ -224,6 +235,10 @@ impl ConfigSave {
             max_syncing_peers: self.max_syncing_peers,
             first_epoch_time: self.first_epoch_time,
             god_account: self.god_account.to_owned(),
+            snapshot_checkpoint: self.snapshot_checkpoint.to_owned(),
+            prune_keep_epochs: self.prune_keep_epochs,
+            target_committee_count: nconf.target_committee_count,
+            min_committee_size: nconf.min_committee_size,
         }
     }
 }
@@ -251,7 +266,7 @@ impl Default for NetworkConfig {
             max_reward: 25000, // 2.5000 AIO
             min_vote: 65,
             probatory_epoch_count: 10,
-            certificate_difficulty: 1000, // TODO find this value
+            certificate_difficulty: 8, // leading zero bits, the minimum certificate difficulty (it is retargeted each epoch) TODO find this value
             fullnode_lock_amount: 50000,
             transaction_timestamp_max_offset: 600000, // 10 mins
             max_time_to_live: 600000,                 // millisecconds
@@ -262,6 +277,7 @@ impl Default for NetworkConfig {
             min_suported_version: vec![0, 1, 0],
             max_supported_version: vec![0, 1, 0],
             target_committee_count: 2, // consensus & normal
+            min_committee_size: 4,
         }
     }
 }
@@ -288,6 +304,8 @@ impl Config {
             max_syncing_peers: self.max_syncing_peers,
             first_epoch_time: self.first_epoch_time,
             god_account: self.god_account,
+            snapshot_checkpoint: self.snapshot_checkpoint,
+            prune_keep_epochs: self.prune_keep_epochs,
         }
     }
 
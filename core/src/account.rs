@@ -3,9 +3,8 @@ extern crate avrio_database;
 use serde::{Deserialize, Serialize};
 extern crate avrio_config;
 use avrio_config::config;
+use avrio_database::store::{get, get_raw, put, put_raw, Table};
 use std::convert::TryInto;
-use std::fs::File;
-use std::io::prelude::*;
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
 pub struct Accesskey {
     // Access keys are keys that provide limited access to a wallet - it allows one wallet to be split
@@ -40,13 +39,10 @@ pub fn to_dec(amount: u64) -> f64 {
 }
 
 pub fn get_nonce(publickey: String) -> u64 {
-    avrio_database::get_data(
-        config().db_path
-            + &"/chains/".to_owned()
-            + &publickey
-            + &"-chainindex".to_owned(),
-        &"txncount".to_owned(),
-    ).parse().unwrap_or(0)
+    avrio_database::store::get(&avrio_database::store::Table::ChainIndex(publickey), "txncount")
+        .ok()
+        .flatten()
+        .unwrap_or(0)
 }
 
 impl Account {
@@ -96,59 +92,30 @@ impl Account {
 /// Gets the account assosiated with the username provided
 /// if the account or the username does not exist it returns an err
 pub fn get_by_username(username: &str) -> Result<Account, String> {
-    let path =
-        config().db_path + &"/usernames/".to_owned() + &avrio_crypto::raw_hash(username) + ".uname";
-    if let Ok(mut file) = File::open(path) {
-        let mut contents = String::new();
-        let _ = file.read_to_string(&mut contents);
-        Ok(get_account(&contents).unwrap_or_default())
-    } else {
-        Err("failed to open file".to_owned())
+    match get_raw(&Table::Usernames, &avrio_crypto::raw_hash(username)) {
+        Ok(Some(public_key)) => Ok(get_account(&public_key).unwrap_or_default()),
+        Ok(None) => Err("username not found".to_owned()),
+        Err(e) => Err(e.to_string()),
     }
 }
 
 pub fn set_account(acc: &Account) -> u8 {
-    let path = config().db_path + "/accounts/" + &acc.public_key + ".account";
-    let serialized: String;
     let get_acc_old = get_account(&acc.public_key);
     if let Ok(deserialized) = get_acc_old {
         if acc.username != deserialized.username && deserialized != Account::default() {
-            let upath = config().db_path
-                + &"/usernames/".to_owned()
-                + &avrio_crypto::raw_hash(&acc.username)
-                + ".uname";
             debug!("saving uname: {}", acc.username);
-            let filetry = File::create(upath);
-            if let Ok(mut file) = filetry {
-                if file.write_all(&acc.public_key.as_bytes()).is_err() {
-                    return 0;
-                }
-            } else {
-                error!(
-                    "Failed to save username, creating file gave error: {}",
-                    filetry.unwrap_err(),
-                );
+            if let Err(e) = put_raw(
+                &Table::Usernames,
+                &avrio_crypto::raw_hash(&acc.username),
+                &acc.public_key,
+            ) {
+                error!("Failed to save username, gave error: {}", e);
                 return 0;
             }
         }
     }
-    serialized = serde_json::to_string(&acc).unwrap_or_else(|e| {
-        error!("Unable To Serilise Account, gave error {}, retrying", e);
-        serde_json::to_string(&acc).unwrap_or_else(|et| {
-            error!("Retry Failed with error: {}", et);
-            panic!("Failed to serilise account");
-        })
-    });
-    let filetry = File::create(path);
-    if let Ok(mut file) = filetry {
-        if file.write_all(&serialized.as_bytes()).is_err() {
-            return 0;
-        }
-    } else {
-        error!(
-            "Failed to save account, creating file gave error: {}",
-            filetry.unwrap_err(),
-        );
+    if let Err(e) = put(&Table::Accounts, &acc.public_key, acc) {
+        error!("Failed to save account, gave error: {}", e);
         return 0;
     }
     1
@@ -156,17 +123,10 @@ pub fn set_account(acc: &Account) -> u8 {
 /// Gets the account assosiated with the public_key provided
 /// if the account does not exist it returns an err
 pub fn get_account(public_key: &str) -> Result<Account, u8> {
-    let path = config().db_path + &"/accounts/".to_owned() + public_key + ".account";
-    if let Ok(mut file) = File::open(path) {
-        let mut contents = String::new();
-        let _ = file.read_to_string(&mut contents);
-        if let Ok(acc) = serde_json::from_str(&contents) {
-            Ok(acc)
-        } else {
-            Err(2)
-        }
-    } else {
-        Err(0)
+    match get(&Table::Accounts, public_key) {
+        Ok(Some(acc)) => Ok(acc),
+        Ok(None) => Err(0),
+        Err(_) => Err(2),
     }
 }
 
@@ -277,3 +237,22 @@ pub fn delta_funds(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_delta_funds() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert_eq!(set_account(&Account::new("abc".to_owned())), 1);
+            delta_funds("abc", 50, 1, String::new()).unwrap();
+            assert!(delta_funds("abc", 60, 0, String::new()).is_err());
+            delta_funds("abc", 20, 0, String::new()).unwrap();
+            assert_eq!(get_account("abc").unwrap().balance, 30);
+            assert!(get_account("def").is_err());
+        });
+    }
+}
@@ -0,0 +1,456 @@
+// This file exports the DAG (every epoch, block chunk and block) to a portable archive file, and imports it, so a new node can be seeded from a file rather than syncing over p2p.
+// The archive is json lines: a header (magic and format version), the records in topological order (an epoch, then its chunks, each after the blocks
+// they contain; a block after its previous block and, for recieve blocks, its send block; a consensus send block after its signatures), then a footer
+// with the record count and the sha256 checksum of the record lines.
+// Imported records are checked against the checksum before any are used, then validated and enacted through the normal Verifiable path.
+// Epochs are never imported as is, they are produced by enacting the consensus chain; each archived epoch is checked against the epoch the import produced.
+extern crate avrio_database;
+
+use avrio_database::store::{self, Table};
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+use crate::{
+    block::{get_block, get_block_from_raw, Block, BlockType},
+    chunk::{chunk_index, BlockChunk},
+    consensus::ConsensusSignatures,
+    epoch::{get_top_epoch, Epoch},
+    prune::pruned_below,
+    validate::Verifiable,
+};
+
+/// The first field of every archive
+const ARCHIVE_MAGIC: &str = "avrio-archive";
+/// The format version of the archives we write, we read archives up to this version
+pub const ARCHIVE_VERSION: u64 = 2; // 2: consensus signatures
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ArchiveHeader {
+    magic: String,
+    version: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "kind", content = "data")]
+enum ArchiveRecord {
+    /// A json encoded epoch
+    Epoch(String),
+    /// A compressed encoded block
+    Block(String),
+    /// An encoded block chunk
+    Chunk(String),
+    /// The json encoded signatures of a consensus send block
+    Signatures(String),
+    /// The end of the archive, with the number of records and the hex sha256 checksum of their lines
+    Footer { records: u64, checksum: String },
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ArchiveSummary {
+    pub epochs: u64,
+    pub blocks: u64,
+    pub chunks: u64,
+    /// Records which were not imported because we already had them
+    pub skipped: u64,
+}
+
+struct ArchiveWriter {
+    file: BufWriter<File>,
+    checksum: Context,
+    records: u64,
+}
+
+impl ArchiveWriter {
+    fn write(&mut self, record: &ArchiveRecord) -> Result<(), Box<dyn std::error::Error>> {
+        let line = serde_json::to_string(record)? + "\n";
+        self.checksum.update(line.as_bytes());
+        self.file.write_all(line.as_bytes())?;
+        self.records += 1;
+        Ok(())
+    }
+}
+
+/// Returns the hashes of the blocks the block depends on: its previous block and, for recieve blocks, its send block
+fn dependencies(block: &Block) -> Vec<String> {
+    let mut dependencies: Vec<String> = vec![];
+    if block.header.height != 0 {
+        dependencies.push(block.header.prev_hash.clone());
+    }
+    if let Some(send_block) = &block.send_block {
+        dependencies.push(send_block.clone());
+    }
+    dependencies
+}
+
+/// Writes the block, after any of its dependencies which have not been written yet
+fn write_block(
+    writer: &mut ArchiveWriter,
+    written: &mut HashSet<String>,
+    summary: &mut ArchiveSummary,
+    hash: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // depth first, without recursion as chains can be very long
+    let mut stack: Vec<(String, bool)> = vec![(hash.to_owned(), false)];
+    while let Some((hash, dependencies_written)) = stack.pop() {
+        if written.contains(&hash) {
+            continue;
+        }
+        let block = get_block_from_raw(hash.clone());
+        if block.is_default() {
+            warn!("Block {} is missing, not exporting it", hash);
+            continue;
+        }
+        if dependencies_written {
+            if block.header.chain_key == "0" && block.block_type == BlockType::Send {
+                // consensus send blocks are only valid once we have their signatures
+                match ConsensusSignatures::get(hash.clone()) {
+                    Ok(signatures) => writer.write(&ArchiveRecord::Signatures(
+                        serde_json::to_string(&signatures)?,
+                    ))?,
+                    Err(_) => warn!(
+                        "Consensus block {} has no signatures, exporting it without",
+                        hash
+                    ),
+                }
+            }
+            writer.write(&ArchiveRecord::Block(block.encode_compressed()))?;
+            written.insert(hash);
+            summary.blocks += 1;
+        } else {
+            stack.push((hash, true));
+            for dependency in dependencies(&block) {
+                if !written.contains(&dependency) {
+                    stack.push((dependency, false));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// # Export
+/// Writes every epoch, block chunk and block to an archive at path, returning what was exported
+pub fn export(path: &str) -> Result<ArchiveSummary, Box<dyn std::error::Error>> {
+    if pruned_below()? != 0 {
+        return Err("This node has pruned old blocks, export from an unpruned node".into());
+    }
+    let temp_path = path.to_owned() + ".tmp";
+    let mut writer = ArchiveWriter {
+        file: BufWriter::new(File::create(&temp_path)?),
+        checksum: Context::new(&SHA256),
+        records: 0,
+    };
+    serde_json::to_writer(
+        &mut writer.file,
+        &ArchiveHeader {
+            magic: ARCHIVE_MAGIC.to_owned(),
+            version: ARCHIVE_VERSION,
+        },
+    )?;
+    writer.file.write_all(b"\n")?;
+    let mut summary = ArchiveSummary::default();
+    let mut written: HashSet<String> = HashSet::new();
+    let chunks = chunk_index()?;
+    for epoch_number in 0..=get_top_epoch()?.epoch_number {
+        let epoch = match Epoch::get(epoch_number) {
+            Ok(epoch) => epoch,
+            Err(_) => continue,
+        };
+        writer.write(&ArchiveRecord::Epoch(serde_json::to_string(&epoch)?))?;
+        summary.epochs += 1;
+        for (_, _, _, hash) in chunks.iter().filter(|chunk| chunk.0 == epoch_number) {
+            let chunk = BlockChunk::get(hash.clone())?;
+            for block in &chunk.blocks {
+                write_block(&mut writer, &mut written, &mut summary, block)?;
+            }
+            writer.write(&ArchiveRecord::Chunk(chunk.encode()?))?;
+            summary.chunks += 1;
+        }
+        debug!("Exported epoch {}, summary={:?}", epoch_number, summary);
+    }
+    // then the blocks which are not in an enacted chunk yet
+    for (chain_key, _) in store::entries(&Table::ChainList)? {
+        let block_count: u64 = store::get(&Table::ChainIndex(chain_key.clone()), "blockcount")?
+            .unwrap_or(0);
+        for height in 0..block_count {
+            let block = get_block(&chain_key, height);
+            if !block.is_default() {
+                write_block(&mut writer, &mut written, &mut summary, &block.hash)?;
+            }
+        }
+    }
+    let footer = ArchiveRecord::Footer {
+        records: writer.records,
+        checksum: hex::encode(writer.checksum.clone().finish()),
+    };
+    serde_json::to_writer(&mut writer.file, &footer)?;
+    writer.file.write_all(b"\n")?;
+    writer.file.flush()?;
+    drop(writer);
+    std::fs::rename(&temp_path, path)?;
+    info!("Exported archive to {}, summary={:?}", path, summary);
+    Ok(summary)
+}
+
+/// Returns the lines of the archive at path after its header, once the header has been checked
+fn open_archive(
+    path: &str,
+) -> Result<impl Iterator<Item = std::io::Result<String>>, Box<dyn std::error::Error>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header: ArchiveHeader =
+        serde_json::from_str(&lines.next().ok_or("Archive is empty")??)?;
+    if header.magic != ARCHIVE_MAGIC {
+        return Err(format!("{} is not an archive", path).into());
+    }
+    if header.version > ARCHIVE_VERSION {
+        return Err(format!(
+            "Archive has format version {}, newer than {} which we read",
+            header.version, ARCHIVE_VERSION
+        )
+        .into());
+    }
+    Ok(lines)
+}
+
+/// # Verify
+/// Checks the header, record count and checksum of the archive at path, returning its number of records
+pub fn verify(path: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut checksum = Context::new(&SHA256);
+    let mut records: u64 = 0;
+    for line in open_archive(path)? {
+        let line = line?;
+        if let ArchiveRecord::Footer {
+            records: expected_records,
+            checksum: expected_checksum,
+        } = serde_json::from_str(&line)?
+        {
+            if records != expected_records {
+                return Err(format!(
+                    "Archive has {} records, footer expected {}",
+                    records, expected_records
+                )
+                .into());
+            }
+            if hex::encode(checksum.finish()) != expected_checksum {
+                return Err("Archive checksum mismatch".into());
+            }
+            return Ok(records);
+        }
+        checksum.update(line.as_bytes());
+        checksum.update(b"\n");
+        records += 1;
+    }
+    Err("Archive is truncated (no footer)".into())
+}
+
+/// # Verify epoch
+/// Checks the archived epoch against the epoch enacting the consensus chain produced, comparing what consensus decides (its salt, shuffle bits,
+/// committees, fullnode count and snapshot). The coin totals depend on the order blocks were enacted in, so are not compared
+fn verify_epoch(archived: &Epoch) -> Result<(), Box<dyn std::error::Error>> {
+    let enacted = Epoch::get(archived.epoch_number).map_err(|_| {
+        format!(
+            "Epoch {} was not produced by enacting the archive",
+            archived.epoch_number
+        )
+    })?;
+    let mismatched = if archived.salt != enacted.salt {
+        Some("salt")
+    } else if archived.shuffle_bits != enacted.shuffle_bits {
+        Some("shuffle bits")
+    } else if archived.committees != enacted.committees {
+        Some("committees")
+    } else if archived.total_fullnodes != enacted.total_fullnodes {
+        Some("fullnode count")
+    } else if !archived.snapshot_hash.is_empty() && archived.snapshot_hash != enacted.snapshot_hash
+    {
+        // epochs from before snapshots have none to compare
+        Some("snapshot")
+    } else {
+        None
+    };
+    match mismatched {
+        Some(field) => Err(format!(
+            "Archived epoch {} has a different {} to the epoch enacting the archive produced",
+            archived.epoch_number, field
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Verifies each pending archived epoch which has started on this node, removing it from pending and counting it in summary
+fn verify_started_epochs(
+    pending: &mut Vec<Epoch>,
+    summary: &mut ArchiveSummary,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let top_epoch_number = get_top_epoch()?.epoch_number;
+    let mut index = 0;
+    while index < pending.len() {
+        if pending[index].epoch_number <= top_epoch_number {
+            verify_epoch(&pending.remove(index))?;
+            summary.epochs += 1;
+        } else {
+            index += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Validates, saves and enacts the record unless we already have it, counting it in summary
+/// Epochs are added to pending, to be verified once enacting the archive has started them
+fn import_record(
+    record: ArchiveRecord,
+    pending: &mut Vec<Epoch>,
+    summary: &mut ArchiveSummary,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match record {
+        ArchiveRecord::Epoch(data) => {
+            let epoch: Epoch = serde_json::from_str(&data)?;
+            if epoch.hash_return() != epoch.hash {
+                return Err(format!("Epoch {} has the wrong hash", epoch.epoch_number).into());
+            }
+            pending.push(epoch);
+        }
+        ArchiveRecord::Block(data) => {
+            let block = Block::from_compressed(data)?;
+            let enacted = store::get_raw(
+                &Table::ChainIndex(block.header.chain_key.clone()),
+                &block.header.height.to_string(),
+            )?;
+            if enacted.as_ref() == Some(&block.hash) {
+                summary.skipped += 1;
+                return Ok(());
+            }
+            block.valid()?;
+            block.save()?;
+            block.enact()?;
+            summary.blocks += 1;
+        }
+        ArchiveRecord::Chunk(data) => {
+            let chunk = BlockChunk::decode(data)?;
+            if BlockChunk::get(chunk.hash.clone()).is_ok() {
+                summary.skipped += 1;
+                return Ok(());
+            }
+            chunk.valid()?;
+            chunk.save()?;
+            chunk.enact()?;
+            summary.chunks += 1;
+        }
+        ArchiveRecord::Signatures(data) => {
+            let signatures: ConsensusSignatures = serde_json::from_str(&data)?;
+            if ConsensusSignatures::get(signatures.block_hash.clone()).is_ok() {
+                return Ok(());
+            }
+            signatures.valid()?;
+            signatures.save()?;
+            signatures.enact()?;
+        }
+        ArchiveRecord::Footer { .. } => {}
+    }
+    Ok(())
+}
+
+/// # Import
+/// Verifies the archive at path then validates and enacts each of its records we do not already have, in order
+/// Stops at the first invalid record, or archived epoch which does not match the epoch enacting the archive produced, returning what was imported
+pub fn import(path: &str) -> Result<ArchiveSummary, Box<dyn std::error::Error>> {
+    let total = verify(path)?;
+    info!("Importing {} records from archive {}", total, path);
+    let mut summary = ArchiveSummary::default();
+    let mut pending: Vec<Epoch> = vec![];
+    for (index, line) in open_archive(path)?.enumerate().take(total as usize) {
+        import_record(serde_json::from_str(&line?)?, &mut pending, &mut summary)
+            .and_then(|_| verify_started_epochs(&mut pending, &mut summary))
+            .map_err(|e| format!("Failed to import record {}/{}: {}", index + 1, total, e))?;
+        if (index + 1) % 1000 == 0 {
+            info!("Imported {}/{} records", index + 1, total);
+        }
+    }
+    if let Some(epoch) = pending.first() {
+        return Err(format!(
+            "Epoch {} was not produced by enacting the archive",
+            epoch.epoch_number
+        )
+        .into());
+    }
+    info!("Imported archive {}, summary={:?}", path, summary);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commitee::Comitee;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    fn enacted_epoch(epoch_number: u64) -> Epoch {
+        let mut epoch = Epoch {
+            epoch_number,
+            salt: 7,
+            committees: vec![Comitee {
+                index: 0,
+                members: vec!["member".to_owned()],
+                hash: "committee".to_owned(),
+            }],
+            ..Default::default()
+        };
+        epoch.save().unwrap();
+        epoch.set_top_epoch().unwrap();
+        epoch
+    }
+
+    #[test]
+    fn test_record_encoding() {
+        let record = ArchiveRecord::Block("a│b\nc".to_owned());
+        let line = serde_json::to_string(&record).unwrap();
+        assert!(!line.contains('\n'));
+        assert_eq!(serde_json::from_str::<ArchiveRecord>(&line).unwrap(), record);
+        let footer = ArchiveRecord::Footer {
+            records: 2,
+            checksum: "ab".to_owned(),
+        };
+        let line = serde_json::to_string(&footer).unwrap();
+        assert_eq!(serde_json::from_str::<ArchiveRecord>(&line).unwrap(), footer);
+    }
+
+    #[test]
+    fn test_verify_epochs() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let mut archived: Epoch =
+                serde_json::from_str(&serde_json::to_string(&enacted_epoch(1)).unwrap()).unwrap();
+            // the totals depend on the order blocks were enacted in
+            archived.total_coins_movement = 10;
+            verify_epoch(&archived).unwrap();
+            archived.salt = 8;
+            assert!(verify_epoch(&archived).is_err());
+            archived.salt = 7;
+            archived.committees[0].members.push("intruder".to_owned());
+            assert!(verify_epoch(&archived).is_err());
+            archived.committees.pop();
+            archived.epoch_number = 2;
+            assert!(verify_epoch(&archived).is_err());
+
+            // an epoch is only verified once it has started
+            let mut pending = vec![archived];
+            let mut summary = ArchiveSummary::default();
+            verify_started_epochs(&mut pending, &mut summary).unwrap();
+            assert_eq!(pending.len(), 1);
+            let mut enacted = enacted_epoch(2);
+            enacted.committees = vec![];
+            enacted.save().unwrap();
+            verify_started_epochs(&mut pending, &mut summary).unwrap();
+            assert!(pending.is_empty());
+            assert_eq!(summary.epochs, 1);
+        });
+    }
+}
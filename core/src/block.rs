@@ -3,13 +3,21 @@ extern crate avrio_database;
 use crate::{
     account::{get_account, set_account, Account},
     block::genesis::{get_genesis_block, GenesisBlockErrors},
+    block_store::BlockStore,
+    consensus::{check_consensus_txns, ConsensusSignatures, MAX_CONSENSUS_TXNS},
     epoch::get_top_epoch,
+    invite::{epoch_invite_limit, over_invite_limit},
+    lottery::duplicate_ticket,
+    participation::fullnode_set,
     states::*,
     transaction::*,
     validate::Verifiable,
 };
 use avrio_config::config;
-use avrio_database::*;
+use avrio_database::{
+    store::{self, Table},
+    *,
+};
 use serde::{Deserialize, Serialize};
 
 extern crate bs58;
@@ -19,8 +27,6 @@ extern crate rand;
 
 use avrio_crypto::{Hashable, Wallet};
 
-use std::fs::File;
-use std::io::prelude::*;
 use thiserror::Error;
 pub mod genesis {
     // This file generates the genesis block for a new network.
@@ -187,6 +193,10 @@ pub enum BlockValidationErrors {
     UnauthorisedConsensusBlock,
     #[error("Consensus block contains non consensus type txn")]
     ContainsNonConsensusTxn,
+    #[error("Another block has been enacted at this height of the chain")]
+    ConflictingBlock,
+    #[error("Consensus block not signed by a quorum of the consensus committee")]
+    UnsignedConsensusBlock,
     #[error("Unknown/Other error")]
     Other,
 }
@@ -228,49 +238,47 @@ impl Default for BlockType {
 
 /// returns the block when you know the chain and the height
 pub fn get_block(chain_key: &str, height: u64) -> Block {
-    let hash = get_data(
-        config().db_path + "/chains/" + chain_key + "-chainindex",
-        &height.to_string(),
-    );
-    if hash == *"-1" || hash == *"0" {
-        Block::default()
-    } else {
-        get_block_from_raw(hash)
+    match store::get_raw(&Table::ChainIndex(chain_key.to_owned()), &height.to_string()) {
+        Ok(Some(hash)) => get_block_from_raw(hash),
+        Ok(None) => Block::default(),
+        Err(e) => {
+            error!(
+                "Failed to read chain index of {} at height {}, error={}",
+                chain_key, height, e
+            );
+            Block::default()
+        }
     }
 }
 
-/// returns the block when you only know the hash by opeining the raw blk-HASH.dat file (where hash == the block hash)
+/// returns the block when you only know the hash, from the block store (Block::default() if we do not have it)
 pub fn get_block_from_raw(hash: String) -> Block {
-    let try_open = File::open(config().db_path + &"/blocks/blk-".to_owned() + &hash + ".dat");
-    if let Ok(mut file) = try_open {
-        let mut contents = String::new();
-        file.read_to_string(&mut contents).unwrap();
-        let mut ret = Block::default();
-        if let Ok(_) = ret.decode_compressed(contents) {
-            return ret;
-        } else {
-            return Block::default();
+    match BlockStore::get(&hash) {
+        Ok(Some(block)) => block,
+        Ok(None) => {
+            trace!("Block {} not in block store", hash);
+            Block::default()
+        }
+        Err(e) => {
+            error!("Failed to read block {} from block store, error={}", hash, e);
+            Block::default()
         }
-    } else {
-        trace!(
-            "Opening raw block file (hash={}) failed. Reason={}",
-            hash,
-            try_open.unwrap_err()
-        );
-        Block::default()
     }
 }
 
-/// formats the block into a .dat file and saves it under block-hash.dat
+/// saves the block in the block store, under its hash
 pub fn save_block(block: Block) -> std::result::Result<(), Box<dyn std::error::Error>> {
     trace!("Saving block with hash: {}", block.hash);
-    let encoded: Vec<u8> = block.encode_compressed().as_bytes().to_vec();
-    let mut file = File::create(config().db_path + "/blocks/blk-" + &block.hash + ".dat")?;
-    file.write_all(&encoded)?;
+    BlockStore::put(&block)?;
     trace!("Saved Block");
     Ok(())
 }
 
+/// deletes the block from the block store, does nothing if we do not have it
+pub fn remove_block(hash: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    BlockStore::remove(hash)
+}
+
 impl Hashable for Header {
     fn bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
@@ -304,8 +312,11 @@ impl Hashable for Block {
     }
 }
 
-impl Verifiable for Block {
-    fn valid(&self) -> Result<(), Box<dyn std::error::Error>> {
+impl Block {
+    /// # Validate
+    /// Validates the block, unsigned_proposal is only true when a consensus committee member checks a consensus send block the round leader asks it to sign
+    /// (which can not have the signatures of the committee yet), see Verifiable::valid
+    pub fn validate(&self, unsigned_proposal: bool) -> Result<(), Box<dyn std::error::Error>> {
         let start_time = SystemTime::now();
         let config = config();
         let block = self.clone();
@@ -357,6 +368,18 @@ impl Verifiable for Block {
             return Err(Box::new(BlockValidationErrors::BlockCollision));
         }
         // else: we dont have this block saved; continue
+        // check we have not enacted another block at this height of the chain (forks are resolved by finality, see fork::resolve)
+        let enacted = store::get_raw(
+            &Table::ChainIndex(block.header.chain_key.clone()),
+            &block.header.height.to_string(),
+        )?;
+        if let Some(enacted) = enacted.filter(|enacted| enacted != &block.hash) {
+            debug!(
+                "Block {} conflicts with enacted block {} at height {} of chain {}",
+                block.hash, enacted, block.header.height, block.header.chain_key
+            );
+            return Err(Box::new(BlockValidationErrors::ConflictingBlock));
+        }
         // check if the block is a consensus block (sent by the round leader of the zero committee)
         if block.header.chain_key == "0" {
             trace!("Block {} sent from consensus chain", block.hash);
@@ -398,29 +421,40 @@ impl Verifiable for Block {
             }
             let curr_epoch = get_top_epoch()?;
             let round_leader = curr_epoch.committees[0].get_round_leader()?;
-            // check every transaction is a consensus txn and valid
-            for txn in &block.txns {
-                if !txn.consensus_type() {
-                    error!(
-                        "Consensus block {} contains non-consensus txn {}, flag={} (type={})",
-                        block.hash,
-                        txn.hash,
-                        txn.flag,
-                        txn.type_transaction()
-                    );
-                    return Err(Box::new(BlockValidationErrors::ContainsNonConsensusTxn));
-                }
-                if txn.sender_key != round_leader {
-                    error!(
-                        "Consensus block {} contains txn {} not sent by round leader {} (sent by {})",
-                        block.hash,
-                        txn.hash,
-                        round_leader,
-                        txn.sender_key
-                    );
-                    return Err(Box::new(BlockValidationErrors::UnauthorisedConsensusBlock));
+            // check every transaction is a consensus txn sent by the round leader, and valid
+            if let Err(e) = check_consensus_txns(&block.txns, &round_leader) {
+                error!(
+                    "Consensus block {} has invalid transactions, reason={}",
+                    block.hash, e
+                );
+                return Err(Box::new(e));
+            }
+            if block.block_type == BlockType::Send && !unsigned_proposal {
+                // a consensus send block is only valid once a quorum of this epochs consensus committee has signed it
+                // (the round leader saves and sends the signatures before the block, see ConsensusSignatures)
+                match ConsensusSignatures::get(block.hash.clone()) {
+                    Ok(signatures) if signatures.epoch == curr_epoch.epoch_number => {
+                        if let Err(e) = signatures.valid() {
+                            error!(
+                                "Consensus block {} has invalid signatures, reason={}",
+                                block.hash, e
+                            );
+                            return Err(Box::new(BlockValidationErrors::UnsignedConsensusBlock));
+                        }
+                    }
+                    Ok(signatures) => {
+                        error!(
+                            "Consensus block {} signed in epoch {}, not the current epoch {}",
+                            block.hash, signatures.epoch, curr_epoch.epoch_number
+                        );
+                        return Err(Box::new(BlockValidationErrors::UnsignedConsensusBlock));
+                    }
+                    Err(_) => {
+                        debug!("Do not have signatures for consensus block {} yet", block.hash);
+                        return Err(Box::new(BlockValidationErrors::UnsignedConsensusBlock));
+                    }
                 }
-                if self.block_type == BlockType::Send {
+                for txn in &block.txns {
                     if let Err(e) = txn.valid() {
                         error!(
                             "Consensus block {} contains invalid txn {}, reason={}",
@@ -466,8 +500,8 @@ impl Verifiable for Block {
                     return Err(Box::new(BlockValidationErrors::BlockTooFarInTheFuture));
                 }
             }
-            //check the block has at most 3 transactions in it
-            if block.txns.len() > 3 {
+            //check the block has at most MAX_CONSENSUS_TXNS transactions in it
+            if block.txns.len() > MAX_CONSENSUS_TXNS {
                 return Err(Box::new(BlockValidationErrors::TooManyTxn));
             } else if std::mem::size_of_val(&block) > 5000000 {
                 // check the block is no larger than 5MiB
@@ -625,6 +659,31 @@ impl Verifiable for Block {
                             return Err(Box::new(BlockValidationErrors::TransactionFromWrongChain));
                         }
                     }
+                    // each txn was validated against the enacted state, check the txns do not clash with each other
+                    if let Some(sender) = duplicate_ticket(&block.txns) {
+                        error!(
+                            "Block {} contains more than one VRF lotto ticket from {}",
+                            block.hash, sender
+                        );
+                        return Err(Box::new(BlockValidationErrors::InvalidTransaction(
+                            Box::new(TransactionValidationErrors::DuplicateTicket),
+                        )));
+                    }
+                    if block.txns.iter().any(|txn| txn.flag == 'i') {
+                        let top_epoch = get_top_epoch()?;
+                        let limit = epoch_invite_limit(fullnode_set(&top_epoch).len() as u64);
+                        if let Some(invite) =
+                            over_invite_limit(&block.txns, top_epoch.epoch_number, limit)
+                        {
+                            error!(
+                                "Block {} creates invite {} over the limits for epoch {}",
+                                block.hash, invite, top_epoch.epoch_number
+                            );
+                            return Err(Box::new(BlockValidationErrors::InvalidTransaction(
+                                Box::new(TransactionValidationErrors::InviteLimitReached),
+                            )));
+                        }
+                    }
                 } else {
                     if let Some(send_block_hash) = block.send_block {
                         // get the corosponding send block for this recieve block
@@ -675,6 +734,12 @@ impl Verifiable for Block {
         );
         Ok(())
     }
+}
+
+impl Verifiable for Block {
+    fn valid(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.validate(false)
+    }
 
     fn get(hash: String) -> Result<Box<Self>, Box<dyn std::error::Error>> {
         let got_block = get_block_from_raw(hash);
@@ -689,16 +754,12 @@ impl Verifiable for Block {
         save_block(self.clone())
     }
 
+    /// Enacts the block in one atomic batch, so after a crash either its entire effect is on disk or none of it is
     fn enact(&self) -> Result<(), Box<dyn std::error::Error>> {
-        match self.block_type {
-            BlockType::Recieve => {
-                enact_recieve(self.clone())?;
-            }
-            BlockType::Send => {
-                enact_send(self.clone())?;
-            }
-        }
-        Ok(())
+        store::atomic(|| match self.block_type {
+            BlockType::Recieve => enact_recieve(self.clone()),
+            BlockType::Send => enact_send(self.clone()),
+        })
     }
 }
 
@@ -857,26 +918,8 @@ impl Block {
             blk_clone.hash();
             Ok(blk_clone)
         } else {
-            let top_block_hash = get_data(
-                config().db_path
-                    + &"/chains/".to_owned()
-                    + &chain_key_value
-                    + &"-chainindex".to_owned(),
-                "topblockhash",
-            );
-            let our_height: u64;
-            let our_height_ = get_data(
-                config().db_path
-                    + &"/chains/".to_owned()
-                    + &chain_key_value
-                    + &"-chainindex".to_owned(),
-                &"blockcount".to_owned(),
-            );
-            if our_height_ == "-1" {
-                our_height = 0
-            } else {
-                our_height = our_height_.parse()?;
-            }
+            let top_block_hash = top_block_hash(&chain_key_value).unwrap_or_default();
+            let our_height = block_count(&chain_key_value);
             trace!("our_height={}", our_height);
             blk_clone.header.chain_key = chain_key_value;
             blk_clone.header.height = our_height; // we DONT need to add 1 to the blockcount as it is the COUNT of blocks on a chain which starts on 1, and block height starts from 0, this means there is already a +1 delta between the two
@@ -896,31 +939,22 @@ impl Block {
         }
         let wallet = Wallet::from_private_key(private_key);
         if consensus {
-            let mut header = Header {
+            let header = Header {
                 version_major: config().version_major,
                 version_breaking: config().version_breaking,
                 version_minor: config().version_minor,
                 chain_key: "0".to_string(),
-                prev_hash: get_data(
-                    config().db_path + &"/chains/0-chainindex".to_owned(),
-                    "topblockhash",
-                ),
-                height: get_data(
-                    config().db_path + &"/chains/0-chainindex".to_owned(),
-                    "blockcount",
-                )
-                .parse()
-                .unwrap_or_default(),
+                prev_hash: top_block_hash("0").unwrap_or_else(|| {
+                    trace!("No top block hash for genesis block");
+                    String::from("00000000000")
+                }),
+                height: block_count("0"),
                 timestamp: SystemTime::now()
                     .duration_since(UNIX_EPOCH)
                     .expect("time went backwards")
                     .as_millis() as u64,
                 network: config().network_id,
             };
-            if header.prev_hash == "-1" {
-                trace!("No top block hash for genesis block");
-                header.prev_hash = String::from("00000000000")
-            }
             let mut blk: Block;
             if send_block.is_some() {
                 blk = Block {
@@ -950,22 +984,9 @@ impl Block {
                 version_breaking: config().version_breaking,
                 version_minor: config().version_minor,
                 chain_key: wallet.public_key.clone(),
-                prev_hash: get_data(
-                    config().db_path
-                        + &"/chains/".to_owned()
-                        + &wallet.public_key
-                        + &"-chainindex".to_owned(),
-                    "topblockhash",
-                ),
-                height: get_data(
-                    config().db_path
-                        + &"/chains/".to_owned()
-                        + &wallet.public_key
-                        + &"-chainindex".to_owned(),
-                    "blockcount",
-                )
-                .parse()
-                .unwrap_or_default(),
+                prev_hash: top_block_hash(&wallet.public_key)
+                    .unwrap_or_else(|| String::from("00000000000")),
+                height: block_count(&wallet.public_key),
                 timestamp: SystemTime::now()
                     .duration_since(UNIX_EPOCH)
                     .expect("time went backwards")
@@ -999,161 +1020,105 @@ impl Block {
     }
 }
 
-/// enacts the relevant stuff for a send block (eg creating inv registry)
-fn enact_send(block: Block) -> Result<(), Box<dyn std::error::Error>> {
-    if get_data(
-        config().db_path
-            + &"/chains/".to_owned()
-            + &block.header.chain_key
-            + &"-chainindex".to_owned(),
-        &block.header.height.to_string(),
-    ) == "-1"
-    {
-        let global_block_count_string = get_data(
-            config().db_path + &"/globalindex".to_owned(),
-            "globalblockcount",
-        );
-        let global_block_count: u64;
-        if global_block_count_string == "-1" {
-            save_data(
-                "1",
-                &(config().db_path + &"/globalindex".to_owned()),
-                "globalblockcount".to_string(),
-            );
-            global_block_count = 1;
-        } else {
-            if let Ok(global_block_count_int) = global_block_count_string.parse::<u64>() {
-                save_data(
-                    &(global_block_count_int + 1).to_string(),
-                    &(config().db_path + &"/globalindex".to_owned()),
-                    "globalblockcount".to_string(),
-                );
-                debug!(
-                    "Incremented global block count: old={}, new={}",
-                    global_block_count_int,
-                    global_block_count_int + 1
-                );
-                global_block_count = global_block_count_int + 1;
-            } else {
-                error!("Failed to parse current globalblockcount, setting to 1");
-                save_data(
-                    "1",
-                    &(config().db_path + &"/globalindex".to_owned()),
-                    "globalblockcount".to_string(),
-                );
-                global_block_count = 1;
-            }
-        }
-        if get_data(
-            config().db_path + "/globalindex",
-            &global_block_count.to_string(),
-        ) != "-1"
-        {
-            error!(
-                "Global invetory entry already present for height={}",
-                global_block_count
-            );
-            panic!(
-                "Global invetory entry already present for height={}",
-                global_block_count
-            );
-        } else {
-            save_data(
-                &block.hash,
-                &(config().db_path + &"/globalindex".to_owned()),
-                global_block_count.to_string(),
-            );
+/// Returns the hash of the top block of the chain, or None if it has no blocks
+pub fn top_block_hash(chain_key: &str) -> Option<String> {
+    store::get_raw(&Table::ChainIndex(chain_key.to_owned()), "topblockhash").unwrap_or_else(|e| {
+        error!("Failed to read top block hash of chain {}, error={}", chain_key, e);
+        None
+    })
+}
+
+/// Returns the number of blocks on the chain (the height of the next block)
+pub fn block_count(chain_key: &str) -> u64 {
+    store::get(&Table::ChainIndex(chain_key.to_owned()), "blockcount")
+        .unwrap_or_else(|e| {
+            error!("Failed to read block count of chain {}, error={}", chain_key, e);
+            None
+        })
+        .unwrap_or(0)
+}
+
+/// Adds the block to the global index (the order we enacted blocks in) and returns its global height
+fn index_globally(block_hash: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let global_block_count = match store::get::<u64>(&Table::GlobalIndex, "globalblockcount") {
+        Ok(Some(global_block_count)) => {
             debug!(
-                "Inserted global inventory entry, global height={}, hash={}",
-                global_block_count, block.hash
-            );
-            save_data(
-                &block.hash,
-                &(config().db_path + &"/globalindex".to_owned()),
-                "globaltopblockhash".to_string(),
+                "Incremented global block count: old={}, new={}",
+                global_block_count,
+                global_block_count + 1
             );
-            debug!("Updated global topblockhash");
+            global_block_count + 1
         }
-        debug!("block {} not in invs", block.hash);
-
-        let hash = block.hash.clone();
-        let chain_key_copy = block.header.chain_key.to_owned();
-        std::thread::spawn(move || {
-            update_chain_digest(
-                &hash,
-                config().db_path + &"/chaindigest".to_owned(),
-                &chain_key_copy,
-            );
-            form_state_digest(config().db_path + &"/chaindigest".to_owned()).unwrap();
-        });
-
-        save_data(
-            &block.hash,
-            &(config().db_path
-                + &"/chains/".to_owned()
-                + &block.header.chain_key
-                + &"-chainindex".to_owned()),
-            "topblockhash".to_string(),
-        );
-        save_data(
-            &(block.header.height + 1).to_string(),
-            &(config().db_path
-                + &"/chains/".to_owned()
-                + &block.header.chain_key
-                + &"-chainindex".to_owned()),
-            "blockcount".to_owned(),
+        Ok(None) => 1,
+        Err(e) => {
+            error!("Failed to parse current globalblockcount, setting to 1, error={}", e);
+            1
+        }
+    };
+    store::put(&Table::GlobalIndex, "globalblockcount", &global_block_count)?;
+    if store::contains(&Table::GlobalIndex, &global_block_count.to_string())? {
+        error!(
+            "Global invetory entry already present for height={}",
+            global_block_count
         );
-
-        trace!("set top block hash for sender");
-
-        let inv_sender_res = save_data(
-            &block.hash,
-            &(config().db_path + "/chains/" + &block.header.chain_key + "-chainindex"),
-            block.header.height.to_string(),
+        panic!(
+            "Global invetory entry already present for height={}",
+            global_block_count
         );
+    }
+    store::put_raw(
+        &Table::GlobalIndex,
+        &global_block_count.to_string(),
+        block_hash,
+    )?;
+    debug!(
+        "Inserted global inventory entry, global height={}, hash={}",
+        global_block_count, block_hash
+    );
+    store::put_raw(&Table::GlobalIndex, "globaltopblockhash", block_hash)?;
+    debug!("Updated global topblockhash");
+    Ok(global_block_count)
+}
 
-        trace!("Saved inv for sender: {}", block.header.chain_key);
+/// Saves the block as the top block of its chain, updating the chains digest and the state digest
+fn index_on_chain(block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+    // the digests are written in the blocks batch, so they always match the enacted blocks
+    update_chain_digest(&block.hash, &block.header.chain_key)?;
+    form_state_digest()?;
+    let index = Table::ChainIndex(block.header.chain_key.clone());
+    store::put_raw(&index, "topblockhash", &block.hash)?;
+    store::put(&index, "blockcount", &(block.header.height + 1))?;
+    trace!("set top block hash for sender");
+    store::put_raw(&index, &block.header.height.to_string(), &block.hash)?;
+    trace!("Saved inv for sender: {}", block.header.chain_key);
+    Ok(())
+}
 
-        if inv_sender_res != 1 {
-            return Err("failed to save sender inv".into());
-        }
+/// Adds the chain of a genesis (height 0) block to the chain list and creates its account
+fn register_chain(chain_key: &str) -> Result<(), Box<dyn std::error::Error>> {
+    store::put_raw(&Table::ChainList, chain_key, "")?;
+    if set_account(&Account::new(chain_key.to_owned())) != 1 {
+        return Err("failed to save new account".into());
+    }
+    let index = Table::ChainIndex(chain_key.to_owned());
+    if !store::contains(&index, "txncount")? {
+        store::put(&index, "txncount", &0u64)?;
+    }
+    Ok(())
+}
 
-        for txn in block.txns {
+/// enacts the relevant stuff for a send block (eg creating inv registry)
+fn enact_send(block: Block) -> Result<(), Box<dyn std::error::Error>> {
+    let index = Table::ChainIndex(block.header.chain_key.clone());
+    if !store::contains(&index, &block.header.height.to_string())? {
+        index_globally(&block.hash)?;
+        debug!("block {} not in invs", block.hash);
+        index_on_chain(&block)?;
+        for txn in &block.txns {
             txn.update_nonce()?;
         }
         if block.header.height == 0 {
-            if save_data(
-                &"".to_owned(),
-                &(config().db_path + "/chainlist"),
-                block.header.chain_key.clone(),
-            ) == 0
-            {
-                return Err("failed to add chain to chainslist".into());
-            } else {
-                let newacc = Account::new(block.header.chain_key.clone());
-
-                if set_account(&newacc) != 1 {
-                    return Err("failed to save new account".into());
-                }
-            }
-
-            if avrio_database::get_data(
-                config().db_path
-                    + &"/chains/".to_owned()
-                    + &block.header.chain_key
-                    + &"-chainindex".to_owned(),
-                &"txncount",
-            ) == *"-1"
-            {
-                avrio_database::save_data(
-                    &"0".to_string(),
-                    &(config().db_path
-                        + &"/chains/".to_owned()
-                        + &block.header.chain_key
-                        + &"-chainindex".to_owned()),
-                    "txncount".to_string(),
-                );
-            }
+            register_chain(&block.header.chain_key)?;
         }
     }
     Ok(())
@@ -1168,213 +1133,33 @@ fn enact_recieve(block: Block) -> std::result::Result<(), Box<dyn std::error::Er
         // we only enact recive blocks, ignore send blocks
         return Err("tried to enact a send block".into());
     }
-    let global_block_count_string = get_data(
-        config().db_path + &"/globalindex".to_owned(),
-        "globalblockcount",
-    );
-    let global_block_count: u64;
-    if global_block_count_string == "-1" {
-        save_data(
-            "1",
-            &(config().db_path + &"/globalindex".to_owned()),
-            "globalblockcount".to_string(),
-        );
-        global_block_count = 1;
-    } else {
-        if let Ok(global_block_count_int) = global_block_count_string.parse::<u64>() {
-            save_data(
-                &(global_block_count_int + 1).to_string(),
-                &(config().db_path + &"/globalindex".to_owned()),
-                "globalblockcount".to_string(),
-            );
-            debug!(
-                "Incremented global block count: old={}, new={}",
-                global_block_count_int,
-                global_block_count_int + 1
-            );
-            global_block_count = global_block_count_int + 1;
-        } else {
-            error!("Failed to parse current globalblockcount, setting to 1");
-            save_data(
-                "1",
-                &(config().db_path + &"/globalindex".to_owned()),
-                "globalblockcount".to_string(),
-            );
-            global_block_count = 1;
-        }
-    }
-    if get_data(
-        config().db_path + "/globalindex",
-        &global_block_count.to_string(),
-    ) != "-1"
-    {
-        error!(
-            "Global invetory entry already present for height={}",
-            global_block_count
-        );
-        panic!(
-            "Global invetory entry already present for height={}",
-            global_block_count
-        );
-    } else {
-        save_data(
-            &block.hash,
-            &(config().db_path + &"/globalindex".to_owned()),
-            global_block_count.to_string(),
-        );
-        debug!(
-            "Inserted global inventory entry, global height={}, hash={}",
-            global_block_count, block.hash
-        );
-        save_data(
-            &block.hash,
-            &(config().db_path + &"/globalindex".to_owned()),
-            "globaltopblockhash".to_string(),
-        );
-        debug!("Updated global topblockhash");
-    }
-    if get_data(
-        config().db_path
-            + &"/chains/".to_owned()
-            + &block.header.chain_key
-            + &"-chainindex".to_owned(),
-        &block.header.height.to_string(),
-    ) == "-1"
-    {
+    index_globally(&block.hash)?;
+    let index = Table::ChainIndex(block.header.chain_key.clone());
+    if !store::contains(&index, &block.header.height.to_string())? {
         debug!("block not in invs");
-        let hash = block.hash.clone();
-
-        let chain_key_copy = block.header.chain_key.to_owned();
-        std::thread::spawn(move || {
-            update_chain_digest(
-                &hash,
-                config().db_path + &"/chaindigest".to_owned(),
-                &chain_key_copy,
-            );
-            form_state_digest(config().db_path + &"/chaindigest".to_owned()).unwrap();
-        });
-        save_data(
-            &block.hash,
-            &(config().db_path
-                + &"/chains/".to_owned()
-                + &block.header.chain_key
-                + &"-chainindex".to_owned()),
-            "topblockhash".to_string(),
-        );
-        save_data(
-            &(block.header.height + 1).to_string(),
-            &(config().db_path
-                + &"/chains/".to_owned()
-                + &block.header.chain_key
-                + &"-chainindex".to_owned()),
-            "blockcount".to_string(),
-        );
-        trace!("set top block hash for sender");
-        let inv_sender_res = save_data(
-            &block.hash,
-            &(config().db_path
-                + &"/chains/".to_owned()
-                + &block.header.chain_key
-                + &"-chainindex".to_owned()),
-            block.header.height.to_string(),
-        );
-        trace!("Saved inv for sender: {}", block.header.chain_key);
-        if inv_sender_res != 1 {
-            return Err("failed to save sender inv".into());
-        }
+        index_on_chain(&block)?;
         if block.header.height == 0 {
-            if save_data(
-                &"".to_owned(),
-                &(config().db_path + "/chainlist"),
-                block.header.chain_key.clone(),
-            ) == 0
-            {
-                return Err("failed to add chain to chainslist".into());
-            } else {
-                let newacc = Account::new(block.header.chain_key.clone());
-                if set_account(&newacc) != 1 {
-                    return Err("failed to save new account".into());
-                }
-            }
-            if avrio_database::get_data(
-                config().db_path
-                    + &"/chains/".to_owned()
-                    + &block.header.chain_key
-                    + &"-chainindex".to_owned(),
-                &"txncount",
-            ) == *"-1"
-            {
-                avrio_database::save_data(
-                    &"0".to_string(),
-                    &(config().db_path
-                        + &"/chains/".to_owned()
-                        + &block.header.chain_key
-                        + &"-chainindex".to_owned()),
-                    "txncount".to_owned(),
-                );
-            }
+            register_chain(&block.header.chain_key)?;
         }
         for txn in block.txns {
             trace!("enacting txn with hash: {}", txn.hash);
             txn.enact()?;
             trace!("Enacted txn. Saving txn to txindex db (db_name  = transactions)");
-            if save_data(
-                &block.hash,
-                &(config().db_path + &"/transactions".to_owned()),
-                txn.hash.to_owned(),
-            ) != 1
-            {
-                return Err("failed to save txn in transactions db".into());
-            }
+            store::put_raw(&Table::Transactions, &txn.hash, &block.hash)?;
             trace!("Saving invs");
             if txn.sender_key != txn.receive_key && txn.sender_key != block.header.chain_key {
-                let inv_receiver_res = save_data(
+                let reciever_index = Table::ChainIndex(txn.receive_key.clone());
+                store::put_raw(
+                    &reciever_index,
+                    &block.header.height.to_string(),
                     &block.hash,
-                    &(config().db_path
-                        + &"/chains/".to_owned()
-                        + &txn.receive_key
-                        + &"-chainindex".to_owned()),
-                    block.header.height.to_string(),
-                );
-                if inv_receiver_res != 1 {
-                    return Err("failed to save reciver inv".into());
-                }
-                let curr_block_count: String = get_data(
-                    config().db_path
-                        + &"/chains/".to_owned()
-                        + &txn.receive_key
-                        + &"-chainindex".to_owned(),
-                    "blockcount",
-                );
-                if curr_block_count == "-1" {
-                    save_data(
-                        &"0".to_owned(),
-                        &(config().db_path
-                            + &"/chains/".to_owned()
-                            + &txn.receive_key
-                            + &"-chainindex".to_owned()),
-                        "blockcount".to_owned(),
-                    );
-                } else {
-                    let curr_block_count_val: u64 = curr_block_count.parse().unwrap_or_default();
-                    save_data(
-                        &(curr_block_count_val + 1).to_string(),
-                        &(config().db_path
-                            + &"/chains/".to_owned()
-                            + &txn.receive_key
-                            + &"-chainindex".to_owned()),
-                        "blockcount".to_owned(),
-                    );
-                }
-
-                save_data(
-                    &block.hash,
-                    &(config().db_path
-                        + &"/chains/".to_owned()
-                        + &txn.receive_key
-                        + &"-chainindex".to_owned()),
-                    "topblockhash".to_owned(),
-                );
+                )?;
+                let curr_block_count = match store::get::<u64>(&reciever_index, "blockcount")? {
+                    Some(curr_block_count) => curr_block_count + 1,
+                    None => 0,
+                };
+                store::put(&reciever_index, "blockcount", &curr_block_count)?;
+                store::put_raw(&reciever_index, "topblockhash", &block.hash)?;
                 trace!("set top block hash for reciever");
             }
         }
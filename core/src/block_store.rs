@@ -0,0 +1,70 @@
+// This file is the block store, where the bodies of blocks are kept by hash. Blocks are stored in the blocks table of the database
+// (the compressed encoding, by hash) so they are written in the same atomic batches as the indexes which point to them.
+// Until schema version 3 each block was its own blocks/blk-<hash>.dat file, see avrio_database::migrate::migrate_block_files.
+extern crate avrio_database;
+
+use avrio_database::store::{self, Table};
+
+use crate::block::Block;
+
+pub struct BlockStore;
+
+impl BlockStore {
+    /// Returns the block with the hash, or None if we do not have it
+    pub fn get(hash: &str) -> Result<Option<Block>, Box<dyn std::error::Error>> {
+        match store::get_raw(&Table::Blocks, hash)? {
+            Some(encoded) => Ok(Some(Block::from_compressed(encoded)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns true if we have the block with the hash
+    pub fn contains(hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(store::contains(&Table::Blocks, hash)?)
+    }
+
+    /// Saves the block under its hash
+    pub fn put(block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(store::put_raw(
+            &Table::Blocks,
+            &block.hash,
+            &block.encode_compressed(),
+        )?)
+    }
+
+    /// Deletes the block with the hash, does nothing if we do not have it
+    pub fn remove(hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(store::delete(&Table::Blocks, hash)?)
+    }
+
+    /// Returns the hash of every block we have, sorted
+    pub fn hashes() -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(store::entries(&Table::Blocks)?
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_block_store() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let mut block = Block::default();
+            block.header.chain_key = "abc".to_owned();
+            block.hash();
+            assert_eq!(BlockStore::get(&block.hash).unwrap(), None);
+            BlockStore::put(&block).unwrap();
+            assert!(BlockStore::contains(&block.hash).unwrap());
+            assert_eq!(BlockStore::get(&block.hash).unwrap(), Some(block.clone()));
+            assert_eq!(BlockStore::hashes().unwrap(), vec![block.hash.clone()]);
+            BlockStore::remove(&block.hash).unwrap();
+            assert_eq!(BlockStore::get(&block.hash).unwrap(), None);
+        });
+    }
+}
@@ -7,16 +7,21 @@ extern crate avrio_config;
 use avrio_config::config;
 extern crate avrio_database;
 use crate::{
+    account::{open_or_create, Account},
     block::get_block_from_raw,
     commitee::Comitee,
-    epoch::get_top_epoch,
-    invite::{invite_valid, mark_spent},
+    epoch::{get_top_epoch, Epoch},
+    invite::{epoch_invite_limit, invite_valid, mark_spent},
+    participation::{forget, fullnode_set},
+    probation::{self, is_probationary, was_live},
     transaction::Transaction,
     validate::Verifiable,
 };
 use avrio_crypto::{public_key_to_address, sign_secp256k1, valid_signature_secp256k1, Hashable};
-use avrio_database::{get_data, save_data};
+use avrio_database::store::{get, get_raw, put, put_raw, Table};
+use avrio_id::{retarget, search_nonce, verify_id, IdSearch};
 use ring::signature::{self, KeyPair};
+use std::sync::Arc;
 use secp256k1::{PublicKey as SecpPublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -50,6 +55,8 @@ pub enum CertificateErrors {
     TransactionNotLock,
     #[error("Bad PoW")]
     DifficultyLow,
+    #[error("PoW searched for the difficulty of a future or stale epoch")]
+    StaleDifficultyEpoch,
     #[error("Invite invalid")]
     InvalidInvite,
     #[error("Certificate hash mismatch")]
@@ -60,13 +67,13 @@ pub enum CertificateErrors {
     Unknown,
 }
 
-#[derive(Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
 pub struct Certificate {
     pub hash: String,
     pub public_key: String, // base58 encoded, must be a valid account
     pub txn_hash: String,
     pub timestamp: u64,
-    pub valid_until: u64,
+    pub valid_until: u64, // the unlock time of the lock txn (0 never expires), extended by renewal txns (which rehash the certificate)
     pub invite: String,     // base58, should be the publickey of a valid invite
     pub invite_sig: String, // base58, signature using the privatekey of above invite publickey
     pub bls_public_key: String, // base58 encoded, used for aggregate signatures on block chunks
@@ -74,12 +81,232 @@ pub struct Certificate {
     pub secp256k1_publickey: String, // base58 encoded, used for VRF's
     pub secp256k1_signature: String, // base58 encoded, used to prove ownership of publickey
     pub signature: String,
+    #[serde(default)]
+    pub nonce: u64, // certificate PoW nonce, see avrio_id::verify_id (keyed by the lock txn hash)
+    #[serde(default)]
+    pub difficulty_epoch: u64, // the epoch whose certificate difficulty the PoW nonce was searched for
+    #[serde(default)]
+    pub registered_until: Option<u64>, // the valid_until the certificate was registered (and signed) with, set by the first renewal
 }
 
 pub fn get_fullnode_count() -> u64 {
-    return get_data(config().db_path + "/candidates", "count")
-        .parse::<u64>()
-        .unwrap_or(0);
+    get(&Table::Candidates, "count")
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Delta list reason for a fullnode whose certificate expired
+pub const REMOVAL_EXPIRED: u8 = 1;
+/// Delta list reason for a fullnode which sent a voluntary exit txn
+pub const REMOVAL_EXIT: u8 = 2;
+/// Delta list reason for a probationary fullnode which was not live
+pub const REMOVAL_PROBATION: u8 = 3;
+
+/// Returns the status of the wallet in the candidates db
+/// c = candidate, f = fullnode, e = exiting fullnode (removed at the end of the epoch), r = revoked (expired or exited), -1 = never registered
+pub fn get_status(publickey: &str) -> String {
+    get_raw(&Table::Candidates, publickey)
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| String::from("-1"))
+}
+
+/// Returns true if the wallet holds a live certificate (it is a candidate, fullnode or exiting fullnode)
+pub fn is_registered(publickey: &str) -> bool {
+    matches!(get_status(publickey).as_str(), "c" | "f" | "e")
+}
+
+pub(crate) fn set_status(publickey: &str, status: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(put_raw(&Table::Candidates, publickey, status)?)
+}
+
+fn set_fullnode_count(count: u64) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(put(&Table::Candidates, "count", &count)?)
+}
+
+/// Returns the publickeys of every wallet holding a live certificate
+fn get_registered() -> Vec<String> {
+    get(&Table::Candidates, "registered")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+fn save_registered(registered: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(put(&Table::Candidates, "registered", registered)?)
+}
+
+/// Returns the stakes waiting to be unlocked: (publickey, amount, time)
+fn get_unlocking() -> Vec<(String, u64, u64)> {
+    get(&Table::Candidates, "unlocking")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Returns the time certificates are checked for expiry against at the end of the epoch (the end of its VrfLotto stage)
+pub fn expiry_cutoff(epoch: &Epoch) -> u64 {
+    epoch.stage_deadline().unwrap_or(epoch.stage_started)
+}
+
+/// # Scheduled removals
+/// Returns the fullnode delta list entries (publickey, reason, proof) of the fullnodes leaving the set at the end of the epoch, sorted by publickey
+/// Fullnodes which sent an exit txn leave with REMOVAL_EXIT (the proof is the exit txn hash)
+/// Fullnodes whose certificate expires by the end of the epoch leave with REMOVAL_EXPIRED (the proof is the certificate hash)
+/// Probationary fullnodes which were not live during the epoch leave with REMOVAL_PROBATION (the proof is the epoch number)
+pub fn scheduled_removals(epoch: &Epoch) -> Vec<(String, u8, String)> {
+    let cutoff = expiry_cutoff(epoch);
+    let mut removals = vec![];
+    for member in fullnode_set(epoch) {
+        if get_status(&member) == "e" {
+            let exit_txn = get_raw(&Table::Candidates, &(member.clone() + "-exit"))
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            removals.push((member, REMOVAL_EXIT, exit_txn));
+        } else if let Ok(cert) = Certificate::get(member.clone()) {
+            if cert.expired(cutoff) {
+                removals.push((member, REMOVAL_EXPIRED, cert.hash));
+                continue;
+            }
+        }
+        if is_probationary(&member) && !was_live(&member, epoch) {
+            removals.push((member, REMOVAL_PROBATION, epoch.epoch_number.to_string()));
+        }
+    }
+    removals.sort();
+    removals
+}
+
+/// Revokes the certificate of a fullnode or candidate, decrementing the fullnode count
+pub fn revoke(publickey: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_registered(publickey) {
+        return Err(format!("{} holds no live certificate", publickey).into());
+    }
+    set_status(publickey, "r")?;
+    set_fullnode_count(get_fullnode_count().saturating_sub(1))?;
+    let mut registered = get_registered();
+    registered.retain(|registered_key| registered_key != publickey);
+    save_registered(&registered)?;
+    forget(publickey)?;
+    probation::forget(publickey)?;
+    info!("Revoked certificate of {}", public_key_to_address(publickey));
+    Ok(())
+}
+
+/// Marks a fullnode as exiting (it is removed from the fullnode set at the end of the epoch), exit_txn is the hash of the exit txn
+pub fn schedule_exit(publickey: &str, exit_txn: &str) -> Result<(), Box<dyn std::error::Error>> {
+    set_status(publickey, "e")?;
+    put_raw(&Table::Candidates, &(publickey.to_owned() + "-exit"), exit_txn)?;
+    Ok(())
+}
+
+/// Schedules the release of amount locked coins of a wallet, the coins are returned to its balance at the first epoch boundary after time
+pub fn schedule_unlock(
+    publickey: &str,
+    amount: u64,
+    time: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut unlocking = get_unlocking();
+    unlocking.push((publickey.to_owned(), amount, time));
+    put(&Table::Candidates, "unlocking", &unlocking)?;
+    Ok(())
+}
+
+/// # Process expiries
+/// Called when an epoch ends, with that epochs expiry_cutoff
+/// Revokes the certificates of candidates which have expired (expired fullnodes are removed through the delta list) and releases every stake whose unlock time has passed
+/// Returns the number of coins unlocked
+pub fn process_expiries(cutoff: u64) -> Result<u64, Box<dyn std::error::Error>> {
+    for publickey in get_registered() {
+        if get_status(&publickey) != "c" {
+            continue;
+        }
+        if let Ok(cert) = Certificate::get(publickey.clone()) {
+            if cert.expired(cutoff) {
+                debug!("Certificate of candidate {} expired", publickey);
+                revoke(&publickey)?;
+                schedule_unlock(&publickey, config().fullnode_lock_amount, cutoff)?;
+            }
+        }
+    }
+    let (due, pending): (Vec<_>, Vec<_>) = get_unlocking()
+        .into_iter()
+        .partition(|(_, _, time)| *time <= cutoff);
+    let mut unlocked = 0;
+    for (publickey, amount, _) in due {
+        let mut acc = open_or_create(&publickey);
+        let amount = amount.min(acc.locked);
+        acc.locked -= amount;
+        acc.balance += amount;
+        acc.save()?;
+        unlocked += amount;
+        debug!("Unlocked {} coins for {}", amount, publickey);
+    }
+    put(&Table::Candidates, "unlocking", &pending)?;
+    Ok(unlocked)
+}
+
+/// The number of epochs after the epoch its PoW was searched in that a certificate can still be registered // TODO: move to config
+pub const CERTIFICATE_EPOCH_SLACK: u64 = 1;
+
+/// Returns the certificate PoW difficulty (leading zero bits) of the epoch, set when the previous epoch ended (config().certificate_difficulty if it never was)
+pub fn certificate_difficulty(epoch_number: u64) -> u128 {
+    get_raw(&Table::Candidates, &(epoch_number.to_string() + "-difficulty"))
+        .ok()
+        .flatten()
+        .and_then(|difficulty| difficulty.parse().ok())
+        .unwrap_or(config().certificate_difficulty)
+}
+
+/// Returns the number of certificates enacted during the epoch
+fn registrations_in(epoch_number: u64) -> u64 {
+    get(
+        &Table::Candidates,
+        &(epoch_number.to_string() + "-registrations"),
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(0)
+}
+
+fn record_registration(epoch_number: u64) -> Result<(), Box<dyn std::error::Error>> {
+    put(
+        &Table::Candidates,
+        &(epoch_number.to_string() + "-registrations"),
+        &(registrations_in(epoch_number) + 1),
+    )?;
+    Ok(())
+}
+
+/// # Retarget difficulty
+/// Called when an epoch ends, sets the certificate difficulty of the next epoch from the number of registrations during this one
+/// The target is the number of invites which could be created this epoch (see avrio_id::retarget), the difficulty never drops below config().certificate_difficulty
+pub fn retarget_difficulty(epoch: &Epoch) -> Result<u128, Box<dyn std::error::Error>> {
+    let current = certificate_difficulty(epoch.epoch_number);
+    let registrations = registrations_in(epoch.epoch_number);
+    let target = epoch_invite_limit(fullnode_set(epoch).len() as u64);
+    let difficulty = retarget(
+        current,
+        registrations,
+        target,
+        config().certificate_difficulty,
+    );
+    put_raw(
+        &Table::Candidates,
+        &((epoch.epoch_number + 1).to_string() + "-difficulty"),
+        &difficulty.to_string(),
+    )?;
+    debug!(
+        "Retargeted certificate difficulty for epoch {}: {} -> {}, registrations={}, target={}",
+        epoch.epoch_number + 1,
+        current,
+        difficulty,
+        registrations,
+        target
+    );
+    Ok(difficulty)
 }
 
 pub fn generate_certificate(
@@ -117,6 +344,9 @@ pub fn generate_certificate(
                 .into_string(),
             secp256k1_signature: String::from(""),
             signature: String::from(""),
+            nonce: 0,
+            difficulty_epoch: 0,
+            registered_until: None,
         };
         let bls_private_key =
             PrivateKey::from_bytes(&bs58::decode(bls_private_key_string).into_vec().unwrap())
@@ -130,7 +360,7 @@ pub fn generate_certificate(
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
             .as_millis() as u64;
-        let block_hash = get_data(config().db_path + "/transactions", &cert.txn_hash);
+        let block_hash = get_raw(&Table::Transactions, &cert.txn_hash)?.unwrap_or_default();
         let blk = get_block_from_raw(block_hash); // get the txn to check if it is correct
         let mut txn: Transaction = Default::default();
 
@@ -145,6 +375,22 @@ pub fn generate_certificate(
 
         cert.valid_until = txn.unlock_time;
 
+        cert.difficulty_epoch = get_top_epoch()
+            .map(|epoch| epoch.epoch_number)
+            .unwrap_or(0);
+        let difficulty = certificate_difficulty(cert.difficulty_epoch);
+        info!("Searching for certificate PoW, difficulty={}", difficulty);
+        let search = IdSearch {
+            progress: Some(Arc::new(|attempts| {
+                debug!("Certificate PoW: {} attempts", attempts)
+            })),
+            ..Default::default()
+        };
+        match search_nonce(&cert.txn_hash, &cert.public_key, difficulty, &search) {
+            Some((nonce, _)) => cert.nonce = nonce,
+            None => return Err(CertificateErrors::DifficultyLow),
+        }
+
         cert.hash();
 
         if let Err(_e) = cert.sign(&private_key, invite, &bls_private_key, secp256k1_secretkey) {
@@ -167,6 +413,11 @@ impl Hashable for Certificate {
         bytes.extend(self.timestamp.to_string().bytes());
         bytes.extend(self.valid_until.to_string().bytes());
         bytes.extend(self.bls_public_key.bytes());
+        bytes.extend(self.nonce.to_string().bytes());
+        if self.difficulty_epoch != 0 {
+            // certificates from before the difficulty epoch was recorded keep their hash
+            bytes.extend(self.difficulty_epoch.to_string().bytes());
+        }
 
         bytes
     }
@@ -189,7 +440,7 @@ impl Verifiable for Certificate {
         {
             return Err(Box::new(CertificateErrors::TimestampHigh));
         }
-        let block_hash = get_data(config().db_path + "/transactions", &cert.txn_hash);
+        let block_hash = get_raw(&Table::Transactions, &cert.txn_hash)?.unwrap_or_default();
         let blk = get_block_from_raw(block_hash); // get the txn to check if it is correct
         let mut txn: Transaction = Default::default();
 
@@ -211,15 +462,24 @@ impl Verifiable for Certificate {
             return Err(Box::new(CertificateErrors::LockedFundsInsufficent));
         }
 
-        if let Ok(exisiting_cert) = Certificate::get(cert.public_key.clone()) {
-            if exisiting_cert.valid_until
-                > (SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards")
-                    .as_millis() as u64)
-            {
-                return Err(Box::new(CertificateErrors::WalletAlreadyRegistered));
-            }
+        if is_registered(&cert.public_key) {
+            return Err(Box::new(CertificateErrors::WalletAlreadyRegistered));
+        }
+        // the PoW is checked against the difficulty of the epoch it was searched in, which must be the current or previous epoch
+        // (so a registration searched for just before the epoch changed is not rejected because the difficulty was retargeted)
+        let epoch_number = get_top_epoch()?.epoch_number;
+        if cert.difficulty_epoch > epoch_number
+            || cert.difficulty_epoch + CERTIFICATE_EPOCH_SLACK < epoch_number
+        {
+            return Err(Box::new(CertificateErrors::StaleDifficultyEpoch));
+        }
+        if !verify_id(
+            &cert.txn_hash,
+            &cert.public_key,
+            cert.nonce,
+            certificate_difficulty(cert.difficulty_epoch),
+        ) {
+            return Err(Box::new(CertificateErrors::DifficultyLow));
         }
         /*if txn.unlock_time - (config().transaction_timestamp_max_offset as u64)
             < (SystemTime::now()
@@ -245,54 +505,30 @@ impl Verifiable for Certificate {
     }
 
     fn get(public_key: String) -> Result<Box<Self>, Box<dyn std::error::Error>> {
-        let got_data = get_data(
-            config().db_path + &"/fn-certificates".to_owned(),
-            &(public_key + &"-cert".to_owned()),
-        );
-        if got_data != "-1" {
-            let decoded: Certificate = serde_json::from_str(&got_data)?;
-            return Ok(Box::new(decoded));
-        } else {
-            return Err("Certificate not found".into());
+        match get(&Table::Certificates, &(public_key + &"-cert".to_owned()))? {
+            Some(decoded) => Ok(Box::new(decoded)),
+            None => Err("Certificate not found".into()),
         }
     }
 
     fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if save_data(
-            &serde_json::to_string(self)?,
-            &(config().db_path + &"/fn-certificates".to_owned()),
-            self.public_key.clone() + &"-cert".to_owned(),
-        ) == 1
-        {
-            return Ok(());
-        } else {
-            return Err("Certificate not found".into());
-        }
+        put(
+            &Table::Certificates,
+            &(self.public_key.clone() + &"-cert".to_owned()),
+            self,
+        )?;
+        Ok(())
     }
 
     fn enact(&self) -> Result<(), Box<dyn std::error::Error>> {
         mark_spent(&self.invite)?;
         // now save the BLS publickey to the ECDSA key lookup table
-        if save_data(
-            &self.public_key,
-            &(config().db_path + "/blslookup"),
-            self.bls_public_key.clone(),
-        ) != 1
-        {
-            return Err("failed to save ECDSA-BLS looukup entry".into());
-        }
+        put_raw(&Table::BlsLookup, &self.bls_public_key, &self.public_key)?;
         let candidate_count = get_fullnode_count();
         if candidate_count == 0 {
             // there are no candidates registered, this must be the god address (TODO: check this was sent by config().god_account)
             // ecolse this candidate fully
-            if save_data(
-                "f",
-                &(config().db_path + "/candidates"),
-                self.public_key.clone(),
-            ) != 1
-            {
-                return Err("failed to save new fullnode candidate".into());
-            }
+            set_status(&self.public_key, "f")?;
             let mut top_epoch = get_top_epoch()?;
             top_epoch.committees.push(
                 Comitee::form_comitees(&mut vec![self.public_key.clone()], &mut vec![], 1)[0]
@@ -304,33 +540,28 @@ impl Verifiable for Certificate {
                 public_key_to_address(&self.public_key)
             );
         } else {
-            if save_data(
-                "c",
-                &(config().db_path + "/candidates"),
-                self.public_key.clone(),
-            ) != 1
-            {
-                return Err("failed to save new fullnode candidate".into());
-            }
+            set_status(&self.public_key, "c")?;
 
             info!(
                 "New fullnode candidate {}!",
                 public_key_to_address(&self.public_key)
             );
         }
-        if save_data(
-            "count",
-            &(config().db_path + "/candidates"),
-            (candidate_count + 1).to_string(),
-        ) != 1
-        {
-            return Err("failed to save candidate count".into());
-        }
+        set_fullnode_count(candidate_count + 1)?;
+        record_registration(get_top_epoch()?.epoch_number)?;
+        let mut registered = get_registered();
+        registered.push(self.public_key.clone());
+        save_registered(&registered)?;
         Ok(())
     }
 }
 
 impl Certificate {
+    /// Returns true if the certificate expires at or before time
+    pub fn expired(&self, time: u64) -> bool {
+        self.valid_until != 0 && self.valid_until <= time
+    }
+
     pub fn sign(
         &mut self,
         private_key: &str,
@@ -376,7 +607,8 @@ impl Certificate {
     }
 
     pub fn valid_signature(&self) -> bool {
-        let msg: &[u8] = self.hash.as_bytes();
+        let registration_hash = self.registration_hash();
+        let msg: &[u8] = registration_hash.as_bytes();
         if let Ok(bls_publickey) = PublicKey::from_bytes(
             &bs58::decode(&self.bls_public_key)
                 .into_vec()
@@ -387,7 +619,7 @@ impl Certificate {
                     .into_vec()
                     .unwrap_or_default(),
             ) {
-                if !verify_messages(&signature, &[self.hash.as_bytes()], &[bls_publickey]) {
+                if !verify_messages(&signature, &[registration_hash.as_bytes()], &[bls_publickey]) {
                     return false;
                 }
             } else {
@@ -460,10 +692,10 @@ impl Certificate {
                 return false;
             }
             // now check the secp256k1 signature
-            if let Ok(res) = valid_signature_secp256k1(&self.secp256k1_publickey, &self.hash, &self.secp256k1_signature) {
+            if let Ok(res) = valid_signature_secp256k1(&self.secp256k1_publickey, &registration_hash, &self.secp256k1_signature) {
                 return res;
             } else {
-                debug!("Invalid secp256k1 sig, error={:#?}", valid_signature_secp256k1(&self.secp256k1_publickey, &self.hash, &self.secp256k1_signature).err());
+                debug!("Invalid secp256k1 sig, error={:#?}", valid_signature_secp256k1(&self.secp256k1_publickey, &registration_hash, &self.secp256k1_signature).err());
                 return false;
             }
         } else {
@@ -474,4 +706,150 @@ impl Certificate {
     pub fn hash(&mut self) {
         self.hash = self.hash_item();
     }
+
+    /// Returns the hash of the certificate as it was registered, which its signatures are over
+    pub fn registration_hash(&self) -> String {
+        match self.registered_until {
+            Some(registered_until) => Certificate {
+                valid_until: registered_until,
+                registered_until: None,
+                ..self.clone()
+            }
+            .hash_item(),
+            None => self.hash.clone(),
+        }
+    }
+
+    /// # Renew
+    /// Extends the certificate to valid_until and rehashes it, the signatures still verify against the registration hash
+    pub fn renew(&mut self, valid_until: u64) {
+        if self.registered_until.is_none() {
+            self.registered_until = Some(self.valid_until);
+        }
+        self.valid_until = valid_until;
+        self.hash();
+    }
+}
+
+/// Returns the coins the fullnode must lock (on top of its currently locked coins) to hold the full fullnode stake
+pub fn stake_shortfall(account: &Account) -> u64 {
+    config().fullnode_lock_amount.saturating_sub(account.locked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        account::{get_account, set_account},
+        reward::record_chunk,
+    };
+    use avrio_database::{backend::MemoryBackend, with_backend};
+
+    fn locked_account(public_key: &str, locked: u64) {
+        let mut acc = Account::new(public_key.to_owned());
+        acc.locked = locked;
+        assert_eq!(set_account(&acc), 1);
+    }
+
+    /// Saves a certificate valid until valid_until and registers its wallet with the status
+    fn register(public_key: &str, valid_until: u64, status: &str) {
+        let mut cert = Certificate {
+            public_key: public_key.to_owned(),
+            valid_until,
+            ..Default::default()
+        };
+        cert.hash();
+        cert.save().unwrap();
+        set_status(public_key, status).unwrap();
+        let mut registered = get_registered();
+        registered.push(public_key.to_owned());
+        save_registered(&registered).unwrap();
+        set_fullnode_count(get_fullnode_count() + 1).unwrap();
+    }
+
+    #[test]
+    fn test_process_expiries() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let lock = config().fullnode_lock_amount;
+            register("expired", 1000, "c");
+            locked_account("expired", lock);
+            register("live", 5000, "c");
+            locked_account("live", lock);
+            // expired fullnodes are removed through the delta list, not here
+            register("fullnode", 1000, "f");
+            locked_account("exited", 20);
+            schedule_unlock("exited", 20, 500).unwrap();
+            schedule_unlock("live", 5, 3000).unwrap();
+            assert_eq!(process_expiries(1000).unwrap(), lock + 20);
+            assert_eq!(get_status("expired"), "r");
+            assert!(!is_registered("expired"));
+            assert_eq!(get_status("live"), "c");
+            assert_eq!(get_status("fullnode"), "f");
+            assert_eq!(get_fullnode_count(), 2);
+            let expired = get_account("expired").unwrap();
+            assert_eq!((expired.balance, expired.locked), (lock, 0));
+            assert_eq!(get_account("exited").unwrap().balance, 20);
+            // stakes which unlock after the cutoff stay locked
+            assert_eq!(get_unlocking(), vec![("live".to_owned(), 5, 3000)]);
+            assert_eq!(get_account("live").unwrap().locked, lock);
+        });
+    }
+
+    #[test]
+    fn test_scheduled_removals() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let epoch = Epoch {
+                epoch_number: 3,
+                stage_started: 1000,
+                committees: vec![Comitee {
+                    index: 0,
+                    members: ["exiting", "expiring", "live", "probationary"]
+                        .iter()
+                        .map(|member| member.to_string())
+                        .collect(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            assert_eq!(expiry_cutoff(&epoch), 1000);
+            register("exiting", 5000, "f");
+            schedule_exit("exiting", "exittxn").unwrap();
+            register("expiring", 1000, "f");
+            register("live", 5000, "f");
+            register("probationary", 5000, "f");
+            probation::admit("probationary").unwrap();
+            // the committee formed a chunk the probationary fullnode did not sign
+            record_chunk(3, 0).unwrap();
+            let expiring = Certificate::get("expiring".to_owned()).unwrap();
+            assert_eq!(
+                scheduled_removals(&epoch),
+                vec![
+                    ("exiting".to_owned(), REMOVAL_EXIT, "exittxn".to_owned()),
+                    ("expiring".to_owned(), REMOVAL_EXPIRED, expiring.hash),
+                    ("probationary".to_owned(), REMOVAL_PROBATION, "3".to_owned()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_renew() {
+        let mut cert = Certificate {
+            public_key: String::from("publickey"),
+            txn_hash: String::from("txn"),
+            valid_until: 1000,
+            ..Default::default()
+        };
+        cert.hash();
+        let registered = cert.hash.clone();
+        assert_eq!(cert.registration_hash(), registered);
+        cert.renew(2000);
+        assert_eq!(cert.valid_until, 2000);
+        assert_ne!(cert.hash, registered);
+        assert_eq!(cert.hash, cert.hash_item());
+        assert_eq!(cert.registration_hash(), registered);
+        cert.renew(3000);
+        assert_eq!(cert.registered_until, Some(1000));
+        assert_eq!(cert.registration_hash(), registered);
+    }
 }
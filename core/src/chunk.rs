@@ -1,14 +1,18 @@
 use crate::{
-    account::{get_account, to_atomc, Account},
     block::Block,
-    epoch::get_top_epoch,
+    commitee::Comitee,
+    consensus::has_quorum,
+    epoch::{get_top_epoch, EpochStage},
+    finality::finalize,
+    fork::{conflict_of, resolve},
+    participation::voting_members,
+    reward::{record_chunk, record_participation},
     validate::Verifiable,
 };
-use avrio_config::config;
 use avrio_crypto::Hashable;
-use avrio_database::{get_data, save_data};
+use avrio_database::store::{self, Table};
 use bls_signatures::{aggregate, verify_messages, PrivateKey, PublicKey, Serialize, Signature};
-use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
 #[derive(Debug)]
 pub struct BlockChunk {
     pub hash: String,
@@ -73,40 +77,37 @@ impl Verifiable for BlockChunk {
                         debug!("Aggregated signature on blockchunk {} valid", self.hash);
                         // now check all the signers are part of the committee, and that the len(self.signers) > 2/3committee_size
                         // first get the committee
-                        let mut committees = get_top_epoch()?.committees;
-                        if committees.len() < (self.committee + 1).try_into().unwrap() {
-                            error!("Block chunk has non existant origin committee index={}, current top committee={}", self.committee, committees.len()-1);
+                        let top_epoch = get_top_epoch()?;
+                        let epoch_number = top_epoch.epoch_number;
+                        let mut committees = top_epoch.committees;
+                        if self.committee >= committees.len() as u64 {
+                            error!("Block chunk has non existant origin committee index={}, committees={}", self.committee, committees.len());
+                            return Err("Non existant origin committee".into());
                         } else {
                             let committee = committees.remove(self.committee as usize);
                             drop(committees);
-                            // now for each signer we get their coorosponding ECDSA publickey and check they are part of the committee
-                            for (index, bls_signer) in self.signers.clone().iter().enumerate() {
+                            // fullnodes which toggled off participation and probationary observers are not counted towards the quorum
+                            let voting = voting_members(epoch_number, &committee);
+                            // now for each signer we get their coorosponding ECDSA publickey
+                            let mut signers: Vec<String> = vec![];
+                            for bls_signer in &self.signers {
                                 let mut buffer = vec![];
                                 if let Err(e) = bls_signer.write_bytes(&mut buffer) {
                                     error!("Failed to write bls publickey bytes to buffer, gave error={}", e);
                                     return Err("Failed to write publickey bytes to buffer".into());
-                                } else {
-                                    let ecdsa_publickey = get_data(
-                                        config().db_path + "/blslookup",
-                                        &bs58::encode(&buffer).into_string(),
-                                    );
-                                    if ecdsa_publickey == "-1" {
-                                        error!("Cannot find corrosponding ECDSA publickey for BLS signer {}", &bs58::encode(buffer).into_string());
-                                        return Err("Could not find ECDSA counterpart for signers BLS publickey".into());
-                                    } else {
-                                        if !committee.members.contains(&ecdsa_publickey) {
-                                            error!("Committee {} (index={}) does not contain ECDSA counterpart {} of signer {:?}", committee.hash, committee.index, ecdsa_publickey, bls_signer);
-                                        } else if index == 0 {
-                                            // now check if the block proposer (the first signature in the vec) is the current round leader
-                                            if committee.get_round_leader()? != ecdsa_publickey {
-                                                error!("Block chunk {} proposed by {}, expected proposer {}", self.hash, ecdsa_publickey, committee.get_round_leader()?);
-                                                return Err("Unauthorised block proposal".into());
-                                            }
-                                        }
-                                    }
                                 }
+                                let ecdsa_publickey = store::get_raw(
+                                    &Table::BlsLookup,
+                                    &bs58::encode(&buffer).into_string(),
+                                )?
+                                .unwrap_or_default();
+                                if ecdsa_publickey.is_empty() {
+                                    error!("Cannot find corrosponding ECDSA publickey for BLS signer {}", &bs58::encode(buffer).into_string());
+                                    return Err("Could not find ECDSA counterpart for signers BLS publickey".into());
+                                }
+                                signers.push(ecdsa_publickey);
                             }
-                            debug!("All signers ECDSA counterparts contained in committee {} (index={})", committee.hash, committee.index);
+                            check_signers(&self.hash, &committee, &voting, &signers)?;
                         }
                     } else {
                         error!("Block chunk with hash={} (round={}, proposer={:?}) has invalid aggregated signature={}", self.hash, self.round, self.signers[0], self.aggregated_signature);
@@ -133,135 +134,65 @@ impl Verifiable for BlockChunk {
     }
 
     fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let serialized = self.encode()?;
-        if save_data(
-            &serialized,
-            &(config().db_path + "/blockchunks"),
-            self.hash.clone(),
-        ) == 1
-        {
-            return Ok(());
-        } else {
-            return Err("Failed to save data".into());
-        }
+        store::put_raw(&Table::BlockChunks, &self.hash, &self.encode()?)?;
+        Ok(())
     }
     fn get(hash: String) -> Result<Box<BlockChunk>, Box<dyn std::error::Error>> {
-        let got_data = get_data(config().db_path + "/blockchunks", &hash);
-        if got_data != "-1" {
-            return Ok(Box::new(BlockChunk::decode(got_data)?));
-        } else {
-            return Err("could not find epoch data on disk".into());
+        match store::get_raw(&Table::BlockChunks, &hash)? {
+            Some(got_data) => Ok(Box::new(BlockChunk::decode(got_data)?)),
+            None => Err("could not find block chunk on disk".into()),
         }
     }
 
     fn enact(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let top_epoch = get_top_epoch()?;
+        let mut top_epoch = get_top_epoch()?;
+        if top_epoch.stage == EpochStage::Reorg {
+            // the first chunk of the epoch ends the reorg stage
+            top_epoch.transition(
+                EpochStage::Main,
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("time went backwards")
+                    .as_millis() as u64,
+            )?;
+        }
         // increase current round for committee
-        if save_data(
-            &self.round.to_string(),
-            &(config().db_path + "/blockchunks"),
-            self.committee.to_string() + "-round-" + &top_epoch.epoch_number.to_string(),
-        ) == 1
-        {
-            // save indexes
-            if save_data(
-                &self.hash,
-                &(config().db_path + "/blockchunks"),
-                self.round.to_string()
-                    + "-"
-                    + &top_epoch.epoch_number.to_string()
-                    + "-"
-                    + &self.committee.to_string(),
-            ) == 1
-            {
-                // Now we calculate the reward for the validators and the proposer:
-                // Proposer reward: txn_fees + (base_reward *  (signers_count / commitee size))
-                // Validator reward: 5 / signers_count
-                let base_reward: u64 = to_atomc(1.0);
-                let mut txn_fees: u64 = 0;
-                for block_hash in &self.blocks {
-                    // get the block
-                    let block = Block::get(block_hash.clone())?;
-                    for txn in block.txns {
-                        txn_fees += txn.fee();
-                    }
-                }
-                // now calculate the percentage of validators who signed the chunk
-                let committee = get_top_epoch()?.committees.remove(self.committee as usize);
-                let signer_percent = committee.members.len() / self.signers.len();
-                let proposer_reward = txn_fees + (base_reward * signer_percent as u64);
-                debug!(
-                    "Proposer reward for block chunk {}: {}",
-                    self.hash, proposer_reward
-                );
-
-                // now the validator reward
-                let validator_reward = 5 / (self.signers.len() as u64);
-                debug!(
-                    "Validator reward for block chunk {}: {}",
-                    self.hash, validator_reward
-                );
-                debug!(
-                    "Total mint for block chunk {} = {}",
-                    self.hash,
-                    (base_reward * signer_percent as u64) + 5
-                );
-                // now apply these rewards
-                for (index, bls_signer) in self.signers.clone().iter().enumerate() {
-                    let mut buffer = vec![];
-                    if let Err(e) = bls_signer.write_bytes(&mut buffer) {
-                        error!(
-                            "Failed to write bls publickey bytes to buffer, gave error={}",
-                            e
-                        );
-                        return Err("Failed to write publickey bytes to buffer".into());
-                    } else {
-                        let ecdsa_publickey = get_data(
-                            config().db_path + "/blslookup",
-                            &bs58::encode(&buffer).into_string(),
-                        );
-                        if ecdsa_publickey == "-1" {
-                            error!(
-                                "Cannot find corrosponding ECDSA publickey for BLS signer {}",
-                                &bs58::encode(buffer).into_string()
-                            );
-                            return Err(
-                                "Could not find ECDSA counterpart for signers BLS publickey".into(),
-                            );
-                        } else {
-                            if index == 0 {
-                                if let Ok(mut proposer) = get_account(&ecdsa_publickey) {
-                                    proposer.balance += proposer_reward;
-                                } else {
-                                    error!(
-                                        "Failed to get proposer {} of block chunk {}'s account",
-                                        ecdsa_publickey, self.hash
-                                    );
-                                    return Err("Failed to get proposer account".into());
-                                }
-                            } else {
-                                if let Ok(mut validator) = get_account(&ecdsa_publickey) {
-                                    validator.balance += validator_reward;
-                                } else {
-                                    error!(
-                                        "Failed to get validator {} of block chunk {}'s account",
-                                        ecdsa_publickey, self.hash
-                                    );
-                                    return Err("Failed to get validator account".into());
-                                }
-                            }
-                        }
-                    }
+        store::put(
+            &Table::BlockChunks,
+            &(self.committee.to_string() + "-round-" + &top_epoch.epoch_number.to_string()),
+            &self.round,
+        )?;
+        // save indexes
+        store::put_raw(
+            &Table::BlockChunks,
+            &(self.round.to_string()
+                + "-"
+                + &top_epoch.epoch_number.to_string()
+                + "-"
+                + &self.committee.to_string()),
+            &self.hash,
+        )?;
+        // record the participation of the proposer and validators, used to calculate the rewards at the end of the epoch
+        record_chunk(top_epoch.epoch_number, self.committee)?;
+        record_participation(top_epoch.epoch_number, &self.proposer()?)?;
+        for signer in self.signers()? {
+            record_participation(top_epoch.epoch_number, &signer)?;
+        }
+        debug!(
+            "Recorded participation of {} signers for chunk {}",
+            self.signers.len(),
+            self.hash
+        );
+        // the chunk is signed by a quorum of the committee (see valid), so its blocks are now final
+        for block in &self.blocks {
+            if finalize(block, &self.hash)? {
+                // the block in the finalized chunk wins any fork it is part of
+                if let Some(conflict) = conflict_of(block) {
+                    resolve(&conflict)?;
                 }
-                debug!("Applied all rewards for chunk {}", self.hash);
-
-                return Ok(());
-            } else {
-                return Err("Failed to save data".into());
             }
-        } else {
-            return Err("Failed to save data".into());
         }
+        Ok(())
     }
 }
 
@@ -282,11 +213,11 @@ impl BlockChunk {
     // forms a empty block chunk
     pub fn empty(committee: u64) -> Result<Box<BlockChunk>, Box<dyn std::error::Error>> {
         let top_epoch = get_top_epoch()?;
-        let top_round_index: u64 = get_data(
-            config().db_path + "/blockchunks",
+        let top_round_index: u64 = store::get(
+            &Table::BlockChunks,
             &(committee.to_string() + "-round-" + &top_epoch.epoch_number.to_string()),
-        )
-        .parse()?;
+        )?
+        .ok_or("could not find top round of committee")?;
         let mut top = BlockChunk::get_by_round(top_round_index, top_epoch.epoch_number, committee)?;
         top.blocks = vec![];
         top.aggregated_signature = String::from("");
@@ -306,11 +237,12 @@ impl BlockChunk {
             );
             return Err("Failed to write publickey bytes to buffer".into());
         } else {
-            let ecdsa_publickey = get_data(
-                config().db_path + "/blslookup",
+            let ecdsa_publickey = store::get_raw(
+                &Table::BlsLookup,
                 &bs58::encode(&buffer).into_string(),
-            );
-            if ecdsa_publickey == "-1" {
+            )?
+            .unwrap_or_default();
+            if ecdsa_publickey.is_empty() {
                 error!(
                     "Cannot find corrosponding ECDSA publickey for BLS signer {}",
                     &bs58::encode(buffer).into_string()
@@ -334,11 +266,12 @@ impl BlockChunk {
                 );
                 return Err("Failed to write publickey bytes to buffer".into());
             } else {
-                let ecdsa_publickey = get_data(
-                    config().db_path + "/blslookup",
+                let ecdsa_publickey = store::get_raw(
+                    &Table::BlsLookup,
                     &bs58::encode(&buffer).into_string(),
-                );
-                if ecdsa_publickey == "-1" {
+                )?
+                .unwrap_or_default();
+                if ecdsa_publickey.is_empty() {
                     error!(
                         "Cannot find corrosponding ECDSA publickey for BLS signer {}",
                         &bs58::encode(buffer).into_string()
@@ -359,11 +292,11 @@ impl BlockChunk {
         committee: u64,
     ) -> Result<Box<BlockChunk>, Box<dyn std::error::Error>> {
         let top_epoch = get_top_epoch()?;
-        let top_round_index: u64 = get_data(
-            config().db_path + "/blockchunks",
+        let top_round_index: u64 = store::get(
+            &Table::BlockChunks,
             &(committee.to_string() + "-round-" + &top_epoch.epoch_number.to_string()),
-        )
-        .parse()?;
+        )?
+        .ok_or("could not find top round of committee")?;
         let mut top = BlockChunk::get_by_round(top_round_index, top_epoch.epoch_number, committee)?;
         top.blocks = vec![];
         top.aggregated_signature = String::from("");
@@ -396,14 +329,12 @@ impl BlockChunk {
         epoch: u64,
         committee: u64,
     ) -> Result<Box<BlockChunk>, Box<dyn std::error::Error>> {
-        let got_data = get_data(
-            config().db_path + "/blockchunks",
+        match store::get_raw(
+            &Table::BlockChunks,
             &(round.to_string() + "-" + &epoch.to_string() + "-" + &committee.to_string()),
-        );
-        if got_data != "-1" {
-            return BlockChunk::get(got_data);
-        } else {
-            return Err("Chunk does not exist".into());
+        )? {
+            Some(got_data) => BlockChunk::get(got_data),
+            None => Err("Chunk does not exist".into()),
         }
     }
     pub fn encode(&self) -> Result<String, Box<dyn std::error::Error>> {
@@ -426,8 +357,8 @@ impl BlockChunk {
                 hash: split[0].to_string(),
                 round: split[1].parse()?,
                 blocks: serde_json::from_str(&split[2])?,
-                aggregated_signature: split[3].to_string(),
-                committee: split[4].parse()?,
+                committee: split[3].parse()?,
+                aggregated_signature: split[4].to_string(),
                 signers: signers_string_to_vec(&serde_json::from_str(&split[5])?)?,
             });
         } else {
@@ -436,6 +367,67 @@ impl BlockChunk {
     }
 }
 
+/// Checks the signers (ECDSA publickeys, the proposer first) of the chunk with the hash: every signer must be a member of the committee,
+/// the proposer its round leader and the distinct voting members among the signers a quorum, so repeating a signature does not count it twice
+fn check_signers(
+    hash: &str,
+    committee: &Comitee,
+    voting: &[String],
+    signers: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (index, signer) in signers.iter().enumerate() {
+        if !committee.members.contains(signer) {
+            error!(
+                "Committee {} (index={}) does not contain signer {}",
+                committee.hash, committee.index, signer
+            );
+            return Err("Signer not in committee".into());
+        } else if index == 0 && committee.get_round_leader()? != *signer {
+            // the block proposer (the first signature in the vec) must be the current round leader
+            error!(
+                "Block chunk {} proposed by {}, expected proposer {}",
+                hash,
+                signer,
+                committee.get_round_leader()?
+            );
+            return Err("Unauthorised block proposal".into());
+        }
+    }
+    debug!(
+        "All signers ECDSA counterparts contained in committee {} (index={})",
+        committee.hash, committee.index
+    );
+    if !has_quorum(signers, voting) {
+        error!(
+            "Block chunk {} does not have a quorum of the {} voting members of committee {} (index={}), signers={:?}",
+            hash,
+            voting.len(),
+            committee.hash,
+            committee.index,
+            signers
+        );
+        return Err("Insufficent signers".into());
+    }
+    Ok(())
+}
+
+/// Returns the (epoch, round, committee, hash) of every enacted block chunk, sorted
+pub fn chunk_index() -> Result<Vec<(u64, u64, u64, String)>, Box<dyn std::error::Error>> {
+    let mut chunks: Vec<(u64, u64, u64, String)> = vec![];
+    for (key, hash) in store::entries(&Table::BlockChunks)? {
+        // the chunk indexes are saved under round-epoch-committee
+        let parts: Vec<u64> = key
+            .split('-')
+            .filter_map(|part| part.parse().ok())
+            .collect();
+        if parts.len() == 3 && key.split('-').count() == 3 {
+            chunks.push((parts[1], parts[0], parts[2], hash));
+        }
+    }
+    chunks.sort();
+    Ok(chunks)
+}
+
 // encode and decode helper functions
 pub fn bls_publickey_to_string(key: &PublicKey) -> Result<String, Box<dyn std::error::Error>> {
     let mut buffer = vec![];
@@ -485,3 +477,46 @@ pub fn signers_string_to_vec(
 
 #[test]
 fn test_aggregate_signatures() {}
+
+#[test]
+fn test_encode_decode() {
+    let chunk = BlockChunk {
+        hash: String::from("chunk"),
+        round: 3,
+        blocks: vec![String::from("a"), String::from("b")],
+        aggregated_signature: String::from("signature"),
+        committee: 2,
+        signers: vec![],
+    };
+    let decoded = BlockChunk::decode(chunk.encode().unwrap()).unwrap();
+    assert_eq!(decoded.hash, chunk.hash);
+    assert_eq!(decoded.round, chunk.round);
+    assert_eq!(decoded.blocks, chunk.blocks);
+    assert_eq!(decoded.committee, chunk.committee);
+    assert_eq!(decoded.aggregated_signature, chunk.aggregated_signature);
+    assert!(decoded.signers.is_empty());
+}
+
+#[test]
+fn test_check_signers() {
+    let keys = |keys: &[&str]| -> Vec<String> { keys.iter().map(|key| key.to_string()).collect() };
+    let committee = Comitee {
+        index: 1,
+        members: keys(&["a", "b", "c", "d"]),
+        hash: String::from("committee"),
+    };
+    let valid = |voting: &[&str], signers: &[&str]| {
+        check_signers("chunk", &committee, &keys(voting), &keys(signers)).is_ok()
+    };
+    let all = ["a", "b", "c", "d"];
+    assert!(valid(&all, &["a", "b", "c"]));
+    // repeating a signer does not count it towards the quorum twice
+    assert!(!valid(&all, &["a", "b", "b", "b"]));
+    // nor does repeating a member which toggled off participation
+    assert!(!valid(&["a", "b", "c"], &["a", "b", "d", "d"]));
+    assert!(valid(&["a", "b", "c"], &["a", "b", "c", "d"]));
+    // the proposer must be the round leader
+    assert!(!valid(&all, &["b", "a", "c"]));
+    // and every signer a member of the committee
+    assert!(!valid(&all, &["a", "b", "c", "e"]));
+}
@@ -1,7 +1,11 @@
-//use avrio_config::config;
-use avrio_crypto::{generate_keypair, raw_hash, Hashable};
-use bigdecimal::BigDecimal;
+use avrio_config::config;
+use avrio_crypto::{generate_keypair, raw_hash, raw_lyra, Hashable};
 use log::*;
+use num_bigint::BigUint;
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaCha20Rng,
+};
 use serde::{Deserialize, Serialize};
 use std::ops::RangeInclusive;
 
@@ -12,91 +16,154 @@ pub struct Comitee {
     pub members: Vec<String>,
     pub hash: String,
 }
-pub fn sort_full_list(full_list: &mut Vec<String>, epoch_salt: u64) {
-    // TODO: Fisher-yates shuffle
-    full_list.sort_by(|a, b| {
-        raw_hash(&(a.clone() + &epoch_salt.to_string()))
-            .cmp(&raw_hash(&(b.clone() + &epoch_salt.to_string())))
-    }); // sort the inital vector alphabeticly but with the node's pubkey hashed with epoch salt to give some dermanistic randomness
+/// # Sort full list
+/// Shuffles the list of fullnodes using a Fisher-Yates shuffle, driven by a ChaCha20 CSPRNG
+/// The list is first sorted so the result does not depend on the order the nodes were collected in
+/// The CSPRNG is seeded with the 32 bytes: epoch_salt (8 bytes, little endian) | shuffle_bits (16 bytes, little endian) | 8 zero bytes
+/// For each index i from len - 1 down to 1, a swap index j in 0..=i is drawn by taking u64s from the CSPRNG (low word first),
+/// rejecting any value >= (u64::MAX / (i + 1)) * (i + 1) and using value % (i + 1) so the shuffle is unbiased
+pub fn sort_full_list(full_list: &mut Vec<String>, epoch_salt: u64, shuffle_bits: u128) {
+    full_list.sort();
+    let mut seed = [0u8; 32];
+    seed[0..8].copy_from_slice(&epoch_salt.to_le_bytes());
+    seed[8..24].copy_from_slice(&shuffle_bits.to_le_bytes());
+    let mut rng = ChaCha20Rng::from_seed(seed);
+    for i in (1..full_list.len()).rev() {
+        let bound = (i + 1) as u64;
+        let zone = (u64::MAX / bound) * bound;
+        let mut value = rng.next_u64();
+        while value >= zone {
+            value = rng.next_u64();
+        }
+        full_list.swap(i, (value % bound) as usize);
+    }
+}
+
+/// Returns the hash of the sorted (preshuffle) fullnode list, as announced in the fullnode delta list
+pub fn preshuffle_hash(full_list: &[String]) -> String {
+    let mut hash = String::from("");
+    for fullnode in full_list {
+        hash = raw_lyra(&(hash + fullnode));
+    }
+    hash
+}
+
+/// Returns the hash of the committees formed from the shuffled fullnode list, as announced in the fullnode delta list
+pub fn postshuffle_hash(committees: &[Comitee]) -> String {
+    let mut hash = String::from("");
+    for committee in committees {
+        hash = raw_lyra(&(hash + &committee.hash));
+    }
+    hash
+}
+/// The number of bytes in a (base58 decoded) publickey, the address space is all 2^(8 * ADDRESS_BYTES) values
+pub const ADDRESS_BYTES: usize = 32;
+
+/// Decodes a base58 publickey into its position in the address space
+pub fn address_to_integer(address: &str) -> Result<BigUint, Box<dyn std::error::Error>> {
+    let decoded = bs58::decode(address).into_vec()?;
+    if decoded.len() != ADDRESS_BYTES {
+        error!(
+            "Address {} has wrong decoded length, {} != {}",
+            address,
+            decoded.len(),
+            ADDRESS_BYTES
+        );
+        return Err("Address wrong length".into());
+    }
+    Ok(BigUint::from_bytes_be(&decoded))
+}
+
+/// # Committee for address
+/// Returns the index of the committee which manages the address, given the total number of committees (including the consensus committee)
+/// The consensus chain ("0") belongs to the consensus committee, as does every address if it is the only committee
+/// Otherwise the address space is split evenly between the k = number_of_committees - 1 shard committees; address a is managed by committee 1 + floor(a * k / 2^256)
+pub fn committee_for_address(
+    address: &str,
+    number_of_committees: u64,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    if number_of_committees == 0 {
+        return Err("No committees".into());
+    }
+    if address == "0" || number_of_committees == 1 {
+        return Ok(0);
+    }
+    let shards = BigUint::from(number_of_committees - 1);
+    let shard: BigUint = (address_to_integer(address)? * shards) >> (ADDRESS_BYTES * 8);
+    Ok(1 + shard.to_u64_digits().first().cloned().unwrap_or(0))
 }
+
 impl Comitee {
     /// # Calculate address range
-    /// calculates the address range of this commitee (given the number of commitees) using:
-    /// commitee_index *((58^44)/(commitee_number-1))..=*((58^44)/(commitee_number-1))-((commitee_number-commite_index)*((58^44)/(commitee_number-1)))
-    pub fn calculate_address_range(&self, number_of_committees: u64) -> RangeInclusive<BigDecimal> {
-        // We know there are 58^44 (389790920000000000000000000000000000000000000000000000000000000000000000000000) diffrent posible publickeys (base58 with len of 44), so the number of addresses per shard = (58^44)/(k-1)
-        // k = total number (including 0 committee hence the - 1) of committees
-        // to figure out the address space for comitee n (n = 0 means commitee 1, n = 1 means commitee 2 etc as we ignore the consensus comitee) you just do:
-        // n*((58^44)/(k-1))..=*((58^44)/(k-1))-((k-n)*((58^44)/(k-1)))
-        // if you let j = (58^44)/(k-1) this can simply be written as:
-        //nj..=j-(j(k-(n+1)))
-        let k = BigDecimal::from(number_of_committees - 1);
-        let j = BigDecimal::from(58 ^ 44) / k.clone(); // not a decimal but bigdecimal works to store large integers too
-        if self.index != 0 {
-            let n = BigDecimal::from(self.index - 1);
-            n * j.clone()..=j.clone() - (j * (k - BigDecimal::from(self.index)))
-        } else {
-            BigDecimal::from(0)..=BigDecimal::from(0)
+    /// Calculates the (inclusive) range of the address space managed by this commitee, given the total number of commitees (including the consensus committee)
+    /// Shard committee n (of k = number_of_committees - 1 shard committees) manages ceil((n - 1) * 2^256 / k)..=ceil(n * 2^256 / k) - 1
+    /// The consensus committee manages the whole address space if it is the only committee, otherwise the range is empty
+    pub fn calculate_address_range(&self, number_of_committees: u64) -> RangeInclusive<BigUint> {
+        let space = BigUint::from(1u8) << (ADDRESS_BYTES * 8);
+        if self.index == 0 || self.index >= number_of_committees {
+            if self.index == 0 && number_of_committees == 1 {
+                return BigUint::from(0u8)..=(space - 1u8);
+            }
+            return BigUint::from(1u8)..=BigUint::from(0u8); // empty
         }
+        let k = BigUint::from(number_of_committees - 1);
+        // ceil(x / k) = (x + k - 1) / k
+        let ceil_div = |x: BigUint| (x + &k - 1u8) / &k;
+        let start = ceil_div(&space * BigUint::from(self.index - 1));
+        let end = ceil_div(&space * BigUint::from(self.index)) - 1u8;
+        start..=end
     }
     /// # Manages Address
-    /// Checks if this committee manages a given address
+    /// Checks if this committee manages a given address, using the number of commitees in the top epoch
     pub fn manages_address(&self, address: &String) -> Result<bool, Box<dyn std::error::Error>> {
-        if self.index == 0 {
-            return Ok(false);
-        }
-        let address_hex = hex::encode(bs58::decode(address).into_vec()?);
-        let address_numerical = i64::from_str_radix(&address_hex, 16)?.abs();
-        let address_bigdec = BigDecimal::from(address_numerical);
-        Ok(self.calculate_address_range(2).contains(&address_bigdec)) // TODO: get the number of commitees (or pass as a parmeter)
+        let number_of_committees = get_top_epoch()?.committees.len() as u64;
+        Ok(committee_for_address(address, number_of_committees)? == self.index)
+    }
+    /// # Committee count
+    /// Calculates the number of committees (including the consensus committee) to form from the given number of fullnodes
+    /// This is the target_committee_count, unless there are too few fullnodes for every committee to have at least min_committee_size members
+    /// There is always at least one committee (the consensus committee)
+    pub fn committee_count(fullnodes: u64) -> u64 {
+        let min_committee_size = config().min_committee_size.max(1);
+        let max_committees = (fullnodes / min_committee_size).max(1);
+        config().target_committee_count.max(1).min(max_committees)
     }
     /// # Form Committees
     /// Forms the commiitees given a list of shuffled nodes, as well as mutable refrence to the vector where excluded nodes are placed and a number (of commitees to be formed)
-    /// Returns a vector of committess which it's length should be count - 1
+    /// If the nodes do not divide evenly the remainder is spread over the first committees (so committee sizes differ by at most one), nodes are only excluded if count is 0
+    /// Returns a vector of count committees
     pub fn form_comitees(
         sorted_list: &mut Vec<String>,
         excluded_nodes: &mut Vec<String>,
         count: u64,
     ) -> Vec<Comitee> {
-        loop {
-            if sorted_list.len() % count as usize != 0 {
-                if let Some(exclued) = sorted_list.pop() {
-                    info!(
-                        "Excluded fullnode {} from epoch (comitee overflow)",
-                        exclued
-                    );
-                    excluded_nodes.push(exclued);
-                } else {
-                    error!("Tried to exclude top node from sorted_list by returned a None value");
-                }
-            } else {
-                break;
-            }
-        }
-        if excluded_nodes.len() != 0 {
-            debug!(
-                "Had to exclude {} fullnodes from epoch to fit",
-                excluded_nodes.len()
-            );
+        if count == 0 {
+            error!("Tried to form 0 comitees, excluding all fullnodes");
+            excluded_nodes.append(sorted_list);
+            return vec![];
         }
         let comitee_size = sorted_list.len() / count as usize;
+        let remainder = sorted_list.len() % count as usize;
         info!(
-            "Comitee count for epoch: {}, comitee size={}",
-            count, comitee_size
+            "Comitee count for epoch: {}, comitee size={} (+1 for {} comitees)",
+            count, comitee_size, remainder
         );
         let mut to_return: Vec<Comitee> = vec![];
-        let mut to_assign = sorted_list.clone();
+        let mut to_assign = sorted_list.iter();
         for comitee_index in 0..count {
             let mut formed_comitee = Comitee {
                 index: comitee_index,
                 members: vec![],
                 hash: String::from(""),
             };
-            while formed_comitee.members.len() != comitee_size {
-                if let Some(top_node) = to_assign.pop() {
-                    debug!("Assigned {} to comitee {}", top_node, comitee_index);
-                    formed_comitee.members.push(top_node);
-                }
+            let this_size = if (comitee_index as usize) < remainder {
+                comitee_size + 1
+            } else {
+                comitee_size
+            };
+            for node in to_assign.by_ref().take(this_size) {
+                debug!("Assigned {} to comitee {}", node, comitee_index);
+                formed_comitee.members.push(node.clone());
             }
             formed_comitee.hash = formed_comitee.hash_item();
             debug!(
@@ -108,6 +175,43 @@ impl Comitee {
         return to_return;
     }
 
+    /// # Form Committees with observers
+    /// Forms the committees from a shuffled list of nodes, some of which are probationary observers (see probation.rs)
+    /// The committee count is calculated from, and the committees are formed with, the voting nodes only (so each committees round leader votes)
+    /// The observers are then dealt out to the committees in turn, in their shuffled order
+    /// If every node is on probation there is no one else to vote, so the observers are formed into committees as voting nodes
+    pub fn form_comitees_with_observers(
+        shuffled_list: &[String],
+        observers: &[String],
+    ) -> Vec<Comitee> {
+        let (mut voting, mut observing): (Vec<String>, Vec<String>) = shuffled_list
+            .iter()
+            .cloned()
+            .partition(|node| !observers.contains(node));
+        if voting.is_empty() {
+            warn!(
+                "No graduated fullnodes to form committees from, {} probationary fullnodes vote",
+                observing.len()
+            );
+            voting = std::mem::take(&mut observing);
+        }
+        let count = Comitee::committee_count(voting.len() as u64);
+        let mut committees = Comitee::form_comitees(&mut voting, &mut vec![], count);
+        let number_of_committees = committees.len();
+        for (index, observer) in observing.into_iter().enumerate() {
+            debug!(
+                "Assigned observer {} to comitee {}",
+                observer,
+                index % number_of_committees
+            );
+            committees[index % number_of_committees].members.push(observer);
+        }
+        for committee in &mut committees {
+            committee.hash = committee.hash_item();
+        }
+        committees
+    }
+
     /// # Get round leader
     /// Calculates the round leader for this committee, returning the ECDSA publickey or an error 
     pub fn get_round_leader(&self) -> Result<String, Box<dyn std::error::Error>> {
@@ -137,6 +241,30 @@ impl Hashable for Comitee {
 }
 
 // TESTS
+#[test]
+fn test_observer_placement() {
+    let nodes: Vec<String> = (0..10).map(|i| format!("node{}", i)).collect();
+    let observers: Vec<String> = vec!["node1".into(), "node4".into(), "node9".into()];
+    let committees = Comitee::form_comitees_with_observers(&nodes, &observers);
+    // committees are formed from the voting nodes, so every round leader votes
+    for committee in &committees {
+        assert!(!observers.contains(&committee.get_round_leader().unwrap()));
+    }
+    let placed: usize = committees.iter().map(|c| c.members.len()).sum();
+    assert_eq!(placed, nodes.len());
+}
+
+#[test]
+fn test_only_observers() {
+    let nodes: Vec<String> = (0..4).map(|i| format!("node{}", i)).collect();
+    // when every node is on probation they are placed as voting nodes, in their shuffled order
+    let committees = Comitee::form_comitees_with_observers(&nodes, &nodes);
+    assert!(!committees.is_empty());
+    assert_eq!(committees[0].get_round_leader().unwrap(), "node0");
+    let placed: usize = committees.iter().map(|c| c.members.len()).sum();
+    assert_eq!(placed, nodes.len());
+}
+
 #[test]
 fn test_list_ordering() {
     let _ = simple_logger::init();
@@ -150,18 +278,55 @@ fn test_list_ordering() {
     let mut keypairs_rep = keypairs.clone();
     let mut diffrent_salt = keypairs.clone();
     println!("Unsorted: {:#?}", keypairs);
-    sort_full_list(&mut keypairs, epoch_salt); // mutates keypairs
+    sort_full_list(&mut keypairs, epoch_salt, 0); // mutates keypairs
     println!("After sort: {:#?}", keypairs);
     for entry in &keypairs {
         assert!(keypairs_rep.contains(entry)); // make sure the public keys have not changed values (only order)
     }
-    sort_full_list(&mut diffrent_salt, other_salt); // mutates diffrent_salt
+    sort_full_list(&mut diffrent_salt, other_salt, 0); // mutates diffrent_salt
     println!("With diffrent salt: {:#?}", diffrent_salt);
     assert_ne!(keypairs, diffrent_salt);
-    sort_full_list(&mut keypairs_rep, epoch_salt); // mutates keypairs_rep
+    sort_full_list(&mut keypairs_rep, epoch_salt, 0); // mutates keypairs_rep
     assert_eq!(keypairs, keypairs_rep)
 }
 #[test]
+fn test_shuffle_vectors() {
+    // test vectors for other implementations of the committee shuffle
+    let nodes: Vec<String> = (0..10).map(|i| format!("node{}", i)).collect();
+    let vectors: Vec<(u64, u128, [&str; 10])> = vec![
+        (
+            12345,
+            67890,
+            [
+                "node8", "node9", "node7", "node6", "node2", "node3", "node1", "node5", "node0",
+                "node4",
+            ],
+        ),
+        (
+            12346,
+            67890,
+            [
+                "node1", "node0", "node4", "node2", "node8", "node9", "node7", "node3", "node6",
+                "node5",
+            ],
+        ),
+        (
+            0,
+            0,
+            [
+                "node9", "node7", "node3", "node6", "node1", "node4", "node8", "node5", "node2",
+                "node0",
+            ],
+        ),
+    ];
+    for (salt, shuffle_bits, expected) in vectors {
+        let mut shuffled = nodes.clone();
+        shuffled.reverse(); // input order must not matter
+        sort_full_list(&mut shuffled, salt, shuffle_bits);
+        assert_eq!(shuffled, expected.to_vec());
+    }
+}
+#[test]
 fn test_comitee_formation() {
     let _ = simple_logger::init();
     let mut keypairs: Vec<String> = vec![];
@@ -169,15 +334,81 @@ fn test_comitee_formation() {
         keypairs.push(generate_keypair().public_key);
     }
     let epoch_salt = 12345;
-    sort_full_list(&mut keypairs, epoch_salt); // mutates keypairs
+    sort_full_list(&mut keypairs, epoch_salt, 0); // mutates keypairs
     let mut excluded_nodes: Vec<String> = vec![];
-    //try with 0 excluded nodes expected
+    // divides evenly
     let comitees = Comitee::form_comitees(&mut keypairs, &mut excluded_nodes, 10);
     assert!(excluded_nodes.len() == 0);
+    assert_eq!(comitees.len(), 10);
+    assert!(comitees.iter().all(|c| c.members.len() == 10));
     println!("First run comitees (c=10): {:#?}", comitees);
-    //try with excluded nodes expected
+    // the remainder is spread over the first comitees, no node is excluded
     let comitees = Comitee::form_comitees(&mut keypairs, &mut excluded_nodes, 9);
     println!("Seccond run comitees (c=9): {:#?}", comitees);
-    println!("Excluded={}", excluded_nodes.len());
-    assert!(excluded_nodes.len() != 0);
+    assert!(excluded_nodes.len() == 0);
+    assert_eq!(comitees.len(), 9);
+    assert!(comitees.iter().all(|c| c.members.len() == 11 || c.members.len() == 12));
+    let mut assigned: Vec<String> = comitees.into_iter().flat_map(|c| c.members).collect();
+    assigned.sort();
+    let mut expected = keypairs.clone();
+    expected.sort();
+    assert_eq!(assigned, expected);
+}
+#[test]
+fn test_committee_count() {
+    // default config: target_committee_count = 2, min_committee_size = 4
+    assert_eq!(Comitee::committee_count(0), 1);
+    assert_eq!(Comitee::committee_count(1), 1);
+    assert_eq!(Comitee::committee_count(7), 1);
+    assert_eq!(Comitee::committee_count(8), 2);
+    assert_eq!(Comitee::committee_count(1000), 2);
+}
+#[test]
+fn test_address_mapping() {
+    let _ = simple_logger::init();
+    let max_address = bs58::encode(vec![255u8; ADDRESS_BYTES]).into_string();
+    let min_address = bs58::encode(vec![0u8; ADDRESS_BYTES]).into_string();
+    for number_of_committees in 1..=17 {
+        let committees: Vec<Comitee> = (0..number_of_committees)
+            .map(|index| Comitee {
+                index,
+                members: vec![],
+                hash: String::from(""),
+            })
+            .collect();
+        // the ranges of the shard committees are contiguous and cover the whole address space
+        if number_of_committees > 1 {
+            let mut next_start = BigUint::from(0u8);
+            for committee in &committees[1..] {
+                let range = committee.calculate_address_range(number_of_committees);
+                assert_eq!(range.start(), &next_start);
+                assert!(range.start() <= range.end());
+                next_start = range.end().clone() + 1u8;
+            }
+            assert_eq!(next_start, BigUint::from(1u8) << (ADDRESS_BYTES * 8));
+        }
+        // every address is in exactly one committee's range, which is the committee it maps to
+        let mut addresses = vec![min_address.clone(), max_address.clone()];
+        for _ in 0..100 {
+            addresses.push(generate_keypair().public_key);
+        }
+        for address in &addresses {
+            let address_integer = address_to_integer(address).unwrap();
+            let containing: Vec<u64> = committees
+                .iter()
+                .filter(|committee| {
+                    committee
+                        .calculate_address_range(number_of_committees)
+                        .contains(&address_integer)
+                })
+                .map(|committee| committee.index)
+                .collect();
+            assert_eq!(containing.len(), 1);
+            assert_eq!(
+                containing[0],
+                committee_for_address(address, number_of_committees).unwrap()
+            );
+        }
+        assert_eq!(committee_for_address("0", number_of_committees).unwrap(), 0);
+    }
 }
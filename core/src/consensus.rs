@@ -0,0 +1,498 @@
+// This file produces and validates consensus blocks, the blocks of the consensus chain ("0").
+// Consensus transactions (epoch salt seeds 'a', fullnode list deltas 'y', shuffle bits 'z' and epoch rewards 'r') are sent by the round leader
+// of the consensus committee (committees[0]), who puts them in a send block on the "0" chain and asks the rest of the committee to sign it.
+// Once a quorum of the committees voting members have signed the block, their aggregated BLS signature is saved and the block is final.
+// Nodes only accept a consensus send block once they have its aggregated signature, which the round leader sends before the block.
+// Fullnode registrations ('f') and penalty proposals ('g') are signed by their senders, so they stay on the senders chains; their effects reach
+// the consensus chain through the next fullnode list delta.
+extern crate avrio_database;
+
+use avrio_crypto::Hashable;
+use avrio_database::store::{get, get_raw, put, Table};
+use bls_signatures::{aggregate, verify_messages, Serialize as BlsSerialize, Signature};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::{get_block_from_raw, Block, BlockType, BlockValidationErrors},
+    chunk::{bls_publickey_to_string, string_to_bls_privatkey, string_to_bls_publickey},
+    epoch::{get_top_epoch, Epoch},
+    finality::finalize,
+    participation::{quorum_size, voting_members},
+    snapshot::Snapshot,
+    transaction::{decode_delta_list, Transaction},
+    validate::Verifiable,
+};
+
+/// The maximum number of transactions in a consensus block // TODO: move to config
+pub const MAX_CONSENSUS_TXNS: usize = 3;
+
+/// # Check consensus txns
+/// Checks the transactions of a consensus block: there are at most MAX_CONSENSUS_TXNS, each is a consensus transaction and each was sent by the round leader
+/// Does not check the transactions themselves are valid
+pub fn check_consensus_txns(
+    txns: &[Transaction],
+    round_leader: &str,
+) -> Result<(), BlockValidationErrors> {
+    if txns.len() > MAX_CONSENSUS_TXNS {
+        return Err(BlockValidationErrors::TooManyTxn);
+    }
+    for txn in txns {
+        if !txn.consensus_type() {
+            debug!(
+                "Non-consensus txn {} in consensus block, flag={} (type={})",
+                txn.hash,
+                txn.flag,
+                txn.type_transaction()
+            );
+            return Err(BlockValidationErrors::ContainsNonConsensusTxn);
+        }
+        if txn.sender_key != round_leader {
+            debug!(
+                "Consensus txn {} not sent by round leader {} (sent by {})",
+                txn.hash, round_leader, txn.sender_key
+            );
+            return Err(BlockValidationErrors::UnauthorisedConsensusBlock);
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the signers (ECDSA publickeys) include a quorum of the voting members, signers outside the voting members and duplicates are not counted
+pub fn has_quorum(signers: &[String], voting: &[String]) -> bool {
+    let mut counted: Vec<&String> = signers
+        .iter()
+        .filter(|signer| voting.contains(signer))
+        .collect();
+    counted.sort();
+    counted.dedup();
+    counted.len() as u64 >= quorum_size(voting.len() as u64)
+}
+
+/// Returns the message a consensus committee member signs for the block, distinct per signer so the signatures can be aggregated
+pub fn consensus_signature_message(block_hash: &str, bls_publickey: &str) -> String {
+    block_hash.to_owned() + "-" + bls_publickey
+}
+
+/// # Valid consensus signature
+/// Returns true if the signature over the block is valid and was made by a member of the consensus committee
+pub fn valid_consensus_signature(
+    block_hash: &str,
+    members: &[String],
+    signature: &(String, String),
+) -> bool {
+    let (publickey, signature) = signature;
+    let ecdsa_publickey = get_raw(&Table::BlsLookup, publickey)
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    if !members.contains(&ecdsa_publickey) {
+        debug!("Consensus block signer {} not in consensus committee", publickey);
+        return false;
+    }
+    match (
+        string_to_bls_publickey(publickey),
+        bs58::decode(signature)
+            .into_vec()
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| Signature::from_bytes(&bytes).map_err(|e| e.to_string())),
+    ) {
+        (Ok(publickey_struct), Ok(signature)) => verify_messages(
+            &signature,
+            &[consensus_signature_message(block_hash, publickey).as_bytes()],
+            &[publickey_struct],
+        ),
+        _ => false,
+    }
+}
+
+/// # Form consensus blocks
+/// Puts the consensus transactions into a send block on the "0" chain and forms its recieve block, both signed with the private key
+pub fn form_consensus_blocks(
+    txns: Vec<Transaction>,
+    private_key: &str,
+) -> Result<(Block, Block), Box<dyn std::error::Error>> {
+    if txns.is_empty() {
+        return Err("No consensus transactions to put in block".into());
+    }
+    if let Some(txn) = txns.iter().find(|txn| !txn.consensus_type()) {
+        error!(
+            "Tried to form consensus block with non-consensus txn {}, flag={}",
+            txn.hash, txn.flag
+        );
+        return Err(Box::new(BlockValidationErrors::ContainsNonConsensusTxn));
+    }
+    let send_block = Block::new(txns, private_key.to_owned(), None);
+    let mut recieve_block = send_block.form_receive_block(Some(String::from("0")))?;
+    recieve_block
+        .sign(private_key)
+        .map_err(|e| format!("Failed to sign consensus recieve block, error={:?}", e))?;
+    Ok((send_block, recieve_block))
+}
+
+/// # Sign consensus block
+/// Called by consensus committee members when the round leader proposes a consensus block
+/// Validates the block (which is not signed by the committee yet) and returns our bs58 encoded BLS publickey and signature
+pub fn sign_consensus_block(
+    block: &Block,
+    bls_private_key: &str,
+) -> Result<(String, String), Box<dyn std::error::Error>> {
+    if block.header.chain_key != "0" || block.block_type != BlockType::Send {
+        return Err("Not a consensus send block".into());
+    }
+    block.validate(true)?;
+    // keep our snapshot of the state the next epoch starts from if it is the one the round leader proposed. A member whose state differs (as it enacted
+    // blocks in a different order) still signs, the delta list is valid and the epoch should not stall on it
+    for txn in block.txns.iter().filter(|txn| txn.flag == 'y') {
+        let (_, _, snapshot_hash) = decode_delta_list(&txn.extra)?;
+        if !snapshot_hash.is_empty() {
+            Snapshot::agree(get_top_epoch()?.epoch_number + 1, Some(&snapshot_hash))?;
+        }
+    }
+    let private_key = string_to_bls_privatkey(&bls_private_key.to_owned())?;
+    let publickey = bls_publickey_to_string(&private_key.public_key())?;
+    let signature = private_key.sign(consensus_signature_message(&block.hash, &publickey));
+    Ok((publickey, bs58::encode(signature.as_bytes()).into_string()))
+}
+
+/// # Enact consensus blocks
+/// Validates, saves and enacts the blocks in order, stopping at the first error
+pub fn enact_consensus_blocks(blocks: &[Block]) -> Result<(), Box<dyn std::error::Error>> {
+    for block in blocks {
+        block.valid()?;
+        block.save()?;
+        block.enact()?;
+        debug!(
+            "Enacted consensus block {} (type={:?}, height={})",
+            block.hash, block.block_type, block.header.height
+        );
+    }
+    Ok(())
+}
+
+/// The aggregated BLS signature of the consensus committee over a consensus send block
+/// Consensus send blocks are only valid once their signatures are saved (see Block::valid), so the round leader sends these before the blocks
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ConsensusSignatures {
+    pub hash: String,
+    pub block_hash: String,
+    pub epoch: u64, // the epoch the block was produced in, the signers must be members of its consensus committee
+    pub aggregated_signature: String,
+    pub signers: Vec<String>, // the bs58 encoded BLS publickeys of the signers
+}
+
+impl Hashable for ConsensusSignatures {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(self.block_hash.bytes());
+        bytes.extend(self.epoch.to_string().bytes());
+        bytes.extend(self.aggregated_signature.bytes());
+        for signer in &self.signers {
+            bytes.extend(signer.bytes());
+        }
+        bytes
+    }
+}
+
+impl ConsensusSignatures {
+    /// # Aggregate
+    /// Aggregates the (bs58 BLS publickey, bs58 BLS signature) pairs over the block produced in the epoch, skipping duplicate signers
+    pub fn aggregate(
+        block_hash: &str,
+        epoch: u64,
+        signatures: &[(String, String)],
+    ) -> Result<ConsensusSignatures, Box<dyn std::error::Error>> {
+        let mut signers: Vec<String> = vec![];
+        let mut decoded: Vec<Signature> = vec![];
+        for (signer, signature) in signatures {
+            if signers.contains(signer) {
+                continue;
+            }
+            decoded.push(Signature::from_bytes(&bs58::decode(signature).into_vec()?)?);
+            signers.push(signer.clone());
+        }
+        if decoded.is_empty() {
+            return Err("No signatures to aggregate".into());
+        }
+        let mut aggregated = ConsensusSignatures {
+            hash: String::default(),
+            block_hash: block_hash.to_owned(),
+            epoch,
+            aggregated_signature: bs58::encode(aggregate(&decoded)?.as_bytes()).into_string(),
+            signers,
+        };
+        aggregated.hash = aggregated.hash_item();
+        Ok(aggregated)
+    }
+
+    /// Returns the ECDSA publickeys of the signers
+    pub fn ecdsa_signers(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let mut ecdsa_signers = vec![];
+        for signer in &self.signers {
+            match get_raw(&Table::BlsLookup, signer)? {
+                Some(ecdsa_publickey) => ecdsa_signers.push(ecdsa_publickey),
+                None => {
+                    error!("Cannot find corrosponding ECDSA publickey for BLS signer {}", signer);
+                    return Err("Could not find ECDSA counterpart for signers BLS publickey".into());
+                }
+            }
+        }
+        Ok(ecdsa_signers)
+    }
+}
+
+impl Verifiable for ConsensusSignatures {
+    fn valid(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.hash_item() != self.hash {
+            return Err("Hash mismatch".into());
+        }
+        // the signatures are validated before the block is (which needs them), so we may not have the block yet
+        let block = get_block_from_raw(self.block_hash.clone());
+        if !block.is_default()
+            && (block.header.chain_key != "0" || block.block_type != BlockType::Send)
+        {
+            return Err("Signed block not a consensus send block".into());
+        }
+        let aggregated =
+            Signature::from_bytes(&bs58::decode(&self.aggregated_signature).into_vec()?)?;
+        let mut publickeys = vec![];
+        for signer in &self.signers {
+            publickeys.push(string_to_bls_publickey(signer)?);
+        }
+        let messages: Vec<String> = self
+            .signers
+            .iter()
+            .map(|signer| consensus_signature_message(&self.block_hash, signer))
+            .collect();
+        let messages: Vec<&[u8]> = messages.iter().map(|message| message.as_bytes()).collect();
+        if !verify_messages(&aggregated, &messages, &publickeys) {
+            error!(
+                "Invalid aggregated signature {} on consensus block {}",
+                self.aggregated_signature, self.block_hash
+            );
+            return Err("Invalid aggregate signature".into());
+        }
+        let epoch = Epoch::get(self.epoch)?;
+        let committee = epoch
+            .committees
+            .first()
+            .cloned()
+            .ok_or("No consensus committee")?;
+        let signers = self.ecdsa_signers()?;
+        if let Some(outsider) = signers
+            .iter()
+            .find(|signer| !committee.members.contains(signer))
+        {
+            error!(
+                "Consensus block {} signed by {}, who is not in the consensus committee",
+                self.block_hash, outsider
+            );
+            return Err("Signer not in consensus committee".into());
+        }
+        // fullnodes which toggled off participation and probationary observers are not counted towards the quorum
+        if !has_quorum(&signers, &voting_members(epoch.epoch_number, &committee)) {
+            error!(
+                "Consensus block {} has {} signers, not a quorum of the consensus committee",
+                self.block_hash,
+                signers.len()
+            );
+            return Err("Insufficent signers".into());
+        }
+        Ok(())
+    }
+
+    fn get(block_hash: String) -> Result<Box<Self>, Box<dyn std::error::Error>> {
+        match get(&Table::Consensus, &block_hash)? {
+            Some(signatures) => Ok(Box::new(signatures)),
+            None => Err("Could not find consensus signatures on disk".into()),
+        }
+    }
+
+    fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        put(&Table::Consensus, &self.block_hash, self)?;
+        Ok(())
+    }
+
+    fn enact(&self) -> Result<(), Box<dyn std::error::Error>> {
+        // the block is signed by a quorum of the consensus committee (see valid), so it is now final
+        finalize(&self.block_hash, &self.hash)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{commitee::Comitee, participation::record_voting_members};
+    use avrio_database::{backend::MemoryBackend, store::put_raw, with_backend};
+    use bls_signatures::PrivateKey;
+    use std::sync::Arc;
+
+    /// Returns the bs58 encoded (BLS privatekey, BLS publickey) derived from seed, registering the ECDSA publickey ecdsa as its owner
+    fn bls_member(seed: u8, ecdsa: &str) -> (String, String) {
+        let private_key = PrivateKey::new([seed; 32]);
+        let publickey = bls_publickey_to_string(&private_key.public_key()).unwrap();
+        put_raw(&Table::BlsLookup, &publickey, ecdsa).unwrap();
+        (bs58::encode(private_key.as_bytes()).into_string(), publickey)
+    }
+
+    fn sign(block_hash: &str, private_key: &str) -> (String, String) {
+        let private_key = string_to_bls_privatkey(&private_key.to_owned()).unwrap();
+        let publickey = bls_publickey_to_string(&private_key.public_key()).unwrap();
+        let signature = private_key.sign(consensus_signature_message(block_hash, &publickey));
+        (publickey, bs58::encode(signature.as_bytes()).into_string())
+    }
+
+    /// Saves epoch 1, whose consensus committee is the ECDSA publickeys members
+    fn consensus_epoch(members: &[&str]) {
+        let mut epoch = Epoch::new();
+        epoch.epoch_number = 1;
+        epoch.committees = vec![Comitee {
+            index: 0,
+            members: members.iter().map(|member| member.to_string()).collect(),
+            hash: String::default(),
+        }];
+        epoch.save().unwrap();
+        record_voting_members(&epoch).unwrap();
+    }
+
+    fn consensus_txn(flag: char, sender_key: &str) -> Transaction {
+        Transaction {
+            flag,
+            sender_key: sender_key.to_owned(),
+            receive_key: String::from("0"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_consensus_txns() {
+        let leader = "leader";
+        assert!(check_consensus_txns(&[], leader).is_ok());
+        for flag in &['a', 'y', 'z', 'r'] {
+            assert!(check_consensus_txns(&[consensus_txn(*flag, leader)], leader).is_ok());
+        }
+        let txns = vec![consensus_txn('z', leader); MAX_CONSENSUS_TXNS];
+        assert!(check_consensus_txns(&txns, leader).is_ok());
+    }
+
+    #[test]
+    fn test_check_consensus_txns_rejected() {
+        let leader = "leader";
+        let too_many = vec![consensus_txn('a', leader); MAX_CONSENSUS_TXNS + 1];
+        assert!(matches!(
+            check_consensus_txns(&too_many, leader),
+            Err(BlockValidationErrors::TooManyTxn)
+        ));
+        for flag in &['n', 'f', 'g', 'v'] {
+            assert!(matches!(
+                check_consensus_txns(&[consensus_txn(*flag, leader)], leader),
+                Err(BlockValidationErrors::ContainsNonConsensusTxn)
+            ));
+        }
+        assert!(matches!(
+            check_consensus_txns(
+                &[consensus_txn('y', leader), consensus_txn('y', "member")],
+                leader
+            ),
+            Err(BlockValidationErrors::UnauthorisedConsensusBlock)
+        ));
+    }
+
+    #[test]
+    fn test_has_quorum() {
+        let voting: Vec<String> = (0..4).map(|i| i.to_string()).collect();
+        let signers = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        assert!(has_quorum(&signers(&["0", "1", "2"]), &voting));
+        assert!(!has_quorum(&signers(&["0", "1"]), &voting));
+        // duplicates and non voting signers are not counted
+        assert!(!has_quorum(&signers(&["0", "1", "1"]), &voting));
+        assert!(!has_quorum(&signers(&["0", "1", "observer"]), &voting));
+    }
+
+    #[test]
+    fn test_valid_consensus_signature() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let (private_key, _) = bls_member(1, "member");
+            let members = vec!["member".to_owned()];
+            let signature = sign("block", &private_key);
+            assert!(valid_consensus_signature("block", &members, &signature));
+            // a signature over another block
+            assert!(!valid_consensus_signature("other", &members, &signature));
+            // a signer outside the committee
+            assert!(!valid_consensus_signature("block", &["outsider".to_owned()], &signature));
+            let (outsider_key, _) = bls_member(2, "outsider");
+            let (_, outsider_signature) = sign("block", &outsider_key);
+            assert!(!valid_consensus_signature(
+                "block",
+                &members,
+                &(signature.0, outsider_signature)
+            ));
+        });
+    }
+
+    #[test]
+    fn test_sign_consensus_block_rejected() {
+        let private_key = bs58::encode(PrivateKey::new([1; 32]).as_bytes()).into_string();
+        let mut block = Block::default();
+        block.header.chain_key = "abc".to_owned();
+        assert!(sign_consensus_block(&block, &private_key).is_err());
+        block.header.chain_key = "0".to_owned();
+        block.block_type = BlockType::Recieve;
+        assert!(sign_consensus_block(&block, &private_key).is_err());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert!(ConsensusSignatures::aggregate("block", 1, &[]).is_err());
+            let (first, first_publickey) = bls_member(1, "first");
+            let (second, second_publickey) = bls_member(2, "second");
+            let signatures = vec![
+                sign("block", &first),
+                sign("block", &second),
+                sign("block", &first),
+            ];
+            let aggregated = ConsensusSignatures::aggregate("block", 1, &signatures).unwrap();
+            // the duplicate signature is skipped
+            assert_eq!(aggregated.signers, vec![first_publickey, second_publickey]);
+            assert_eq!(aggregated.epoch, 1);
+            assert_eq!(aggregated.hash, aggregated.hash_item());
+            assert_eq!(
+                aggregated.ecdsa_signers().unwrap(),
+                vec!["first".to_owned(), "second".to_owned()]
+            );
+        });
+    }
+
+    #[test]
+    fn test_consensus_signatures_valid() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            consensus_epoch(&["0", "1", "2", "3"]);
+            let keys: Vec<String> = (0..5u8)
+                .map(|i| bls_member(i + 1, &i.to_string()).0)
+                .collect();
+            let signed_by = |signers: &[usize], epoch: u64| {
+                let signatures: Vec<(String, String)> = signers
+                    .iter()
+                    .map(|signer| sign("block", &keys[*signer]))
+                    .collect();
+                ConsensusSignatures::aggregate("block", epoch, &signatures).unwrap()
+            };
+            assert!(signed_by(&[0, 1, 2], 1).valid().is_ok());
+            // not a quorum of the committee
+            assert!(signed_by(&[0, 1], 1).valid().is_err());
+            // signed by a fullnode outside the consensus committee
+            assert!(signed_by(&[0, 1, 2, 4], 1).valid().is_err());
+            // the signers are checked against the committee of the epoch the block was produced in
+            assert!(signed_by(&[0, 1, 2], 2).valid().is_err());
+            // tampered with after aggregating
+            let mut tampered = signed_by(&[0, 1, 2], 1);
+            tampered.block_hash = "other".to_owned();
+            tampered.hash = tampered.hash_item();
+            assert!(tampered.valid().is_err());
+            let mut moved = signed_by(&[0, 1, 2], 1);
+            moved.epoch = 2;
+            assert!(moved.valid().is_err());
+        });
+    }
+}
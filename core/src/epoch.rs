@@ -6,14 +6,29 @@ extern crate avrio_config;
 extern crate rand;
 
 use avrio_config::config;
-use avrio_crypto::Hashable;
-use avrio_database::{get_data, save_data};
+use avrio_crypto::{proof_to_hash, raw_hash, raw_lyra, Hashable};
+use avrio_database::store::{get, get_raw, put, put_raw, Table};
+use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
 
 use crate::commitee::Comitee;
 extern crate bs58;
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+lazy_static! {
+    /// Called when an epoch moves from one stage to another, with the epoch number, the old stage and the new stage
+    /// The callbacks are called after the lock is released, so they can register callbacks or move epochs themselves
+    pub static ref EPOCH_STAGE_CALLBACKS: Mutex<Vec<Arc<dyn Fn(u64, EpochStage, EpochStage) + Send + Sync>>> = Mutex::new(vec![]);
+}
+
+#[derive(Debug, Error)]
+pub enum EpochErrors {
+    #[error("Invalid epoch stage transition from {0:?} to {1:?}")]
+    InvalidTransition(EpochStage, EpochStage),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
 pub enum EpochStage {
     Reorg, // period of time during which the network is handshaking with its new commitee members. Lasts untill the first block chunk is enacted, logically identical to Main 
     Failed, // Untill we get enough fullnodes to form the commitees the network sits in this state
@@ -28,6 +43,41 @@ impl Default for EpochStage {
     }
 }
 
+impl EpochStage {
+    /// Returns true if an epoch in this stage can move to the stage to
+    /// Failed -> Reorg -> Main -> VrfLotto -> Final, Reorg can skip to VrfLotto (no chunks were formed) and Reorg, Main and VrfLotto can fail
+    /// A Failed epoch can still be closed by the network (the epoch salt txn moves it to VrfLotto)
+    pub fn can_transition_to(self, to: EpochStage) -> bool {
+        match (self, to) {
+            (EpochStage::Failed, EpochStage::Reorg) => true,
+            (EpochStage::Failed, EpochStage::VrfLotto) => true,
+            (EpochStage::Reorg, EpochStage::Main) => true,
+            (EpochStage::Reorg, EpochStage::VrfLotto) => true,
+            (EpochStage::Main, EpochStage::VrfLotto) => true,
+            (EpochStage::VrfLotto, EpochStage::Final) => true,
+            (EpochStage::Reorg, EpochStage::Failed) => true,
+            (EpochStage::Main, EpochStage::Failed) => true,
+            (EpochStage::VrfLotto, EpochStage::Failed) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns how long (in ms) an epoch should stay in this stage, or None if the stage has no deadline
+    /// Reorg and Main last target_epoch_length, VrfLotto lasts a third of target_epoch_length
+    pub fn length(self) -> Option<u64> {
+        match self {
+            EpochStage::Reorg | EpochStage::Main => Some(config().target_epoch_length),
+            EpochStage::VrfLotto => Some(config().target_epoch_length / 3),
+            _ => None,
+        }
+    }
+
+    /// Returns how long (in ms) past its deadline an epoch can stay in this stage before it has stalled and fails
+    pub fn grace_period(self) -> Option<u64> {
+        self.length().map(|_| config().target_epoch_length / 3)
+    }
+}
+
 #[derive(Default, Serialize, Deserialize, Debug)]
 pub struct Epoch {
     pub hash: String,
@@ -43,6 +93,10 @@ pub struct Epoch {
     pub committees: Vec<Comitee>,
     pub shuffle_bits: u128,
     pub stage: EpochStage,
+    #[serde(default)]
+    pub stage_started: u64, // the time (in ms) the epoch moved to its current stage, local to this node so not hashed
+    #[serde(default)]
+    pub snapshot_hash: String, // the hash of the state snapshot taken as the epoch started
 }
 impl Hashable for Epoch {
     fn bytes(&self) -> Vec<u8> {
@@ -59,6 +113,10 @@ impl Hashable for Epoch {
             bytes.extend(committee.hash.bytes());
         }
         bytes.extend(self.shuffle_bits.to_string().bytes());
+        if !self.snapshot_hash.is_empty() {
+            // epochs from before snapshots keep their hash
+            bytes.extend(self.snapshot_hash.bytes());
+        }
         bytes
     }
 }
@@ -83,50 +141,192 @@ impl Epoch {
             salt: 0,
             committees: vec![],
             shuffle_bits: 0,
-            stage: EpochStage::Main
+            stage: EpochStage::default(), // not running until its committees are formed
+            stage_started: 0,
+            snapshot_hash: String::from(""),
+        }
+    }
+
+    /// # Transition
+    /// Moves the epoch to the stage to, at the time at (in ms), then saves it and calls the EPOCH_STAGE_CALLBACKS
+    /// Returns an error if the epoch cannot move from its current stage to to
+    pub fn transition(&mut self, to: EpochStage, at: u64) -> Result<(), Box<dyn std::error::Error>> {
+        let from = self.stage;
+        if !from.can_transition_to(to) {
+            error!(
+                "Epoch {} can not move from stage {:?} to {:?}",
+                self.epoch_number, from, to
+            );
+            return Err(Box::new(EpochErrors::InvalidTransition(from, to)));
+        }
+        self.stage = to;
+        self.stage_started = at;
+        self.save()?;
+        info!(
+            "Epoch {} moved from stage {:?} to {:?}",
+            self.epoch_number, from, to
+        );
+        let callbacks = EPOCH_STAGE_CALLBACKS.lock()?.clone();
+        for callback in callbacks {
+            (callback)(self.epoch_number, from, to);
+        }
+        Ok(())
+    }
+
+    /// # Stage deadline
+    /// Returns the time (in ms) the current stage should be over by, or None if the stage has no deadline
+    pub fn stage_deadline(&self) -> Option<u64> {
+        self.stage
+            .length()
+            .map(|length| self.stage_started + length)
+    }
+
+    /// Returns the number of ms until the current stage's deadline (0 if it has passed), or None if the stage has no deadline
+    /// An epoch which does not know when its stage started (stage_started is 0, eg it was saved before stages were timed) counts it as starting now
+    pub fn time_till_deadline(&self, now: u64) -> Option<u64> {
+        if self.stage_started == 0 {
+            return self.stage.length();
+        }
+        self.stage_deadline()
+            .map(|deadline| deadline.saturating_sub(now))
+    }
+
+    /// Returns true if the epoch has stayed in its current stage for longer than the stage's length and grace period
+    /// Epochs which do not know when their stage started are never overdue
+    pub fn overdue(&self, now: u64) -> bool {
+        if self.stage_started == 0 {
+            return false;
+        }
+        match (self.stage_deadline(), self.stage.grace_period()) {
+            (Some(deadline), Some(grace_period)) => now > deadline + grace_period,
+            _ => false,
         }
     }
 
     pub fn save(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.hash();
-        let serialized = serde_json::to_string(self)?;
-        if save_data(
-            &serialized,
-            &(config().db_path + "/epochdata"),
-            self.epoch_number.to_string(),
-        ) == 1
-        {
-            return Ok(());
-        } else {
-            return Err("Failed to save data".into());
-        }
+        put(&Table::EpochData, &self.epoch_number.to_string(), self)?;
+        Ok(())
     }
     pub fn set_top_epoch(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if save_data(
-            &self.epoch_number.to_string(),
-            &(config().db_path + "/epochdata"),
-            "topepoch".to_string(),
-        ) == 1
-        {
-            return Ok(());
-        } else {
-            return Err("Failed to save data".into());
-        }
+        put_raw(&Table::EpochData, "topepoch", &self.epoch_number.to_string())?;
+        Ok(())
     }
     pub fn get(epoch_number: u64) -> Result<Epoch, Box<dyn std::error::Error>> {
-        let got_data = get_data(config().db_path + "/epochdata", &epoch_number.to_string());
-        if got_data != "-1" {
-            return Ok(serde_json::from_str(&got_data)?);
-        } else {
-            return Err("could not find epoch data on disk".into());
+        match get(&Table::EpochData, &epoch_number.to_string())? {
+            Some(epoch) => Ok(epoch),
+            None => Err("could not find epoch data on disk".into()),
         }
     }
 }
-pub fn get_top_epoch() -> Result<Epoch, Box<dyn std::error::Error>> {
-    let got_data = get_data(config().db_path + "/epochdata", "topepoch");
-    if got_data != "-1" {
-        return Epoch::get(got_data.parse()?);
+/// Returns the message each consensus committee member signs (with their VRF) to create their salt seed for the epoch after epoch_number
+pub fn salt_seed_message(epoch_number: u64) -> String {
+    if epoch_number == 0 {
+        String::from("genesis")
     } else {
-        return Err("could not find top epoch height on disk".into());
+        raw_lyra(&(epoch_number.to_string() + "epoch"))
+    }
+}
+
+/// Returns the salt of the next epoch from the salt seeds (the (publickey, VRF proof) of each consensus committee member which contributed one)
+/// The VRF hashes are concatenated in publickey order and hashed, the salt is the first 8 bytes of the digest, so it does not depend on the order the seeds were listed in
+pub fn epoch_salt(salt_seeds: &[(String, String)]) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut salt_seeds: Vec<&(String, String)> = salt_seeds.iter().collect();
+    salt_seeds.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut vrf_hashes = String::from("");
+    for (_, seed) in salt_seeds {
+        vrf_hashes += &proof_to_hash(seed)?;
+    }
+    let digest = bs58::decode(raw_hash(&vrf_hashes)).into_vec()?;
+    let mut salt = [0u8; 8];
+    salt.copy_from_slice(digest.get(..8).ok_or("epoch salt digest too short")?);
+    Ok(u64::from_be_bytes(salt))
+}
+
+pub fn get_top_epoch() -> Result<Epoch, Box<dyn std::error::Error>> {
+    match get_raw(&Table::EpochData, "topepoch")? {
+        Some(top_epoch) => Epoch::get(top_epoch.parse()?),
+        None => Err("could not find top epoch height on disk".into()),
     }
 }
+
+#[test]
+fn test_stage_transitions() {
+    use EpochStage::*;
+    let stages = [Reorg, Failed, Main, VrfLotto, Final];
+    let allowed = [
+        (Failed, Reorg),
+        (Reorg, Main),
+        (Reorg, VrfLotto),
+        (Main, VrfLotto),
+        (VrfLotto, Final),
+        (Reorg, Failed),
+        (Main, Failed),
+        (VrfLotto, Failed),
+        (Failed, VrfLotto),
+    ];
+    for from in &stages {
+        for to in &stages {
+            assert_eq!(
+                from.can_transition_to(*to),
+                allowed.contains(&(*from, *to)),
+                "{:?} -> {:?}",
+                from,
+                to
+            );
+        }
+    }
+}
+
+#[test]
+fn test_stage_deadlines() {
+    let length = config().target_epoch_length;
+    let mut epoch = Epoch {
+        stage: EpochStage::Main,
+        ..Default::default()
+    };
+    // an epoch which does not know when its stage started counts it as starting now
+    assert_eq!(epoch.time_till_deadline(u64::MAX), Some(length));
+    assert!(!epoch.overdue(u64::MAX));
+    epoch.stage_started = 1000;
+    assert_eq!(epoch.time_till_deadline(1000), Some(length));
+    assert_eq!(epoch.time_till_deadline(1000 + length * 2), Some(0));
+    assert!(!epoch.overdue(1000 + length + length / 3));
+    assert!(epoch.overdue(1000 + length + length / 3 + 1));
+    epoch.stage = EpochStage::Failed;
+    assert_eq!(epoch.time_till_deadline(1000), None);
+    assert!(!epoch.overdue(u64::MAX));
+}
+
+#[test]
+fn test_transition_callbacks() {
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static CALLED: AtomicUsize = AtomicUsize::new(0);
+    with_backend(Arc::new(MemoryBackend::new()), || {
+        // a callback can register another callback without deadlocking
+        EPOCH_STAGE_CALLBACKS
+            .lock()
+            .unwrap()
+            .push(Arc::new(|epoch_number, _, _| {
+                if epoch_number == 9999 && CALLED.fetch_add(1, Ordering::SeqCst) == 0 {
+                    EPOCH_STAGE_CALLBACKS
+                        .lock()
+                        .unwrap()
+                        .push(Arc::new(|epoch_number, _, _| {
+                            if epoch_number == 9999 {
+                                CALLED.fetch_add(1, Ordering::SeqCst);
+                            }
+                        }));
+                }
+            }));
+        let mut epoch = Epoch {
+            epoch_number: 9999,
+            ..Default::default()
+        };
+        epoch.transition(EpochStage::Reorg, 1).unwrap();
+        assert_eq!(CALLED.load(Ordering::SeqCst), 1);
+        epoch.transition(EpochStage::Main, 2).unwrap();
+        assert_eq!(CALLED.load(Ordering::SeqCst), 3);
+    });
+}
@@ -0,0 +1,120 @@
+// This file tracks the finality of blocks. A block is tentative while it is in the mempool, enacted once it is saved to our copy of its chain
+// and final once it is included in a BlockChunk whose aggregated signature covers a quorum (more than two thirds) of its committees voting members.
+// Final blocks can not be reverted: anyone crediting deposits (eg exchanges) should only credit a deposit once the block containing it is final.
+extern crate avrio_database;
+
+use avrio_database::store::{get_raw, put_raw, Table};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+use crate::{block::get_block_from_raw, mempool};
+
+lazy_static! {
+    /// Called with the hash of a block and the hash of the chunk which finalized it when the block becomes final
+    pub static ref BLOCK_FINALIZED_CALLBACKS: Mutex<Vec<Box<dyn Fn(String, String) + Send>>> = Mutex::new(vec![]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FinalityStatus {
+    /// We have never seen the block
+    Unknown,
+    /// The block is in the mempool
+    Tentative,
+    /// The block has been enacted but is not yet in a quorum signed chunk
+    Enacted,
+    /// The block is in a quorum signed chunk and can not be reverted
+    Final,
+}
+
+impl FinalityStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinalityStatus::Unknown => "unknown",
+            FinalityStatus::Tentative => "tentative",
+            FinalityStatus::Enacted => "enacted",
+            FinalityStatus::Final => "final",
+        }
+    }
+}
+
+/// Returns the hash of the chunk which finalized the block, or None if the block is not final
+pub fn finalized_by(block_hash: &str) -> Option<String> {
+    match get_raw(&Table::Finality, block_hash) {
+        Ok(chunk_hash) => chunk_hash,
+        Err(e) => {
+            error!("Failed to read finality of block {}, error={}", block_hash, e);
+            None
+        }
+    }
+}
+
+/// Returns true if the block is final (deposits in it can be credited)
+pub fn is_final(block_hash: &str) -> bool {
+    finalized_by(block_hash).is_some()
+}
+
+/// Returns the finality status of the block
+pub fn finality_status(block_hash: &str) -> FinalityStatus {
+    if is_final(block_hash) {
+        FinalityStatus::Final
+    } else if !get_block_from_raw(block_hash.to_owned()).is_default() {
+        FinalityStatus::Enacted
+    } else if mempool::get_block(block_hash).is_ok() {
+        FinalityStatus::Tentative
+    } else {
+        FinalityStatus::Unknown
+    }
+}
+
+/// # Finalize
+/// Marks the block as final, called for every block of a (valid, so quorum signed) chunk when it is enacted
+/// Returns false if the block was already final
+pub fn finalize(block_hash: &str, chunk_hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    if is_final(block_hash) {
+        return Ok(false);
+    }
+    put_raw(&Table::Finality, block_hash, chunk_hash)?;
+    debug!("Block {} finalized by chunk {}", block_hash, chunk_hash);
+    for callback in &*(BLOCK_FINALIZED_CALLBACKS.lock()?) {
+        (callback)(block_hash.to_owned(), chunk_hash.to_owned());
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use avrio_crypto::Hashable;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_finalize() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let mut block = Block::default();
+            block.header.chain_key = "finality".to_owned();
+            block.hash();
+            assert_eq!(finality_status(&block.hash), FinalityStatus::Unknown);
+            mempool::requeue(block.clone()).unwrap();
+            assert_eq!(finality_status(&block.hash), FinalityStatus::Tentative);
+            mempool::remove_block(&block.hash).unwrap();
+            assert!(finalize(&block.hash, "chunk").unwrap());
+            // a block is only finalized once, by the first chunk including it
+            assert!(!finalize(&block.hash, "other").unwrap());
+            assert_eq!(finalized_by(&block.hash), Some("chunk".to_owned()));
+            assert!(is_final(&block.hash));
+            assert_eq!(finality_status(&block.hash), FinalityStatus::Final);
+        });
+    }
+
+    #[test]
+    fn test_finality_status_str() {
+        assert_eq!(FinalityStatus::Final.as_str(), "final");
+        assert_eq!(
+            serde_json::to_string(&FinalityStatus::Tentative).unwrap(),
+            "\"Tentative\""
+        );
+    }
+}
@@ -0,0 +1,521 @@
+// This file detects and resolves forks on account chains. Two blocks on the same chain at the same height conflict,
+// eg when a malicious sender double spends by sending different send blocks (with the same previous block) to different peers.
+// Conflicts are resolved by finality: the block included in a finalized (quorum signed) chunk wins, until then the block we enacted first is kept.
+// If the losing block was enacted it is rolled back, along with every block built on it and the recieve blocks of its transactions.
+extern crate avrio_database;
+
+extern crate avrio_config;
+
+use avrio_database::store::{atomic, delete, get, get_raw, put, put_raw, Table};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+use crate::{
+    block::{get_block, get_block_from_raw, remove_block, save_block, Block, BlockType},
+    finality::is_final,
+    mempool,
+    validate::Verifiable,
+};
+
+lazy_static! {
+    /// Called when a conflict is detected (winner is None) and when it is resolved
+    pub static ref FORK_CALLBACKS: Mutex<Vec<Box<dyn Fn(ForkConflict) + Send>>> = Mutex::new(vec![]);
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ForkConflict {
+    pub chain_key: String,
+    pub height: u64,
+    pub blocks: Vec<String>, // the hashes of the conflicting blocks, in the order we saw them
+    pub winner: Option<String>,
+}
+
+fn conflict_key(chain_key: &str, height: u64) -> String {
+    chain_key.to_owned() + "-" + &height.to_string()
+}
+
+/// Returns the recorded conflict at the height of the chain, if there is one
+pub fn get_conflict(chain_key: &str, height: u64) -> Option<ForkConflict> {
+    get(&Table::Forks, &conflict_key(chain_key, height))
+        .ok()
+        .flatten()
+}
+
+/// Returns the recorded conflict the block is part of, if there is one
+pub fn conflict_of(block_hash: &str) -> Option<ForkConflict> {
+    let key = get_raw(&Table::Forks, block_hash).ok().flatten()?;
+    get(&Table::Forks, &key).ok().flatten()
+}
+
+fn save_conflict(conflict: &ForkConflict) -> Result<(), Box<dyn std::error::Error>> {
+    let key = conflict_key(&conflict.chain_key, conflict.height);
+    put(&Table::Forks, &key, conflict)?;
+    for block_hash in &conflict.blocks {
+        put_raw(&Table::Forks, block_hash, &key)?;
+    }
+    Ok(())
+}
+
+fn announce(conflict: &ForkConflict) -> Result<(), Box<dyn std::error::Error>> {
+    for callback in &*(FORK_CALLBACKS.lock()?) {
+        (callback)(conflict.clone());
+    }
+    Ok(())
+}
+
+/// # Conflicting block
+/// Returns the hash of a different block at the same height of the same chain as the block, if we have one (enacted or in the mempool)
+/// Must not be called while holding the mempool lock
+pub fn conflicting_block(block: &Block) -> Option<String> {
+    let enacted = get_raw(
+        &Table::ChainIndex(block.header.chain_key.clone()),
+        &block.header.height.to_string(),
+    )
+    .ok()
+    .flatten();
+    if let Some(enacted) = enacted.filter(|enacted| enacted != &block.hash) {
+        return Some(enacted);
+    }
+    mempool::get_blocks()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|other| {
+            other.hash != block.hash
+                && other.header.chain_key == block.header.chain_key
+                && other.header.height == block.header.height
+        })
+        .map(|other| other.hash)
+}
+
+/// # Record conflict
+/// Records that the block conflicts with the block with hash other and announces the conflict
+pub fn record_conflict(
+    block: &Block,
+    other: &str,
+) -> Result<ForkConflict, Box<dyn std::error::Error>> {
+    let mut conflict = get_conflict(&block.header.chain_key, block.header.height)
+        .unwrap_or_else(|| ForkConflict {
+            chain_key: block.header.chain_key.clone(),
+            height: block.header.height,
+            ..Default::default()
+        });
+    for block_hash in &[other.to_owned(), block.hash.clone()] {
+        if !conflict.blocks.contains(block_hash) {
+            conflict.blocks.push(block_hash.clone());
+        }
+    }
+    save_conflict(&conflict)?;
+    warn!(
+        "Fork detected on chain {} at height {}, blocks={:?}",
+        conflict.chain_key, conflict.height, conflict.blocks
+    );
+    announce(&conflict)?;
+    Ok(conflict)
+}
+
+/// Returns the winning block of the conflicting blocks: the first which is final, or None if none are final yet
+pub fn winner_of<F: Fn(&str) -> bool>(blocks: &[String], is_final: F) -> Option<String> {
+    blocks.iter().find(|block_hash| is_final(block_hash)).cloned()
+}
+
+/// Returns the hashes of the enacted recieve blocks of the send blocks transactions
+fn dependent_recieve_blocks(send_block: &Block) -> Vec<String> {
+    let mut dependents: Vec<String> = vec![];
+    for txn in &send_block.txns {
+        let block_hash = match get_raw(&Table::Transactions, &txn.hash).ok().flatten() {
+            Some(block_hash) if !dependents.contains(&block_hash) => block_hash,
+            _ => continue,
+        };
+        let block = get_block_from_raw(block_hash.clone());
+        if block.block_type == BlockType::Recieve
+            && block.send_block.as_ref() == Some(&send_block.hash)
+        {
+            dependents.push(block_hash);
+        }
+    }
+    dependents
+}
+
+/// Adds the blocks rolling back the block undoes to the plan, in the order they are undone: every later block on its chain and
+/// (for send blocks) the recieve blocks of its transactions, each after the blocks which depend on it, then the block itself
+fn rollback_plan(
+    block_hash: &str,
+    plan: &mut Vec<Block>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if plan.iter().any(|planned| planned.hash == block_hash) {
+        return Ok(());
+    }
+    let block = get_block_from_raw(block_hash.to_owned());
+    if block.is_default() {
+        return Err(format!("Can not roll back unknown block {}", block_hash).into());
+    }
+    let index = Table::ChainIndex(block.header.chain_key.clone());
+    let block_count: u64 = get(&index, "blockcount")?.unwrap_or(0);
+    for height in (block.header.height + 1..block_count).rev() {
+        let later = get_block(&block.header.chain_key, height);
+        if !later.is_default() {
+            rollback_plan(&later.hash, plan)?;
+        }
+    }
+    if block.block_type == BlockType::Send {
+        for dependent in dependent_recieve_blocks(&block) {
+            rollback_plan(&dependent, plan)?;
+        }
+    }
+    plan.push(block);
+    Ok(())
+}
+
+/// Returns the rollback plan of the block, or an error if a transaction it would revert can not be reverted
+/// (in which case nothing can be rolled back, so the state is never left half reverted)
+fn plan_rollback(block_hash: &str) -> Result<Vec<Block>, Box<dyn std::error::Error>> {
+    let mut plan = vec![];
+    rollback_plan(block_hash, &mut plan)?;
+    for block in plan.iter().filter(|block| block.block_type == BlockType::Recieve) {
+        if let Some(txn) = block.txns.iter().find(|txn| !txn.revertible()) {
+            return Err(format!(
+                "Can not roll back block {}, recieve block {} contains {} transaction {}",
+                block_hash,
+                block.hash,
+                txn.type_transaction(),
+                txn.hash
+            )
+            .into());
+        }
+    }
+    Ok(plan)
+}
+
+/// # Rollback
+/// Rolls back an enacted block: first every later block on its chain and (for send blocks) its recieve blocks, then the block itself
+/// The block is deleted so it can not be enacted again. The rolled back state is written in one atomic batch, and nothing is written
+/// if any of the blocks can not be reverted. Rolled back recieve blocks of other send blocks are put back in the mempool
+pub fn rollback(block_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let plan = plan_rollback(block_hash)?;
+    atomic(|| undo_blocks(&plan))?;
+    requeue_dropped(block_hash, &plan);
+    Ok(())
+}
+
+fn undo_blocks(plan: &[Block]) -> Result<(), Box<dyn std::error::Error>> {
+    for block in plan {
+        undo_block(block)?;
+    }
+    Ok(())
+}
+
+fn undo_block(block: &Block) -> Result<(), Box<dyn std::error::Error>> {
+    let index = Table::ChainIndex(block.header.chain_key.clone());
+    match block.block_type {
+        BlockType::Send => {
+            let txn_count: u64 = get(&index, "txncount")?.unwrap_or(0);
+            put(
+                &index,
+                "txncount",
+                &txn_count.saturating_sub(block.txns.len() as u64),
+            )?;
+        }
+        BlockType::Recieve => {
+            for txn in block.txns.iter().rev() {
+                txn.revert()?;
+                delete(&Table::Transactions, &txn.hash)?;
+            }
+        }
+    }
+    delete(&index, &block.header.height.to_string())?;
+    put_raw(&index, "topblockhash", &block.header.prev_hash)?;
+    put(&index, "blockcount", &block.header.height)?;
+    remove_block(&block.hash)?;
+    info!(
+        "Rolled back block {} (chain={}, height={})",
+        block.hash, block.header.chain_key, block.header.height
+    );
+    Ok(())
+}
+
+/// Puts the rolled back recieve blocks whose send block is still enacted back in the mempool (eg recieve blocks of other senders later on the
+/// chain of a rolled back block), rebuilt on top of what is left of their chain, so the transactions in them are not lost
+fn requeue_dropped(root: &str, plan: &[Block]) {
+    let rolled_back: Vec<&str> = plan.iter().map(|block| block.hash.as_str()).collect();
+    let mut requeued: Vec<Block> = vec![];
+    // the plan undoes the top of each chain first, so walk it backwards to rebuild each chain from the bottom
+    for dropped in plan.iter().rev() {
+        let send_block = match dropped.send_block.as_deref() {
+            Some(send_block) => send_block,
+            None => continue,
+        };
+        if dropped.hash == root
+            || dropped.block_type != BlockType::Recieve
+            || rolled_back.contains(&send_block)
+        {
+            continue;
+        }
+        let mut block = dropped.clone();
+        if let Some(prev) = requeued
+            .iter()
+            .rev()
+            .find(|prev| prev.header.chain_key == block.header.chain_key)
+        {
+            block.header.height = prev.header.height + 1;
+            block.header.prev_hash = prev.hash.clone();
+        } else {
+            let index = Table::ChainIndex(block.header.chain_key.clone());
+            block.header.height = get(&index, "blockcount").ok().flatten().unwrap_or(0);
+            block.header.prev_hash = get_raw(&index, "topblockhash")
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+        }
+        block.hash();
+        if let Err(e) = mempool::requeue(block.clone()) {
+            error!(
+                "Failed to requeue recieve block {} (was {}), error={}",
+                block.hash, dropped.hash, e
+            );
+            continue;
+        }
+        debug!(
+            "Requeued rolled back recieve block {} as {} (chain={}, height={})",
+            dropped.hash, block.hash, block.header.chain_key, block.header.height
+        );
+        requeued.push(block);
+    }
+}
+
+/// # Valid competitor
+/// Checks the block is valid against the state it competes for: Block::valid rejects any block at a height we have enacted a block at,
+/// so the enacted block (and everything depending on it) is rolled back in a batch which is then discarded, and the block is validated against that
+/// Returns an error if the block is invalid, or if the enacted block could not be rolled back to check it. Must not be called inside a batch
+pub fn valid_competitor(
+    block: &Block,
+    enacted: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let enacted = match enacted {
+        Some(enacted) => enacted,
+        None => return block.valid(),
+    };
+    let plan = plan_rollback(enacted)?;
+    let mut result: Result<(), Box<dyn std::error::Error>> =
+        Err(format!("Failed to roll back {} to validate {}", enacted, block.hash).into());
+    let _: Result<(), Box<dyn std::error::Error>> = atomic(|| {
+        undo_blocks(&plan)?;
+        result = block.valid();
+        Err("discarding trial rollback".into())
+    });
+    result
+}
+
+/// # Resolve
+/// Resolves the conflict once one of its blocks is final: rolls back the enacted block if it lost, then enacts the winner if we have it
+/// Returns the winning block hash, or None if no block is final yet
+pub fn resolve(conflict: &ForkConflict) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let winner = match winner_of(&conflict.blocks, is_final) {
+        Some(winner) => winner,
+        None => return Ok(None),
+    };
+    let enacted = get_raw(
+        &Table::ChainIndex(conflict.chain_key.clone()),
+        &conflict.height.to_string(),
+    )?;
+    if let Some(enacted) = enacted.as_ref().filter(|enacted| *enacted != &winner) {
+        rollback(enacted)?;
+    }
+    if enacted.as_ref() != Some(&winner) {
+        if let Ok(block) = Block::get(winner.clone()) {
+            // saved blocks have already been validated
+            block.enact()?;
+        } else if let Ok(block) = mempool::get_block(&winner) {
+            block.valid()?;
+            save_block(block.clone())?;
+            block.enact()?;
+        } else {
+            debug!("Do not have winning block {} yet", winner);
+        }
+    }
+    for block_hash in &conflict.blocks {
+        let _ = mempool::remove_block(block_hash);
+    }
+    let mut resolved = conflict.clone();
+    resolved.winner = Some(winner.clone());
+    save_conflict(&resolved)?;
+    info!(
+        "Resolved fork on chain {} at height {}, winner={}",
+        resolved.chain_key, resolved.height, winner
+    );
+    announce(&resolved)?;
+    Ok(Some(winner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        account::{get_account, set_account, Account},
+        epoch::Epoch,
+        transaction::Transaction,
+    };
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    fn transfer(hash: &str, sender: &str, reciever: &str, amount: u64) -> Transaction {
+        Transaction {
+            hash: hash.to_owned(),
+            flag: 'n',
+            sender_key: sender.to_owned(),
+            receive_key: reciever.to_owned(),
+            amount,
+            ..Default::default()
+        }
+    }
+
+    /// Saves and indexes the block as enacted (without enacting its transactions)
+    fn enacted(
+        chain_key: &str,
+        height: u64,
+        prev_hash: &str,
+        send_block: Option<&Block>,
+        txns: Vec<Transaction>,
+    ) -> Block {
+        let mut block = Block::default();
+        block.header.chain_key = chain_key.to_owned();
+        block.header.height = height;
+        block.header.prev_hash = prev_hash.to_owned();
+        if let Some(send_block) = send_block {
+            block.block_type = BlockType::Recieve;
+            block.send_block = Some(send_block.hash.clone());
+        }
+        block.txns = txns;
+        block.hash();
+        save_block(block.clone()).unwrap();
+        let index = Table::ChainIndex(chain_key.to_owned());
+        put_raw(&index, &height.to_string(), &block.hash).unwrap();
+        put_raw(&index, "topblockhash", &block.hash).unwrap();
+        put(&index, "blockcount", &(height + 1)).unwrap();
+        if block.block_type == BlockType::Recieve {
+            for txn in &block.txns {
+                put_raw(&Table::Transactions, &txn.hash, &block.hash).unwrap();
+            }
+        }
+        block
+    }
+
+    fn set_balance(public_key: &str, balance: u64) {
+        let mut acc = Account::new(public_key.to_owned());
+        acc.balance = balance;
+        assert_eq!(set_account(&acc), 1);
+    }
+
+    fn block_count(chain_key: &str) -> u64 {
+        get(&Table::ChainIndex(chain_key.to_owned()), "blockcount")
+            .unwrap()
+            .unwrap()
+    }
+
+    /// alice sends 10 to bob at alice-1, then carol sends 5 to bob, both enacted on bob's chain (bob-1 and bob-2)
+    /// The tag is added to the transaction hashes, so the blocks tests requeue in the (shared) mempool differ
+    fn setup(tag: &str) -> (Block, Block, Block) {
+        let mut epoch = Epoch::new();
+        epoch.save().unwrap();
+        epoch.set_top_epoch().unwrap();
+        let alice_0 = enacted("alice", 0, "00000000000", None, vec![]);
+        let bob_0 = enacted("bob", 0, "00000000000", None, vec![]);
+        let carol_0 = enacted("carol", 0, "00000000000", None, vec![]);
+        let to_bob = transfer(&format!("t1{}", tag), "alice", "bob", 10);
+        let alice_1 = enacted("alice", 1, &alice_0.hash, None, vec![to_bob.clone()]);
+        let bob_1 = enacted("bob", 1, &bob_0.hash, Some(&alice_1), vec![to_bob]);
+        let from_carol = transfer(&format!("t2{}", tag), "carol", "bob", 5);
+        let carol_1 = enacted("carol", 1, &carol_0.hash, None, vec![from_carol.clone()]);
+        let bob_2 = enacted("bob", 2, &bob_1.hash, Some(&carol_1), vec![from_carol]);
+        set_balance("alice", 90);
+        set_balance("bob", 15);
+        set_balance("carol", 95);
+        (alice_1, bob_1, bob_2)
+    }
+
+    #[test]
+    fn test_rollback() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let (alice_1, bob_1, bob_2) = setup("rollback");
+            rollback(&alice_1.hash).unwrap();
+            // the send block, its recieve block and the later recieve block on bob's chain are rolled back
+            for block in &[&alice_1, &bob_1, &bob_2] {
+                assert!(get_block_from_raw(block.hash.clone()).is_default());
+            }
+            assert_eq!(block_count("alice"), 1);
+            assert_eq!(block_count("bob"), 1);
+            assert_eq!(block_count("carol"), 2);
+            assert_eq!(get_account("alice").unwrap().balance, 100);
+            assert_eq!(get_account("bob").unwrap().balance, 0);
+            assert_eq!(get_account("carol").unwrap().balance, 100);
+            assert_eq!(get_raw(&Table::Transactions, "t2rollback").unwrap(), None);
+            // carol's transfer was not rolled back, so its recieve block is requeued on top of bob's chain
+            let requeued = mempool::get_blocks()
+                .unwrap()
+                .into_iter()
+                .find(|block| block.send_block == bob_2.send_block)
+                .unwrap();
+            assert_eq!(requeued.header.height, 1);
+            assert_eq!(requeued.header.prev_hash, bob_1.header.prev_hash);
+            assert_eq!(requeued.txns, bob_2.txns);
+            assert_eq!(requeued.hash, requeued.hash_return());
+            mempool::remove_block(&requeued.hash).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_rollback_refuses_unrevertible() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let (alice_1, bob_1, bob_2) = setup("refuses");
+            let mut username = transfer("t3", "bob", "bob", 0);
+            username.flag = 'u';
+            let bob_3 = enacted("bob", 3, &bob_2.hash, Some(&bob_2), vec![username]);
+            assert!(rollback(&alice_1.hash).is_err());
+            // nothing was rolled back
+            for block in &[&alice_1, &bob_1, &bob_2, &bob_3] {
+                assert!(!get_block_from_raw(block.hash.clone()).is_default());
+            }
+            assert_eq!(block_count("bob"), 4);
+            assert_eq!(get_account("bob").unwrap().balance, 15);
+        });
+    }
+
+    #[test]
+    fn test_resolve() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let (alice_1, _, bob_2) = setup("resolve");
+            let conflict = ForkConflict {
+                chain_key: "alice".to_owned(),
+                height: 1,
+                blocks: vec![alice_1.hash.clone(), "other".to_owned()],
+                winner: None,
+            };
+            save_conflict(&conflict).unwrap();
+            // neither block is final yet
+            assert_eq!(resolve(&conflict).unwrap(), None);
+            assert!(!get_block_from_raw(alice_1.hash.clone()).is_default());
+            // the competing block is finalized, so the enacted one is rolled back
+            put_raw(&Table::Finality, "other", "chunk").unwrap();
+            assert_eq!(resolve(&conflict).unwrap(), Some("other".to_owned()));
+            assert!(get_block_from_raw(alice_1.hash.clone()).is_default());
+            assert_eq!(block_count("alice"), 1);
+            assert_eq!(get_account("alice").unwrap().balance, 100);
+            let resolved = conflict_of(&alice_1.hash).unwrap();
+            assert_eq!(resolved.winner, Some("other".to_owned()));
+            let requeued = mempool::get_blocks()
+                .unwrap()
+                .into_iter()
+                .find(|block| block.send_block == bob_2.send_block)
+                .unwrap();
+            mempool::remove_block(&requeued.hash).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_winner_of() {
+        let blocks = vec!["a".to_owned(), "b".to_owned(), "c".to_owned()];
+        assert_eq!(winner_of(&blocks, |_| false), None);
+        assert_eq!(winner_of(&blocks, |hash| hash == "b"), Some("b".to_owned()));
+        assert_eq!(winner_of(&blocks, |hash| hash != "a"), Some("b".to_owned()));
+    }
+}
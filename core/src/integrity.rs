@@ -0,0 +1,663 @@
+// This file checks the integrity of the database, eg after an unclean shutdown. It walks every chain index checking each height -> hash entry resolves to
+// a block with that hash, height and chain whose prev_hash is the block below it, checks each transactions index entry points to a block containing the transaction,
+// and recomputes the account balances (from the enacted transactions) and the chain and state digests, comparing them with the stored values.
+// Rebuilding re-derives the chain indexes, transactions index, chain list and digests from the blocks in the block store. Accounts are state, not an index, so are only checked.
+extern crate avrio_database;
+
+use avrio_crypto::Hashable;
+use avrio_database::store::{self, DbError, Table};
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+use crate::{
+    account::Account,
+    block::{get_block_from_raw, Block, BlockType},
+    finality::is_final,
+    prune::pruned_below,
+    states::{form_chain_digest, form_state_digest},
+    transaction::Transaction,
+};
+
+/// The number of blocks read from the block store, and re-indexed in one batch, at a time when rebuilding
+const BLOCKS_PER_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum Discrepancy {
+    #[error("Chain {chain} has no index entry at height {height}")]
+    MissingIndexEntry { chain: String, height: u64 },
+    #[error(
+        "Chain {chain} height {height} points to block {hash}, which is not in the block store"
+    )]
+    MissingBlock {
+        chain: String,
+        height: u64,
+        hash: String,
+    },
+    #[error("Chain {chain} height {height} points to block {hash}, which has the wrong {field}")]
+    BlockMismatch {
+        chain: String,
+        height: u64,
+        hash: String,
+        field: &'static str,
+    },
+    #[error("Chain {chain} has top block hash {stored}, expected {expected}")]
+    TopBlockMismatch {
+        chain: String,
+        stored: String,
+        expected: String,
+    },
+    #[error("Chain {chain} has txn count {stored}, expected {expected}")]
+    TxnCountMismatch {
+        chain: String,
+        stored: u64,
+        expected: u64,
+    },
+    #[error("Transaction {txn} points to block {block}, which {reason}")]
+    TransactionMismatch {
+        txn: String,
+        block: String,
+        reason: &'static str,
+    },
+    #[error("Account {public_key} holds {stored} (balance and locked), its transactions add up to {expected}")]
+    BalanceMismatch {
+        public_key: String,
+        stored: i128,
+        expected: i128,
+    },
+    #[error("Chain {chain} has chain digest {stored}, recomputed {expected}")]
+    ChainDigestMismatch {
+        chain: String,
+        stored: String,
+        expected: String,
+    },
+    #[error("State digest is {stored}, recomputed {expected}")]
+    StateDigestMismatch { stored: String, expected: String },
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct IntegrityReport {
+    pub chains: u64,
+    pub blocks: u64,
+    pub transactions: u64,
+    pub accounts: u64,
+    /// True if old blocks have been pruned, so missing blocks below the top of each chain are expected and balances and digests can not be recomputed
+    pub pruned: bool,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+/// Returns the field of the block (at height of chain, below the block with hash prev_hash if it is known) which does not match, if there is one
+fn block_mismatch(
+    block: &Block,
+    chain: &str,
+    height: u64,
+    hash: &str,
+    prev_hash: Option<&str>,
+) -> Option<&'static str> {
+    if block.hash != hash || block.hash_return() != hash {
+        Some("hash")
+    } else if block.header.height != height {
+        Some("height")
+    } else if block.header.chain_key != chain {
+        Some("chain key")
+    } else if height != 0
+        && prev_hash.map_or(false, |prev_hash| prev_hash != block.header.prev_hash)
+    {
+        Some("prev_hash")
+    } else {
+        None
+    }
+}
+
+/// Returns true if the block is indexed in the chain of one of its recievers, rather than its own (see enact_recieve)
+fn reciever_entry(block: &Block, chain: &str) -> bool {
+    block.header.chain_key != chain && block.txns.iter().any(|txn| txn.receive_key == chain)
+}
+
+/// Checks the height -> hash entries, top block hash and txn count of the chain index
+fn check_chain(chain: &str, report: &mut IntegrityReport) -> Result<(), DbError> {
+    let index = Table::ChainIndex(chain.to_owned());
+    let block_count: u64 = store::get(&index, "blockcount")?.unwrap_or(0);
+    let mut prev_hash: Option<String> = None;
+    let mut txn_count: u64 = 0;
+    for height in 0..block_count {
+        let hash = match store::get_raw(&index, &height.to_string())? {
+            Some(hash) => hash,
+            None => {
+                if !report.pruned {
+                    report.discrepancies.push(Discrepancy::MissingIndexEntry {
+                        chain: chain.to_owned(),
+                        height,
+                    });
+                }
+                prev_hash = None;
+                continue;
+            }
+        };
+        report.blocks += 1;
+        let block = get_block_from_raw(hash.clone());
+        if block.is_default() {
+            report.discrepancies.push(Discrepancy::MissingBlock {
+                chain: chain.to_owned(),
+                height,
+                hash,
+            });
+            prev_hash = None;
+            continue;
+        }
+        if reciever_entry(&block, chain) {
+            prev_hash = None;
+            continue;
+        }
+        if let Some(field) = block_mismatch(&block, chain, height, &hash, prev_hash.as_deref()) {
+            report.discrepancies.push(Discrepancy::BlockMismatch {
+                chain: chain.to_owned(),
+                height,
+                hash: hash.clone(),
+                field,
+            });
+        }
+        if block.block_type == BlockType::Send {
+            txn_count += block
+                .txns
+                .iter()
+                .filter(|txn| txn.sender_key == chain)
+                .count() as u64;
+        }
+        prev_hash = Some(hash);
+    }
+    if let Some(top) = prev_hash {
+        let stored = store::get_raw(&index, "topblockhash")?.unwrap_or_default();
+        if stored != top {
+            report.discrepancies.push(Discrepancy::TopBlockMismatch {
+                chain: chain.to_owned(),
+                stored,
+                expected: top,
+            });
+        }
+    }
+    if !report.pruned {
+        let stored: u64 = store::get(&index, "txncount")?.unwrap_or(0);
+        if stored != txn_count {
+            report.discrepancies.push(Discrepancy::TxnCountMismatch {
+                chain: chain.to_owned(),
+                stored,
+                expected: txn_count,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the change to the total coins (balance and locked) of each account made by enacting the transaction
+/// Every flag is matched explicitly, so a new transaction type has to say what it moves rather than silently moving nothing
+fn balance_changes(txn: &Transaction) -> Result<Vec<(String, i128)>, Box<dyn std::error::Error>> {
+    let amount = txn.amount as i128;
+    let fee = txn.fee() as i128;
+    // the amount moved by the transaction, excluding the fee
+    let mut changes = match txn.flag {
+        'n' if txn.sender_key != txn.receive_key => vec![
+            (txn.sender_key.clone(), -amount),
+            (txn.receive_key.clone(), amount),
+        ],
+        'c' => vec![(txn.sender_key.clone(), amount)],
+        'u' | 'b' | 'i' | 'f' => vec![(txn.sender_key.clone(), -amount)],
+        'r' => serde_json::from_str::<Vec<(String, u64)>>(&String::from_utf8(
+            bs58::decode(&txn.extra).into_vec()?,
+        )?)?
+        .into_iter()
+        .map(|(public_key, reward)| (public_key, reward as i128))
+        .collect(),
+        // self transfers, locks and relocks (which move coins from balance to locked) and txns which only pay the fee
+        'n' | 'l' | 'e' | 'd' | 'q' | 'v' | 'a' | 'y' | 'z' | 'o' => vec![],
+        flag => return Err(format!("Unsupported transaction type {}", flag).into()),
+    };
+    // every flag which has gas pays the fee from the senders balance (see enact)
+    if fee != 0 {
+        match changes
+            .iter_mut()
+            .find(|(public_key, _)| *public_key == txn.sender_key)
+        {
+            Some((_, change)) => *change -= fee,
+            None => changes.push((txn.sender_key.clone(), -fee)),
+        }
+    }
+    Ok(changes)
+}
+
+/// Checks each transactions index entry points to a block containing the transaction, returning the total coins of each account the transactions add up to
+fn check_transactions(
+    report: &mut IntegrityReport,
+) -> Result<HashMap<String, i128>, Box<dyn std::error::Error>> {
+    let mut totals: HashMap<String, i128> = HashMap::new();
+    for (txn_hash, block_hash) in store::entries(&Table::Transactions)? {
+        report.transactions += 1;
+        let block = get_block_from_raw(block_hash.clone());
+        if block.is_default() {
+            if !report.pruned {
+                report.discrepancies.push(Discrepancy::TransactionMismatch {
+                    txn: txn_hash,
+                    block: block_hash,
+                    reason: "is not in the block store",
+                });
+            }
+            continue;
+        }
+        match block.txns.iter().find(|txn| txn.hash == txn_hash) {
+            Some(txn) => {
+                for (public_key, change) in balance_changes(txn)? {
+                    *totals.entry(public_key).or_insert(0) += change;
+                }
+            }
+            None => report.discrepancies.push(Discrepancy::TransactionMismatch {
+                txn: txn_hash,
+                block: block_hash,
+                reason: "does not contain it",
+            }),
+        }
+    }
+    Ok(totals)
+}
+
+/// Compares the total coins of each account with what its transactions add up to
+fn check_balances(
+    totals: &HashMap<String, i128>,
+    report: &mut IntegrityReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut expected = totals.clone();
+    for (public_key, raw) in store::entries(&Table::Accounts)? {
+        report.accounts += 1;
+        let account: Account = serde_json::from_str(&raw)?;
+        let stored = account.balance as i128 + account.locked as i128;
+        let total = expected.remove(&public_key).unwrap_or(0);
+        if stored != total {
+            report.discrepancies.push(Discrepancy::BalanceMismatch {
+                public_key,
+                stored,
+                expected: total,
+            });
+        }
+    }
+    // accounts we have no record of, but which transactions paid
+    for (public_key, total) in expected.into_iter().filter(|(_, total)| *total != 0) {
+        report.discrepancies.push(Discrepancy::BalanceMismatch {
+            public_key,
+            stored: 0,
+            expected: total,
+        });
+    }
+    Ok(())
+}
+
+/// Recomputes the chain digest of each chain and the state digest, returning them without saving them
+fn recompute_digests(
+    chains: &[String],
+) -> Result<(Vec<String>, String), Box<dyn std::error::Error>> {
+    let mut digests: Option<(Vec<String>, String)> = None;
+    // form_chain_digest and form_state_digest save what they compute, so run them in a batch which is then discarded
+    let _ = store::atomic(|| -> Result<(), Box<dyn std::error::Error>> {
+        let chain_digests = form_chain_digest(chains.to_vec())?;
+        digests = Some((chain_digests, form_state_digest()?));
+        Err("discarded".into())
+    });
+    digests.ok_or_else(|| "Failed to recompute digests".into())
+}
+
+/// Compares the stored chain and state digests with recomputed ones
+fn check_digests(
+    chains: &[String],
+    report: &mut IntegrityReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (chain_digests, state_digest) = recompute_digests(chains)?;
+    for (chain, expected) in chains.iter().zip(chain_digests) {
+        let stored = store::get_raw(&Table::ChainDigest, chain)?.unwrap_or_default();
+        if stored != expected {
+            report.discrepancies.push(Discrepancy::ChainDigestMismatch {
+                chain: chain.clone(),
+                stored,
+                expected,
+            });
+        }
+    }
+    let stored = store::get_raw(&Table::ChainDigest, "master")?.unwrap_or_default();
+    if stored != state_digest {
+        report.discrepancies.push(Discrepancy::StateDigestMismatch {
+            stored,
+            expected: state_digest,
+        });
+    }
+    Ok(())
+}
+
+/// # Check
+/// Checks every chain index, the transactions index, account balances and the chain and state digests, returning what was checked and every discrepancy found
+pub fn check() -> Result<IntegrityReport, Box<dyn std::error::Error>> {
+    let mut report = IntegrityReport {
+        pruned: pruned_below()? != 0,
+        ..Default::default()
+    };
+    let chains: Vec<String> = store::entries(&Table::ChainList)?
+        .into_iter()
+        .map(|(chain, _)| chain)
+        .collect();
+    info!("Checking {} chains", chains.len());
+    for chain in &chains {
+        check_chain(chain, &mut report)?;
+        report.chains += 1;
+        if report.chains % 1000 == 0 {
+            info!("Checked {}/{} chains", report.chains, chains.len());
+        }
+    }
+    info!("Checking transactions index");
+    let totals = check_transactions(&mut report)?;
+    if report.pruned {
+        info!("Old blocks have been pruned, not recomputing balances and digests");
+    } else {
+        info!("Checking account balances");
+        check_balances(&totals, &mut report)?;
+        info!("Recomputing chain digests");
+        check_digests(&chains, &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Returns the block to rebuild the chain from: the stored top block if it is in the block store, otherwise the highest (preferring final) block of the chain
+fn rebuild_tip(chain: &str, highest: &[String]) -> Result<Option<Block>, DbError> {
+    if let Some(top) = store::get_raw(&Table::ChainIndex(chain.to_owned()), "topblockhash")? {
+        let block = get_block_from_raw(top);
+        if !block.is_default() && block.header.chain_key == chain {
+            return Ok(Some(block));
+        }
+    }
+    Ok(highest
+        .iter()
+        .find(|hash| is_final(hash))
+        .or_else(|| highest.first())
+        .and_then(|hash| {
+            let block = get_block_from_raw(hash.clone());
+            if block.is_default() {
+                None
+            } else {
+                Some(block)
+            }
+        }))
+}
+
+/// Rewrites the chain index (and the transactions index entries of its recieve blocks) by walking back from its top block along prev_hash
+/// The entries are committed BLOCKS_PER_BATCH blocks at a time and the top block hash, block count and txn count last, so a long chain does not build
+/// an unbounded batch. If rebuilding is interrupted the chain index is left partly rewritten (which check reports) until it is rebuilt again
+fn rebuild_chain(chain: &str, highest: &[String]) -> Result<u64, Box<dyn std::error::Error>> {
+    let tip = match rebuild_tip(chain, highest)? {
+        Some(tip) => tip,
+        None => return Ok(0),
+    };
+    let index = Table::ChainIndex(chain.to_owned());
+    let mut rebuilt: u64 = 0;
+    let mut txn_count: u64 = 0;
+    let mut next = Some(tip.clone());
+    while let Some(top) = next.take() {
+        // returns the block to continue from in the next batch, or None once the walk is over
+        next = store::atomic(|| -> Result<Option<Block>, Box<dyn std::error::Error>> {
+            let mut block = top;
+            for _ in 0..BLOCKS_PER_BATCH {
+                store::put_raw(&index, &block.header.height.to_string(), &block.hash)?;
+                rebuilt += 1;
+                match block.block_type {
+                    BlockType::Send => {
+                        txn_count += block
+                            .txns
+                            .iter()
+                            .filter(|txn| txn.sender_key == chain)
+                            .count() as u64
+                    }
+                    BlockType::Recieve => {
+                        for txn in &block.txns {
+                            store::put_raw(&Table::Transactions, &txn.hash, &block.hash)?;
+                        }
+                    }
+                }
+                if block.header.height == 0 {
+                    store::put_raw(&Table::ChainList, chain, "")?;
+                    return Ok(None);
+                }
+                let prev = get_block_from_raw(block.header.prev_hash.clone());
+                if prev.is_default() {
+                    warn!(
+                        "Block {} below height {} of chain {} is missing, rebuilt index from there",
+                        block.header.prev_hash, block.header.height, chain
+                    );
+                    return Ok(None);
+                }
+                block = prev;
+            }
+            Ok(Some(block))
+        })?;
+    }
+    // remove the entries above the top block
+    let old_count: u64 = store::get(&index, "blockcount")?.unwrap_or(0);
+    let mut height = tip.header.height + 1;
+    while height < old_count {
+        let end = old_count.min(height + BLOCKS_PER_BATCH as u64);
+        store::atomic(|| -> Result<(), DbError> {
+            for height in height..end {
+                store::delete(&index, &height.to_string())?;
+            }
+            Ok(())
+        })?;
+        height = end;
+    }
+    store::atomic(|| -> Result<(), DbError> {
+        store::put_raw(&index, "topblockhash", &tip.hash)?;
+        store::put_raw(&index, "blockcount", &(tip.header.height + 1).to_string())?;
+        store::put_raw(&index, "txncount", &txn_count.to_string())?;
+        Ok(())
+    })?;
+    Ok(rebuilt)
+}
+
+/// # Rebuild
+/// Rebuilds the chain indexes, transactions index, chain list and chain and state digests from the blocks in the block store, returning the number of blocks indexed
+/// The global index is not rebuilt, the order blocks were enacted in is not recorded
+pub fn rebuild() -> Result<u64, Box<dyn std::error::Error>> {
+    // the height and hashes of the highest blocks of each chain, the block store is read a page at a time so only these are held in memory
+    let mut chains: BTreeMap<String, (u64, Vec<String>)> = BTreeMap::new();
+    let mut after: Option<String> = None;
+    let mut read: u64 = 0;
+    loop {
+        let page = store::entries_page(&Table::Blocks, after.as_deref(), BLOCKS_PER_BATCH)?;
+        after = match page.last() {
+            Some((hash, _)) => Some(hash.clone()),
+            None => break,
+        };
+        read += page.len() as u64;
+        for (hash, encoded) in page {
+            match Block::from_compressed(encoded) {
+                Ok(block) => {
+                    let (height, hashes) = chains
+                        .entry(block.header.chain_key)
+                        .or_insert((block.header.height, vec![]));
+                    if block.header.height > *height {
+                        *height = block.header.height;
+                        hashes.clear();
+                    }
+                    if block.header.height == *height {
+                        hashes.push(hash);
+                    }
+                }
+                Err(e) => warn!("Block {} in the block store is corrupt, error={}", hash, e),
+            }
+        }
+        debug!("Read {} blocks from the block store", read);
+    }
+    for (chain, _) in store::entries(&Table::ChainList)? {
+        chains.entry(chain).or_default();
+    }
+    info!("Rebuilding the indexes of {} chains", chains.len());
+    let mut rebuilt: u64 = 0;
+    for (chain, (_, highest)) in &chains {
+        rebuilt += rebuild_chain(chain, highest)?;
+    }
+    let chain_keys: Vec<String> = chains.keys().cloned().collect();
+    if pruned_below()? == 0 {
+        store::atomic(|| -> Result<(), Box<dyn std::error::Error>> {
+            form_chain_digest(chain_keys)?;
+            form_state_digest()?;
+            Ok(())
+        })?;
+    }
+    info!(
+        "Rebuilt indexes, chains={}, blocks={}",
+        chains.len(),
+        rebuilt
+    );
+    Ok(rebuilt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{save_block, Header},
+        transaction::TRANSACTION_FLAGS,
+    };
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    /// Saves (without indexing) the block at height on the chain, on top of the block with hash prev_hash
+    fn stored_block(chain_key: &str, height: u64, hash: &str, prev_hash: &str) {
+        save_block(Block {
+            hash: hash.to_owned(),
+            header: Header {
+                chain_key: chain_key.to_owned(),
+                height,
+                prev_hash: prev_hash.to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_balance_changes() {
+        let mut txn = Transaction {
+            flag: 'n',
+            sender_key: "a".to_owned(),
+            receive_key: "b".to_owned(),
+            amount: 10,
+            ..Default::default()
+        };
+        let fee = txn.fee() as i128;
+        assert_eq!(
+            balance_changes(&txn).unwrap(),
+            vec![("a".to_owned(), -10 - fee), ("b".to_owned(), 10)]
+        );
+        txn.flag = 'l';
+        assert_eq!(balance_changes(&txn).unwrap(), vec![("a".to_owned(), -fee)]);
+        txn.flag = 'y';
+        assert!(balance_changes(&txn).unwrap().is_empty());
+        txn.flag = 'g';
+        assert!(balance_changes(&txn).is_err());
+    }
+
+    #[test]
+    fn test_balance_changes_fees() {
+        // every supported flag which has gas charges the sender at least its fee
+        for flag in TRANSACTION_FLAGS.iter() {
+            let txn = Transaction {
+                flag: *flag,
+                sender_key: "a".to_owned(),
+                receive_key: "b".to_owned(),
+                amount: 10,
+                gas_price: 10,
+                extra: if *flag == 'r' {
+                    bs58::encode("[]").into_string()
+                } else {
+                    String::from("")
+                },
+                ..Default::default()
+            };
+            let sender: i128 = balance_changes(&txn)
+                .unwrap()
+                .into_iter()
+                .filter(|(public_key, _)| public_key == "a")
+                .map(|(_, change)| change)
+                .sum();
+            if txn.fee() != 0 {
+                assert!(
+                    sender <= -(txn.fee() as i128),
+                    "flag {} does not charge its fee",
+                    flag
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_rebuild() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            stored_block("a", 0, "a-0", "");
+            for height in 1..4 {
+                stored_block(
+                    "a",
+                    height,
+                    &format!("a-{}", height),
+                    &format!("a-{}", height - 1),
+                );
+            }
+            // a block which lost a fork, below the top of the chain
+            stored_block("a", 2, "a-2-fork", "a-1");
+            stored_block("b", 0, "b-0", "");
+            assert_eq!(rebuild().unwrap(), 5);
+            let index = Table::ChainIndex("a".to_owned());
+            for height in 0..4 {
+                assert_eq!(
+                    store::get_raw(&index, &height.to_string()).unwrap(),
+                    Some(format!("a-{}", height))
+                );
+            }
+            assert_eq!(
+                store::get_raw(&index, "topblockhash").unwrap(),
+                Some("a-3".to_owned())
+            );
+            assert_eq!(store::get::<u64>(&index, "blockcount").unwrap(), Some(4));
+            assert!(store::contains(&Table::ChainList, "b").unwrap());
+        });
+    }
+
+    #[test]
+    fn test_rebuild_long_chain() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            // longer than a batch, so it is read and re-indexed in several
+            let length = BLOCKS_PER_BATCH as u64 * 2 + 1;
+            stored_block("a", 0, "a-0", "");
+            for height in 1..length {
+                stored_block(
+                    "a",
+                    height,
+                    &format!("a-{}", height),
+                    &format!("a-{}", height - 1),
+                );
+            }
+            // an index left pointing past the top of the chain
+            let index = Table::ChainIndex("a".to_owned());
+            store::put_raw(&index, "topblockhash", "missing").unwrap();
+            store::put_raw(&index, "blockcount", &(length + 5).to_string()).unwrap();
+            store::put_raw(&index, &(length + 2).to_string(), "stale").unwrap();
+            assert_eq!(rebuild().unwrap(), length);
+            for height in &[0, BLOCKS_PER_BATCH as u64, length - 1] {
+                assert_eq!(
+                    store::get_raw(&index, &height.to_string()).unwrap(),
+                    Some(format!("a-{}", height))
+                );
+            }
+            assert_eq!(
+                store::get_raw(&index, &(length + 2).to_string()).unwrap(),
+                None
+            );
+            assert_eq!(
+                store::get::<u64>(&index, "blockcount").unwrap(),
+                Some(length)
+            );
+        });
+    }
+}
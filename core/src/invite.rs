@@ -8,15 +8,116 @@ use ring::{
 extern crate bs58;
 
 extern crate avrio_database;
-use avrio_database::{get_data, save_data};
+use avrio_database::store::{get, get_raw, put, put_raw, Table};
 
-extern crate avrio_config;
-use avrio_config::config;
+use crate::{epoch::get_top_epoch, transaction::Transaction};
+use std::collections::{HashMap, HashSet};
+
+/// The number of epochs an invite can be spent in after the epoch it was created in // TODO: move to config
+pub const INVITE_LIFETIME_EPOCHS: u64 = 4;
+/// The number of invites a single fullnode may create per epoch // TODO: move to config
+pub const MAX_INVITES_PER_ISSUER: u64 = 1;
 
 pub fn per_epoch_limit(nodes: u64) -> u64 {
     ((1.0 / 3.0) * (nodes / 2) as f64) as u64
 }
 
+/// # Epoch invite limit
+/// The number of invites which can be created (network wide) in one epoch with fullnodes fullnodes
+/// This is per_epoch_limit, but at least one so a small network can still grow
+pub fn epoch_invite_limit(fullnodes: u64) -> u64 {
+    per_epoch_limit(fullnodes).max(1)
+}
+
+/// Returns the number of invites created (network wide) during the epoch
+pub fn invites_issued(epoch_number: u64) -> u64 {
+    get(&Table::Invites, &(epoch_number.to_string() + "-issued"))
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Returns the number of invites the fullnode created during the epoch
+pub fn invites_issued_by(epoch_number: u64, issuer: &str) -> u64 {
+    get(&Table::Invites, &(epoch_number.to_string() + "-" + issuer))
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Returns the publickey of the fullnode which created the invite, or None for invites with no issuer (eg the seed invites)
+pub fn get_issuer(invite: &str) -> Option<String> {
+    get_raw(&Table::Invites, &(invite.to_owned() + "-issuer"))
+        .ok()
+        .flatten()
+}
+
+/// Returns the epoch the invite was created in, or None for invites with no issuer
+pub fn issued_in(invite: &str) -> Option<u64> {
+    get(&Table::Invites, &(invite.to_owned() + "-epoch"))
+        .ok()
+        .flatten()
+}
+
+/// Returns true if the invite can no longer be spent in the epoch (invites with no issuer never expire)
+pub fn expired(invite: &str, epoch_number: u64) -> bool {
+    match issued_in(invite) {
+        Some(issued) => epoch_number > issued + INVITE_LIFETIME_EPOCHS,
+        None => false,
+    }
+}
+
+/// Returns true if the invite was revoked by its issuer
+pub fn is_revoked(invite: &str) -> bool {
+    invite_status(invite).as_deref() == Some("r")
+}
+
+/// # Over invite limit
+/// Returns the invite of the first create invite ('i') txn in txns which would take the epoch over its network wide or per issuer limit
+/// (counting the invites already issued during the epoch and the txns before it), or which creates the same invite as an earlier txn
+/// Each txn is validated against the enacted state alone, this catches txns which are only over the limits together (eg in one block)
+pub fn over_invite_limit(txns: &[Transaction], epoch_number: u64, limit: u64) -> Option<String> {
+    let mut issued = invites_issued(epoch_number);
+    let mut issued_by: HashMap<&str, u64> = HashMap::new();
+    let mut invites: HashSet<&str> = HashSet::new();
+    for txn in txns.iter().filter(|txn| txn.flag == 'i') {
+        let issued_by_sender = issued_by
+            .entry(txn.sender_key.as_str())
+            .or_insert_with(|| invites_issued_by(epoch_number, &txn.sender_key));
+        if issued >= limit
+            || *issued_by_sender >= MAX_INVITES_PER_ISSUER
+            || !invites.insert(txn.extra.as_str())
+        {
+            return Some(txn.extra.clone());
+        }
+        issued += 1;
+        *issued_by_sender += 1;
+    }
+    None
+}
+
+/// # Record issuance
+/// Links the invite to the fullnode which created it and counts it towards the epochs limits
+pub fn record_issuance(
+    invite: &str,
+    issuer: &str,
+    epoch_number: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    put_raw(&Table::Invites, &(invite.to_owned() + "-issuer"), issuer)?;
+    put(&Table::Invites, &(invite.to_owned() + "-epoch"), &epoch_number)?;
+    put(
+        &Table::Invites,
+        &(epoch_number.to_string() + "-issued"),
+        &(invites_issued(epoch_number) + 1),
+    )?;
+    put(
+        &Table::Invites,
+        &(epoch_number.to_string() + "-" + issuer),
+        &(invites_issued_by(epoch_number, issuer) + 1),
+    )?;
+    Ok(())
+}
+
 /// Generates the public private key pair for a new invite, returns a tupe (publickey, privatekey)
 pub fn generate_invite() -> (String, String) {
     let rngc = randc::SystemRandom::new();
@@ -29,52 +130,129 @@ pub fn generate_invite() -> (String, String) {
     );
 }
 
+/// Returns the status of the invite (u = unspent, s = spent, r = revoked), or None if it does not exist
+fn invite_status(invite: &str) -> Option<String> {
+    get_raw(&Table::Invites, invite).ok().flatten()
+}
+
 /// Returns true if the invite is in existance and not spent.
 pub fn unspent(invite: &str) -> bool {
-    get_data(config().db_path + &"/invites".to_owned(), invite) == *"u"
+    invite_status(invite).as_deref() == Some("u")
 }
 
 /// Returns true if the invite is in existance and spent.
 // TODO: Phase out, duplicate of unspent() above
 pub fn is_spent(invite: &str) -> bool {
-    get_data(config().db_path + &"/invites".to_owned(), invite) == *"s"
+    invite_status(invite).as_deref() == Some("s")
 }
 
 /// Marks the invite as spent
 pub fn mark_spent(invite: &str) -> Result<(), &str> {
     if !unspent(invite) {
         Err("Invite has already been spent")
-    } else if save_data(
-        &"s".to_string(),
-        &(config().db_path + &"/invites".to_owned()),
-        invite.to_owned(),
-    ) != 1
-    {
+    } else if put_raw(&Table::Invites, invite, "s").is_err() {
         Err("Error marking invite as spent")
     } else {
         Ok(())
     }
 }
 
+/// Revokes the (unspent) invite, it can no longer be spent
+/// Revoked invites still count towards the limits of the epoch they were created in
+pub fn revoke_invite(invite: &str) -> Result<(), &str> {
+    if !unspent(invite) {
+        Err("Invite has already been spent or revoked")
+    } else if put_raw(&Table::Invites, invite, "r").is_err() {
+        Err("Error marking invite as revoked")
+    } else {
+        Ok(())
+    }
+}
+
 /// Saves the public key into our invites db (and sets to unspent)
 pub fn new_invite(invite: &str) -> Result<(), &str> {
-    if get_data(config().db_path + &"/invites".to_owned(), invite) != *"-1" {
+    if invite_status(invite).is_some() {
         Err("Error creating invite")
-    } else if save_data(
-        &"u".to_owned(),
-        &(config().db_path + &"/invites".to_owned()),
-        invite.to_owned(),
-    ) != 1
-    {
+    } else if put_raw(&Table::Invites, invite, "u").is_err() {
         Err("Error saving invite")
     } else {
         Ok(())
     }
 }
 
+/// Returns true if the invite is well formed (a base58 encoded ed25519 publickey)
+pub fn invite_format_valid(invite: &str) -> bool {
+    bs58::decode(invite)
+        .into_vec()
+        .map(|bytes| bytes.len() == 32)
+        .unwrap_or(false)
+}
+
 /// Returns true if:
 /// * 1) The invite format is valid
-/// * 2) It is on the blockchain and unspent.
+/// * 2) It is on the blockchain and unspent (not spent or revoked).
+/// * 3) It has not expired
 pub fn invite_valid(invite: &str) -> bool {
-    unspent(invite)
+    let epoch_number = match get_top_epoch() {
+        Ok(epoch) => epoch.epoch_number,
+        Err(e) => {
+            error!("Failed to get top epoch while validating invite, error={}", e);
+            return false;
+        }
+    };
+    invite_format_valid(invite) && unspent(invite) && !expired(invite, epoch_number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_over_invite_limit() {
+        let txn = |sender: &str, invite: &str| Transaction {
+            flag: 'i',
+            sender_key: sender.to_owned(),
+            extra: invite.to_owned(),
+            ..Default::default()
+        };
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert_eq!(over_invite_limit(&[], 1, 1), None);
+            assert_eq!(over_invite_limit(&[txn("a", "1"), txn("b", "2")], 1, 2), None);
+            // network wide limit
+            assert_eq!(
+                over_invite_limit(&[txn("a", "1"), txn("b", "2"), txn("c", "3")], 1, 2),
+                Some("3".to_owned())
+            );
+            // per issuer limit
+            assert_eq!(
+                over_invite_limit(&[txn("a", "1"), txn("a", "2")], 1, 5),
+                Some("2".to_owned())
+            );
+            // the same invite twice
+            assert_eq!(
+                over_invite_limit(&[txn("a", "1"), txn("b", "1")], 1, 5),
+                Some("1".to_owned())
+            );
+            // invites already issued during the epoch count towards the limits
+            record_issuance("0", "a", 1).unwrap();
+            assert_eq!(over_invite_limit(&[txn("b", "1")], 1, 1), Some("1".to_owned()));
+            assert_eq!(over_invite_limit(&[txn("a", "1")], 1, 5), Some("1".to_owned()));
+            assert_eq!(over_invite_limit(&[txn("a", "1")], 2, 5), None);
+        });
+    }
+
+
+    #[test]
+    fn test_invite_format_valid() {
+        assert!(invite_format_valid(&generate_invite().0));
+        assert!(invite_format_valid(
+            "FZ2YbpGw1ZjRW2dkwMRfy7N98iZCkcfezy5BxCGWRPgZ"
+        ));
+        assert!(!invite_format_valid("not an invite"));
+        assert!(!invite_format_valid(&"0".repeat(44)));
+        assert_eq!(epoch_invite_limit(1), 1);
+        assert_eq!(epoch_invite_limit(30), 5);
+    }
 }
@@ -2,16 +2,28 @@
 extern crate log;
 
 pub mod account;
+pub mod archive;
 pub mod block;
+pub mod block_store;
 pub mod callback;
 pub mod certificate;
 pub mod chunk;
 pub mod commitee;
+pub mod consensus;
 pub mod encodings;
 pub mod epoch;
+pub mod finality;
+pub mod fork;
 pub mod gas;
+pub mod integrity;
 pub mod invite;
+pub mod lottery;
 pub mod mempool;
+pub mod participation;
+pub mod probation;
+pub mod prune;
+pub mod reward;
+pub mod snapshot;
 pub mod states;
 pub mod transaction;
 pub mod validate;
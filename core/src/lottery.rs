@@ -0,0 +1,212 @@
+// This file handles the VRF lottery, which selects the fullnode candidates that join the fullnode set at the start of the next epoch.
+extern crate avrio_database;
+
+use avrio_crypto::{proof_to_hash, raw_hash, vrf_hash_to_u64};
+use avrio_database::store::{contains, get, put, Table};
+
+use crate::{
+    certificate::get_fullnode_count, epoch::Epoch, invite::per_epoch_limit,
+    transaction::Transaction,
+};
+use std::collections::HashSet;
+
+/// Returns the message candidates create their VRF lottery tickets over during current_epoch's lottery
+pub fn lotto_seed(current_epoch: &Epoch, next_epoch: &Epoch) -> String {
+    raw_hash(&format!(
+        "{}{}{}",
+        current_epoch.salt, next_epoch.salt, "-vrflotto"
+    ))
+}
+
+/// Returns the value of a VRF lottery ticket (the first 8 bytes of the VRF hash), lower is better
+pub fn ticket_value(proof: &String) -> Result<u64, Box<dyn std::error::Error>> {
+    vrf_hash_to_u64(proof_to_hash(proof)?)
+}
+
+/// Returns the number of fullnodes in the epoch
+fn fullnodes_in(epoch: &Epoch) -> u64 {
+    epoch
+        .committees
+        .iter()
+        .map(|committee| committee.members.len() as u64)
+        .sum()
+}
+
+/// # Target new fullnodes
+/// The number of candidates we aim to eclose in one epoch
+/// This is invite::per_epoch_limit of the fullnode count (but at least one, so a small network can still grow), and never more than the number of candidates
+pub fn target_new_fullnodes(fullnodes: u64, candidates: u64) -> u64 {
+    per_epoch_limit(fullnodes).max(1).min(candidates)
+}
+
+/// # Ticket threshold
+/// Ticket values are uniformly distributed over the u64 space, so for target of the candidates to (on average) hold a viable ticket the threshold is u64::MAX * target / candidates
+pub fn ticket_threshold(target: u64, candidates: u64) -> u64 {
+    if candidates == 0 || target == 0 {
+        return 0;
+    }
+    if target >= candidates {
+        return u64::MAX;
+    }
+    ((u64::MAX as u128 * target as u128) / candidates as u128) as u64
+}
+
+/// Opens the VRF lottery for the epoch, recording the number of candidates so every node derives the same threshold
+pub fn open_lottery(epoch: &Epoch) -> Result<(), Box<dyn std::error::Error>> {
+    let candidates = get_fullnode_count().saturating_sub(fullnodes_in(epoch));
+    debug!(
+        "Opening VRF lottery for epoch {}, candidates={}",
+        epoch.epoch_number, candidates
+    );
+    put(
+        &Table::Lottery,
+        &(epoch.epoch_number.to_string() + "-candidates"),
+        &candidates,
+    )?;
+    Ok(())
+}
+
+/// Returns the number of candidates recorded when the epochs lottery opened
+fn get_candidate_count(epoch_number: u64) -> u64 {
+    get(&Table::Lottery, &(epoch_number.to_string() + "-candidates"))
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Returns the (target new fullnodes, ticket threshold) for the epochs lottery
+pub fn lottery_threshold(epoch: &Epoch) -> (u64, u64) {
+    let candidates = get_candidate_count(epoch.epoch_number);
+    let target = target_new_fullnodes(fullnodes_in(epoch), candidates);
+    (target, ticket_threshold(target, candidates))
+}
+
+/// Returns true if the candidate has already entered the epochs lottery
+pub fn has_ticket(epoch_number: u64, publickey: &str) -> bool {
+    contains(&Table::Lottery, &(epoch_number.to_string() + "-" + publickey)).unwrap_or(false)
+}
+
+/// Returns the sender of a VRF lottery ticket ('v') txn in txns which follows another ticket from the same sender, or None if each sender sends at most one
+/// has_ticket only sees enacted tickets, so this catches a candidate entering twice in one block
+pub fn duplicate_ticket(txns: &[Transaction]) -> Option<String> {
+    let mut senders: HashSet<&str> = HashSet::new();
+    for txn in txns.iter().filter(|txn| txn.flag == 'v') {
+        if !senders.insert(&txn.sender_key) {
+            return Some(txn.sender_key.clone());
+        }
+    }
+    None
+}
+
+/// Records the enacted ticket (sent in the transaction with hash txn_hash) of a candidate
+pub fn record_ticket(
+    epoch_number: u64,
+    publickey: &str,
+    txn_hash: &str,
+    value: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if has_ticket(epoch_number, publickey) {
+        return Err("Candidate already entered lottery".into());
+    }
+    put(
+        &Table::Lottery,
+        &(epoch_number.to_string() + "-" + publickey),
+        &(txn_hash, value),
+    )?;
+    let mut entrants = get_entrants(epoch_number);
+    entrants.push(publickey.to_owned());
+    put(
+        &Table::Lottery,
+        &(epoch_number.to_string() + "-entrants"),
+        &entrants,
+    )?;
+    Ok(())
+}
+
+/// Returns the publickeys of every candidate who entered the epochs lottery, in the order their tickets were enacted
+fn get_entrants(epoch_number: u64) -> Vec<String> {
+    get(&Table::Lottery, &(epoch_number.to_string() + "-entrants"))
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Returns the (publickey, txn hash, value) of every enacted ticket in the epochs lottery
+pub fn get_tickets(epoch_number: u64) -> Vec<(String, String, u64)> {
+    let mut tickets = vec![];
+    for publickey in get_entrants(epoch_number) {
+        match get::<(String, u64)>(&Table::Lottery, &(epoch_number.to_string() + "-" + &publickey)) {
+            Ok(Some((txn_hash, value))) => tickets.push((publickey, txn_hash, value)),
+            Ok(None) => error!(
+                "Missing lottery ticket of {} in epoch {}",
+                publickey, epoch_number
+            ),
+            Err(e) => error!(
+                "Failed to decode lottery ticket of {} in epoch {}, error={}",
+                publickey, epoch_number, e
+            ),
+        }
+    }
+    tickets
+}
+
+/// # Select candidates
+/// Returns the (publickey, ticket txn hash) of the candidates eclosed by the epochs lottery
+/// Viable tickets are ordered by value (lowest first, ties broken by publickey) and at most target_new_fullnodes are selected
+/// Only depends on enacted tickets, so any node can recompute the round leaders selection
+pub fn select_candidates(epoch: &Epoch) -> Vec<(String, String)> {
+    let (target, threshold) = lottery_threshold(epoch);
+    let mut viable: Vec<(u64, String, String)> = get_tickets(epoch.epoch_number)
+        .into_iter()
+        .filter(|(_, _, value)| *value <= threshold)
+        .map(|(publickey, txn_hash, value)| (value, publickey, txn_hash))
+        .collect();
+    viable.sort();
+    viable.truncate(target as usize);
+    debug!(
+        "Selected {} candidates from lottery in epoch {}, target={}, threshold={}",
+        viable.len(),
+        epoch.epoch_number,
+        target,
+        threshold
+    );
+    viable
+        .into_iter()
+        .map(|(_, publickey, txn_hash)| (publickey, txn_hash))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_ticket() {
+        let txn = |flag: char, sender: &str| Transaction {
+            flag,
+            sender_key: sender.to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(duplicate_ticket(&[]), None);
+        assert_eq!(
+            duplicate_ticket(&[txn('v', "a"), txn('v', "b"), txn('n', "a")]),
+            None
+        );
+        assert_eq!(
+            duplicate_ticket(&[txn('v', "a"), txn('n', "b"), txn('v', "a")]),
+            Some("a".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_ticket_threshold() {
+        // at least one candidate can join a small network, never more than there are candidates
+        assert_eq!(target_new_fullnodes(1, 3), 1);
+        assert_eq!(target_new_fullnodes(30, 3), 3);
+        assert_eq!(target_new_fullnodes(30, 100), 5);
+        assert_eq!(ticket_threshold(0, 10), 0);
+        assert_eq!(ticket_threshold(5, 0), 0);
+        assert_eq!(ticket_threshold(3, 3), u64::MAX);
+        assert_eq!(ticket_threshold(1, 2), u64::MAX / 2);
+    }
+}
@@ -1,5 +1,13 @@
-use crate::{block::{save_block, Block, BlockType}, certificate};
-use log::{error, info, trace};
+use crate::{
+    block::{save_block, Block, BlockType},
+    certificate,
+    fork::{conflicting_block, record_conflict, valid_competitor},
+};
+use avrio_database::{
+    store::{get_raw, Table},
+    with_current_backend,
+};
+use log::{debug, error, info, trace};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::mpsc;
@@ -67,7 +75,54 @@ impl Default for MempoolState {
     }
 }
 
+/// Records a conflict if the block forks its chain (before taking the mempool lock, conflicting_block reads the mempool)
+/// A conflict is only recorded once both blocks are valid, otherwise anyone could announce forks by sending invalid blocks
+fn detect_fork(blk: &Block) {
+    if let Some(other) = conflicting_block(blk) {
+        let enacted = get_raw(
+            &Table::ChainIndex(blk.header.chain_key.clone()),
+            &blk.header.height.to_string(),
+        )
+        .ok()
+        .flatten()
+        .filter(|enacted| enacted != &blk.hash);
+        if let Err(e) = valid_competitor(blk, enacted.as_deref()) {
+            debug!(
+                "Block {} conflicts with {} but is not valid, not recording fork, reason={}",
+                blk.hash, other, e
+            );
+            return;
+        }
+        if enacted.as_ref() != Some(&other) {
+            // neither block is enacted, the other block has only been checked by the peer who sent it
+            let other_valid = get_block(&other).and_then(|other| other.valid());
+            if let Err(e) = other_valid {
+                debug!(
+                    "Block {} conflicts with {} which is not valid, not recording fork, reason={}",
+                    blk.hash, other, e
+                );
+                return;
+            }
+        }
+        if let Err(e) = record_conflict(blk, &other) {
+            error!("Failed to record fork between {} and {}, error={}", blk.hash, other, e);
+        }
+    }
+}
+
+/// Puts a block in the mempool without a caller (eg a recieve block rolled back by fork::rollback), it is enacted by purge once valid
+pub fn requeue(blk: Block) -> Result<(), Box<dyn std::error::Error>> {
+    let mut map = MEMPOOL.lock()?;
+    if map.contains_key(&blk.hash) {
+        Err("block already in mempool".into())
+    } else {
+        map.insert(blk.hash.clone(), (blk, SystemTime::now(), None));
+        Ok(())
+    }
+}
+
 pub fn add_block(blk: &Block, callback: Caller) -> Result<(), Box<dyn std::error::Error>> {
+    detect_fork(blk);
     let mut map = MEMPOOL.lock()?;
     if map.contains_key(&blk.hash) {
         Err("block already in mempool".into())
@@ -167,7 +222,7 @@ impl Mempool {
             purge_handle: None,
             purge_stream: None,
         };
-        Ok(std::thread::spawn(move || {
+        Ok(std::thread::spawn(with_current_backend(move || {
             self_clone.load().unwrap();
             let mut start = SystemTime::now();
             loop {
@@ -190,7 +245,7 @@ impl Mempool {
                     std::thread::sleep(std::time::Duration::from_millis(50));
                 }
             }
-        }))
+        })))
     }
 
     pub fn into_string() -> Result<String, Box<dyn std::error::Error>> {
@@ -229,6 +284,7 @@ impl Mempool {
         if self.init != MempoolState::Initialized {
             return Err("mempool not initalised".into());
         }
+        detect_fork(blk);
         let mut map = MEMPOOL.lock()?;
         if map.contains_key(&blk.hash) {
             Err("block already in mempool".into())
@@ -0,0 +1,250 @@
+// This file handles fullnode participation, toggled with 'o' (toggle participation) transactions.
+// Inactive fullnodes keep their certificate but, from the next epoch, are left out of committees and not counted towards quorums. They cannot be penalised for absence.
+extern crate avrio_database;
+
+extern crate avrio_config;
+
+use avrio_config::config;
+use avrio_database::store::{get, get_raw, put, put_raw, Table};
+
+use crate::{commitee::Comitee, epoch::Epoch, probation::is_probationary};
+
+/// The number of epochs a fullnode must wait between toggling its participation // TODO: move to config
+pub const TOGGLE_COOLDOWN_EPOCHS: u64 = 2;
+
+/// Returns true if the fullnode is participating (fullnodes are active unless they toggle off)
+pub fn is_active(publickey: &str) -> bool {
+    get_raw(&Table::Participation, publickey)
+        .ok()
+        .flatten()
+        .as_deref()
+        != Some("0")
+}
+
+/// Returns the epoch the fullnode last toggled its participation in, or None if it never has
+pub fn last_toggled(publickey: &str) -> Option<u64> {
+    get(&Table::Participation, &(publickey.to_owned() + "-toggled"))
+        .ok()
+        .flatten()
+}
+
+/// Returns true if the fullnode may toggle its participation during the epoch
+pub fn can_toggle(publickey: &str, epoch_number: u64) -> bool {
+    match last_toggled(publickey) {
+        Some(toggled) => epoch_number >= toggled + TOGGLE_COOLDOWN_EPOCHS,
+        None => true,
+    }
+}
+
+/// Returns the publickeys of every inactive fullnode
+pub fn get_inactive() -> Vec<String> {
+    get(&Table::Participation, "inactive")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+fn save_inactive(inactive: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    put(&Table::Participation, "inactive", inactive)?;
+    Ok(())
+}
+
+/// # Toggle
+/// Flips the participation of the fullnode, recording the epoch it happened in
+/// Returns true if the fullnode is now active
+pub fn toggle(publickey: &str, epoch_number: u64) -> Result<bool, Box<dyn std::error::Error>> {
+    let active = !is_active(publickey);
+    put_raw(
+        &Table::Participation,
+        publickey,
+        if active { "1" } else { "0" },
+    )?;
+    put(
+        &Table::Participation,
+        &(publickey.to_owned() + "-toggled"),
+        &epoch_number,
+    )?;
+    let mut inactive = get_inactive();
+    inactive.retain(|inactive_key| inactive_key != publickey);
+    if !active {
+        inactive.push(publickey.to_owned());
+    }
+    save_inactive(&inactive)?;
+    Ok(active)
+}
+
+/// Forgets the participation of a fullnode whose certificate was revoked
+pub fn forget(publickey: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut inactive = get_inactive();
+    if inactive.iter().any(|inactive_key| inactive_key == publickey) {
+        inactive.retain(|inactive_key| inactive_key != publickey);
+        save_inactive(&inactive)?;
+    }
+    Ok(())
+}
+
+/// # Fullnode set
+/// Returns every fullnode of the epoch, sorted: the members of its committees and the inactive fullnodes (which are not placed in committees)
+pub fn fullnode_set(epoch: &Epoch) -> Vec<String> {
+    let mut fullnodes: Vec<String> = vec![];
+    for committee in &epoch.committees {
+        fullnodes.extend(committee.members.iter().cloned());
+    }
+    fullnodes.extend(get_inactive());
+    fullnodes.sort();
+    fullnodes.dedup();
+    fullnodes
+}
+
+/// Returns the members of the committee which are participating
+pub fn active_members(committee: &Comitee) -> Vec<String> {
+    committee
+        .members
+        .iter()
+        .filter(|member| is_active(member))
+        .cloned()
+        .collect()
+}
+
+fn voting_key(epoch_number: u64, committee_index: u64) -> String {
+    epoch_number.to_string() + "-voting-" + &committee_index.to_string()
+}
+
+/// Returns the members of the committee which are participating and not on probation right now
+/// A committee without graduated members is made up of probationary fullnodes placed as voting nodes (see Comitee::form_comitees_with_observers), so they all vote
+fn current_voting_members(committee: &Comitee) -> Vec<String> {
+    let active = active_members(committee);
+    let graduated: Vec<String> = active
+        .iter()
+        .filter(|member| !is_probationary(member))
+        .cloned()
+        .collect();
+    if graduated.is_empty() {
+        active
+    } else {
+        graduated
+    }
+}
+
+/// # Record voting members
+/// Called as the epoch starts, records the members of each of its committees whose signatures count towards the committees quorum
+/// Toggles and graduations during the epoch do not change its quorums, they take effect when the next epoch starts
+pub fn record_voting_members(epoch: &Epoch) -> Result<(), Box<dyn std::error::Error>> {
+    for committee in &epoch.committees {
+        put(
+            &Table::Participation,
+            &voting_key(epoch.epoch_number, committee.index),
+            &current_voting_members(committee),
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the members of the epoch's committee whose signatures count towards its quorum (participating and not on probation as the epoch started)
+/// Epochs from before the voting members were recorded fall back to the current participation and probation state
+pub fn voting_members(epoch_number: u64, committee: &Comitee) -> Vec<String> {
+    get(
+        &Table::Participation,
+        &voting_key(epoch_number, committee.index),
+    )
+    .ok()
+    .flatten()
+    .unwrap_or_else(|| current_voting_members(committee))
+}
+
+/// Returns the number of signatures a committee with voting_members voting members needs (more than two thirds)
+/// A committee without voting members can never reach quorum
+pub fn quorum_size(voting_members: u64) -> u64 {
+    voting_members * 2 / 3 + 1
+}
+
+/// Returns the number of epoch salt seeds the consensus committee must contribute (min_vote percent of its participating members, at least one)
+pub fn required_salt_seeds(committee: &Comitee) -> usize {
+    salt_seed_threshold(active_members(committee).len())
+}
+
+fn salt_seed_threshold(active_members: usize) -> usize {
+    ((active_members * config().min_vote as usize + 99) / 100).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probation::admit;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    fn committee(index: u64, members: &[&str]) -> Comitee {
+        Comitee {
+            index,
+            members: members.iter().map(|member| member.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_toggle() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert!(is_active("node"));
+            assert!(can_toggle("node", 0));
+            assert!(!toggle("node", 3).unwrap());
+            assert!(!is_active("node"));
+            assert_eq!(get_inactive(), vec!["node".to_owned()]);
+            assert_eq!(last_toggled("node"), Some(3));
+            assert!(!can_toggle("node", 3 + TOGGLE_COOLDOWN_EPOCHS - 1));
+            assert!(can_toggle("node", 3 + TOGGLE_COOLDOWN_EPOCHS));
+            // inactive fullnodes are left out of committees, but are still in the fullnode set
+            let epoch = Epoch {
+                committees: vec![committee(0, &["member"])],
+                ..Default::default()
+            };
+            assert_eq!(fullnode_set(&epoch), vec!["member".to_owned(), "node".to_owned()]);
+            assert!(toggle("node", 5).unwrap());
+            assert!(is_active("node"));
+            assert!(get_inactive().is_empty());
+            assert_eq!(fullnode_set(&epoch), vec!["member".to_owned()]);
+        });
+    }
+
+    #[test]
+    fn test_voting_members() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let committee = committee(1, &["a", "b", "c", "d"]);
+            let epoch = Epoch {
+                epoch_number: 2,
+                committees: vec![committee.clone()],
+                ..Default::default()
+            };
+            toggle("d", 1).unwrap();
+            admit("c").unwrap();
+            // inactive and probationary members do not vote
+            assert_eq!(voting_members(2, &committee), vec!["a".to_owned(), "b".to_owned()]);
+            record_voting_members(&epoch).unwrap();
+            // toggles during the epoch take effect when the next epoch starts
+            toggle("a", 2).unwrap();
+            assert_eq!(voting_members(2, &committee), vec!["a".to_owned(), "b".to_owned()]);
+            assert_eq!(voting_members(3, &committee), vec!["b".to_owned()]);
+            // a committee without graduated members is voted on by its probationary members
+            toggle("b", 2).unwrap();
+            assert_eq!(voting_members(3, &committee), vec!["c".to_owned()]);
+        });
+    }
+
+    #[test]
+    fn test_quorum_size() {
+        assert_eq!(quorum_size(0), 1);
+        assert_eq!(quorum_size(2), 2);
+        assert_eq!(quorum_size(1), 1);
+        assert_eq!(quorum_size(3), 3);
+        assert_eq!(quorum_size(10), 7);
+        assert_eq!(quorum_size(12), 9);
+    }
+
+    #[test]
+    fn test_salt_seed_threshold() {
+        assert_eq!(salt_seed_threshold(0), 1);
+        assert_eq!(salt_seed_threshold(1), 1);
+        let min_vote = config().min_vote as usize;
+        assert_eq!(salt_seed_threshold(100), min_vote.max(1));
+    }
+}
@@ -0,0 +1,185 @@
+// This file handles fullnode probation. Newly eclosed fullnodes spend config().probatory_epoch_count epochs on probation.
+// Probationary fullnodes are placed in committees as non-voting observers: they sign block chunks (so their liveness is tracked)
+// but their signatures do not count towards the quorum. At the end of each epoch they are dropped if they signed fewer than
+// min_vote percent of their committees chunks, otherwise once they have served probatory_epoch_count epochs they graduate to full membership.
+extern crate avrio_database;
+
+extern crate avrio_config;
+
+use avrio_config::config;
+use avrio_database::store::{get, put, Table};
+
+use crate::{
+    epoch::Epoch,
+    reward::{get_chunk_count, get_participation},
+};
+
+/// Returns the publickeys of every probationary fullnode
+pub fn get_probationary() -> Vec<String> {
+    get(&Table::Probation, "list")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+fn save_probationary(probationary: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    put(&Table::Probation, "list", probationary)?;
+    Ok(())
+}
+
+/// Returns true if the fullnode is on probation
+pub fn is_probationary(publickey: &str) -> bool {
+    get_probationary().iter().any(|key| key == publickey)
+}
+
+/// Returns the number of epochs the fullnode has served on probation
+fn served(publickey: &str) -> u64 {
+    get(&Table::Probation, publickey)
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Returns the committee index of the fullnode in the epoch, if it was in one
+fn committee_of(publickey: &str, epoch: &Epoch) -> Option<u64> {
+    epoch
+        .committees
+        .iter()
+        .find(|committee| committee.members.iter().any(|member| member == publickey))
+        .map(|committee| committee.index)
+}
+
+/// Places a newly eclosed fullnode on probation (does nothing if probatory_epoch_count is 0)
+/// A fullnode admitted again (eg after re-certification) restarts its probation but is only listed once
+pub fn admit(publickey: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if config().probatory_epoch_count == 0 {
+        return Ok(());
+    }
+    put(&Table::Probation, publickey, &0u64)?;
+    let mut probationary = get_probationary();
+    if !probationary.iter().any(|key| key == publickey) {
+        probationary.push(publickey.to_owned());
+        save_probationary(&probationary)?;
+    }
+    debug!("{} admitted on probation", publickey);
+    Ok(())
+}
+
+/// Takes a fullnode off probation (once it graduates or its certificate is revoked)
+pub fn forget(publickey: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut probationary = get_probationary();
+    if probationary.iter().any(|key| key == publickey) {
+        probationary.retain(|key| key != publickey);
+        save_probationary(&probationary)?;
+    }
+    Ok(())
+}
+
+/// # Was live
+/// Returns true if the fullnode signed at least min_vote percent of its committees block chunks during the epoch
+/// Fullnodes not in a committee (eg toggled off) and committees which produced no chunks count as live
+pub fn was_live(publickey: &str, epoch: &Epoch) -> bool {
+    match committee_of(publickey, epoch) {
+        Some(committee) => {
+            let chunks = get_chunk_count(epoch.epoch_number, committee);
+            get_participation(epoch.epoch_number, publickey) * 100
+                >= chunks * config().min_vote as u64
+        }
+        None => true,
+    }
+}
+
+/// Returns true if the fullnode graduates at the end of the epoch (epochs are only served in a committee)
+fn graduates(publickey: &str, epoch: &Epoch) -> bool {
+    let served_this_epoch = committee_of(publickey, epoch).is_some() as u64;
+    served(publickey) + served_this_epoch >= config().probatory_epoch_count as u64
+}
+
+/// # Observers after
+/// Returns the fullnodes which will be on probation in the epoch after this one, given the candidates eclosed at its end
+/// Used to form the next epochs committees before the delta list is enacted
+pub fn observers_after(epoch: &Epoch, eclosed: &[String]) -> Vec<String> {
+    let mut observers: Vec<String> = get_probationary()
+        .into_iter()
+        .filter(|publickey| !graduates(publickey, epoch))
+        .collect();
+    if config().probatory_epoch_count != 0 {
+        observers.extend(eclosed.iter().cloned());
+    }
+    observers
+}
+
+/// # Advance
+/// Called at the end of the epoch (after the fullnodes dropped from probation have been removed)
+/// Counts the epoch towards each probationary fullnodes probation, graduating those which have served probatory_epoch_count epochs
+pub fn advance(epoch: &Epoch) -> Result<(), Box<dyn std::error::Error>> {
+    for publickey in get_probationary() {
+        if graduates(&publickey, epoch) {
+            forget(&publickey)?;
+            info!("{} graduated from probation", publickey);
+        } else if committee_of(&publickey, epoch).is_some() {
+            put(&Table::Probation, &publickey, &(served(&publickey) + 1))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commitee::Comitee,
+        reward::{record_chunk, record_participation},
+    };
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_probation() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let epoch = Epoch {
+                epoch_number: 1,
+                committees: vec![Comitee {
+                    index: 0,
+                    members: vec!["new".to_owned(), "old".to_owned()],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            admit("new").unwrap();
+            assert!(is_probationary("new"));
+            assert!(!is_probationary("old"));
+            // no chunks were formed, so every member was live
+            assert!(was_live("new", &epoch));
+            record_chunk(1, 0).unwrap();
+            record_chunk(1, 0).unwrap();
+            record_participation(1, "new").unwrap();
+            assert!(!was_live("new", &epoch));
+            record_participation(1, "new").unwrap();
+            assert!(was_live("new", &epoch));
+            // fullnodes outside of the epochs committees count as live
+            assert!(was_live("toggled", &epoch));
+            assert_eq!(
+                observers_after(&epoch, &["next".to_owned()]),
+                vec!["new".to_owned(), "next".to_owned()]
+            );
+            for _ in 0..config().probatory_epoch_count {
+                assert!(is_probationary("new"));
+                advance(&epoch).unwrap();
+            }
+            assert!(!is_probationary("new"));
+            assert!(observers_after(&epoch, &[]).is_empty());
+        });
+    }
+
+    #[test]
+    fn test_admit_twice() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            admit("new").unwrap();
+            admit("new").unwrap();
+            assert_eq!(get_probationary(), vec!["new".to_owned()]);
+            forget("new").unwrap();
+            assert!(!is_probationary("new"));
+        });
+    }
+}
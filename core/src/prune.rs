@@ -0,0 +1,203 @@
+// This file prunes old blocks on nodes with prune_keep_epochs set in the config, for nodes with small disks. The full state is kept, but the block bodies
+// and chain index entries of the blocks in the chunks of epochs before the last prune_keep_epochs are deleted.
+// The top block of each chain is always kept, so new blocks can still be validated against it. Pruned nodes say so in their handshake, so peers do not sync old blocks from them.
+extern crate avrio_database;
+
+use avrio_config::config;
+use avrio_database::{
+    store::{self, DbError, Table},
+    with_current_backend,
+};
+use std::ops::Range;
+
+use crate::{
+    block::{get_block_from_raw, remove_block},
+    chunk::{chunk_index, BlockChunk},
+    epoch::get_top_epoch,
+    validate::Verifiable,
+};
+
+/// The key in the meta table of the first epoch whose blocks have not been pruned
+const PRUNED_KEY: &str = "pruned-below-epoch";
+
+/// Returns true if we prune old blocks
+pub fn is_pruning() -> bool {
+    config().prune_keep_epochs != 0
+}
+
+/// Returns the first epoch whose blocks have not been pruned, 0 if we have never pruned
+pub fn pruned_below() -> Result<u64, DbError> {
+    Ok(store::get(&Table::Meta, PRUNED_KEY)?.unwrap_or(0))
+}
+
+/// Returns the epochs to prune when the top epoch is top_epoch, keeping the last keep_epochs and having pruned the epochs before pruned_below
+fn prune_range(top_epoch: u64, keep_epochs: u64, pruned_below: u64) -> Range<u64> {
+    pruned_below..(top_epoch + 1).saturating_sub(keep_epochs)
+}
+
+/// Deletes the block and its chain index entry, unless it is the top block of its chain. Returns true if the block was deleted
+fn prune_block(hash: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let block = get_block_from_raw(hash.to_owned());
+    if block.is_default() {
+        return Ok(false); // already pruned
+    }
+    let index = Table::ChainIndex(block.header.chain_key.clone());
+    let block_count: u64 = store::get(&index, "blockcount")?.unwrap_or(0);
+    if block.header.height + 1 >= block_count {
+        return Ok(false);
+    }
+    let height = block.header.height.to_string();
+    if store::get_raw(&index, &height)?.as_deref() == Some(hash) {
+        store::delete(&index, &height)?;
+    }
+    remove_block(hash)?;
+    Ok(true)
+}
+
+/// # Prune
+/// Deletes the blocks (and their chain index entries) of the epochs before the last prune_keep_epochs which have not been pruned yet
+/// Returns the number of blocks pruned
+pub fn prune() -> Result<u64, Box<dyn std::error::Error>> {
+    if !is_pruning() {
+        return Ok(0);
+    }
+    prune_keeping(config().prune_keep_epochs)
+}
+
+/// Prunes the blocks of the epochs before the last keep_epochs which have not been pruned yet, one atomic batch per epoch
+fn prune_keeping(keep_epochs: u64) -> Result<u64, Box<dyn std::error::Error>> {
+    let epochs = prune_range(get_top_epoch()?.epoch_number, keep_epochs, pruned_below()?);
+    if epochs.is_empty() {
+        return Ok(0);
+    }
+    let chunks = chunk_index()?;
+    let mut pruned: u64 = 0;
+    for epoch in epochs {
+        let removed = store::atomic(|| -> Result<u64, Box<dyn std::error::Error>> {
+            let mut removed: u64 = 0;
+            for (_, _, _, hash) in chunks.iter().filter(|chunk| chunk.0 == epoch) {
+                for block in &BlockChunk::get(hash.clone())?.blocks {
+                    if prune_block(block)? {
+                        removed += 1;
+                    }
+                }
+            }
+            store::put(&Table::Meta, PRUNED_KEY, &(epoch + 1))?;
+            Ok(removed)
+        })?;
+        pruned += removed;
+        debug!("Pruned {} blocks of epoch {}", removed, epoch);
+    }
+    info!(
+        "Pruned {} blocks, keeping the last {} epochs",
+        pruned, keep_epochs
+    );
+    Ok(pruned)
+}
+
+/// Prunes on a new thread, for EPOCH_STARTED_CALLBACKS. If called in a batch (eg of the block starting the epoch) the thread is only started once it commits,
+/// so the new epoch is on disk before we prune below it (and nothing is pruned if the block is discarded)
+pub fn prune_in_background() -> Result<bool, Box<dyn std::error::Error>> {
+    store::after_commit(|| {
+        // prune the backend we are running with, eg a test's
+        let prune_thread = with_current_backend(|| {
+            if let Err(e) = prune() {
+                error!("Failed to prune old blocks, error={}", e);
+            }
+        });
+        let spawned = std::thread::Builder::new()
+            .name("prune".to_string())
+            .spawn(prune_thread);
+        if let Err(e) = spawned {
+            error!("Failed to start prune thread, error={}", e);
+        }
+    })?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::{save_block, Block, Header},
+        epoch::Epoch,
+    };
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    /// Saves and indexes the block at height on the chain, returning its hash
+    fn chain_block(chain_key: &str, height: u64) -> String {
+        let block = Block {
+            hash: format!("{}-{}", chain_key, height),
+            header: Header {
+                chain_key: chain_key.to_owned(),
+                height,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        save_block(block.clone()).unwrap();
+        let index = Table::ChainIndex(chain_key.to_owned());
+        store::put_raw(&index, &height.to_string(), &block.hash).unwrap();
+        store::put(&index, "blockcount", &(height + 1)).unwrap();
+        block.hash
+    }
+
+    /// Saves and indexes the chunk of the blocks in round 0 of the epoch
+    fn chunk(epoch: u64, blocks: Vec<String>) {
+        let chunk = BlockChunk {
+            hash: format!("chunk-{}", epoch),
+            round: 0,
+            blocks,
+            aggregated_signature: String::from(""),
+            committee: 1,
+            signers: vec![],
+        };
+        chunk.save().unwrap();
+        store::put_raw(&Table::BlockChunks, &format!("0-{}-1", epoch), &chunk.hash).unwrap();
+    }
+
+    #[test]
+    fn test_prune_range() {
+        assert_eq!(prune_range(10, 3, 0), 0..8);
+        assert_eq!(prune_range(10, 3, 8), 8..8);
+        assert!(prune_range(1, 3, 0).is_empty());
+        assert_eq!(prune_range(0, 1, 0), 0..0);
+    }
+
+    #[test]
+    fn test_prune() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let epoch_0 = vec![chain_block("a", 0), chain_block("b", 0)];
+            let epoch_1 = vec![chain_block("a", 1)];
+            let epoch_2 = vec![chain_block("a", 2)];
+            chunk(0, epoch_0.clone());
+            chunk(1, epoch_1.clone());
+            chunk(2, epoch_2.clone());
+            let mut top_epoch = Epoch {
+                epoch_number: 2,
+                ..Default::default()
+            };
+            top_epoch.save().unwrap();
+            top_epoch.set_top_epoch().unwrap();
+
+            // keeping the last 2 epochs prunes epoch 0, except the top block of chain b
+            assert_eq!(prune_keeping(2).unwrap(), 1);
+            assert_eq!(pruned_below().unwrap(), 1);
+            assert!(get_block_from_raw(epoch_0[0].clone()).is_default());
+            assert_eq!(
+                store::get_raw(&Table::ChainIndex("a".to_owned()), "0").unwrap(),
+                None
+            );
+            assert!(!get_block_from_raw(epoch_0[1].clone()).is_default());
+            assert!(!get_block_from_raw(epoch_1[0].clone()).is_default());
+            // pruning again does nothing until another epoch starts
+            assert_eq!(prune_keeping(2).unwrap(), 0);
+            // the top block of chain a is kept too
+            assert_eq!(prune_keeping(0).unwrap(), 1);
+            assert_eq!(pruned_below().unwrap(), 3);
+            assert!(get_block_from_raw(epoch_1[0].clone()).is_default());
+            assert!(!get_block_from_raw(epoch_2[0].clone()).is_default());
+        });
+    }
+}
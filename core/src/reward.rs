@@ -0,0 +1,195 @@
+// This file handles the calculation of fullnode rewards at the end of each epoch.
+extern crate avrio_database;
+
+extern crate avrio_config;
+
+use avrio_config::config;
+use avrio_database::store::{contains, get, put, put_raw, Table};
+
+use crate::{account::get_account, epoch::Epoch};
+
+/// Records that the given fullnode (by ECDSA publickey) signed a block chunk during the epoch
+/// The proposer of a chunk counts as a signer
+pub fn record_participation(
+    epoch_number: u64,
+    publickey: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = epoch_number.to_string() + "-" + publickey;
+    let signed: u64 = get(&Table::Rewards, &key)?.unwrap_or(0);
+    put(&Table::Rewards, &key, &(signed + 1))?;
+    Ok(())
+}
+
+/// Records that a block chunk was enacted for the committee during the epoch
+pub fn record_chunk(epoch_number: u64, committee: u64) -> Result<(), Box<dyn std::error::Error>> {
+    let key = epoch_number.to_string() + "-committee-" + &committee.to_string();
+    let chunks: u64 = get(&Table::Rewards, &key)?.unwrap_or(0);
+    put(&Table::Rewards, &key, &(chunks + 1))?;
+    Ok(())
+}
+
+/// Returns the number of chunks the fullnode signed during the epoch
+pub fn get_participation(epoch_number: u64, publickey: &str) -> u64 {
+    get(&Table::Rewards, &(epoch_number.to_string() + "-" + publickey))
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+/// Returns the number of chunks enacted by the committee during the epoch
+pub fn get_chunk_count(epoch_number: u64, committee: u64) -> u64 {
+    get(
+        &Table::Rewards,
+        &(epoch_number.to_string() + "-committee-" + &committee.to_string()),
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(0)
+}
+
+/// Returns true if the rewards for this epoch have already been paid out
+pub fn rewards_paid(epoch_number: u64) -> bool {
+    contains(&Table::Rewards, &(epoch_number.to_string() + "-paid")).unwrap_or(false)
+}
+
+/// Marks the rewards for this epoch as paid by the reward transaction with hash txn_hash
+pub fn mark_rewards_paid(epoch_number: u64, txn_hash: &str) -> Result<(), Box<dyn std::error::Error>> {
+    put_raw(&Table::Rewards, &(epoch_number.to_string() + "-paid"), txn_hash)?;
+    Ok(())
+}
+
+/// # Reward for
+/// Calculates the reward of a fullnode from the amount of coins it has locked and the chunks it signed
+/// The interest rate scales linearly from min_intrest (the node signed min_vote percent of the chunks) to max_intrest (the node signed every chunk)
+/// Nodes which signed less than min_vote percent of their committees chunks recieve nothing. The reward is capped at max_reward
+/// All calculations are done with integers (interest is in hundredths of a percent) so every node gets the same result
+pub fn reward_for(locked: u64, signed: u64, chunks: u64) -> u64 {
+    if chunks == 0 || signed == 0 {
+        return 0;
+    }
+    let signed = signed.min(chunks) as u128;
+    let chunks = chunks as u128;
+    let min_vote = config().min_vote as u128;
+    if signed * 100 < chunks * min_vote {
+        return 0;
+    }
+    let min_rate = (config().min_intrest * 100.0).round() as u128;
+    let max_rate = (config().max_intrest * 100.0).round().max(min_rate as f32) as u128;
+    // scale the participation between min_vote percent (0) and 100 percent (1)
+    let participation_numerator = signed * 100 - chunks * min_vote;
+    let participation_denominator = chunks * (100 - min_vote.min(99));
+    let rate = min_rate
+        + ((max_rate - min_rate) * participation_numerator) / participation_denominator;
+    let reward = (locked as u128 * rate) / 10_000;
+    reward.min(config().max_reward as u128) as u64
+}
+
+/// # Calculate epoch rewards
+/// Calculates the reward owed to each fullnode for the provided epoch
+/// Returns a vector of (publickey, reward) tuples, sorted by publickey. Fullnodes owed nothing are not included
+pub fn calculate_epoch_rewards(
+    epoch: &Epoch,
+) -> Result<Vec<(String, u64)>, Box<dyn std::error::Error>> {
+    let mut rewards: Vec<(String, u64)> = vec![];
+    for committee in &epoch.committees {
+        let chunks = get_chunk_count(epoch.epoch_number, committee.index);
+        for member in &committee.members {
+            let locked = match get_account(member) {
+                Ok(account) => account.locked,
+                Err(_) => 0,
+            };
+            let reward = reward_for(
+                locked,
+                get_participation(epoch.epoch_number, member),
+                chunks,
+            );
+            trace!(
+                "Reward for {} in epoch {} (committee={}): {}",
+                member,
+                epoch.epoch_number,
+                committee.index,
+                reward
+            );
+            if reward != 0 {
+                rewards.push((member.clone(), reward));
+            }
+        }
+    }
+    rewards.sort();
+    Ok(rewards)
+}
+
+/// # Snapshot epoch rewards
+/// Calculates the rewards owed for the epoch and saves them, called as the epoch leaves its main stage (when the epoch salt is announced)
+/// Chunks enacted and coins locked after this do not change the rewards, so every node checks the reward txn against the same list
+pub fn snapshot_epoch_rewards(epoch: &Epoch) -> Result<(), Box<dyn std::error::Error>> {
+    let rewards = calculate_epoch_rewards(epoch)?;
+    debug!(
+        "Snapshotted rewards for epoch {}, fullnodes={}",
+        epoch.epoch_number,
+        rewards.len()
+    );
+    put(&Table::Rewards, &owed_key(epoch.epoch_number), &rewards)?;
+    Ok(())
+}
+
+/// Returns the rewards owed for the epoch, as snapshotted when it left its main stage (see snapshot_epoch_rewards)
+pub fn epoch_rewards(epoch_number: u64) -> Result<Vec<(String, u64)>, Box<dyn std::error::Error>> {
+    get(&Table::Rewards, &owed_key(epoch_number))?
+        .ok_or_else(|| format!("rewards for epoch {} were not snapshotted", epoch_number).into())
+}
+
+fn owed_key(epoch_number: u64) -> String {
+    epoch_number.to_string() + "-owed"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        account::{set_account, Account},
+        commitee::Comitee,
+    };
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reward_for() {
+        // no chunks, or below min_vote participation gets nothing
+        assert_eq!(reward_for(1_000_000, 0, 0), 0);
+        assert_eq!(reward_for(1_000_000, 60, 100), 0);
+        // min_vote participation gets min_intrest, full participation gets max_intrest
+        assert_eq!(reward_for(500_000, 65, 100), 2_500);
+        assert_eq!(reward_for(500_000, 100, 100), 12_500);
+        // capped at max_reward (uncapped these would be 50_000 and 250_000)
+        assert_eq!(reward_for(10_000_000, 65, 100), 25_000);
+        assert_eq!(reward_for(10_000_000, 100, 100), 25_000);
+    }
+
+    #[test]
+    fn test_snapshot_epoch_rewards() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let mut account = Account::new(String::from("a"));
+            account.locked = 500_000;
+            assert_eq!(set_account(&account), 1);
+            let epoch = Epoch {
+                epoch_number: 1,
+                committees: vec![Comitee {
+                    index: 1,
+                    members: vec![String::from("a")],
+                    hash: String::from(""),
+                }],
+                ..Default::default()
+            };
+            record_chunk(1, 1).unwrap();
+            record_participation(1, "a").unwrap();
+            assert!(epoch_rewards(1).is_err());
+            snapshot_epoch_rewards(&epoch).unwrap();
+            // chunks enacted and coins locked after the snapshot do not change the rewards
+            record_chunk(1, 1).unwrap();
+            account.locked = 1_000_000;
+            assert_eq!(set_account(&account), 1);
+            assert_eq!(epoch_rewards(1).unwrap(), vec![(String::from("a"), 12_500)]);
+        });
+    }
+}
@@ -0,0 +1,400 @@
+// This file forms the state snapshots taken at each epoch boundary, and applies them so a new node can start from a recent epoch rather than replaying every block.
+// A snapshot holds every entry of the state tables (accounts, usernames, invites, certificates...) and the tip of every chain, as the round leader proposes the
+// delta list which starts the epoch. Nodes enact blocks in different orders, so their state at the boundary differs: the hash of the leaders snapshot is put in the
+// delta list txn, which the consensus committee signs, and every node records that agreed hash in the epoch (covering it by the epoch hash). Only nodes whose state
+// matched the proposal hold the snapshot. A snapshot downloaded from a peer is trusted if it matches the epoch of the checkpoint set in the config.
+extern crate avrio_database;
+
+use avrio_config::config;
+use avrio_crypto::Hashable;
+use avrio_database::store::{self, Table};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    block::{genesis::genesis_blocks, get_block, save_block, Block},
+    epoch::{get_top_epoch, Epoch, EpochStage},
+};
+
+/// The number of snapshots kept, older ones are deleted as new ones are saved
+const SNAPSHOTS_KEPT: u64 = 2; // TODO: move to config
+
+/// The tables which hold the state, every entry of which is in the snapshot. Tables which only hold history (eg transactions, epochdata) are not
+const STATE_TABLES: &[Table] = &[
+    Table::Accounts,
+    Table::Usernames,
+    Table::ChainList,
+    Table::ChainDigest,
+    Table::Invites,
+    Table::Candidates,
+    Table::Certificates,
+    Table::BlsLookup,
+    Table::Probation,
+    Table::Participation,
+    Table::Rewards,
+    Table::Lottery,
+];
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Epoch {0} does not match the checkpoint")]
+    CheckpointMismatch(u64),
+    #[error("Epoch {0} has the wrong hash")]
+    BadEpochHash(u64),
+    #[error("Snapshot is of epoch {found}, expected epoch {expected}")]
+    WrongEpoch { found: u64, expected: u64 },
+    #[error("Epoch {0} does not precede the checkpoint epoch")]
+    BadPreviousEpoch(u64),
+    #[error("Snapshot hash {0} does not match the hash recorded in its epoch")]
+    BadSnapshotHash(String),
+    #[error("Top block of chain {0} is invalid")]
+    BadChainTip(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ChainTip {
+    pub chain_key: String,
+    pub block_count: u64,
+    pub txn_count: u64,
+    /// The compressed encoding of the top block of the chain
+    pub top_block: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Snapshot {
+    pub hash: String,
+    pub epoch_number: u64,
+    /// The name and (key, canonical value) entries of each state table, sorted by key
+    pub tables: Vec<(String, Vec<(String, String)>)>,
+    /// Sorted by chain key
+    pub chain_tips: Vec<ChainTip>,
+}
+
+/// Adds the field to the bytes, prefixed by its length so the fields of two different snapshots can not run into each other to the same bytes
+fn extend_field(bytes: &mut Vec<u8>, field: &str) {
+    bytes.extend((field.len() as u64).to_be_bytes().iter());
+    bytes.extend(field.bytes());
+}
+
+/// Returns the canonical encoding of the raw value: json values are re-encoded with sorted object keys (so values encoded from hashmaps hash the same on
+/// every node), anything else is left as is
+fn canonical_value(value: String) -> String {
+    match serde_json::from_str::<serde_json::Value>(&value) {
+        Ok(json) => json.to_string(),
+        Err(_) => value,
+    }
+}
+
+impl Hashable for Snapshot {
+    fn bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![];
+        extend_field(&mut bytes, &self.epoch_number.to_string());
+        for (name, entries) in &self.tables {
+            extend_field(&mut bytes, name);
+            extend_field(&mut bytes, &entries.len().to_string());
+            for (key, value) in entries {
+                extend_field(&mut bytes, key);
+                extend_field(&mut bytes, value);
+            }
+        }
+        for tip in &self.chain_tips {
+            extend_field(&mut bytes, &tip.chain_key);
+            extend_field(&mut bytes, &tip.block_count.to_string());
+            extend_field(&mut bytes, &tip.txn_count.to_string());
+            extend_field(&mut bytes, &tip.top_block);
+        }
+        bytes
+    }
+}
+
+impl Snapshot {
+    pub fn hash(&mut self) {
+        self.hash = self.hash_item();
+    }
+
+    pub fn hash_return(&self) -> String {
+        self.hash_item()
+    }
+
+    /// # Form
+    /// Forms (and hashes) the snapshot of the current state, to be recorded in epoch epoch_number. Two nodes with the same state form the same snapshot
+    pub fn form(epoch_number: u64) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        let mut snapshot = Snapshot {
+            epoch_number,
+            ..Default::default()
+        };
+        for table in STATE_TABLES {
+            let mut entries: Vec<(String, String)> = store::entries(table)?
+                .into_iter()
+                .map(|(key, value)| (key, canonical_value(value)))
+                .collect();
+            entries.sort();
+            snapshot.tables.push((table.name(), entries));
+        }
+        for (chain_key, _) in store::entries(&Table::ChainList)? {
+            let index = Table::ChainIndex(chain_key.clone());
+            let block_count: u64 = store::get(&index, "blockcount")?.unwrap_or(0);
+            if block_count == 0 {
+                continue;
+            }
+            let top_block = get_block(&chain_key, block_count - 1);
+            if top_block.is_default() {
+                return Err(format!("Top block of chain {} is missing", chain_key).into());
+            }
+            snapshot.chain_tips.push(ChainTip {
+                chain_key,
+                block_count,
+                txn_count: store::get(&index, "txncount")?.unwrap_or(0),
+                top_block: top_block.encode_compressed(),
+            });
+        }
+        snapshot
+            .chain_tips
+            .sort_by(|a, b| a.chain_key.cmp(&b.chain_key));
+        snapshot.hash();
+        debug!(
+            "Formed snapshot for epoch {}, hash={}, chains={}",
+            epoch_number,
+            snapshot.hash,
+            snapshot.chain_tips.len()
+        );
+        Ok(snapshot)
+    }
+
+    /// # Agree
+    /// Forms the snapshot of the state epoch epoch_number starts from, as the round leader proposes (or a consensus committee member signs) the delta list
+    /// which starts it, and saves it if its hash is proposed_hash (or there is no proposed hash, when we are the round leader). Returns the snapshot
+    pub fn agree(
+        epoch_number: u64,
+        proposed_hash: Option<&str>,
+    ) -> Result<Snapshot, Box<dyn std::error::Error>> {
+        let snapshot = Snapshot::form(epoch_number)?;
+        match proposed_hash {
+            Some(proposed_hash) if proposed_hash != snapshot.hash => warn!(
+                "Our state differs from the proposed snapshot of epoch {}, not keeping ours. Ours={}, proposed={}",
+                epoch_number, snapshot.hash, proposed_hash
+            ),
+            _ => snapshot.save()?,
+        }
+        Ok(snapshot)
+    }
+
+    /// Saves the snapshot, deleting the snapshots older than the last SNAPSHOTS_KEPT
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        store::put(&Table::Snapshots, &self.epoch_number.to_string(), self)?;
+        if self.epoch_number >= SNAPSHOTS_KEPT {
+            store::delete(
+                &Table::Snapshots,
+                &(self.epoch_number - SNAPSHOTS_KEPT).to_string(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns the snapshot of epoch epoch_number, or None if we do not have it
+    pub fn get(epoch_number: u64) -> Result<Option<Snapshot>, Box<dyn std::error::Error>> {
+        Ok(store::get(&Table::Snapshots, &epoch_number.to_string())?)
+    }
+
+    /// # Verify
+    /// Checks the epoch matches the checkpoint, previous is the epoch before it and the snapshot (and each of its chain tips) matches the hash recorded in the epoch
+    pub fn verify(
+        &self,
+        previous: &Epoch,
+        epoch: &Epoch,
+        checkpoint: &(u64, String),
+    ) -> Result<(), SnapshotError> {
+        if epoch.epoch_number != checkpoint.0 || epoch.hash != checkpoint.1 {
+            return Err(SnapshotError::CheckpointMismatch(epoch.epoch_number));
+        }
+        if epoch.hash_return() != epoch.hash {
+            return Err(SnapshotError::BadEpochHash(epoch.epoch_number));
+        }
+        if previous.epoch_number + 1 != epoch.epoch_number {
+            return Err(SnapshotError::BadPreviousEpoch(previous.epoch_number));
+        }
+        if previous.hash_return() != previous.hash {
+            return Err(SnapshotError::BadEpochHash(previous.epoch_number));
+        }
+        if self.epoch_number != epoch.epoch_number {
+            return Err(SnapshotError::WrongEpoch {
+                found: self.epoch_number,
+                expected: epoch.epoch_number,
+            });
+        }
+        if self.hash_return() != self.hash || self.hash != epoch.snapshot_hash {
+            return Err(SnapshotError::BadSnapshotHash(self.hash.clone()));
+        }
+        for tip in &self.chain_tips {
+            match Block::from_compressed(tip.top_block.clone()) {
+                Ok(block)
+                    if block.header.chain_key == tip.chain_key
+                        && block.header.height + 1 == tip.block_count
+                        && block.hash_return() == block.hash => {}
+                _ => return Err(SnapshotError::BadChainTip(tip.chain_key.clone())),
+            }
+        }
+        Ok(())
+    }
+
+    /// # Apply
+    /// Writes the state in the snapshot and the chain tips, then sets previous as the top epoch and epoch back to how it was before the delta list which started it
+    /// was enacted, in one atomic batch. Syncing the consensus chain then enacts the delta list, starting epoch from the snapshot as every other node did
+    /// Only apply a snapshot once it has been verified, to an empty data directory (see data_dir_empty)
+    pub fn apply(
+        &self,
+        mut previous: Epoch,
+        epoch: &Epoch,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        store::atomic(|| {
+            for (name, entries) in &self.tables {
+                let table = Table::from_name(name);
+                for (key, value) in entries {
+                    store::put_raw(&table, key, value)?;
+                }
+            }
+            for tip in &self.chain_tips {
+                let block = Block::from_compressed(tip.top_block.clone())?;
+                let index = Table::ChainIndex(tip.chain_key.clone());
+                save_block(block.clone())?;
+                store::put_raw(&index, &block.header.height.to_string(), &block.hash)?;
+                store::put_raw(&index, "topblockhash", &block.hash)?;
+                store::put_raw(&index, "blockcount", &tip.block_count.to_string())?;
+                store::put_raw(&index, "txncount", &tip.txn_count.to_string())?;
+            }
+            self.save()?;
+            // the delta list moves previous from the VRF lottery to final
+            previous.stage = EpochStage::VrfLotto;
+            previous.save()?;
+            previous.set_top_epoch()?;
+            Epoch {
+                epoch_number: epoch.epoch_number,
+                salt: epoch.salt,
+                shuffle_bits: epoch.shuffle_bits,
+                ..Default::default()
+            }
+            .save()?;
+            Ok(())
+        })?;
+        info!(
+            "Applied snapshot of epoch {}, chains={}",
+            self.epoch_number,
+            self.chain_tips.len()
+        );
+        Ok(())
+    }
+}
+
+/// Returns true if the data directory holds no state a snapshot could be applied over: no block other than the genesis blocks has been enacted and
+/// no epoch after the genesis epoch has started
+pub fn data_dir_empty() -> Result<bool, Box<dyn std::error::Error>> {
+    let global_block_count: u64 = store::get(&Table::GlobalIndex, "globalblockcount")?.unwrap_or(0);
+    Ok(global_block_count <= genesis_blocks().len() as u64
+        && get_top_epoch().map_or(true, |top| top.epoch_number == 0))
+}
+
+/// Parses a checkpoint, "<epoch number>:<epoch hash>"
+fn parse_checkpoint(checkpoint: &str) -> Option<(u64, String)> {
+    let mut parts = checkpoint.trim().splitn(2, ':');
+    let epoch_number = parts.next()?.parse().ok()?;
+    let hash = parts.next()?;
+    if hash.is_empty() {
+        return None;
+    }
+    Some((epoch_number, hash.to_owned()))
+}
+
+/// Returns the (epoch number, epoch hash) of the trusted checkpoint set in the config, or None if it is not set (or invalid)
+pub fn checkpoint() -> Option<(u64, String)> {
+    let checkpoint = config().snapshot_checkpoint;
+    if checkpoint.is_empty() {
+        return None;
+    }
+    let parsed = parse_checkpoint(&checkpoint);
+    if parsed.is_none() {
+        error!(
+            "Invalid snapshot_checkpoint {} in config, expected <epoch number>:<epoch hash>",
+            checkpoint
+        );
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_checkpoint() {
+        assert_eq!(parse_checkpoint("12:abc"), Some((12, "abc".to_owned())));
+        assert_eq!(parse_checkpoint("12:"), None);
+        assert_eq!(parse_checkpoint("abc"), None);
+        assert_eq!(parse_checkpoint("x:abc"), None);
+    }
+
+    #[test]
+    fn test_snapshot_hash() {
+        let mut snapshot = Snapshot {
+            epoch_number: 3,
+            tables: vec![(
+                "accounts".to_owned(),
+                vec![("a".to_owned(), "{}".to_owned())],
+            )],
+            ..Default::default()
+        };
+        snapshot.hash();
+        assert_eq!(snapshot.hash_return(), snapshot.hash);
+        snapshot.tables[0].1[0].1 = "{\"balance\":1}".to_owned();
+        assert_ne!(snapshot.hash_return(), snapshot.hash);
+    }
+
+    #[test]
+    fn test_snapshot_deterministic() {
+        // the same state, written in a different order with differently ordered json objects
+        let first = with_backend(Arc::new(MemoryBackend::new()), || {
+            store::put_raw(&Table::Accounts, "a", r#"{"balance":1,"locked":2}"#).unwrap();
+            store::put_raw(&Table::Candidates, "b", "c").unwrap();
+            Snapshot::form(4).unwrap()
+        });
+        let second = with_backend(Arc::new(MemoryBackend::new()), || {
+            store::put_raw(&Table::Candidates, "b", "c").unwrap();
+            store::put_raw(&Table::Accounts, "a", r#"{"locked":2, "balance":1}"#).unwrap();
+            Snapshot::form(4).unwrap()
+        });
+        assert_eq!(first.hash, second.hash);
+        assert_eq!(first, second);
+        // fields can not run into each other
+        let mut joined = first.clone();
+        joined.tables[0].1 = vec![("ab".to_owned(), "c".to_owned())];
+        let mut split = first;
+        split.tables[0].1 = vec![("a".to_owned(), "bc".to_owned())];
+        assert_ne!(joined.hash_return(), split.hash_return());
+    }
+
+    #[test]
+    fn test_agree() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let proposed = Snapshot::agree(2, None).unwrap();
+            assert_eq!(Snapshot::get(2).unwrap(), Some(proposed.clone()));
+            store::delete(&Table::Snapshots, "2").unwrap();
+            // a committee member whose state differs does not keep its snapshot
+            store::put_raw(&Table::Candidates, "b", "c").unwrap();
+            Snapshot::agree(2, Some(&proposed.hash)).unwrap();
+            assert_eq!(Snapshot::get(2).unwrap(), None);
+            store::delete(&Table::Candidates, "b").unwrap();
+            Snapshot::agree(2, Some(&proposed.hash)).unwrap();
+            assert_eq!(Snapshot::get(2).unwrap(), Some(proposed));
+        });
+    }
+
+    #[test]
+    fn test_data_dir_empty() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert!(data_dir_empty().unwrap());
+            store::put(&Table::GlobalIndex, "globalblockcount", &1u64).unwrap();
+            assert!(!data_dir_empty().unwrap());
+        });
+    }
+}
@@ -1,26 +1,30 @@
 use crate::block::get_block;
-use avrio_database::{get_data, open_database, save_data};
+use avrio_database::store::{self, Table};
 
-pub fn update_chain_digest(new_blk_hash: &str, cd_db: String, chain: &str) -> String {
+pub fn update_chain_digest(
+    new_blk_hash: &str,
+    chain: &str,
+) -> std::result::Result<String, Box<dyn std::error::Error>> {
     trace!(target: "blockchain::chain_digest","Updating chain digest for chain={}, hash={}", chain, new_blk_hash);
-    let curr = get_data(cd_db.to_owned(), chain);
     let root: String;
-    if &curr == "-1" {
-        trace!(target: "blockchain::chain_digest","chain digest not set");
-        root = avrio_crypto::raw_lyra(new_blk_hash);
-    } else {
-        trace!(target: "blockchain::chain_digest","Updating set chain digest. Curr: {}", curr);
-        root = avrio_crypto::raw_lyra(&(curr + new_blk_hash));
+    match store::get_raw(&Table::ChainDigest, chain)? {
+        None => {
+            trace!(target: "blockchain::chain_digest","chain digest not set");
+            root = avrio_crypto::raw_lyra(new_blk_hash);
+        }
+        Some(curr) => {
+            trace!(target: "blockchain::chain_digest","Updating set chain digest. Curr: {}", curr);
+            root = avrio_crypto::raw_lyra(&(curr + new_blk_hash));
+        }
     }
-    let _ = save_data(&root, &cd_db, chain.to_owned());
+    store::put_raw(&Table::ChainDigest, chain, &root)?;
     trace!(target: "blockchain::chain_digest","Chain digest for chain={} updated to {}", chain, root);
-    root
+    Ok(root)
 }
 
-/// takes a DB object of the chains digest (chaindigest) db and a vector of chain_keys (as strings) and calculates the chain digest for each chain.
-/// It then sets the value of chain digest (for each chain) in the db, and returns it in the vector of strings
+/// takes a vector of chain_keys (as strings) and calculates the chain digest for each chain.
+/// It then sets the value of chain digest (for each chain) in the chaindigest table, and returns it in the vector of strings
 pub fn form_chain_digest(
-    cd_db: String,
     chains: Vec<String>,
 ) -> std::result::Result<Vec<String>, Box<dyn std::error::Error>> {
     // TODO: do we need to return a Result<vec, err>? Cant we just return vec as there is no unwrapping needing to be done that could be replaced with the ? operator (and hence no chance of errors)?
@@ -52,7 +56,7 @@ pub fn form_chain_digest(
             }
         }
         // we are finished, update the chain_digest on disk and add it to the output vector
-        avrio_database::save_data(&temp_leaf, &cd_db, chain.to_owned());
+        store::put_raw(&Table::ChainDigest, &chain, &temp_leaf)?;
         output.push(temp_leaf);
         trace!(
             "Chain digest: Finished chain={}, new output={:?}",
@@ -65,21 +69,21 @@ pub fn form_chain_digest(
 }
 
 /// Calculates the 'overall' digest of the DAG.
-/// Pass it a database object of the chaindigest database. This database should contain all the chains chain digests (with the key being the publickey)
+/// Reads the chaindigest table. This table should contain all the chains chain digests (with the key being the publickey)
 /// as well as 'master' (as a key) being the state digest.
 /// Run form_chain_digest(chain) (with chain being the publickey of the chain you want, or * for every chain) first which will form a chain digest
-/// from scratch (or update_chain_digest(chain, new_block_hash, cd_db)). This function will return the new state digest as a string as well as update it in the database
+/// from scratch (or update_chain_digest(new_block_hash, chain)). This function will return the new state digest as a string as well as update it in the database
 ///
-pub fn form_state_digest(cd_db: String) -> std::result::Result<String, Box<dyn std::error::Error>> {
+pub fn form_state_digest() -> std::result::Result<String, Box<dyn std::error::Error>> {
     debug!("Updating state digest");
     let start = std::time::Instant::now();
-    let current_state_digest = get_data(cd_db.to_owned(), "master"); // get the current state digest, for refrence
-    if &current_state_digest == "-1" {
+    let current_state_digest = store::get_raw(&Table::ChainDigest, "master")?.unwrap_or_default(); // get the current state digest, for refrence
+    if current_state_digest.is_empty() {
         trace!("State digest not set");
     } else {
         trace!("Updating set state digest. Curr: {}", current_state_digest);
     }
-    // we now recursivley loop through cd_db and add every value (other than master) to a vector
+    // we now recursivley loop through the chaindigest table and add every value (other than master) to a vector
     // now we have every chain digest in a vector we sort it alphabeticly
     // now the vector of chain digests is sorted alphabeticly we recursivley hash them
     // like so: (TODO: use a merkle tree not a recursive hash chain)
@@ -89,7 +93,7 @@ pub fn form_state_digest(cd_db: String) -> std::result::Result<String, Box<dyn s
     let mut _roots: Vec<(String, String)> = vec![]; // 0: chain_key, 1: chain_digest
                                                     //iter.seek_to_first();
     let _chains_list: Vec<String> = Vec::new();
-    for (chain_key_string, chain_digest_string) in open_database(cd_db.to_owned())?.iter() {
+    for (chain_key_string, chain_digest_string) in store::entries(&Table::ChainDigest)? {
         if chain_key_string != "master"
             && chain_key_string != "blockcount"
             && chain_key_string != "topblockhash"
@@ -144,6 +148,6 @@ pub fn form_state_digest(cd_db: String) -> std::result::Result<String, Box<dyn s
         temp_leaf,
         start.elapsed().as_millis()
     );
-    avrio_database::save_data(&temp_leaf, &cd_db, "master".to_string());
+    store::put_raw(&Table::ChainDigest, "master", &temp_leaf)?;
     Ok(temp_leaf)
 }
@@ -1,3 +1,4 @@
+use avrio_database::with_current_backend;
 use std::{
     thread,
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -9,7 +10,7 @@ pub fn create_timer<A: 'static>(duration: Duration, callback: Box<dyn Fn(A) + Se
 where
     A: Send,
 {
-    thread::spawn(move || {
+    thread::spawn(with_current_backend(move || {
         debug!(
             "Created timer, executes_at={}",
             SystemTime::now()
@@ -20,5 +21,5 @@ where
         );
         thread::sleep(duration);
         (callback)(params)
-    });
+    }));
 }
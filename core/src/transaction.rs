@@ -5,18 +5,38 @@ extern crate avrio_config;
 extern crate bs58;
 use avrio_config::config;
 extern crate rand;
-use avrio_database::{get_data, save_data};
+use avrio_database::store::{self, Table};
 use thiserror::Error;
 extern crate avrio_database;
 
 use crate::{
     account::{get_account, open_or_create, Accesskey, Account},
-    certificate::Certificate,
+    certificate::{
+        expiry_cutoff, get_status, is_registered, process_expiries, retarget_difficulty, revoke,
+        schedule_exit, schedule_unlock, scheduled_removals, set_status, stake_shortfall,
+        Certificate,
+        REMOVAL_EXIT,
+    },
     chunk::BlockChunk,
-    commitee::{sort_full_list, Comitee},
-    epoch::{get_top_epoch, Epoch, EpochStage},
+    commitee::{postshuffle_hash, preshuffle_hash, sort_full_list, Comitee},
+    epoch::{epoch_salt, get_top_epoch, salt_seed_message, Epoch, EpochStage},
     gas::*,
-    invite::{invite_valid, new_invite},
+    invite::{
+        epoch_invite_limit, get_issuer, invite_format_valid, invites_issued, invites_issued_by,
+        new_invite, over_invite_limit, record_issuance, revoke_invite, unspent,
+        MAX_INVITES_PER_ISSUER,
+    },
+    lottery::{
+        has_ticket, lottery_threshold, lotto_seed, open_lottery, record_ticket, select_candidates,
+        ticket_value,
+    },
+    participation::{
+        can_toggle, fullnode_set, is_active, last_toggled, record_voting_members,
+        required_salt_seeds, toggle,
+    },
+    probation::{admit, advance, observers_after},
+    reward::{epoch_rewards, mark_rewards_paid, rewards_paid, snapshot_epoch_rewards},
+    snapshot::Snapshot,
     validate::Verifiable,
 };
 
@@ -26,7 +46,6 @@ use lazy_static::lazy_static;
 use std::{
     collections::HashSet,
     iter::FromIterator,
-    str::FromStr,
     sync::Mutex,
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -96,6 +115,10 @@ pub enum TransactionValidationErrors {
     WouldOverflowBalance,
     #[error("Invite invalid")]
     InviteInvalid,
+    #[error("Invite limit for epoch reached")]
+    InviteLimitReached,
+    #[error("Wallet did not create invite")]
+    NotInviteIssuer,
     #[error("Not round leader, but sent consensus message")]
     UnauthorisedConsensusMessage,
     #[error("Sent consensus message has wrong reciever")]
@@ -106,6 +129,8 @@ pub enum TransactionValidationErrors {
     InvalidVrf,
     #[error("Could not decode VRF proof and signer from announceEpochSaltSeedTxn")]
     FailedToDecodeSaltSeeds,
+    #[error("Too few consensus committee members contributed an epoch salt seed")]
+    NotEnoughSaltSeeds,
     #[error("Hashed preshuffle node list does not equal expected hash")]
     BadPreshuffleHash,
     #[error("Hashed post shuffle committee list does not equal expected hash")]
@@ -118,9 +143,33 @@ pub enum TransactionValidationErrors {
     NotInCommitee,
     #[error("Report role type unknown")]
     UnknownRoleType,
+    #[error("Fullnode toggled participation too recently")]
+    ToggledTooRecently,
+    #[error("Reported fullnode is not participating")]
+    FullnodeInactive,
+    #[error("Wallet holds no live fullnode certificate")]
+    NotRegistered,
+    #[error("Removed fullnodes do not equal the scheduled removals")]
+    BadRemovalList,
+    #[error("Eclosed candidates do not equal the VRF lottery selection")]
+    BadCandidateSelection,
+    #[error("Vrf lotto entry ticket does not fufill the eclosion threshold")]
+    TicketNotViable,
+    #[error("Candidate already sent a Vrf lotto entry ticket this epoch")]
+    DuplicateTicket,
+    #[error("Reward transaction sent outside of Vrf Lotto period")]
+    RewardSentOutsideVrfLotto,
+    #[error("Rewards for epoch already paid")]
+    RewardsAlreadyPaid,
+    #[error("Reward list does not equal expected rewards")]
+    BadRewardList,
     #[error("Other")]
     Other,
 }
+/// The flags of every supported transaction type (see type_transaction)
+pub const TRANSACTION_FLAGS: [char; 16] = [
+    'c', 'n', 'b', 'u', 'l', 'i', 'f', 'a', 'y', 'z', 'v', 'r', 'e', 'q', 'o', 'd',
+];
 
 impl Default for TransactionValidationErrors {
     fn default() -> TransactionValidationErrors {
@@ -128,6 +177,23 @@ impl Default for TransactionValidationErrors {
     }
 }
 
+/// The extra of an announce fullnode list delta ('y') txn: the (preshuffle, postshuffle) hashes, the delta list of (publickey, reason (0 = eclosed
+/// candidate, anything else = removal), proof) and the hash of the state snapshot the next epoch starts from, agreed by the consensus committee signing the txn
+pub type FullnodeDeltaList = ((String, String), Vec<(String, u8, String)>, String);
+
+/// Decodes the extra of an announce fullnode list delta txn. Txns from before snapshots were agreed in consensus have no snapshot hash, it is returned empty
+pub fn decode_delta_list(extra: &str) -> Result<FullnodeDeltaList, Box<dyn std::error::Error>> {
+    let json = String::from_utf8(bs58::decode(extra).into_vec()?)?;
+    match serde_json::from_str::<FullnodeDeltaList>(&json) {
+        Ok(delta_list) => Ok(delta_list),
+        Err(_) => {
+            let (hashes, delta_list): ((String, String), Vec<(String, u8, String)>) =
+                serde_json::from_str(&json)?;
+            Ok((hashes, delta_list, String::from("")))
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
 pub struct Transaction {
     pub hash: String,
@@ -192,25 +258,19 @@ impl Verifiable for Transaction {
         if self.hash_return() != self.hash {
             return Err(Box::new(TransactionValidationErrors::BadHash));
         }
-        let account_nonce = get_data(
-            config().db_path
-                + &"/chains/".to_owned()
-                + &self.sender_key
-                + &"-chainindex".to_owned(),
-            &"txncount".to_owned(),
-        );
-        if self.nonce.to_string() != account_nonce && account_nonce != "-1" {
+        let account_nonce: Option<u64> =
+            store::get(&Table::ChainIndex(self.sender_key.clone()), "txncount")?;
+        if account_nonce.map_or(false, |nonce| nonce != self.nonce) {
             return Err(Box::new(TransactionValidationErrors::BadNonce));
         }
-        let block_txn_is_in = get_data(config().db_path + &"/transactions".to_owned(), &self.hash);
-        if block_txn_is_in != *"-1" {
+        if let Some(block_txn_is_in) = store::get_raw(&Table::Transactions, &self.hash)? {
             error!(
                 "Transaction {} already in block {}",
                 self.hash, block_txn_is_in
             );
             return Err(Box::new(TransactionValidationErrors::TransactionExists));
         }
-        if !['c', 'n', 'b', 'u', 'l', 'i', 'f', 'a', 'y', 'z', 'v'].contains(&self.flag) {
+        if !TRANSACTION_FLAGS.contains(&self.flag) {
             error!(
                 "Transaction {} has unsupported type={} ({})",
                 self.hash,
@@ -400,9 +460,78 @@ impl Verifiable for Transaction {
                     );
                     return Err(Box::new(TransactionValidationErrors::MaxGasExpended));
                 }
-                if self.unlock_time != 0 {
-                    return Err(Box::new(TransactionValidationErrors::UnsupportedType));
-                    // TODO: Implement unlock time
+                if self.unlock_time != 0 && self.unlock_time <= self.timestamp {
+                    error!(
+                        "Lock transaction {} unlocks before it was sent, unlock_time={}, timestamp={}",
+                        self.hash, self.unlock_time, self.timestamp
+                    );
+                    return Err(Box::new(TransactionValidationErrors::BadUnlockTime));
+                }
+            }
+            'e' => {
+                // renew fullnode certificate, unlock_time is the new valid_until of the certificate
+                if !self.extra.is_empty() {
+                    error!(
+                        "Renew certificate type transaction {}'s extra ({}) not empty",
+                        self.hash, self.extra
+                    );
+                    return Err(Box::new(TransactionValidationErrors::ExtraTooLarge));
+                }
+                if !is_registered(&self.sender_key) || get_status(&self.sender_key) == "e" {
+                    error!(
+                        "Wallet {} without a live certificate tried to renew, status={}",
+                        self.sender_key,
+                        get_status(&self.sender_key)
+                    );
+                    return Err(Box::new(TransactionValidationErrors::NotRegistered));
+                }
+                let cert = Certificate::get(self.sender_key.clone())?;
+                if cert.valid_until == 0
+                    || self.unlock_time <= cert.valid_until
+                    || self.unlock_time <= self.timestamp
+                {
+                    error!(
+                        "Renew certificate transaction {} does not extend lock, unlock_time={}, valid_until={}",
+                        self.hash, self.unlock_time, cert.valid_until
+                    );
+                    return Err(Box::new(TransactionValidationErrors::BadUnlockTime));
+                }
+                if self.amount != 0 {
+                    return Err(Box::new(TransactionValidationErrors::InsufficentAmount));
+                }
+                // renewing relocks the full stake, topping up the lock if part of it was released
+                if sender_account.balance < self.fee() + stake_shortfall(&sender_account) {
+                    return Err(Box::new(TransactionValidationErrors::InsufficentBalance));
+                }
+                if self.max_gas < self.gas() {
+                    return Err(Box::new(TransactionValidationErrors::MaxGasExpended));
+                }
+            }
+            'q' => {
+                // voluntary exit
+                if !self.extra.is_empty() {
+                    error!(
+                        "Exit type transaction {}'s extra ({}) not empty",
+                        self.hash, self.extra
+                    );
+                    return Err(Box::new(TransactionValidationErrors::ExtraTooLarge));
+                }
+                if !is_registered(&self.sender_key) || get_status(&self.sender_key) == "e" {
+                    error!(
+                        "Wallet {} without a live certificate tried to exit, status={}",
+                        self.sender_key,
+                        get_status(&self.sender_key)
+                    );
+                    return Err(Box::new(TransactionValidationErrors::NotRegistered));
+                }
+                if self.amount != 0 || self.unlock_time != 0 {
+                    return Err(Box::new(TransactionValidationErrors::InsufficentAmount));
+                }
+                if sender_account.balance < self.fee() {
+                    return Err(Box::new(TransactionValidationErrors::InsufficentBalance));
+                }
+                if self.max_gas < self.gas() {
+                    return Err(Box::new(TransactionValidationErrors::MaxGasExpended));
                 }
             }
             'i' => {
@@ -415,12 +544,12 @@ impl Verifiable for Transaction {
                     return Err(Box::new(TransactionValidationErrors::ExtraTooLarge));
                 }
                 // check if the sender is a fullnode
-                if get_data(config().db_path + "/candidates", &self.sender_key) != "f" {
+                if get_status(&self.sender_key) != "f" {
                     error!("Non fullnode {} tried to create a invite", self.sender_key);
                     return Err(Box::new(TransactionValidationErrors::NotFullNode));
                 }
                 // check the invite does not already exist
-                if get_data(config().db_path + &"/invites".to_owned(), &self.extra) != "-1" {
+                if store::contains(&Table::Invites, &self.extra)? {
                     error!(
                         "Fullnode {} tried creating an invite that already exists ({})",
                         self.sender_key, self.extra
@@ -428,13 +557,67 @@ impl Verifiable for Transaction {
                     return Err(Box::new(TransactionValidationErrors::InviteAlreadyExists));
                 }
                 // check the invite is valid format (len = 44, can be decoded into a valid public key)
-                if !invite_valid(&self.extra) {
+                if !invite_format_valid(&self.extra) {
                     error!(
                         "Invite: {} (created by {}) is invalid",
                         self.extra, self.sender_key
                     );
                     return Err(Box::new(TransactionValidationErrors::InviteInvalid));
                 }
+                // check the network wide and per issuer limits for this epoch
+                let top_epoch = get_top_epoch()?;
+                let limit = epoch_invite_limit(fullnode_set(&top_epoch).len() as u64);
+                let issued = invites_issued(top_epoch.epoch_number);
+                let issued_by_sender = invites_issued_by(top_epoch.epoch_number, &self.sender_key);
+                if issued >= limit || issued_by_sender >= MAX_INVITES_PER_ISSUER {
+                    error!(
+                        "Fullnode {} tried to create invite {} over the limit for epoch {}, issued={}/{}, issued_by_sender={}/{}",
+                        self.sender_key,
+                        self.extra,
+                        top_epoch.epoch_number,
+                        issued,
+                        limit,
+                        issued_by_sender,
+                        MAX_INVITES_PER_ISSUER
+                    );
+                    return Err(Box::new(TransactionValidationErrors::InviteLimitReached));
+                }
+            }
+            'd' => {
+                // revoke invite, the extra is the invite
+                let size_of_extra = self.extra.len();
+                if size_of_extra != 44 {
+                    error!(
+                        "Revoke invite type transaction {}'s extra ({}) wrong size, {} != 44",
+                        self.hash, self.extra, size_of_extra
+                    );
+                    return Err(Box::new(TransactionValidationErrors::ExtraTooLarge));
+                }
+                if get_issuer(&self.extra) != Some(self.sender_key.clone()) {
+                    error!(
+                        "Wallet {} tried to revoke invite {} it did not create, issuer={:?}",
+                        self.sender_key,
+                        self.extra,
+                        get_issuer(&self.extra)
+                    );
+                    return Err(Box::new(TransactionValidationErrors::NotInviteIssuer));
+                }
+                if !unspent(&self.extra) {
+                    error!(
+                        "Fullnode {} tried to revoke spent or revoked invite {}",
+                        self.sender_key, self.extra
+                    );
+                    return Err(Box::new(TransactionValidationErrors::InviteInvalid));
+                }
+                if self.amount != 0 {
+                    return Err(Box::new(TransactionValidationErrors::InsufficentAmount));
+                }
+                if sender_account.balance < self.fee() {
+                    return Err(Box::new(TransactionValidationErrors::InsufficentBalance));
+                }
+                if self.max_gas < self.gas() {
+                    return Err(Box::new(TransactionValidationErrors::MaxGasExpended));
+                }
             }
             'o' => {
                 let size_of_extra = self.extra.len();
@@ -446,15 +629,28 @@ impl Verifiable for Transaction {
                     return Err(Box::new(TransactionValidationErrors::ExtraTooLarge));
                 }
                 // check if the sender is a fullnode
-                if get_data(config().db_path + "/candidates", &self.sender_key) != "f" {
+                if get_status(&self.sender_key) != "f" {
                     error!(
                         "Non fullnode {} tried to toggle participation",
                         self.sender_key
                     );
                     return Err(Box::new(TransactionValidationErrors::NotFullNode));
                 }
+                let epoch_number = get_top_epoch()?.epoch_number;
+                if !can_toggle(&self.sender_key, epoch_number) {
+                    error!(
+                        "Fullnode {} toggled participation too recently, last toggled in epoch {:?}, current epoch {}",
+                        self.sender_key,
+                        last_toggled(&self.sender_key),
+                        epoch_number
+                    );
+                    return Err(Box::new(TransactionValidationErrors::ToggledTooRecently));
+                }
+                if self.amount != 0 {
+                    return Err(Box::new(TransactionValidationErrors::InsufficentAmount));
+                }
             }
-            'o' => {
+            'g' => {
                 let size_of_extra = self.extra.len();
                 if size_of_extra >= 200 {
                     error!(
@@ -464,7 +660,7 @@ impl Verifiable for Transaction {
                     return Err(Box::new(TransactionValidationErrors::ExtraTooLarge));
                 }
                 // check if the sender is a fullnode
-                if get_data(config().db_path + "/candidates", &self.sender_key) != "f" {
+                if get_status(&self.sender_key) != "f" {
                     error!(
                         "Non fullnode {} tried to report {}",
                         self.sender_key, self.receive_key
@@ -472,13 +668,21 @@ impl Verifiable for Transaction {
                     return Err(Box::new(TransactionValidationErrors::NotFullNode));
                 }
                 // check if the reciever (node being reported) is a fullnode
-                if get_data(config().db_path + "/candidates", &self.receive_key) != "f" {
+                if get_status(&self.receive_key) != "f" {
                     error!(
                         "Non fullnode {} reported by {}",
                         self.receive_key, self.sender_key
                     );
                     return Err(Box::new(TransactionValidationErrors::NotFullNode));
                 }
+                // fullnodes which toggled off participation are not absent
+                if !is_active(&self.receive_key) {
+                    error!(
+                        "Inactive fullnode {} reported by {}",
+                        self.receive_key, self.sender_key
+                    );
+                    return Err(Box::new(TransactionValidationErrors::FullnodeInactive));
+                }
                 if let Some(commitee) = Comitee::find_for(&self.receive_key) {
                     // get each reported round and see if the fullnode did miss it
                     let rounds: Vec<(u64, u8)> = serde_json::from_str(&String::from_utf8(
@@ -597,13 +801,38 @@ impl Verifiable for Transaction {
                 )?) {
                     Ok(salt_seeds) => {
                         debug!("Decoded salt_seeds={:#?}", salt_seeds);
-
-                        let mut message = String::from("genesis");
-                        if top_epoch.epoch_number != 0 {
-                            message = raw_lyra(&(top_epoch.epoch_number.to_string() + "epoch"))
+                        // min_vote percent of the participating consensus committee members must contribute a seed
+                        let required = required_salt_seeds(&top_epoch.committees[0]);
+                        if salt_seeds.len() < required {
+                            error!(
+                                "Only {} epoch salt seeds in txn {}, required={}",
+                                salt_seeds.len(),
+                                self.hash,
+                                required
+                            );
+                            return Err(Box::new(TransactionValidationErrors::NotEnoughSaltSeeds));
                         }
+                        let message = salt_seed_message(top_epoch.epoch_number);
+                        let mut seen: HashSet<String> = HashSet::new();
                         for (publickey, seed) in salt_seeds {
                             trace!("Validating seed {}", seed);
+                            // every seed must come from a unique member of the consensus committee
+                            if !top_epoch.committees[0].members.contains(&publickey) {
+                                error!(
+                                    "Epoch salt seed creator {} not in consensus committee",
+                                    publickey
+                                );
+                                return Err(Box::new(TransactionValidationErrors::NotInCommitee));
+                            }
+                            if !seen.insert(publickey.clone()) {
+                                error!(
+                                    "Epoch salt seed creator {} included more than once",
+                                    publickey
+                                );
+                                return Err(Box::new(
+                                    TransactionValidationErrors::FailedToDecodeSaltSeeds,
+                                ));
+                            }
                             // get the secp256k1 publickey for this salter
                             let cert = Certificate::get(publickey)?;
                             if !validate_vrf(
@@ -642,10 +871,8 @@ impl Verifiable for Transaction {
                         TransactionValidationErrors::WrongAmountRecieverConsensusMessage,
                     ));
                 }
-                match serde_json::from_str::<((String, String), Vec<(String, u8, String)>)>(
-                    &String::from_utf8(bs58::decode(&self.extra).into_vec()?)?,
-                ) {
-                    Ok((hashes, delta_list)) => {
+                match decode_delta_list(&self.extra) {
+                    Ok((hashes, delta_list, _)) => {
                         debug!("Decoded fullnode delta list, len={}, expected preshuffle_hash={}, expected postshuffle_hash={}", delta_list.len(), hashes.0, hashes.1);
                         trace!(
                             "fullnode_delta_list={:#?}, hashes: {:#?}",
@@ -653,73 +880,69 @@ impl Verifiable for Transaction {
                             hashes
                         );
                         let top_epoch = get_top_epoch()?;
-                        let mut fullnodes_hashset: HashSet<String> = HashSet::new();
-                        for committee in top_epoch.committees {
-                            for fullnode in committee.members {
-                                fullnodes_hashset.insert(fullnode);
-                            }
+                        let mut fullnodes_hashset: HashSet<String> =
+                            fullnode_set(&top_epoch).into_iter().collect();
+                        // the eclosed candidates must be exactly those selected by the VRF lottery
+                        let eclosed: Vec<(String, String)> = delta_list
+                            .iter()
+                            .filter(|delta| delta.1 == 0)
+                            .map(|delta| (delta.0.clone(), delta.2.clone()))
+                            .collect();
+                        let selected = select_candidates(&top_epoch);
+                        if eclosed != selected {
+                            error!(
+                                "Eclosed candidates do not equal lottery selection, eclosed={:?}, selected={:?}",
+                                eclosed, selected
+                            );
+                            return Err(Box::new(TransactionValidationErrors::BadCandidateSelection));
+                        }
+                        // as must the removed fullnodes (exited or expired)
+                        let removed: Vec<(String, u8, String)> = delta_list
+                            .iter()
+                            .filter(|delta| delta.1 != 0)
+                            .cloned()
+                            .collect();
+                        let scheduled = scheduled_removals(&top_epoch);
+                        if removed != scheduled {
+                            error!(
+                                "Removed fullnodes do not equal scheduled removals, removed={:?}, scheduled={:?}",
+                                removed, scheduled
+                            );
+                            return Err(Box::new(TransactionValidationErrors::BadRemovalList));
                         }
                         for delta in delta_list {
                             if delta.1 != 0 {
                                 // remove the fullnode
-                                // TODO: validate remove proof
-                                if fullnodes_hashset.contains(&delta.0) {
-                                    trace!(
-                                        "Removing {} from fullnode set, reason={}, proof={}",
-                                        delta.0,
-                                        delta.1,
-                                        delta.2
-                                    );
-                                } else {
-                                    error!("Fullnode set did not contain node removed by delta entry, delta entry={:?}", delta);
-                                }
+                                trace!(
+                                    "Removing {} from fullnode set, reason={}, proof={}",
+                                    delta.0,
+                                    delta.1,
+                                    delta.2
+                                );
+                                fullnodes_hashset.remove(&delta.0);
                             } else {
                                 // eclose a candidate
                                 fullnodes_hashset.insert(delta.0.clone());
-                                // now update their on disk flag to validator, from candidate
-                                if save_data(
-                                    "f",
-                                    &(config().db_path + "/candidates"),
-                                    delta.0.clone(),
-                                ) != 1
-                                {
-                                    return Err("failed to save new fullnode candidate".into());
-                                }
                             }
                         }
                         let mut fullnodes: Vec<String> = Vec::from_iter(fullnodes_hashset);
-                        let mut preshuffle_hash = String::from("");
-                        for fullnode in &fullnodes {
-                            preshuffle_hash = raw_lyra(&(preshuffle_hash + fullnode));
-                        }
+                        fullnodes.retain(|fullnode| is_active(fullnode)); // inactive fullnodes are not placed in committees
+                        fullnodes.sort(); // the preshuffle hash is taken over the sorted list
+                        let preshuffle_hash = preshuffle_hash(&fullnodes);
                         if preshuffle_hash != hashes.0 {
                             error!("Preshuffle hash (after delta) does not equal expected, expected={}, got={}", hashes.0, preshuffle_hash);
                             return Err(Box::new(TransactionValidationErrors::BadPreshuffleHash));
                         }
                         // now we shuffle the list
                         let curr_epoch = Epoch::get(top_epoch.epoch_number + 1)?;
-                        let shuffle_seed = vrf_hash_to_integer(raw_lyra(
-                            &(curr_epoch.shuffle_bits.to_string()
-                                + &curr_epoch.salt.to_string()
-                                + &curr_epoch.epoch_number.to_string()),
-                        ));
-                        let shuffle_seed = (shuffle_seed.clone()
-                            / (shuffle_seed + BigDecimal::from(1))) // map between 0-1
-                        .to_string() // turn to string
-                        .parse::<f64>()?; // parse as f64
-                        sort_full_list(&mut fullnodes, (shuffle_seed * (u64::MAX as f64)) as u64);
-                        // now form the committees from this shuffled list
-                        let mut excluded_nodes: Vec<String> = vec![]; // will contain the publickey of any nodes not included in tis epoch
-                        let number_of_committes = 1;
-                        let committees: Vec<Comitee> = Comitee::form_comitees(
-                            &mut fullnodes,
-                            &mut excluded_nodes,
-                            number_of_committes,
-                        );
-                        let mut postshuffle_hash = String::from("");
-                        for committee in &committees {
-                            postshuffle_hash = raw_lyra(&(postshuffle_hash + &committee.hash));
-                        }
+                        sort_full_list(&mut fullnodes, curr_epoch.salt, curr_epoch.shuffle_bits);
+                        // now form the committees from this shuffled list, probationary fullnodes are placed as observers
+                        let eclosed_keys: Vec<String> =
+                            eclosed.into_iter().map(|(publickey, _)| publickey).collect();
+                        let observers = observers_after(&top_epoch, &eclosed_keys);
+                        let committees: Vec<Comitee> =
+                            Comitee::form_comitees_with_observers(&fullnodes, &observers);
+                        let postshuffle_hash = postshuffle_hash(&committees);
                         if postshuffle_hash != hashes.1 {
                             error!("Post shuffle committee list hash does not equal expected, expected={}, got={}", hashes.1, postshuffle_hash);
                             return Err(Box::new(TransactionValidationErrors::BadShuffledHash));
@@ -771,6 +994,63 @@ impl Verifiable for Transaction {
                     return Err(Box::new(TransactionValidationErrors::InvalidVrf));
                 }
             }
+            'r' => {
+                // epoch reward txn
+                let top_epoch = get_top_epoch()?;
+                let consensus_round_leader = top_epoch
+                    .committees
+                    .first()
+                    .ok_or("epoch has no consensus committee")?
+                    .get_round_leader()?;
+                if self.sender_key != consensus_round_leader {
+                    return Err(Box::new(
+                        TransactionValidationErrors::UnauthorisedConsensusMessage,
+                    ));
+                } else if self.receive_key != "0" {
+                    return Err(Box::new(
+                        TransactionValidationErrors::WrongRecieverConsensusMessage,
+                    ));
+                }
+                // rewards are paid once the main stage of the epoch is over (after the epoch salt is announced)
+                if top_epoch.stage != EpochStage::VrfLotto {
+                    error!(
+                        "Reward transaction {} sent outside of VrfLotto, epoch={}, stage={:?}",
+                        self.hash, top_epoch.epoch_number, top_epoch.stage
+                    );
+                    return Err(Box::new(
+                        TransactionValidationErrors::RewardSentOutsideVrfLotto,
+                    ));
+                }
+                if rewards_paid(top_epoch.epoch_number) {
+                    error!(
+                        "Rewards for epoch {} already paid, transaction={}",
+                        top_epoch.epoch_number, self.hash
+                    );
+                    return Err(Box::new(TransactionValidationErrors::RewardsAlreadyPaid));
+                }
+                let rewards: Vec<(String, u64)> = serde_json::from_str(&String::from_utf8(
+                    bs58::decode(&self.extra).into_vec()?,
+                )?)?;
+                // every node snapshots the rewards as the epoch leaves its main stage, they must match exactly
+                let expected_rewards = epoch_rewards(top_epoch.epoch_number)?;
+                if rewards != expected_rewards {
+                    error!(
+                        "Reward list in transaction {} does not equal expected, expected={:?}, got={:?}",
+                        self.hash, expected_rewards, rewards
+                    );
+                    return Err(Box::new(TransactionValidationErrors::BadRewardList));
+                }
+                let total: u64 = rewards.iter().map(|(_, reward)| reward).sum();
+                if self.amount != total {
+                    error!(
+                        "Reward transaction {} amount does not equal total reward, amount={}, total={}",
+                        self.hash, self.amount, total
+                    );
+                    return Err(Box::new(
+                        TransactionValidationErrors::WrongAmountRecieverConsensusMessage,
+                    ));
+                }
+            }
             'v' => {
                 // VRF lotto ticket
                 // check we are in the right epoch period (vrf lotto)
@@ -782,26 +1062,38 @@ impl Verifiable for Transaction {
                     ));
                 }
                 // check if the sender is a fullnode candidate
-                if get_data(config().db_path + "/candidates", &self.sender_key) != "c" {
+                if get_status(&self.sender_key) != "c" {
                     error!(
                         "Non candidate={} sent VRF lotto ticket, in transaction={}, sender_type={}",
                         self.sender_key,
                         self.hash,
-                        get_data(config().db_path + "/candidates", &self.sender_key)
+                        get_status(&self.sender_key)
                     );
                     return Err(Box::new(TransactionValidationErrors::NotCandidate));
                 }
+                // each candidate gets a single ticket per lottery
+                if has_ticket(top_epoch.epoch_number, &self.sender_key) {
+                    error!(
+                        "Candidate={} already sent VRF lotto ticket in epoch {}, transaction={}",
+                        self.sender_key, top_epoch.epoch_number, self.hash
+                    );
+                    return Err(Box::new(TransactionValidationErrors::DuplicateTicket));
+                }
                 // check the vrf is valid
-                let current_epoch = get_top_epoch().unwrap_or_default();
-                let next_epoch = Epoch::get(current_epoch.epoch_number + 1).unwrap_or_default();
-                let vrf_seed = raw_hash(
-                    &(format!("{}{}{}", current_epoch.salt, next_epoch.salt, "-vrflotto")),
-                );
+                let next_epoch = Epoch::get(top_epoch.epoch_number + 1).unwrap_or_default();
+                let vrf_seed = lotto_seed(&top_epoch, &next_epoch);
                 debug!("VRF seed: {}", vrf_seed);
                 let ticket_hash =
                     raw_hash(&format!("{}{}{}", self.hash, self.sender_key, self.extra))[0..5]
                         .to_string();
                 let cert = Certificate::get(self.sender_key.clone())?;
+                if cert.expired(expiry_cutoff(&top_epoch)) {
+                    error!(
+                        "Candidate={} sent VRF lotto ticket but certificate expires before next epoch, valid_until={}",
+                        self.sender_key, cert.valid_until
+                    );
+                    return Err(Box::new(TransactionValidationErrors::NotRegistered));
+                }
                 if !validate_vrf(
                     cert.secp256k1_publickey.clone(),
                     self.extra.clone(),
@@ -811,19 +1103,21 @@ impl Verifiable for Transaction {
                         "Invalid VRF in VRF lotto ticket txn={}, sender={}, vrf={}, seed={}, ticket_hash={}",
                         self.hash, self.sender_key, self.extra, vrf_seed, ticket_hash
                     );
+                    return Err(Box::new(TransactionValidationErrors::InvalidVrf));
                 }
                 // check if the value fufills the eclosion requirments
-                // TODO: calculate this ecolosion threshold, for now all tickets work
-                let threshhold = BigDecimal::from(1);
-                let ticket = vrf_hash_to_integer(proof_to_hash(&self.extra)?);
+                let (_, threshhold) = lottery_threshold(&top_epoch);
+                let ticket = ticket_value(&self.extra)?;
                 if ticket > threshhold {
-                    error!("VRF lotto ticket does not fufill requirement, threshold={:.4}, ticket={:.4}, ticket hash={}, transaction={}, sender={}", threshhold, ticket, ticket_hash, self.hash, self.sender_key);
+                    error!("VRF lotto ticket does not fufill requirement, threshold={}, ticket={}, ticket hash={}, transaction={}, sender={}", threshhold, ticket, ticket_hash, self.hash, self.sender_key);
+                    return Err(Box::new(TransactionValidationErrors::TicketNotViable));
                 }
                 // this VRF lotto ticket is valid, check default stuff like fee
                 let size_of_extra = self.extra.len();
-                if size_of_extra > 110 {
+                if size_of_extra > 162 {
+                    // VRF proofs are 81 bytes, hex encoded
                     error!(
-                        "VRFLottoTicket type transaction {}'s extra ({}) too large, {} > 110",
+                        "VRFLottoTicket type transaction {}'s extra ({}) too large, {} > 162",
                         self.hash, self.extra, size_of_extra
                     );
                     return Err(Box::new(TransactionValidationErrors::ExtraTooLarge));
@@ -1039,9 +1333,19 @@ impl Verifiable for Transaction {
                 self.sender_key,
                 self.hash
             );
-            new_invite(&self.extra)?;
             trace!("Get epoch struct");
             let mut top_epoch = get_top_epoch()?;
+            // invites enacted since this txn was validated may have used up the epochs limits
+            let limit = epoch_invite_limit(fullnode_set(&top_epoch).len() as u64);
+            if over_invite_limit(std::slice::from_ref(self), top_epoch.epoch_number, limit).is_some() {
+                error!(
+                    "Invite {} created by {} is over the limits for epoch {}",
+                    self.extra, self.sender_key, top_epoch.epoch_number
+                );
+                return Err("Invite limit for epoch reached".into());
+            }
+            new_invite(&self.extra)?;
+            record_issuance(&self.extra, &self.sender_key, top_epoch.epoch_number)?;
             top_epoch.total_coins_movement += self.amount + self.fee();
             top_epoch.hash();
             debug!(
@@ -1086,52 +1390,33 @@ impl Verifiable for Transaction {
             )?) {
                 Ok(salt_seeds) => {
                     debug!("Decoded salt_seeds={:#?}", salt_seeds);
-                    let mut salt_string = String::from("");
-                    for (_, seed) in salt_seeds {
-                        trace!("Validating seed {}", seed);
+                    let epoch_salt = epoch_salt(&salt_seeds)?;
+                    debug!("Calculated epoch salt: {}", epoch_salt);
+                    // now we create the next epoch on disk
+                    let mut top_epoch = get_top_epoch()?;
+                    let mut next_epoch = Epoch::new();
+                    next_epoch.salt = epoch_salt; // the epoch is not running (Failed) till the delta list moves it to reorg
 
-                        let vrf_hash = proof_to_hash(&seed).unwrap_or_default();
-                        salt_string += &vrf_hash_to_integer(vrf_hash).to_string();
+                    if let Err(e) = next_epoch.save() {
+                        error!("Failed to save next_epoch to disk, error={}", e);
                     }
-                    debug!("Final salt_string={}", salt_string);
-                    // now parse the string into a big number
-                    let salt_big = BigDecimal::from_str(&salt_string)?;
-                    let salt_mod = salt_big.clone() * BigDecimal::from(u64::MAX); // now SHOULD be safe to cast to u64
-                    trace!(
-                        "Moduloed big salt: salt_big={}, salt_mod={}",
-                        salt_big,
-                        salt_mod
-                    );
-                    if let Ok(epoch_salt) =
-                        salt_mod.to_string().split('.').collect::<Vec<&str>>()[0].parse::<u64>()
-                    {
-                        debug!("Calculated epoch salt: {}", epoch_salt);
-                        // now we create the next epoch on disk
-                        let mut top_epoch = get_top_epoch()?;
-                        top_epoch.stage = EpochStage::VrfLotto;
-                        top_epoch.hash();
-                        let mut next_epoch = Epoch::new();
-                        next_epoch.salt = epoch_salt;
-                        next_epoch.stage = EpochStage::Failed; // Set to failed state till we move it to reorg
-
-                        if let Err(e) = next_epoch.save() {
-                            error!("Failed to save top_epoch to disk, error={}", e);
-                        }
-                        if let Err(e) = top_epoch.save() {
-                            error!("Failed to save next_epoch to disk, error={}", e);
-                        }
-                        info!("Next epoch salt: {}", epoch_salt);
-                        // dont set the top epoch until we get a announceCommiteeListDelta txn
-                        // next txn should be an announceShuffleBits txn which sets the vrf used to shuffle the fullnode list for next epoch
-                        // which is followed by an announceCommiteeListDelta which tells you what fullnodes have been removed or added and once enacted starts the next epoch
-                        let callbacks = VRF_LOTTERY_CALLBACKS.lock()?;
-                        for callback in &*callbacks {
-                            (callback)();
+                    // the rewards are fixed as the main stage ends, chunks enacted during the lottery do not change them
+                    snapshot_epoch_rewards(&top_epoch)?;
+                    top_epoch.transition(EpochStage::VrfLotto, self.timestamp)?;
+                    open_lottery(&top_epoch)?;
+                    info!("Next epoch salt: {}", epoch_salt);
+                    // dont set the top epoch until we get a announceCommiteeListDelta txn
+                    // next txn should be an announceShuffleBits txn which sets the vrf used to shuffle the fullnode list for next epoch
+                    // which is followed by an announceCommiteeListDelta which tells you what fullnodes have been removed or added and once enacted starts the next epoch
+                    // the callbacks act on the open lottery, so only run them once the block which opened it is on disk
+                    store::after_commit(|| match VRF_LOTTERY_CALLBACKS.lock() {
+                        Ok(callbacks) => {
+                            for callback in &*callbacks {
+                                (callback)();
+                            }
                         }
-                    } else {
-                        error!("Failed to parse epoch salt as u64");
-                        return Err("Failed to parse epoch salt as u64".into());
-                    }
+                        Err(e) => error!("Failed to lock VRF_LOTTERY_CALLBACKS, error={}", e),
+                    })?;
                 }
                 Err(e) => {
                     error!("Failed to decode epoch salt seeds from extra ({}) in txn {}, gave error={}", self.extra, self.hash, e);
@@ -1150,7 +1435,9 @@ impl Verifiable for Transaction {
                 shuffle_bits_big,
                 shuffle_bits_mod
             );
-            if let Ok(shuffle_bits) = shuffle_bits_mod.to_string().parse::<u128>() {
+            if let Ok(shuffle_bits) =
+                shuffle_bits_mod.to_string().split('.').collect::<Vec<&str>>()[0].parse::<u128>()
+            {
                 info!("Shuffle bits for next epoch: {}", shuffle_bits);
                 let mut epoch = Epoch::get(get_top_epoch()?.epoch_number + 1)?;
                 epoch.shuffle_bits = shuffle_bits;
@@ -1160,11 +1447,9 @@ impl Verifiable for Transaction {
             }
         } else if self.flag == 'y' {
             // fullnode list delta
-            // format: ((String, String), Vec<(String, u8, String)) 0.0: Preshuffle hash, 0.1: postshuffle hash, 1.0: publickey, 1.1: reason/type (0 = join via vrf eevrythign else = leave), 1.2: proof (the hash of the block it happened in)
-            match serde_json::from_str::<((String, String), Vec<(String, u8, String)>)>(
-                &String::from_utf8(bs58::decode(&self.extra).into_vec()?)?,
-            ) {
-                Ok((hashes, delta_list)) => {
+            // format: see FullnodeDeltaList
+            match decode_delta_list(&self.extra) {
+                Ok((hashes, delta_list, snapshot_hash)) => {
                     debug!("Decoded fullnode delta list, len={}, expected preshuffle_hash={}, expected postshuffle_hash={}", delta_list.len(), hashes.0, hashes.1);
                     trace!(
                         "fullnode_delta_list={:#?}, hashes: {:#?}",
@@ -1172,18 +1457,22 @@ impl Verifiable for Transaction {
                         hashes
                     );
                     let top_epoch = get_top_epoch()?;
-                    let mut fullnodes_hashset: HashSet<String> = HashSet::new();
-                    for committee in top_epoch.committees {
-                        for fullnode in committee.members {
-                            fullnodes_hashset.insert(fullnode);
-                        }
-                    }
+                    let mut fullnodes_hashset: HashSet<String> =
+                        fullnode_set(&top_epoch).into_iter().collect();
+                    let cutoff = expiry_cutoff(&top_epoch);
+                    // work out who observes next epoch before anyone is admitted to or graduates from probation
+                    let eclosed: Vec<String> = delta_list
+                        .iter()
+                        .filter(|delta| delta.1 == 0)
+                        .map(|delta| delta.0.clone())
+                        .collect();
+                    let observers = observers_after(&top_epoch, &eclosed);
                     let mut new_fullnodes = 0;
                     let mut removed_fullnodes = 0;
                     for delta in delta_list {
                         if delta.1 != 0 {
                             // remove the fullnode
-                            if fullnodes_hashset.contains(&delta.0) {
+                            if fullnodes_hashset.remove(&delta.0) {
                                 trace!(
                                     "Removing {} from fullnode set, reason={}, proof={}",
                                     delta.0,
@@ -1193,60 +1482,73 @@ impl Verifiable for Transaction {
                             } else {
                                 error!("Fullnode set did not contain node removed by delta entry, delta entry={:?}", delta);
                             }
+                            revoke(&delta.0)?;
+                            if delta.1 != REMOVAL_EXIT {
+                                // the lock of an expired certificate has already ended, exiting fullnodes started their unlock countdown when they sent their exit txn
+                                schedule_unlock(&delta.0, config().fullnode_lock_amount, cutoff)?;
+                            }
                             removed_fullnodes += 1;
                         } else {
                             // eclose a candidate
                             fullnodes_hashset.insert(delta.0.clone());
                             // now update their on disk flag to validator, from candidate
-                            if save_data("f", &(config().db_path + "/candidates"), delta.0.clone())
-                                != 1
-                            {
-                                return Err("failed to save new fullnode candidate".into());
-                            }
+                            set_status(&delta.0, "f")?;
+                            admit(&delta.0)?;
                             new_fullnodes += 1;
                         }
                     }
                     let mut fullnodes: Vec<String> = Vec::from_iter(fullnodes_hashset);
+                    fullnodes.retain(|fullnode| is_active(fullnode)); // inactive fullnodes are not placed in committees
+                    let unlocked = process_expiries(cutoff)?;
+                    debug!("Unlocked {} coins at end of epoch {}", unlocked, top_epoch.epoch_number);
+                    advance(&top_epoch)?;
+                    retarget_difficulty(&top_epoch)?;
 
                     // now we shuffle the list
                     let mut curr_epoch = Epoch::get(top_epoch.epoch_number + 1)?;
-                    let shuffle_seed = vrf_hash_to_integer(raw_lyra(
-                        &(curr_epoch.shuffle_bits.to_string()
-                            + &curr_epoch.salt.to_string()
-                            + &curr_epoch.epoch_number.to_string()),
-                    ));
-                    let shuffle_seed = (shuffle_seed.clone()
-                        / (shuffle_seed + BigDecimal::from(1))) // map between 0-1
-                    .to_string() // turn to string
-                    .parse::<f64>()?; // parse as f64
-                    sort_full_list(&mut fullnodes, (shuffle_seed * (u64::MAX as f64)) as u64);
-                    // now form the committees from this shuffled list
-                    let mut excluded_nodes: Vec<String> = vec![]; // will contain the publickey of any nodes not included in tis epoch
-                    let number_of_committes = 1; // TODO: calculate number of committees, for now its hardcoded as 2
-                    let committees: Vec<Comitee> = Comitee::form_comitees(
-                        &mut fullnodes,
-                        &mut excluded_nodes,
-                        number_of_committes,
-                    );
+                    sort_full_list(&mut fullnodes, curr_epoch.salt, curr_epoch.shuffle_bits);
+                    // now form the committees from this shuffled list, probationary fullnodes are placed as observers
+                    let committees: Vec<Comitee> =
+                        Comitee::form_comitees_with_observers(&fullnodes, &observers);
                     // now add the list to the current epoch data, save and set to top epoch
                     curr_epoch.committees = committees;
                     curr_epoch.total_fullnodes += new_fullnodes;
-                    curr_epoch.total_fullnodes -= removed_fullnodes;
-                    curr_epoch.stage = EpochStage::Reorg;
-                    curr_epoch.save()?;
+                    curr_epoch.total_fullnodes = curr_epoch.total_fullnodes.saturating_sub(removed_fullnodes);
+                    // record the hash of the snapshot of the state the epoch starts from, agreed by the committee which signed this txn (see Snapshot::propose)
+                    // it is covered by the epoch hash. We only hold the snapshot if our state matched when it was proposed
+                    if !snapshot_hash.is_empty()
+                        && !Snapshot::get(curr_epoch.epoch_number)?
+                            .map_or(false, |snapshot| snapshot.hash == snapshot_hash)
+                    {
+                        debug!(
+                            "Do not have the agreed snapshot of epoch {}, not serving it",
+                            curr_epoch.epoch_number
+                        );
+                    }
+                    curr_epoch.snapshot_hash = snapshot_hash;
+                    // fix the quorums of the epoch before any toggles or graduations during it
+                    record_voting_members(&curr_epoch)?;
+                    curr_epoch.transition(EpochStage::Reorg, self.timestamp)?;
                     curr_epoch.set_top_epoch()?;
-                    let mut top_epoch = get_top_epoch()?;
-                    top_epoch.stage = EpochStage::Final;
-                    top_epoch.save()?;
+                    let mut previous_epoch = Epoch::get(curr_epoch.epoch_number - 1)?;
+                    previous_epoch.transition(EpochStage::Final, self.timestamp)?;
                     info!(
-                        "New epoch number {} started, included fullnodes {}, excluded fullnodes {}",
+                        "New epoch number {} started, included fullnodes {}, observers {}",
                         curr_epoch.epoch_number,
                         curr_epoch.total_fullnodes,
-                        excluded_nodes.len()
+                        observers.len()
                     );
-                    for callback in &*(EPOCH_STARTED_CALLBACKS.lock()?) {
-                        (callback)()?;
-                    }
+                    // the callbacks act on (and prune below) the new epoch, so only run them once the block which started it is on disk
+                    store::after_commit(|| match EPOCH_STARTED_CALLBACKS.lock() {
+                        Ok(callbacks) => {
+                            for callback in &*callbacks {
+                                if let Err(e) = (callback)() {
+                                    error!("Epoch started callback failed, error={}", e);
+                                }
+                            }
+                        }
+                        Err(e) => error!("Failed to lock EPOCH_STARTED_CALLBACKS, error={}", e),
+                    })?;
                 }
                 Err(e) => {
                     error!(
@@ -1255,6 +1557,97 @@ impl Verifiable for Transaction {
                     );
                 }
             }
+        } else if self.flag == 'r' {
+            // epoch reward txn
+            let rewards: Vec<(String, u64)> = serde_json::from_str(&String::from_utf8(
+                bs58::decode(&self.extra).into_vec()?,
+            )?)?;
+            for (publickey, reward) in &rewards {
+                trace!("Paying reward {} to {}", reward, publickey);
+                let mut acc = open_or_create(publickey);
+                acc.balance += reward;
+                if acc.save().is_err() {
+                    return Err("failed to save account (after reward)".into());
+                }
+            }
+            trace!("Get epoch struct");
+            let mut top_epoch = get_top_epoch()?;
+            top_epoch.total_coins_movement += self.amount;
+            top_epoch.new_coins += self.amount;
+            mark_rewards_paid(top_epoch.epoch_number, &self.hash)?;
+            top_epoch.hash();
+            debug!(
+                "Rehashed epoch struct at height={}, new hash={}",
+                top_epoch.epoch_number, top_epoch.hash
+            );
+            top_epoch.save()?;
+            info!(
+                "Paid rewards for epoch {} to {} fullnodes, total={}",
+                top_epoch.epoch_number,
+                rewards.len(),
+                self.amount
+            );
+        } else if self.flag == 'e' {
+            trace!("Opening senders account");
+            let mut sendacc = open_or_create(&self.sender_key);
+            // relock the stake until the new valid_until
+            let relocked = stake_shortfall(&sendacc);
+            sendacc.balance -= self.fee() + relocked;
+            sendacc.locked += relocked;
+            sendacc.save()?;
+            let mut cert = Certificate::get(self.sender_key.clone())?;
+            info!(
+                "Renewed certificate of {}, valid_until {} -> {}, relocked {}",
+                self.sender_key, cert.valid_until, self.unlock_time, relocked
+            );
+            cert.renew(self.unlock_time);
+            cert.save()?;
+            let mut top_epoch = get_top_epoch()?;
+            top_epoch.total_coins_movement += self.fee() + relocked;
+            top_epoch.locked_coins += relocked;
+            top_epoch.hash();
+            top_epoch.save()?;
+        } else if self.flag == 'd' {
+            trace!("Opening senders account");
+            let mut sendacc = open_or_create(&self.sender_key);
+            sendacc.balance -= self.fee();
+            sendacc.save()?;
+            revoke_invite(&self.extra)?;
+            info!("Invite {} revoked by {}", self.extra, self.sender_key);
+            let mut top_epoch = get_top_epoch()?;
+            top_epoch.total_coins_movement += self.fee();
+            top_epoch.hash();
+            top_epoch.save()?;
+        } else if self.flag == 'q' {
+            trace!("Opening senders account");
+            let mut sendacc = open_or_create(&self.sender_key);
+            sendacc.balance -= self.fee();
+            sendacc.save()?;
+            // the stake unlocks fullnode_lock_time epochs after the exit
+            schedule_unlock(
+                &self.sender_key,
+                config().fullnode_lock_amount,
+                self.timestamp + config().fullnode_lock_time * config().target_epoch_length,
+            )?;
+            if get_status(&self.sender_key) == "f" {
+                // fullnodes stay in their committee until the end of the epoch
+                schedule_exit(&self.sender_key, &self.hash)?;
+                info!("Fullnode {} exiting at end of epoch", self.sender_key);
+            } else {
+                revoke(&self.sender_key)?;
+                info!("Candidate {} exited", self.sender_key);
+            }
+            let mut top_epoch = get_top_epoch()?;
+            top_epoch.total_coins_movement += self.fee();
+            top_epoch.hash();
+            top_epoch.save()?;
+        } else if self.flag == 'o' {
+            // toggle participation txns are free (see gas)
+            let active = toggle(&self.sender_key, get_top_epoch()?.epoch_number)?;
+            info!(
+                "Fullnode {} toggled participation, active={} (committees change next epoch)",
+                self.sender_key, active
+            );
         } else if self.flag == 'v' {
             trace!("Opening senders account");
             let mut sendacc = open_or_create(&self.sender_key);
@@ -1271,6 +1664,12 @@ impl Verifiable for Transaction {
             );
             top_epoch.save()?;
             trace!("Saved epoch");
+            record_ticket(
+                top_epoch.epoch_number,
+                &self.sender_key,
+                &self.hash,
+                ticket_value(&self.extra)?,
+            )?;
             info!(
                 "Recieved new VRF entry ticket. Sender={}, ticket={}",
                 self.sender_key, self.extra
@@ -1286,9 +1685,91 @@ impl Verifiable for Transaction {
         Ok(())
     }
 }
+/// Takes the movement of a reverted transaction off the top epoch's totals, and applies the flag specific change
+fn revert_epoch_totals<F: FnOnce(&mut Epoch)>(
+    movement: u64,
+    revert: F,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut top_epoch = get_top_epoch()?;
+    top_epoch.total_coins_movement = top_epoch.total_coins_movement.saturating_sub(movement);
+    revert(&mut top_epoch);
+    top_epoch.hash();
+    top_epoch.save()?;
+    Ok(())
+}
+
 impl Transaction {
+    /// # Revert
+    /// Undoes the effects of enacting the transaction, used to roll back the losing block of a fork (see fork::rollback)
+    /// Only transfers, claims, burns and locks can be reverted (see revertible), reverting any other type returns an error
+    pub fn revert(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.flag {
+            'n' => {
+                let mut sendacc = open_or_create(&self.sender_key);
+                if self.sender_key != self.receive_key {
+                    let mut reqacc: Account = open_or_create(&self.receive_key);
+                    if reqacc.balance < self.amount {
+                        return Err("reciever balance lower than reverted amount".into());
+                    }
+                    reqacc.balance -= self.amount;
+                    sendacc.balance += self.amount;
+                    reqacc.save()?;
+                }
+                sendacc.balance += self.fee();
+                sendacc.save()?;
+                revert_epoch_totals(self.amount + self.fee(), |_| {})?;
+            }
+            'c' => {
+                let mut acc = open_or_create(&self.sender_key);
+                if acc.balance < self.amount {
+                    return Err("claimer balance lower than reverted amount".into());
+                }
+                acc.balance -= self.amount;
+                acc.save()?;
+                revert_epoch_totals(self.amount, |epoch| {
+                    epoch.new_coins = epoch.new_coins.saturating_sub(self.amount)
+                })?;
+            }
+            'b' => {
+                let mut acc = open_or_create(&self.sender_key);
+                acc.balance += self.amount + self.fee();
+                acc.save()?;
+                revert_epoch_totals(self.amount + self.fee(), |epoch| {
+                    epoch.burnt_coins = epoch.burnt_coins.saturating_sub(self.amount)
+                })?;
+            }
+            'l' => {
+                let mut acc = open_or_create(&self.sender_key);
+                if acc.locked < self.amount {
+                    return Err("locked balance lower than reverted amount".into());
+                }
+                acc.locked -= self.amount;
+                acc.balance += self.amount + self.fee();
+                acc.save()?;
+                revert_epoch_totals(self.amount + self.fee(), |epoch| {
+                    epoch.locked_coins = epoch.locked_coins.saturating_sub(self.amount)
+                })?;
+            }
+            _ => {
+                return Err(format!(
+                    "can not revert {} transaction {}",
+                    self.type_transaction(),
+                    self.hash
+                )
+                .into());
+            }
+        }
+        debug!("Reverted transaction {}", self.hash);
+        Ok(())
+    }
+
+    /// Returns true if revert can undo the transaction (so a block containing it can be rolled back)
+    pub fn revertible(&self) -> bool {
+        matches!(self.flag, 'n' | 'c' | 'b' | 'l')
+    }
+
     pub fn consensus_type(&self) -> bool {
-        self.flag == 'a' || self.flag == 'y' || self.flag == 'z'
+        self.flag == 'a' || self.flag == 'y' || self.flag == 'z' || self.flag == 'r'
     }
 
     pub fn type_transaction(&self) -> String {
@@ -1303,9 +1784,12 @@ impl Transaction {
             'm' => "message".to_string(),
             'c' => "claim".to_owned(), // This is only availble on the testnet it will be removed before the mainet
             'i' => "create invite".to_owned(),
+            'd' => "Revoke invite".to_owned(), // revokes an unspent invite (in the extra field) created by the sender
             'x' => "Block/ restrict account".to_owned(), // means the account (linked via public key in the extra field) you block cannot send you transactions
             'p' => "Unblock account".to_owned(), // reverts the block transaction (linked by the txn hash in extra field)
             'v' => "Publish VRF lottery ticket".to_owned(),
+            'e' => "Renew fullnode certificate".to_owned(), // extends the fullnodes lock, setting the certificates valid_until to the unlock_time
+            'q' => "Exit fullnode set".to_owned(), // removes the fullnode at the end of the epoch and starts the unlock countdown on its stake
             'g' => "Propose penalty".to_owned(), // Proposes, with attached proof of absence thata  fullnode should recieve a penalty
             'o' => "Toggle participation".to_owned(), // Toggles the fullnodes participation status (eg if they are taking part in validation)
             // CONSENSUS ONLY
@@ -1316,26 +1800,18 @@ impl Transaction {
         }
     }
     pub fn update_nonce(&self) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        let chain_index_db = config().db_path + "/chains/" + &self.sender_key + "-chainindex";
-        let txn_count: u64 =
-            avrio_database::get_data(chain_index_db.to_owned(), &"txncount").parse()?;
+        let chain_index = Table::ChainIndex(self.sender_key.clone());
+        let txn_count: u64 = store::get(&chain_index, "txncount")?
+            .ok_or("failed to read send acc nonce")?;
         trace!("Setting txn count");
-        if avrio_database::save_data(
-            &(txn_count + 1).to_string(),
-            &chain_index_db,
-            "txncount".to_string(),
-        ) != 1
-        {
-            return Err("failed to update send acc nonce".into());
-        } else {
-            trace!(
-                "Updated account nonce (txn count) for account: {}, prev: {}, new: {}",
-                self.sender_key,
-                txn_count,
-                txn_count + 1
-            );
-            return Ok(());
-        };
+        store::put(&chain_index, "txncount", &(txn_count + 1))?;
+        trace!(
+            "Updated account nonce (txn count) for account: {}, prev: {}, new: {}",
+            self.sender_key,
+            txn_count,
+            txn_count + 1
+        );
+        Ok(())
     }
     pub fn gas(&self) -> u64 {
         return match self.flag {
@@ -1352,6 +1828,7 @@ impl Transaction {
             'v' => {
                 TX_GAS as u64 + ((GAS_PER_EXTRA_BYTE_NORMAL / 2) as u64 * self.extra.len() as u64)
             }
+            'e' | 'q' | 'd' => TX_GAS as u64,
             _ => 0, // f, c, o, g
         };
     }
@@ -1368,3 +1845,151 @@ impl Transaction {
         self.hash_item()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::set_account;
+    use avrio_database::{backend::MemoryBackend, with_backend};
+    use std::sync::Arc;
+
+    fn set_balance(public_key: &str, balance: u64) {
+        let mut acc = Account::new(public_key.to_owned());
+        acc.balance = balance;
+        assert_eq!(set_account(&acc), 1);
+    }
+
+    fn transfer(amount: u64, nonce: u64) -> Transaction {
+        let mut txn = Transaction {
+            flag: 'n',
+            sender_key: "sender".to_owned(),
+            receive_key: "reciever".to_owned(),
+            amount,
+            gas_price: 10,
+            max_gas: u64::MAX,
+            nonce,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64,
+            ..Default::default()
+        };
+        txn.hash();
+        txn
+    }
+
+    fn setup() {
+        let mut epoch = Epoch::new();
+        epoch.save().unwrap();
+        epoch.set_top_epoch().unwrap();
+        set_balance("sender", 1000);
+        set_balance("reciever", 0);
+    }
+
+    /// Returns a salt seed (publickey, VRF proof) for the epoch after epoch_number, made with a new keypair
+    fn salt_seed(publickey: &str, epoch_number: u64) -> (String, String) {
+        let keypair = avrio_crypto::generate_secp256k1_keypair();
+        let (proof, _) =
+            avrio_crypto::get_vrf(keypair[0].clone(), salt_seed_message(epoch_number)).unwrap();
+        (publickey.to_owned(), proof)
+    }
+
+    fn validation_error(txn: &Transaction) -> Box<dyn std::error::Error> {
+        txn.valid().expect_err("transaction should be invalid")
+    }
+
+    #[test]
+    fn test_transfer() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            setup();
+            let txn = transfer(100, 0);
+            txn.valid().unwrap();
+            txn.enact().unwrap();
+            assert_eq!(get_account("sender").unwrap().balance, 900 - txn.fee());
+            assert_eq!(get_account("reciever").unwrap().balance, 100);
+            assert_eq!(
+                get_top_epoch().unwrap().total_coins_movement,
+                100 + txn.fee()
+            );
+            assert!(txn.revertible());
+            txn.revert().unwrap();
+            assert_eq!(get_account("sender").unwrap().balance, 1000);
+            assert_eq!(get_account("reciever").unwrap().balance, 0);
+            assert_eq!(get_top_epoch().unwrap().total_coins_movement, 0);
+        });
+    }
+
+    #[test]
+    fn test_transfer_invalid() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            setup();
+            assert!(matches!(
+                validation_error(&transfer(1000, 0)).downcast_ref::<TransactionValidationErrors>(),
+                Some(TransactionValidationErrors::InsufficentBalance)
+            ));
+            assert!(matches!(
+                validation_error(&transfer(0, 0)).downcast_ref::<TransactionValidationErrors>(),
+                Some(TransactionValidationErrors::InsufficentAmount)
+            ));
+            let mut cheap = transfer(100, 0);
+            cheap.gas_price = 1;
+            cheap.hash();
+            assert!(matches!(
+                validation_error(&cheap).downcast_ref::<TransactionValidationErrors>(),
+                Some(TransactionValidationErrors::GasPriceLow)
+            ));
+            let mut tampered = transfer(100, 0);
+            tampered.amount = 200;
+            assert!(matches!(
+                validation_error(&tampered).downcast_ref::<TransactionValidationErrors>(),
+                Some(TransactionValidationErrors::BadHash)
+            ));
+            let txn = transfer(100, 0);
+            store::put_raw(&Table::Transactions, &txn.hash, "block").unwrap();
+            assert!(matches!(
+                validation_error(&txn).downcast_ref::<TransactionValidationErrors>(),
+                Some(TransactionValidationErrors::TransactionExists)
+            ));
+            // the nonce must match the senders transaction count once its chain exists
+            store::put(&Table::ChainIndex("sender".to_owned()), "txncount", &1u64).unwrap();
+            assert!(matches!(
+                validation_error(&transfer(100, 0)).downcast_ref::<TransactionValidationErrors>(),
+                Some(TransactionValidationErrors::BadNonce)
+            ));
+            transfer(100, 1).valid().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_announce_salt_seeds() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            setup();
+            let epoch_number = get_top_epoch().unwrap().epoch_number;
+            let seeds = vec![
+                salt_seed("b", epoch_number),
+                salt_seed("a", epoch_number),
+                salt_seed("c", epoch_number),
+            ];
+            let txn = Transaction {
+                flag: 'a',
+                sender_key: "leader".to_owned(),
+                receive_key: "0".to_owned(),
+                extra: bs58::encode(serde_json::to_string(&seeds).unwrap()).into_string(),
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_millis() as u64,
+                ..Default::default()
+            };
+            txn.enact().unwrap();
+            let salt = Epoch::get(epoch_number + 1).unwrap().salt;
+            assert_eq!(salt, epoch_salt(&seeds).unwrap());
+            assert_eq!(get_top_epoch().unwrap().stage, EpochStage::VrfLotto);
+            // the salt depends on every seed but not on the order they are listed in
+            let mut reordered = seeds.clone();
+            reordered.reverse();
+            assert_eq!(epoch_salt(&reordered).unwrap(), salt);
+            assert_ne!(epoch_salt(&seeds[..2]).unwrap(), salt);
+        });
+    }
+}
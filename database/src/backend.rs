@@ -0,0 +1,214 @@
+// This file holds the storage backends of the database. Tables are stored through the StorageBackend trait as (column family, key) -> value pairs:
+// RocksDbBackend stores them in a rocksdb database with a column family per table, MemoryBackend in memory (for tests, it is lost when dropped).
+use rocksdb::{ColumnFamily, Direction, IteratorMode, Options, WriteBatch, WriteOptions, DB};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use crate::{
+    store::{DbError, Table},
+    PendingWrites,
+};
+
+pub trait StorageBackend: Send + Sync {
+    /// Returns the value of the key in the column family, or None if it is not set
+    fn get(&self, column_family: &str, key: &str) -> Result<Option<Vec<u8>>, DbError>;
+    /// Applies the writes atomically, durably (synced to disk) if sync is true
+    fn write(&self, writes: &PendingWrites, sync: bool) -> Result<(), DbError>;
+    /// Returns every (key, value) of the column family whose key starts with prefix, sorted by key. Keys which are not utf8 are skipped
+    fn scan_prefix(&self, column_family: &str, prefix: &str)
+        -> Result<Vec<(String, Vec<u8>)>, DbError>;
+    /// Returns at most limit (key, value) pairs of the column family whose key starts with prefix and is not less than start, sorted by key.
+    /// Keys which are not utf8 are skipped
+    fn scan_page(
+        &self,
+        column_family: &str,
+        prefix: &str,
+        start: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError>;
+    /// Flushes any buffered writes to disk
+    fn flush(&self) -> Result<(), DbError>;
+}
+
+fn database_options() -> Options {
+    let mut opts = Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    opts.set_skip_stats_update_on_db_open(false);
+    opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
+    opts
+}
+
+pub struct RocksDbBackend {
+    db: DB,
+}
+
+impl RocksDbBackend {
+    /// Opens (or creates) the database at the path, with a column family per table
+    pub fn open(path: &str) -> Result<RocksDbBackend, DbError> {
+        debug!("Opening database at {}", path);
+        Ok(RocksDbBackend {
+            db: DB::open_cf(&database_options(), path, Table::column_families())?,
+        })
+    }
+
+    fn column_family(&self, name: &str) -> Result<&ColumnFamily, DbError> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| DbError::MissingColumnFamily(name.to_owned()))
+    }
+}
+
+impl StorageBackend for RocksDbBackend {
+    fn get(&self, column_family: &str, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        Ok(self.db.get_cf(self.column_family(column_family)?, key)?)
+    }
+
+    fn write(&self, writes: &PendingWrites, sync: bool) -> Result<(), DbError> {
+        let mut batch = WriteBatch::default();
+        for ((column_family, key), value) in writes {
+            let cf = self.column_family(column_family)?;
+            match value {
+                Some(value) => batch.put_cf(cf, key, value),
+                None => batch.delete_cf(cf, key),
+            }
+        }
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(sync);
+        Ok(self.db.write_opt(batch, &write_options)?)
+    }
+
+    fn scan_prefix(
+        &self,
+        column_family: &str,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        Ok(self
+            .db
+            .iterator_cf(
+                self.column_family(column_family)?,
+                IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+            )
+            .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+            .filter_map(|(key, value)| Some((String::from_utf8(key.to_vec()).ok()?, value.to_vec())))
+            .collect())
+    }
+
+    fn scan_page(
+        &self,
+        column_family: &str,
+        prefix: &str,
+        start: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        Ok(self
+            .db
+            .iterator_cf(
+                self.column_family(column_family)?,
+                IteratorMode::From(start.max(prefix).as_bytes(), Direction::Forward),
+            )
+            .take_while(|(key, _)| key.starts_with(prefix.as_bytes()))
+            .filter_map(|(key, value)| Some((String::from_utf8(key.to_vec()).ok()?, value.to_vec())))
+            .take(limit)
+            .collect())
+    }
+
+    fn flush(&self) -> Result<(), DbError> {
+        Ok(self.db.flush()?)
+    }
+}
+
+/// Stores every table in memory, for tests
+#[derive(Default)]
+pub struct MemoryBackend {
+    values: RwLock<BTreeMap<(String, String), Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn get(&self, column_family: &str, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+        let values = self.values.read().map_err(|_| DbError::Poisoned)?;
+        Ok(values
+            .get(&(column_family.to_owned(), key.to_owned()))
+            .cloned())
+    }
+
+    fn write(&self, writes: &PendingWrites, _sync: bool) -> Result<(), DbError> {
+        let mut values = self.values.write().map_err(|_| DbError::Poisoned)?;
+        for ((column_family, key), value) in writes {
+            let key = ((*column_family).to_owned(), key.clone());
+            match value {
+                Some(value) => values.insert(key, value.clone()),
+                None => values.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(
+        &self,
+        column_family: &str,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        let values = self.values.read().map_err(|_| DbError::Poisoned)?;
+        Ok(values
+            .range((column_family.to_owned(), prefix.to_owned())..)
+            .take_while(|((cf, key), _)| cf == column_family && key.starts_with(prefix))
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn scan_page(
+        &self,
+        column_family: &str,
+        prefix: &str,
+        start: &str,
+        limit: usize,
+    ) -> Result<Vec<(String, Vec<u8>)>, DbError> {
+        let values = self.values.read().map_err(|_| DbError::Poisoned)?;
+        Ok(values
+            .range((column_family.to_owned(), start.max(prefix).to_owned())..)
+            .take_while(|((cf, key), _)| cf == column_family && key.starts_with(prefix))
+            .take(limit)
+            .map(|((_, key), value)| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn flush(&self) -> Result<(), DbError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_backend() {
+        let backend = MemoryBackend::new();
+        let mut writes = PendingWrites::new();
+        writes.insert(("chainindex", "a/1".to_owned()), Some(b"x".to_vec()));
+        writes.insert(("chainindex", "a/2".to_owned()), Some(b"y".to_vec()));
+        writes.insert(("chainindex", "b/1".to_owned()), Some(b"z".to_vec()));
+        writes.insert(("invites", "a/3".to_owned()), Some(b"w".to_vec()));
+        backend.write(&writes, false).unwrap();
+        assert_eq!(backend.get("chainindex", "a/2").unwrap(), Some(b"y".to_vec()));
+        assert_eq!(backend.get("invites", "a/2").unwrap(), None);
+        let keys: Vec<String> = backend
+            .scan_prefix("chainindex", "a/")
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec!["a/1".to_owned(), "a/2".to_owned()]);
+        let mut deletes = PendingWrites::new();
+        deletes.insert(("chainindex", "a/1".to_owned()), None);
+        backend.write(&deletes, true).unwrap();
+        assert_eq!(backend.get("chainindex", "a/1").unwrap(), None);
+    }
+}
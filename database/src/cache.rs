@@ -0,0 +1,154 @@
+// This file is the optional read cache of the database, a LRU cache of values bounded by the bytes of the keys and values it holds.
+// It is write through: committed writes update it while holding its lock, so it never holds a value which differs from the one on disk.
+// Values are loaded without holding the lock; every commit bumps the caches generation, and a loaded value is only cached if no commit happened while it loaded.
+use lru::LruCache;
+use std::sync::Mutex;
+
+use crate::{store::DbError, PendingWrites};
+
+type CacheKey = (&'static str, String);
+
+lazy_static! {
+    /// The read cache, None if it is not enabled
+    static ref READ_CACHE: Mutex<Option<ReadCache>> = Mutex::new(None);
+}
+
+pub struct ReadCache {
+    values: LruCache<CacheKey, Vec<u8>>,
+    size: usize,
+    max_size: usize,
+    /// The number of commits written through the cache
+    generation: u64,
+}
+
+fn entry_size(key: &CacheKey, value: &[u8]) -> usize {
+    key.0.len() + key.1.len() + value.len()
+}
+
+impl ReadCache {
+    pub fn new(max_size: usize) -> ReadCache {
+        ReadCache {
+            values: LruCache::unbounded(),
+            size: 0,
+            max_size,
+            generation: 0,
+        }
+    }
+
+    /// Returns the number of bytes of keys and values the cache holds
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&mut self, key: &CacheKey) -> Option<Vec<u8>> {
+        self.values.get(key).cloned()
+    }
+
+    /// Caches the value, evicting the least recently used values until the cache fits in its max size
+    pub fn insert(&mut self, key: CacheKey, value: Vec<u8>) {
+        self.remove(&key);
+        let size = entry_size(&key, &value);
+        if size > self.max_size {
+            return;
+        }
+        self.size += size;
+        self.values.put(key, value);
+        while self.size > self.max_size {
+            match self.values.pop_lru() {
+                Some((key, value)) => self.size -= entry_size(&key, &value),
+                None => break,
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &CacheKey) {
+        if let Some(value) = self.values.pop(key) {
+            self.size -= entry_size(key, &value);
+        }
+    }
+}
+
+/// Enables the read cache, holding at most max_size bytes
+pub fn enable(max_size: usize) -> Result<(), DbError> {
+    *READ_CACHE.lock().map_err(|_| DbError::Poisoned)? = Some(ReadCache::new(max_size));
+    Ok(())
+}
+
+/// Empties the read cache, eg when the backend changes
+pub fn clear() -> Result<(), DbError> {
+    if let Some(cache) = &mut *READ_CACHE.lock().map_err(|_| DbError::Poisoned)? {
+        let generation = cache.generation + 1;
+        *cache = ReadCache::new(cache.max_size);
+        cache.generation = generation;
+    }
+    Ok(())
+}
+
+/// Returns the cached value of the key, or loads (and caches) it if it is not cached
+pub(crate) fn get_or_load(
+    column_family: &'static str,
+    key: &str,
+    load: impl FnOnce() -> Result<Option<Vec<u8>>, DbError>,
+) -> Result<Option<Vec<u8>>, DbError> {
+    let cache_key = (column_family, key.to_owned());
+    let generation = match &mut *READ_CACHE.lock().map_err(|_| DbError::Poisoned)? {
+        Some(cache) => {
+            if let Some(value) = cache.get(&cache_key) {
+                return Ok(Some(value));
+            }
+            cache.generation
+        }
+        None => return load(),
+    };
+    // load without holding the lock, so reads of other keys (and commits) are not blocked on the disk
+    let value = load()?;
+    if let Some(value) = &value {
+        if let Some(cache) = &mut *READ_CACHE.lock().map_err(|_| DbError::Poisoned)? {
+            // a commit while we loaded may have written the key, in which case the loaded value could be stale
+            if cache.generation == generation {
+                cache.insert(cache_key, value.clone());
+            }
+        }
+    }
+    Ok(value)
+}
+
+/// Calls write (which writes the writes to disk) and, if it succeeds, updates the cached values of the written keys
+pub(crate) fn write_through(
+    writes: &PendingWrites,
+    write: impl FnOnce() -> Result<(), DbError>,
+) -> Result<(), DbError> {
+    let mut cache_lock = READ_CACHE.lock().map_err(|_| DbError::Poisoned)?;
+    write()?;
+    if let Some(cache) = &mut *cache_lock {
+        cache.generation += 1;
+        for (key, value) in writes {
+            match value {
+                Some(value) => cache.insert(key.clone(), value.clone()),
+                None => cache.remove(key),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_cache_eviction() {
+        let mut cache = ReadCache::new(20);
+        cache.insert(("cf", "a".to_owned()), vec![0; 5]);
+        cache.insert(("cf", "b".to_owned()), vec![0; 5]);
+        assert_eq!(cache.size(), 16);
+        cache.get(&("cf", "a".to_owned()));
+        cache.insert(("cf", "c".to_owned()), vec![0; 5]);
+        assert!(cache.get(&("cf", "a".to_owned())).is_some());
+        assert!(cache.get(&("cf", "b".to_owned())).is_none());
+        cache.insert(("cf", "d".to_owned()), vec![0; 50]);
+        assert!(cache.get(&("cf", "d".to_owned())).is_none());
+        cache.remove(&("cf", "a".to_owned()));
+        assert_eq!(cache.size(), 8);
+    }
+}
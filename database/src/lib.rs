@@ -1,549 +1,413 @@
 extern crate avrio_config;
 extern crate num_cpus;
-use std::collections::HashMap;
-use std::sync::{
-    mpsc::{Receiver, Sender},
-    Mutex,
-};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
-use rocksdb::{DBRawIterator, IteratorMode, Options, DB};
+use rocksdb::DBRawIterator;
 use serde::{Deserialize, Serialize};
-use std::mem::size_of_val;
 use std::net::SocketAddr;
 
 use avrio_config::config;
-static CACHE_VALUES: bool = false; // should we use a memtable to store database values in memory (NO, this is not yet working)
-                                   // a lazy static muxtex (essentially a 'global' variable)
-                                   // The first hashmap is wrapped in Option (either None, or Some) for startup saftey
-                                   // It is indexed by hashes of paths of db. eg to get the db at path "~/leocornelius/some_db"
-                                   // You hash "~/leocornelius/some_db" and get the value from the hashmap
-                                   // This returns a tuple (HashMap, u16), the u16 acts a modified tag. If tha value is != 0 the database is marked as 'dirty'
-                                   // and a flush to disk operation is queued, the HashMap is the database itself, to get a value simply look up the corrosponding key in this hashmap
-                                   // NOTE: this is dev docs, for writing avrio_db functions, to get data from the databases please use the get_data and save_data wrappers
-
-// Complex types to satisfy most of clippy's nagging
-type Databases = Mutex<Option<HashMap<String, (HashMap<String, (String, u16)>, u16)>>>;
-type FlushStreamHandler = Mutex<Option<std::sync::mpsc::Sender<String>>>;
-type DatabaseFiles = Mutex<HashMap<String, rocksdb::DB>>;
-type DatabaseHashmap = HashMap<String, (HashMap<String, (String, u16)>, u16)>;
-type DatabaseLock<'a> =
-    std::sync::MutexGuard<'a, Option<HashMap<String, (HashMap<String, (String, u16)>, u16)>>>;
+
+pub mod backend;
+pub mod cache;
+pub mod migrate;
+pub mod schema;
+pub mod store;
+use backend::{RocksDbBackend, StorageBackend};
+use store::{DbError, Table};
+
+// Tables are stored through a StorageBackend: the one set with set_backend (the rocksdb database at database_path() unless set), or the one
+// a thread is running with (see with_backend), which lets tests use a MemoryBackend each, in parallel. A thread does not inherit the backend of
+// the thread which spawned it, threads which may be spawned under with_backend wrap their function with with_current_backend so they do.
+// Writes go straight to the backend, through rocksdb's write ahead log, so a write which returned is not lost if the process crashes.
+// A thread can group its writes into a batch (see store::atomic): they are held in PENDING_BATCHES (where the thread, and only it, reads them back)
+// and committed as one atomic write, synced to disk, so after a crash either every write of the batch is on disk or none are.
+// Side effects which must only happen once the writes are on disk (eg announcing a new epoch) are queued with store::after_commit and run after the commit.
+
+/// The writes of a batch, by (column family, key). None is a delete
+pub type PendingWrites = BTreeMap<(&'static str, String), Option<Vec<u8>>>;
+
+/// A function to run once the batch it was queued in is committed
+pub(crate) type AfterCommit = Box<dyn FnOnce() + Send>;
 
 lazy_static! {
-    static ref DATABASES: Databases = Mutex::new(None);
-    static ref FLUSH_STREAM_HANDLER: FlushStreamHandler = Mutex::new(None);
-    static ref DATABASEFILES: DatabaseFiles = Mutex::new(HashMap::new());
+    /// The backend of the database, every table is a column family (or a prefix of one) of it. The rocksdb database is opened on first use if it is not set
+    static ref DATABASE: Mutex<Option<Arc<dyn StorageBackend>>> = Mutex::new(None);
+    /// The backend of each thread running with its own (see with_backend)
+    static ref THREAD_BACKENDS: Mutex<HashMap<ThreadId, Arc<dyn StorageBackend>>> = Mutex::new(HashMap::new());
+    /// The uncommitted batch of each thread which is in one
+    static ref PENDING_BATCHES: Mutex<HashMap<ThreadId, PendingWrites>> = Mutex::new(HashMap::new());
+    /// The functions queued (with store::after_commit) by each thread which is in a batch, in the order they were queued
+    static ref AFTER_COMMIT: Mutex<HashMap<ThreadId, Vec<AfterCommit>>> = Mutex::new(HashMap::new());
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct PeerlistSave {
     peers: Vec<String>,
 }
-pub fn close_flush_stream() {
-    if CACHE_VALUES {
-        info!("Shutting down dirty page flusher stream");
-        if let Some(sender) = FLUSH_STREAM_HANDLER.lock().unwrap().clone() {
-            if let Err(e) = sender.send("stop".to_string()) {
-                error!(
-                "CRITICAL: failed to send stop message to dirty data flush stream. Got error={}",
-                e
-            );
-            } else {
-                info!("Safley shut down dirty data flush stream!");
-            }
+
+/// # Close database
+/// Flushes the database to disk and closes it, call before exiting
+pub fn close_database() {
+    let backend = match DATABASE.lock() {
+        Ok(mut database_lock) => database_lock.take(),
+        Err(_) => {
+            error!("Called close_database() but the database lock is poisoned");
+            return;
+        }
+    };
+    if let Some(backend) = backend {
+        info!("Flushing database to disk");
+        if let Err(e) = backend.flush() {
+            error!("CRITICAL: failed to flush database, got error={}", e);
         } else {
-            error!("Called close_flush_stream() but failed to get access to FLUSH_STRAM_HANDLER");
+            info!("Safley flushed and closed database!");
         }
     }
 }
 
 pub fn open_database(path: String) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
-    //  gain a lock on the DATABASES lazy_sataic
-    if CACHE_VALUES {
-        if let Ok(database_lock) = DATABASES.lock() {
-            // check if it contains a Some(x) value
-            if let Some(databases) = database_lock.clone() {
-                // it does; now check if the databases hashmap contains our path (eg is this db cached)
-                if databases.contains_key(&path) {
-                    //  we have this database cached, read from it
-                    trace!("Open database: Database cached (path={})", path);
-                    let mut return_databases: HashMap<String, String> = HashMap::new();
-                    for (key, (val, _)) in databases[&path].0.clone() {
-                        trace!("OD: key={}, val={}", key, val);
-                        return_databases.insert(key, val);
-                    }
-                    return Ok(return_databases);
-                }
-            }
-        }
+    // read every key of the table
+    Ok(entries(&Table::from_path(&path))?.into_iter().collect())
+}
+
+pub fn get_iterator(db: &rocksdb::DB) -> DBRawIterator {
+    db.raw_iterator()
+}
+
+/// # Init cache
+/// Opens the database and, if max_size is not 0, enables the read cache, holding at most max_size bytes of keys and values
+pub fn init_cache(max_size: usize) -> Result<(), Box<dyn std::error::Error>> {
+    // open the database now, so we fail at startup rather than on the first read
+    backend()?;
+    if max_size != 0 {
+        log::info!("Starting database read cache, max size (bytes)={}", max_size);
+        cache::enable(max_size)?;
     }
-    // we need to write to disc
-    let mut db_file_lock = DATABASEFILES.lock().unwrap();
-    let db_deref: DB;
-    let db: &DB;
-    match db_file_lock.keys().len() {
-        0 => {
-            let mut opts = Options::default();
-            opts.create_if_missing(true);
-            opts.set_skip_stats_update_on_db_open(false);
-            opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-            db_deref = DB::open(&opts, path).unwrap();
-            db = &db_deref;
-        }
-        _ => {
-            let db_hashmap = &mut *db_file_lock;
-            if db_hashmap.contains_key(&path) {
-                trace!("OD: db lock cached in hashmap");
-                db = &db_hashmap[&path];
-            } else {
-                trace!("OD: db lock not cached in hashmap");
-                let mut opts = Options::default();
-                opts.create_if_missing(true);
-                opts.set_skip_stats_update_on_db_open(false);
-                opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-                db_deref = DB::open(&opts, &path).unwrap();
-                db = &db_deref;
-                let cloned_path = path.clone();
-                trace!("Open database, reloading cache");
-                let _ = reload_cache(vec![cloned_path], &mut db_file_lock, &mut DATABASES.lock()?);
-                trace!("Finished reloading cache, continuing");
+    Ok(())
+}
+
+/// Returns the path of the database
+pub fn database_path() -> String {
+    config().db_path + "/db"
+}
+
+/// # Set backend
+/// Sets the backend every thread (which is not running with its own) stores the database in, replacing (and flushing) the current one
+pub fn set_backend(backend: Arc<dyn StorageBackend>) -> Result<(), DbError> {
+    let previous = DATABASE
+        .lock()
+        .map_err(|_| DbError::Poisoned)?
+        .replace(backend);
+    cache::clear()?;
+    if let Some(previous) = previous {
+        previous.flush()?;
+    }
+    Ok(())
+}
+
+/// # With backend
+/// Runs f with the current thread storing the database in backend instead of the shared one, eg a MemoryBackend in a test
+/// Threads f spawns only use backend if their function is wrapped with with_current_backend
+pub fn with_backend<T>(backend: Arc<dyn StorageBackend>, f: impl FnOnce() -> T) -> T {
+    struct ThreadBackendGuard(ThreadId);
+    impl Drop for ThreadBackendGuard {
+        fn drop(&mut self) {
+            if let Ok(mut thread_backends) = THREAD_BACKENDS.lock() {
+                thread_backends.remove(&self.0);
             }
         }
-    };
-    let mut return_databases: HashMap<String, String> = HashMap::new();
-    let iter = db.iterator(IteratorMode::Start); // Always iterates forward
-    for (key, value) in iter {
-        trace!(
-            "OD: saw {}, {}",
-            String::from_utf8(key.to_vec())?,
-            String::from_utf8(value.to_vec())?,
-        );
-        return_databases.insert(
-            String::from_utf8(key.to_vec())?,
-            String::from_utf8(value.to_vec())?,
-        );
     }
-    Ok(return_databases)
+    let id = thread::current().id();
+    if let Ok(mut thread_backends) = THREAD_BACKENDS.lock() {
+        thread_backends.insert(id, backend);
+    }
+    let _guard = ThreadBackendGuard(id);
+    f()
 }
 
-pub fn get_iterator(db: &rocksdb::DB) -> DBRawIterator {
-    db.raw_iterator()
+/// # With current backend
+/// Wraps f so it runs with the backend of the current thread if it is running with its own (see with_backend), pass the result to thread::spawn
+/// Without this a spawned thread stores the database in the shared backend, eg a test's background thread would write to the data dir in the config
+pub fn with_current_backend<T>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> impl FnOnce() -> T + Send + 'static {
+    let backend = thread_backend().ok().flatten();
+    move || match backend {
+        Some(backend) => with_backend(backend, f),
+        None => f(),
+    }
 }
 
-fn reload_cache(
-    db_paths: Vec<String>,
-    db_file_lock: &mut std::sync::MutexGuard<HashMap<String, rocksdb::DB>>,
-    db_lock: &mut DatabaseLock,
-) -> Result<(), Box<dyn std::error::Error>> {
-    debug!("Reloading cache for DBs: {:?}", db_paths);
-    let mut new_db_hashmap: HashMap<String, rocksdb::DB> = HashMap::new();
-    let mut additions = 0;
-    let mut unchanged = 0;
-    let mut databases_hashmap: DatabaseHashmap = HashMap::new();
-    for path in db_paths {
-        // iterate over all the new paths to add
-        trace!("Recaching {}", path);
-        let db: DB;
-        match db_file_lock.keys().len() {
-            0 => {
-                // the existing hashmap is empty, open the db from disk
-                let mut opts = Options::default();
-                opts.create_if_missing(true);
-                opts.set_skip_stats_update_on_db_open(false);
-                opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-                db = DB::open(&opts, &path)?;
-                additions += 1;
-            }
-            _ => {
-                // db file locks hashmap is not empty
-                let db_hashmap = &mut *db_file_lock;
-                if db_hashmap.contains_key(&path) {
-                    // the db files lock hashmap contains the database we are looking for, call remove on it to gain ownership of it
-                    db = db_hashmap.remove(&path).unwrap();
-                    unchanged += 1;
-                } else {
-                    // open the db file, we dont have it already
-                    let mut opts = Options::default();
-                    opts.create_if_missing(true);
-                    opts.set_skip_stats_update_on_db_open(false);
-                    opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-                    db = DB::open(&opts, &path)?;
-                    additions += 1;
-                }
-            }
-        };
-        if CACHE_VALUES {
-            // now we use the opened db to load all values into values_hashmap
-            let mut values_hashmap: HashMap<String, (String, u16)> = HashMap::new();
-
-            {
-                let db_iter = db.raw_iterator();
-                while db_iter.valid() {
-                    if let Some(key_bytes) = db_iter.key() {
-                        if let Ok(key) = String::from_utf8(Vec::from(key_bytes)) {
-                            // now get the value
-                            if let Some(value_bytes) = db_iter.value() {
-                                if let Ok(value) = String::from_utf8(Vec::from(value_bytes)) {
-                                    trace!("(DB={}) Got key={} for value={}", path, key, value);
-                                    // now put that into a hashmap
-                                    values_hashmap.insert(key, (value, 0));
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            // we now add values_hashmap to databases_hashmap
+/// Returns the backend of the current thread if it is running with its own
+fn thread_backend() -> Result<Option<Arc<dyn StorageBackend>>, DbError> {
+    Ok(THREAD_BACKENDS
+        .lock()
+        .map_err(|_| DbError::Poisoned)?
+        .get(&thread::current().id())
+        .cloned())
+}
+
+/// Returns the shared backend, opening the rocksdb database if no backend is set
+fn shared_backend() -> Result<Arc<dyn StorageBackend>, DbError> {
+    let mut database_lock = DATABASE.lock().map_err(|_| DbError::Poisoned)?;
+    if let Some(backend) = &*database_lock {
+        return Ok(backend.clone());
+    }
+    let backend: Arc<dyn StorageBackend> = Arc::new(RocksDbBackend::open(&database_path())?);
+    *database_lock = Some(backend.clone());
+    Ok(backend)
+}
 
-            databases_hashmap.insert(path.to_owned(), (values_hashmap, 0));
+/// Returns the backend the current thread stores the database in
+pub(crate) fn backend() -> Result<Arc<dyn StorageBackend>, DbError> {
+    match thread_backend()? {
+        Some(backend) => Ok(backend),
+        None => shared_backend(),
+    }
+}
+
+/// Returns the write of the key pending in the current threads batch, if it is in one and has written the key
+fn pending_write(column_family: &'static str, key: &str) -> Result<Option<Option<Vec<u8>>>, DbError> {
+    let batches = PENDING_BATCHES.lock().map_err(|_| DbError::Poisoned)?;
+    Ok(batches
+        .get(&thread::current().id())
+        .and_then(|writes| writes.get(&(column_family, key.to_owned())).cloned()))
+}
+
+/// Writes the writes to the backend as one atomic batch (synced to disk if sync is true), and through the read cache (which only caches the shared backend)
+fn commit(writes: &PendingWrites, sync: bool) -> Result<(), DbError> {
+    match thread_backend()? {
+        Some(backend) => backend.write(writes, sync),
+        None => {
+            let backend = shared_backend()?;
+            cache::write_through(writes, || backend.write(writes, sync))
         }
-        // now add the db object into the db locks hashmap
-        new_db_hashmap.insert(path, db); //  add DB lock file to new_db_hashmap
     }
-    // now we iterate over all the keys left over in the DATABASES lazy static, in other owrds we move the existing DBs into the new hashmap
-    let mut keys: Vec<String> = vec![];
+}
+
+/// Adds the write to the current threads batch, or commits it on its own if the thread is not in one
+fn apply_write(table: &Table, key: &str, value: Option<&[u8]>) -> Result<(), DbError> {
+    let write_key = (table.column_family(), table.key(key));
+    let value = value.map(<[u8]>::to_vec);
     {
-        // open a closure to drop the immutable borrow of db_file_lock created by the keys() function
-        // now we iterate over all the other open DBs
-        let local_keys = db_file_lock.keys().clone();
-        for key in local_keys {
-            // iterate over the vec of keys
-            keys.push(key.to_string());
+        let mut batches = PENDING_BATCHES.lock().map_err(|_| DbError::Poisoned)?;
+        if let Some(writes) = batches.get_mut(&thread::current().id()) {
+            writes.insert(write_key, value);
+            return Ok(());
         }
     }
-    for key in keys {
-        if db_file_lock.contains_key(&key) {
-            // thi should always be true, it checks if the key is contained in the db locks hashmap. If this ever returns false; its a bug
-            trace!("Moving DB lock file (key={}) to new hashmap", key);
-            let moved_db = db_file_lock.remove(&key).unwrap(); // get the DB object by key
-            unchanged += 1; // for logging purposes
-            new_db_hashmap.insert(key, moved_db); // insert it into the new ahshmap
-        } else {
-            error!(
-                "Unexpected error. Key listed in hm.keys() is not contained, key={}. THIS IS A BUG, please open a bug report at github",
-                key
-            );
+    let mut writes = PendingWrites::new();
+    writes.insert(write_key, value);
+    commit(&writes, false)
+}
+
+/// Starts a batch on the current thread, returns false if the thread is already in one
+pub(crate) fn begin_batch() -> Result<bool, DbError> {
+    let mut batches = PENDING_BATCHES.lock().map_err(|_| DbError::Poisoned)?;
+    let id = thread::current().id();
+    if batches.contains_key(&id) {
+        return Ok(false);
+    }
+    batches.insert(id, PendingWrites::new());
+    Ok(true)
+}
+
+/// Ends the batch of the current thread, committing its writes atomically if commit_writes is true and discarding them otherwise
+/// The functions queued during the batch are run once it is committed, and dropped if it is discarded (or the commit fails)
+pub(crate) fn end_batch(commit_writes: bool) -> Result<(), DbError> {
+    let id = thread::current().id();
+    let writes = PENDING_BATCHES
+        .lock()
+        .map_err(|_| DbError::Poisoned)?
+        .remove(&id);
+    let after_commit = AFTER_COMMIT
+        .lock()
+        .map_err(|_| DbError::Poisoned)?
+        .remove(&id)
+        .unwrap_or_default();
+    match writes {
+        Some(writes) if commit_writes => {
+            if !writes.is_empty() {
+                trace!("Committing batch of {} writes", writes.len());
+                commit(&writes, true)?;
+            }
+            for f in after_commit {
+                f();
+            }
+            Ok(())
         }
+        _ => Ok(()),
     }
-    let new_size = std::mem::size_of_val(&new_db_hashmap); // get the current size of the db_locks hashmap
-                                                           // now we need to set the lazy_static to new_hashmap
-    *(*db_file_lock) = new_db_hashmap;
-    debug!(
-        "Updated db_file_lock to new db hashmap, additions_count={}, unchanged_count={}, new_lock_cache_size={}",
-        additions, unchanged, new_size
-    );
-    if CACHE_VALUES {
-        trace!(
-            "Cached new valuess into global hashmap, new_values_cache_size={}",
-            std::mem::size_of_val(&databases_hashmap)
-        );
-        *(*db_lock) = Some(databases_hashmap);
-        trace!("Set db global varible to the database_hashmap");
+}
+
+/// Runs f once the current threads batch is committed (it is dropped if the batch is discarded), or now if the thread is not in one
+pub(crate) fn queue_after_commit(f: AfterCommit) -> Result<(), DbError> {
+    let id = thread::current().id();
+    if !PENDING_BATCHES
+        .lock()
+        .map_err(|_| DbError::Poisoned)?
+        .contains_key(&id)
+    {
+        f();
+        return Ok(());
     }
+    AFTER_COMMIT
+        .lock()
+        .map_err(|_| DbError::Poisoned)?
+        .entry(id)
+        .or_default()
+        .push(f);
     Ok(())
 }
-pub fn init_cache(
-    max_size: usize,
-) -> Result<(Sender<String>, std::thread::JoinHandle<()>), Box<dyn std::error::Error>> {
-    // max_size is the max memory to use for caching, in bytes. Eg 1000000000 = 1gb (TODO, limmit mem used to this)
-    // gain a lock on the DATABASES global varible to prevent people reading from it before we have assiged to it
-    let mut db_lock = DATABASES.lock()?;
-    trace!("Gained lock on lazy static");
-    // TODO move this to config
-    let to_cache_paths = /* (config().db_path + )*/ vec!["/chains/masterchainindex", "/chaindigest", "/peers"];
-    log::info!(
-        "Starting database cache, max size (bytes)={}, number_cachable_dbs={}",
-        max_size,
-        to_cache_paths.len()
-    );
-    let mut databases_hashmap: DatabaseHashmap = HashMap::new();
-    let mut database_lock_hashmap: HashMap<String, rocksdb::DB> = HashMap::new();
-    for raw_path in to_cache_paths {
-        let final_path = config().db_path + raw_path;
-        log::debug!("Caching db, path={}", final_path);
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.set_skip_stats_update_on_db_open(false);
-        opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-        let mut values_hashmap: HashMap<String, (String, u16)> = HashMap::new();
-        if CACHE_VALUES {
-            let db = DB::open(&opts, final_path.to_owned())?;
-            let db_iter = db.raw_iterator();
-            while db_iter.valid() {
-                if let Some(key_bytes) = db_iter.key() {
-                    if let Ok(key) = String::from_utf8(Vec::from(key_bytes)) {
-                        // now get the value
-                        if let Some(value_bytes) = db_iter.value() {
-                            if let Ok(value) = String::from_utf8(Vec::from(value_bytes)) {
-                                trace!("(DB={}) Got key={} for value={}", final_path, key, value);
-                                // now put that into a hashmap
-                                values_hashmap.insert(key, (value, 0));
-                            }
-                        }
-                    }
-                }
-            }
 
-            // get size of values_hashmap HashMap
-            let size_of_local = size_of_val(&values_hashmap);
-            // we have gone through every key value pair and added it to values_hashmap, now add the values_hashmap HashMap to the databases_hashmap HashMap
-            databases_hashmap.insert(final_path.to_owned(), (values_hashmap, 0));
-            let size_of_total = size_of_val(&databases_hashmap);
-            debug!(
-                "Cached db with path={}, db_hashmap_size={} bytes, databases_hashmap_size={} bytes",
-                final_path, size_of_local, size_of_total
-            );
-            if max_size != 0 {
-                debug!(
-                    "Used {}% of allocated cache memory ({}/{} bytes)",
-                    size_of_total / max_size,
-                    size_of_local,
-                    size_of_total
-                );
+pub(crate) fn read_value(table: &Table, key: &str) -> Result<Option<Vec<u8>>, DbError> {
+    let key = table.key(key);
+    if let Some(pending) = pending_write(table.column_family(), &key)? {
+        return Ok(pending);
+    }
+    match thread_backend()? {
+        Some(backend) => backend.get(table.column_family(), &key),
+        None => cache::get_or_load(table.column_family(), &key, || {
+            shared_backend()?.get(table.column_family(), &key)
+        }),
+    }
+}
+
+pub(crate) fn write_value(table: &Table, key: &str, value: &[u8]) -> Result<(), DbError> {
+    apply_write(table, key, Some(value))
+}
+
+pub(crate) fn remove_value(table: &Table, key: &str) -> Result<(), DbError> {
+    apply_write(table, key, None)
+}
+
+/// Returns every (key, value) of the table (including the writes pending in the current threads batch), values which are not utf8 are skipped
+pub(crate) fn entries(table: &Table) -> Result<Vec<(String, String)>, DbError> {
+    let prefix = table.key("");
+    let mut values: BTreeMap<String, Vec<u8>> = backend()?
+        .scan_prefix(table.column_family(), &prefix)?
+        .into_iter()
+        .map(|(key, value)| (key[prefix.len()..].to_owned(), value))
+        .collect();
+    if let Some(writes) = PENDING_BATCHES
+        .lock()
+        .map_err(|_| DbError::Poisoned)?
+        .get(&thread::current().id())
+    {
+        for ((column_family, key), value) in writes {
+            if *column_family != table.column_family() || !key.starts_with(&prefix) {
+                continue;
             }
+            let key = key[prefix.len()..].to_owned();
+            match value {
+                Some(value) => values.insert(key, value.clone()),
+                None => values.remove(&key),
+            };
         }
-        // (re)open the db
-        let db_new = DB::open(&opts, final_path.to_owned())?;
-        // now we add the on-disk DB lock to DATABASEFILES
-        database_lock_hashmap.insert(final_path.to_string(), db_new);
     }
-    let percent_usage: f64 =
-        ((size_of_val(&databases_hashmap) + size_of_val(&database_lock_hashmap)) / max_size) as f64;
-    debug!(
-        "Cached all DB's, total used mem={}, set_max={} ({}%)",
-        size_of_val(&databases_hashmap) + size_of_val(&database_lock_hashmap),
-        max_size,
-        percent_usage
-    );
-    // now we need to set the DATABASES global var to this
-    trace!("Allocating to databases");
-    *db_lock = Some(databases_hashmap);
-    trace!("Set db global varible to the database_hashmap");
-    // now set the DATABASE_FILES lazy static
-    trace!("Adding on-disk database locks to lazy_static");
-    let mut db_file_lock = DATABASEFILES.lock()?;
-    *db_file_lock = database_lock_hashmap;
-    trace!("Added on-disk database locks to memory");
-
-    // all done, launch the dirty data flush thread
-    let (send, recv) = std::sync::mpsc::channel();
-    let flush_handler = std::thread::spawn(move || {
-        if CACHE_VALUES {
-            debug!(
-                "Rrunning flush_dirty_data_to_disk, CACHE_VALUES={}",
-                CACHE_VALUES
-            );
-            let _ = flush_dirty_to_disk(recv).expect("Flush dirty page function returned an error");
-        } else {
-            debug!(
-                "Not running flush_dirty_data_to_disk, CACHE_VALUES={}",
-                CACHE_VALUES
-            );
-        }
-    });
-    *FLUSH_STREAM_HANDLER.lock().unwrap() = Some(send.clone()); // set global var
-    Ok((send, flush_handler))
+    Ok(values
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, String::from_utf8(value).ok()?)))
+        .collect())
 }
 
-fn flush_dirty_to_disk(rec: Receiver<String>) -> Result<(), Box<dyn std::error::Error>> {
-    debug!("Starting dirty flush loop");
+/// Returns at most limit (key, value) pairs of the table with keys after after (from the first key if it is None), sorted by key and including the writes pending
+/// in the current threads batch. Pass the last key returned as after to read the next page, an empty page means there are no more entries
+pub(crate) fn entries_page(
+    table: &Table,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<Vec<(String, String)>, DbError> {
+    let prefix = table.key("");
+    let backend = backend()?;
+    let mut after = after.map(|after| after.to_owned());
     loop {
-        let mut to_break = false;
-        if let Ok(mesg) = rec.try_recv() {
-            if mesg == "stop" {
-                to_break = true;
-            }
-        }
-        std::thread::sleep(std::time::Duration::from_millis(5000)); // check every 5 secconds for dirty entries
+        // the smallest key greater than after is after followed by a nul byte
+        let start = match &after {
+            Some(after) => format!("{}{}\0", prefix, after),
+            None => prefix.clone(),
+        };
+        let scanned = backend.scan_page(table.column_family(), &prefix, &start, limit)?;
+        // a full page may be followed by more stored keys, pending writes past its last key belong to a later page
+        let last = if scanned.len() == limit {
+            scanned.last().map(|(key, _)| key[prefix.len()..].to_owned())
+        } else {
+            None
+        };
+        let mut values: BTreeMap<String, Vec<u8>> = scanned
+            .into_iter()
+            .map(|(key, value)| (key[prefix.len()..].to_owned(), value))
+            .collect();
+        if let Some(writes) = PENDING_BATCHES
+            .lock()
+            .map_err(|_| DbError::Poisoned)?
+            .get(&thread::current().id())
         {
-            let mut db_lock = DATABASES.lock()?; // get a lock
-            if let Some(mut db_lock_val) = db_lock.clone() {
-                for (path, db_tuple) in db_lock_val.clone() {
-                    if db_tuple.1 != 0 {
-                        trace!(
-                            /*target="dirty flush loop", */
-                            "path={}, dirty=true",
-                            path,
-                        );
-                        // we need to write to disc
-                        let mut db_file_lock = DATABASEFILES.lock()?;
-                        let db_deref: DB;
-                        let db: &DB;
-                        match db_file_lock.keys().len() {
-                            0 => {
-                                let mut opts = Options::default();
-                                opts.create_if_missing(true);
-                                opts.set_skip_stats_update_on_db_open(false);
-                                opts.increase_parallelism(
-                                    ((1.0 / 3.0) * num_cpus::get() as f64) as i32,
-                                );
-                                db_deref = DB::open(&opts, &path)?;
-                                db = &db_deref;
-                            }
-                            _ => {
-                                let db_hashmap = &mut *db_file_lock;
-                                if db_hashmap.contains_key(&path) {
-                                    db = &db_hashmap[&path];
-                                } else {
-                                    let mut opts = Options::default();
-                                    opts.create_if_missing(true);
-                                    opts.set_skip_stats_update_on_db_open(false);
-                                    opts.increase_parallelism(
-                                        ((1.0 / 3.0) * num_cpus::get() as f64) as i32,
-                                    );
-                                    db_deref = DB::open(&opts, &path)?;
-                                    db = &db_deref;
-                                    let cloned_path = path.clone();
-                                    trace!("Dirty dataflush stream, reloading cache");
-                                    let _ = reload_cache(
-                                        vec![cloned_path],
-                                        &mut db_file_lock,
-                                        &mut db_lock,
-                                    );
-                                    trace!("Finished reloading cache, continuing");
-                                }
-                            }
-                        };
-                        for (key, value) in db_tuple.0 {
-                            if value.1 != 0 {
-                                if let Err(e) = db.put(key.clone(), value.0.clone()) {
-                                    error!("Failed to save data to db, gave error: {}", e);
-                                } else {
-                                    trace!(
-                                        "flushed data to db: {}, key: {}, value, {}",
-                                        db.path().display(),
-                                        key,
-                                        value.0
-                                    );
-                                    // set per-value dirty flag to 0 (clean)
-                                    //     let mut reset_bit_buffer = db_lock_val[&path].0.clone();
-                                    let mut reset_bit = db_lock_val.get_mut(&key).unwrap();
-                                    reset_bit.1 = 0;
-                                    *db_lock_val.get_mut(&path).unwrap() = reset_bit.clone();
-                                    *db_lock_val.get_mut(&path).unwrap() =
-                                        (db_lock_val[&path].0.clone(), db_lock_val[&path].1);
-                                }
-                            }
-                        }
-                        // set per-database dirty flag to 0 (clean)
-                        *db_lock_val.get_mut(&path).unwrap() = (db_lock_val[&path].0.clone(), 0);
-                    } else {
-                        trace!(
-                            /*target="dirty flush loop", */
-                            "path={}, dirty=false",
-                            path,
-                        );
-                    }
+            for ((column_family, key), value) in writes {
+                if *column_family != table.column_family() || !key.starts_with(&prefix) {
+                    continue;
                 }
+                let key = key[prefix.len()..].to_owned();
+                if after.as_ref().map_or(false, |after| key <= *after)
+                    || last.as_ref().map_or(false, |last| key > *last)
+                {
+                    continue;
+                }
+                match value {
+                    Some(value) => values.insert(key, value.clone()),
+                    None => values.remove(&key),
+                };
             }
-            if to_break {
-                break;
-            }
         }
+        // every stored key of the page was deleted in the batch, move on rather than reporting the end of the table
+        if values.is_empty() && last.is_some() {
+            after = last;
+            continue;
+        }
+        return Ok(values
+            .into_iter()
+            .take(limit)
+            .filter_map(|(key, value)| Some((key, String::from_utf8(value).ok()?)))
+            .collect());
     }
-    Ok(())
 }
 
+/// # Save data
+/// Legacy, use store::put or store::put_raw (which report why a write failed) in new code
+/// Returns 1 on success and 0 on failure
 pub fn save_data(serialized: &str, path: &str, key: String) -> u8 {
-    if CACHE_VALUES {
-        //  gain a lock on the DATABASES lazy_sataic
-        if let Ok(mut database_lock) = DATABASES.lock() {
-            // check if it contains a Some(x) value
-            if let Some(databases) = database_lock.clone() {
-                // it does; now check if the databases hashmap contains our path (eg is this db cached)
-                if databases.contains_key(path) {
-                    //  we have this database cached, read from it
-                    // safe to get the value
-                    let mut db = databases[path].clone().0;
-                    // write to our local copy of the lazy_static
-                    db.insert(key.to_string(), (serialized.to_owned(), 1));
-                    trace!(
-                        "Updated DB cache, path={}, key={}, serialized={}",
-                        path,
-                        key,
-                        serialized
-                    );
-                    // now update the global lazy_static
-                    *database_lock = Some(databases);
-                    return 1;
-                }
-            }
-        }
-    }
     // used to save data without having to create 1000's of functions (eg saveblock, savepeerlist, ect)
-    // we need to write to disc
-    let mut db_file_lock = DATABASEFILES.lock().unwrap();
-    let db_deref: DB;
-    let db: &DB;
-    match db_file_lock.keys().len() {
-        0 => {
-            debug!("db_file_lock contains no keys");
-            let mut opts = Options::default();
-            opts.create_if_missing(true);
-            opts.set_skip_stats_update_on_db_open(false);
-            opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-            db_deref = DB::open(&opts, path).unwrap();
-            db = &db_deref;
+    match write_value(&Table::from_path(path), &key, serialized.as_bytes()) {
+        Ok(()) => {
+            trace!("set data to db: {}, key: {}, value, {}", path, key, serialized);
+            1
         }
-        _ => {
-            trace!("db_file_lock contains keys");
-            let db_hashmap = &mut *db_file_lock;
-            if db_hashmap.contains_key(path) {
-                db = &db_hashmap[path];
-            } else {
-                let mut opts = Options::default();
-                opts.create_if_missing(true);
-                opts.set_skip_stats_update_on_db_open(false);
-                opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-                db_deref = DB::open(&opts, &path).unwrap();
-                db = &db_deref;
-                let cloned_path = path.to_string();
-                let try_lock = DATABASES.lock();
-                if let Ok(mut db_lock) = try_lock {
-                    trace!("Save data, reloading cache");
-                    let _ = reload_cache(vec![cloned_path], &mut db_file_lock, &mut db_lock);
-                    trace!("Finished reloading cache, continuing");
-                } else {
-                    error!("Save data, failed to gain lock on DATABASES mutex; skipping recache, error={}", try_lock.unwrap_err());
-                }
-            }
+        Err(e) => {
+            error!("Failed to save data to db, gave error: {}", e);
+            0
         }
-    };
-
-    if let Err(e) = db.put(key.clone(), serialized.to_string()) {
-        error!("Failed to save data to db, gave error: {}", e);
-
-        0
-    } else {
-        trace!(
-            "set data to db: {}, key: {}, value, {}",
-            db.path().display(),
-            key,
-            serialized
-        );
-
-        1
     }
 }
 
 pub fn get_peerlist() -> std::result::Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
-    let s = get_data(config().db_path + &"/peers".to_string(), &"white");
+    match store::get::<PeerlistSave>(&Table::Peers, "white")? {
+        Some(peerlist) => {
+            let mut as_socket_addr: Vec<SocketAddr> = vec![];
 
-    if s == *"-1" {
-        Err("peerlist not found".into())
-    } else {
-        let peerlist: PeerlistSave = serde_json::from_str(&s)?;
-        let mut as_socket_addr: Vec<SocketAddr> = vec![];
+            for peer in peerlist.peers {
+                as_socket_addr.push(peer.parse()?);
+            }
 
-        for peer in peerlist.peers {
-            as_socket_addr.push(peer.parse()?);
+            Ok(as_socket_addr)
         }
-
-        Ok(as_socket_addr)
+        None => Err("peerlist not found".into()),
     }
 }
 
@@ -564,110 +428,30 @@ pub fn save_peerlist(list: &[SocketAddr]) -> std::result::Result<(), Box<dyn std
         as_string.peers.push(peer.to_string());
     }
 
-    let s = serde_json::to_string(&as_string)?;
-
-    save_data(
-        &s,
-        &(config().db_path + &"/peers".to_string()),
-        "white".to_string(),
-    );
+    store::put(&Table::Peers, "white", &as_string)?;
 
     Ok(())
 }
 
+/// # Get data
+/// Legacy, use store::get or store::get_raw (which tell a missing key apart from a failed read) in new code
+/// Returns "-1" if the key is missing, "0" if the read failed and "" if the value is not utf8
 pub fn get_data(dbpath: String, key: &str) -> String {
-    if CACHE_VALUES {
-        //  gain a lock on the DATABASES lazy_sataic
-        if let Ok(database_lock) = DATABASES.lock() {
-            // check if it contains a Some(x) value
-            if let Some(databases) = database_lock.clone() {
-                // it does; now check if the databases hashmap contains our path (eg is this db cached)
-                if databases.contains_key(&dbpath) {
-                    //  we have this database cached, read from it
-                    // safe to get the value
-                    let db = databases[&dbpath].clone().0;
-                    // does the database cache have this value?
-                    if db.contains_key(key) {
-                        //  we have this database cached, read from it
-                        // safe to get the value
-                        let val = db[key].clone().0;
-                        trace!(
-                            "Got data from DB cache, path={}, key={}, value={}",
-                            dbpath,
-                            key,
-                            val
-                        );
-                        return val;
-                        // return the first element of the tuple (the string value)
-                    } // we dont have this key-value pair cached we continue with reading from disk to be sure we are not missing data that has not been cached
-                }
-            }
-        }
-    }
-    // either did not have:
-    // 1) the database cached
-    // or 2) the key cached
-    // therefore we read from disk to be sure we dont have this value there instead
-    let mut opts = Options::default();
-    opts.create_if_missing(true);
-    opts.set_skip_stats_update_on_db_open(false);
-    opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-    let data: String;
-    // we need to write to disc
-    let mut db_file_lock = DATABASEFILES.lock().unwrap();
-    let db_deref: DB;
-    let db: &DB;
-    match db_file_lock.keys().len() {
-        0 => {
-            let mut opts = Options::default();
-            opts.create_if_missing(true);
-            opts.set_skip_stats_update_on_db_open(false);
-            opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-            db_deref = DB::open(&opts, &dbpath).unwrap();
-            db = &db_deref;
-        }
-        _ => {
-            let db_hashmap = &mut *db_file_lock;
-            if db_hashmap.contains_key(&dbpath) {
-                db = &db_hashmap[&dbpath];
-            } else {
-                let mut opts = Options::default();
-                opts.create_if_missing(true);
-                opts.set_skip_stats_update_on_db_open(false);
-                opts.increase_parallelism(((1.0 / 3.0) * num_cpus::get() as f64) as i32);
-                db_deref = DB::open(&opts, &dbpath).unwrap();
-                db = &db_deref;
-                let cloned_path = dbpath.clone();
-                let try_lock = DATABASES.lock();
-                if let Ok(mut db_lock) = try_lock {
-                    trace!("Get data, reloading cache");
-                    let _ = reload_cache(vec![cloned_path], &mut db_file_lock, &mut db_lock);
-                    trace!("Finished reloading cache, continuing");
-                } else {
-                    error!("Get data, failed to gain lock on DATABASES mutex; skipping recache, error={}", try_lock.unwrap_err());
-                }
-            }
-        }
-    };
-    match db.get(key) {
+    match read_value(&Table::from_path(&dbpath), key) {
         Ok(Some(value)) => {
-            data = String::from_utf8(value).unwrap_or_else(|_| "".to_owned());
-
+            let data = String::from_utf8(value).unwrap_or_else(|_| "".to_owned());
             trace!("got data from db={}, data={}, key={}", dbpath, data, key);
+            data
         }
-
         Ok(None) => {
-            data = "-1".to_owned();
-
             trace!("got data from db={}, data=None, key={}", dbpath, key);
+            "-1".to_owned()
         }
-
         Err(e) => {
-            data = "0".to_owned();
             error!("Error {} getting data from db", e);
+            "0".to_owned()
         }
     }
-    data
 }
 
 pub fn get_data_from_database(db: &HashMap<String, String>, key: &str) -> String {
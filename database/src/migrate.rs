@@ -0,0 +1,322 @@
+// This file holds the migrations of old data directories, run in order by schema::upgrade.
+// Schema version 0 is the legacy layout, with one rocksdb database per table (eg /chains/<key>-chainindex, /wallets/<name>, /invites), migrated
+// to the single database with a column family per table. In version 1 accounts and usernames were still stored as one file per value
+// (/accounts/<publickey>.account, /usernames/<hash>.uname), they are migrated into their tables. In version 2 each block was its own file
+// (/blocks/blk-<hash>.dat), they are migrated into the blocks table.
+// The legacy databases and files are only read, so they are left in place and can be deleted once the migrated node has been checked.
+use avrio_config::config;
+use rocksdb::{IteratorMode, Options, DB};
+use std::path::{Path, PathBuf};
+
+use crate::{
+    backend, database_path,
+    store::{self, DbError, Table},
+    PendingWrites,
+};
+
+/// The key in the meta table set once the legacy databases have been migrated
+const MIGRATED_KEY: &str = "legacy-migrated";
+
+/// The number of files migrated in each batch, bounding the memory used by the migration
+const FILES_PER_BATCH: usize = 10000;
+
+/// The directories of the legacy layout which hold one file per value, with the extension of the files and the table they are migrated into
+const LEGACY_FILE_TABLES: &[(&str, &str, Table)] = &[
+    ("accounts", ".account", Table::Accounts),
+    ("usernames", ".uname", Table::Usernames),
+];
+
+/// Returns true if the directory is a rocksdb database
+fn is_database(path: &Path) -> bool {
+    path.join("CURRENT").is_file()
+}
+
+/// Returns the names (paths relative to the data directory) of the legacy databases in the data directory
+pub fn legacy_databases(db_path: &str) -> Vec<String> {
+    let mut found: Vec<String> = vec![];
+    for dir in &["", "chains", "wallets"] {
+        let read_dir = match std::fs::read_dir(Path::new(db_path).join(dir)) {
+            Ok(read_dir) => read_dir,
+            Err(_) => continue,
+        };
+        for entry in read_dir.flatten() {
+            let path = entry.path();
+            if path == Path::new(&database_path()) || !is_database(&path) {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                found.push(if dir.is_empty() {
+                    name.to_owned()
+                } else {
+                    format!("{}/{}", dir, name)
+                });
+            }
+        }
+    }
+    found.sort();
+    found
+}
+
+/// Returns the (path, table, key) of each legacy value file in the data directory
+pub fn legacy_files(db_path: &str) -> Vec<(PathBuf, Table, String)> {
+    let mut found: Vec<(PathBuf, Table, String)> = vec![];
+    for (dir, extension, table) in LEGACY_FILE_TABLES {
+        let read_dir = match std::fs::read_dir(Path::new(db_path).join(dir)) {
+            Ok(read_dir) => read_dir,
+            Err(_) => continue,
+        };
+        for entry in read_dir.flatten() {
+            if let Some(key) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(extension))
+            {
+                found.push((entry.path(), table.clone(), key.to_owned()));
+            }
+        }
+    }
+    found
+}
+
+/// Returns the (path, hash) of each legacy block file in the data directory
+pub fn legacy_block_files(db_path: &str) -> Vec<(PathBuf, String)> {
+    let read_dir = match std::fs::read_dir(Path::new(db_path).join("blocks")) {
+        Ok(read_dir) => read_dir,
+        Err(_) => return vec![],
+    };
+    read_dir
+        .flatten()
+        .filter_map(|entry| {
+            let hash = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("blk-")?
+                .strip_suffix(".dat")?
+                .to_owned();
+            Some((entry.path(), hash))
+        })
+        .collect()
+}
+
+/// Returns true if the legacy databases have been migrated
+pub fn legacy_databases_migrated() -> Result<bool, DbError> {
+    store::contains(&Table::Meta, MIGRATED_KEY)
+}
+
+/// # Migrate legacy databases
+/// Copies every key of every legacy database into its table of the database, one atomic batch per legacy database
+/// Safe to run again if interrupted (keys are overwritten with the same values). Returns the number of keys migrated
+pub fn migrate_legacy_databases() -> Result<u64, Box<dyn std::error::Error>> {
+    migrate_legacy_databases_in(&config().db_path)
+}
+
+fn migrate_legacy_databases_in(db_path: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let legacy = legacy_databases(db_path);
+    info!(
+        "Migrating {} legacy databases in {} into {}",
+        legacy.len(),
+        db_path,
+        database_path()
+    );
+    let backend = backend()?;
+    let mut migrated: u64 = 0;
+    for (index, name) in legacy.iter().enumerate() {
+        let table = Table::from_name(name);
+        let legacy_db =
+            DB::open_for_read_only(&Options::default(), Path::new(db_path).join(name), false)?;
+        let mut writes = PendingWrites::new();
+        for (key, value) in legacy_db.iterator(IteratorMode::Start) {
+            writes.insert(
+                (
+                    table.column_family(),
+                    table.key(&String::from_utf8(key.to_vec())?),
+                ),
+                Some(value.to_vec()),
+            );
+        }
+        backend.write(&writes, true)?;
+        migrated += writes.len() as u64;
+        debug!(
+            "Migrated {} into table {}, keys={}",
+            name,
+            table,
+            writes.len()
+        );
+        if (index + 1) % 100 == 0 {
+            info!(
+                "Migrated {}/{} legacy databases ({} keys)",
+                index + 1,
+                legacy.len(),
+                migrated
+            );
+        }
+    }
+    store::put_raw(&Table::Meta, MIGRATED_KEY, "1")?;
+    info!(
+        "Migrated {} keys from {} legacy databases, they can be deleted once the node has been checked",
+        migrated,
+        legacy.len()
+    );
+    Ok(migrated)
+}
+
+/// # Migrate legacy files
+/// Copies every account and username file into its table of the database, in atomic batches of FILES_PER_BATCH files.
+/// Safe to run again if interrupted. Returns the number of files migrated
+pub fn migrate_legacy_files() -> Result<u64, Box<dyn std::error::Error>> {
+    migrate_legacy_files_in(&config().db_path)
+}
+
+fn migrate_legacy_files_in(db_path: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let files = legacy_files(db_path);
+    info!("Migrating {} account and username files", files.len());
+    let backend = backend()?;
+    for (index, batch) in files.chunks(FILES_PER_BATCH).enumerate() {
+        let mut writes = PendingWrites::new();
+        for (path, table, key) in batch {
+            writes.insert(
+                (table.column_family(), table.key(key)),
+                Some(std::fs::read(path)?),
+            );
+        }
+        backend.write(&writes, true)?;
+        info!(
+            "Migrated {}/{} account and username files",
+            index * FILES_PER_BATCH + batch.len(),
+            files.len()
+        );
+    }
+    info!(
+        "Migrated {} account and username files, they can be deleted once the node has been checked",
+        files.len()
+    );
+    Ok(files.len() as u64)
+}
+
+/// # Migrate block files
+/// Copies every block file into the blocks table, in atomic batches of FILES_PER_BATCH files. The files hold the compressed encoding of the block,
+/// which is what the blocks table stores. Safe to run again if interrupted. Returns the number of blocks migrated
+pub fn migrate_block_files() -> Result<u64, Box<dyn std::error::Error>> {
+    migrate_block_files_in(&config().db_path)
+}
+
+fn migrate_block_files_in(db_path: &str) -> Result<u64, Box<dyn std::error::Error>> {
+    let files = legacy_block_files(db_path);
+    info!("Migrating {} block files", files.len());
+    let backend = backend()?;
+    for (index, batch) in files.chunks(FILES_PER_BATCH).enumerate() {
+        let mut writes = PendingWrites::new();
+        for (path, hash) in batch {
+            writes.insert(
+                (Table::Blocks.column_family(), Table::Blocks.key(hash)),
+                Some(std::fs::read(path)?),
+            );
+        }
+        backend.write(&writes, true)?;
+        info!(
+            "Migrated {}/{} block files",
+            index * FILES_PER_BATCH + batch.len(),
+            files.len()
+        );
+    }
+    info!(
+        "Migrated {} block files, the blocks directory can be deleted once the node has been checked",
+        files.len()
+    );
+    Ok(files.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::MemoryBackend, with_backend};
+    use std::{fs, sync::Arc};
+
+    /// Creates an empty data directory for the test, removing any left by a previous run
+    fn data_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("avrio-migrate-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_migrate_legacy_databases() {
+        let dir = data_dir("databases");
+        let db_path = dir.to_str().unwrap();
+        for (name, key, value) in &[
+            ("chains/chainkey-chainindex", "txncount", "2"),
+            ("wallets/main", "publickey", "walletkey"),
+        ] {
+            let legacy_db = DB::open_default(dir.join(name)).unwrap();
+            legacy_db.put(key, value).unwrap();
+        }
+        assert_eq!(
+            legacy_databases(db_path),
+            vec!["chains/chainkey-chainindex", "wallets/main"]
+        );
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert!(!legacy_databases_migrated().unwrap());
+            assert_eq!(migrate_legacy_databases_in(db_path).unwrap(), 2);
+            assert!(legacy_databases_migrated().unwrap());
+            assert_eq!(
+                store::get_raw(&Table::ChainIndex("chainkey".to_owned()), "txncount").unwrap(),
+                Some("2".to_owned())
+            );
+            assert_eq!(
+                store::get_raw(&Table::Wallet("main".to_owned()), "publickey").unwrap(),
+                Some("walletkey".to_owned())
+            );
+            // running it again migrates the same keys
+            assert_eq!(migrate_legacy_databases_in(db_path).unwrap(), 2);
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_legacy_files() {
+        let dir = data_dir("files");
+        fs::create_dir_all(dir.join("accounts")).unwrap();
+        fs::create_dir_all(dir.join("usernames")).unwrap();
+        fs::write(dir.join("accounts/publickey.account"), "account").unwrap();
+        fs::write(dir.join("usernames/namehash.uname"), "publickey").unwrap();
+        fs::write(dir.join("accounts/notes.txt"), "not an account").unwrap();
+        let db_path = dir.to_str().unwrap();
+        assert_eq!(legacy_files(db_path).len(), 2);
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert_eq!(migrate_legacy_files_in(db_path).unwrap(), 2);
+            assert_eq!(
+                store::get_raw(&Table::Accounts, "publickey").unwrap(),
+                Some("account".to_owned())
+            );
+            assert_eq!(
+                store::get_raw(&Table::Usernames, "namehash").unwrap(),
+                Some("publickey".to_owned())
+            );
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_block_files() {
+        let dir = data_dir("blocks");
+        fs::create_dir_all(dir.join("blocks")).unwrap();
+        for hash in &["hasha", "hashb"] {
+            fs::write(dir.join(format!("blocks/blk-{}.dat", hash)), hash).unwrap();
+        }
+        fs::write(dir.join("blocks/index.dat"), "not a block").unwrap();
+        let db_path = dir.to_str().unwrap();
+        assert_eq!(legacy_block_files(db_path).len(), 2);
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            assert_eq!(migrate_block_files_in(db_path).unwrap(), 2);
+            for hash in &["hasha", "hashb"] {
+                assert_eq!(
+                    store::get_raw(&Table::Blocks, hash).unwrap(),
+                    Some(hash.to_string())
+                );
+            }
+        });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+// This file versions the layout and encodings of the data directory. The schema version is stored in the meta table and checked at startup:
+// older data directories are upgraded in place by running the migrations from their version in order, newer ones are refused.
+// Changing the layout or an encoding means bumping SCHEMA_VERSION and adding the migration from the previous version to MIGRATIONS.
+use avrio_config::config;
+use std::time::Instant;
+use thiserror::Error;
+
+use crate::{
+    migrate,
+    store::{self, DbError, Table},
+};
+
+/// The schema version this binary reads and writes
+pub const SCHEMA_VERSION: u64 = 3;
+
+/// The key of the schema version in the meta table
+const VERSION_KEY: &str = "schema-version";
+
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("Data directory has schema version {found}, which is newer than version {expected} this binary supports. Upgrade avrio-daemon")]
+    TooNew { found: u64, expected: u64 },
+    #[error("No migration from schema version {0}, the data directory is too old to upgrade. Resync from scratch")]
+    NoMigration(u64),
+    #[error("Migration from schema version {from} failed: {reason}")]
+    MigrationFailed { from: u64, reason: String },
+    #[error("{0}")]
+    Db(#[from] DbError),
+}
+
+pub struct Migration {
+    /// The version the migration upgrades from (to from + 1)
+    pub from: u64,
+    pub description: &'static str,
+    /// Runs the migration, returning the number of keys migrated
+    pub run: fn() -> Result<u64, Box<dyn std::error::Error>>,
+}
+
+/// The migrations, in order
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "Move the one database per table layout into column families of a single database",
+        run: migrate::migrate_legacy_databases,
+    },
+    Migration {
+        from: 1,
+        description: "Move the account and username files into their tables",
+        run: migrate::migrate_legacy_files,
+    },
+    Migration {
+        from: 2,
+        description: "Move the block files into the blocks table",
+        run: migrate::migrate_block_files,
+    },
+];
+
+/// Returns the schema version recorded in the database, if there is one
+pub fn recorded_version() -> Result<Option<u64>, DbError> {
+    store::get(&Table::Meta, VERSION_KEY)
+}
+
+/// # Detect version
+/// Returns the schema version of the data directory. Data directories written before the version was recorded are detected by their layout,
+/// a new data directory has the current version
+pub fn detect_version() -> Result<u64, DbError> {
+    if let Some(version) = recorded_version()? {
+        return Ok(version);
+    }
+    let db_path = config().db_path;
+    if !migrate::legacy_databases(&db_path).is_empty() && !migrate::legacy_databases_migrated()? {
+        return Ok(0);
+    }
+    if !migrate::legacy_files(&db_path).is_empty() {
+        return Ok(1);
+    }
+    if !migrate::legacy_block_files(&db_path).is_empty() {
+        return Ok(2);
+    }
+    Ok(SCHEMA_VERSION)
+}
+
+/// # Upgrade
+/// Checks the schema version of the data directory, upgrading it in place if it is older than SCHEMA_VERSION
+/// Returns the version of the data directory, now SCHEMA_VERSION, or an error if it is newer or can not be upgraded
+pub fn upgrade() -> Result<u64, SchemaError> {
+    let mut version = detect_version()?;
+    if version > SCHEMA_VERSION {
+        return Err(SchemaError::TooNew {
+            found: version,
+            expected: SCHEMA_VERSION,
+        });
+    }
+    if version < SCHEMA_VERSION {
+        info!(
+            "Upgrading data directory from schema version {} to {}",
+            version, SCHEMA_VERSION
+        );
+    }
+    while version < SCHEMA_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(SchemaError::NoMigration(version))?;
+        info!(
+            "Running migration {} -> {}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+        let start = Instant::now();
+        let migrated = (migration.run)().map_err(|e| SchemaError::MigrationFailed {
+            from: version,
+            reason: e.to_string(),
+        })?;
+        version += 1;
+        store::put(&Table::Meta, VERSION_KEY, &version)?;
+        info!(
+            "Upgraded data directory to schema version {}, migrated {} keys in {} ms",
+            version,
+            migrated,
+            start.elapsed().as_millis()
+        );
+    }
+    if recorded_version()? != Some(version) {
+        store::put(&Table::Meta, VERSION_KEY, &version)?;
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_ordered() {
+        assert_eq!(MIGRATIONS.len() as u64, SCHEMA_VERSION);
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from, index as u64);
+        }
+    }
+}
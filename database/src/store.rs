@@ -0,0 +1,474 @@
+// This file is the typed storage API. Every table of the database is named in the Table enum, which owns the key schema:
+// tables are column families of the single database, and the per chain and per wallet tables share a column family, with their keys prefixed.
+// Unlike get_data, reads distinguish a missing key (Ok(None)) from a failed or corrupt read (Err), which get_data reported as "-1", "0" or "".
+use avrio_config::config;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+use thiserror::Error;
+
+use crate::{begin_batch, end_batch, queue_after_commit, read_value, remove_value, write_value};
+
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("Database backend error: {0}")]
+    Backend(#[from] rocksdb::Error),
+    #[error("Corrupt value for key {key} in table {table}: {reason}")]
+    Corrupt {
+        table: String,
+        key: String,
+        reason: String,
+    },
+    #[error("Failed to encode value: {0}")]
+    Encode(serde_json::Error),
+    #[error("Database lock poisoned")]
+    Poisoned,
+    #[error("Column family {0} missing from database")]
+    MissingColumnFamily(String),
+}
+
+/// The tables of the database
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Table {
+    /// The height -> block hash index (and block count, top block hash, txn count) of an account chain
+    ChainIndex(String),
+    MasterChainIndex,
+    ChainList,
+    ChainDigest,
+    GlobalIndex,
+    Transactions,
+    Candidates,
+    Invites,
+    BlockChunks,
+    EpochData,
+    BlsLookup,
+    Certificates,
+    Lottery,
+    Rewards,
+    Probation,
+    Participation,
+    Forks,
+    Finality,
+    Consensus,
+    Peers,
+    /// The account of each publickey
+    Accounts,
+    /// The publickey of each username, by the hash of the username
+    Usernames,
+    /// The state snapshot taken at the start of each epoch, by epoch number
+    Snapshots,
+    /// The compressed encoding of each block, by hash
+    Blocks,
+    /// A wallet of the wallet binary, by name
+    Wallet(String),
+    /// Information about the database itself
+    Meta,
+    /// A table not (yet) in the schema, by name; only created through the legacy get_data and save_data functions
+    Other(String),
+}
+
+/// The names of the tables which have their own column family
+const NAMED_TABLES: &[(&str, Table)] = &[
+    ("chains/masterchainindex", Table::MasterChainIndex),
+    ("chainlist", Table::ChainList),
+    ("chaindigest", Table::ChainDigest),
+    ("globalindex", Table::GlobalIndex),
+    ("transactions", Table::Transactions),
+    ("candidates", Table::Candidates),
+    ("invites", Table::Invites),
+    ("blockchunks", Table::BlockChunks),
+    ("epochdata", Table::EpochData),
+    ("blslookup", Table::BlsLookup),
+    ("fn-certificates", Table::Certificates),
+    ("lottery", Table::Lottery),
+    ("rewards", Table::Rewards),
+    ("probation", Table::Probation),
+    ("participation", Table::Participation),
+    ("forks", Table::Forks),
+    ("finality", Table::Finality),
+    ("consensus", Table::Consensus),
+    ("peers", Table::Peers),
+    ("accounts", Table::Accounts),
+    ("usernames", Table::Usernames),
+    ("snapshots", Table::Snapshots),
+    ("blocks", Table::Blocks),
+    ("meta", Table::Meta),
+];
+
+impl Table {
+    /// Returns the name of the table, which is its path (relative to the data directory) in the legacy one database per table layout
+    pub fn name(&self) -> String {
+        match self {
+            Table::ChainIndex(chain_key) => format!("chains/{}-chainindex", chain_key),
+            Table::Wallet(name) => format!("wallets/{}", name),
+            Table::Other(name) => name.clone(),
+            table => NAMED_TABLES
+                .iter()
+                .find(|(_, named)| named == table)
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Returns the table with the name, tables not in the schema are Other
+    pub fn from_name(name: &str) -> Table {
+        let name = name.trim_matches('/');
+        if let Some((_, table)) = NAMED_TABLES.iter().find(|(named, _)| *named == name) {
+            return table.clone();
+        }
+        if let Some(chain_key) = name
+            .strip_prefix("chains/")
+            .and_then(|chain| chain.strip_suffix("-chainindex"))
+        {
+            return Table::ChainIndex(chain_key.to_owned());
+        }
+        if let Some(wallet) = name.strip_prefix("wallets/") {
+            return Table::Wallet(wallet.to_owned());
+        }
+        Table::Other(name.to_owned())
+    }
+
+    /// Returns the table at the legacy path (config().db_path + "/" + name), as passed to get_data and save_data
+    pub fn from_path(path: &str) -> Table {
+        Table::from_name(path.strip_prefix(&config().db_path).unwrap_or(path))
+    }
+
+    /// Returns the column family the table is stored in
+    pub fn column_family(&self) -> &'static str {
+        match self {
+            Table::ChainIndex(_) => "chainindex",
+            Table::Wallet(_) => "wallets",
+            Table::Other(_) => "other",
+            table => NAMED_TABLES
+                .iter()
+                .find(|(_, named)| named == table)
+                .map(|(name, _)| name.trim_start_matches("chains/"))
+                .unwrap_or("other"),
+        }
+    }
+
+    /// Returns every column family of the database
+    pub fn column_families() -> Vec<&'static str> {
+        let mut column_families: Vec<&'static str> = vec!["chainindex", "wallets", "other"];
+        column_families.extend(
+            NAMED_TABLES
+                .iter()
+                .map(|(name, _)| name.trim_start_matches("chains/")),
+        );
+        column_families
+    }
+
+    /// Returns the key the tables key is stored under in its column family
+    /// Tables sharing a column family prefix their keys with the chain key, wallet or table name (none of which contain a '/')
+    pub fn key(&self, key: &str) -> String {
+        match self {
+            Table::ChainIndex(prefix) | Table::Wallet(prefix) | Table::Other(prefix) => {
+                prefix.to_owned() + "/" + key
+            }
+            _ => key.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Table {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Returns the raw (string) value of the key, or None if the table does not contain it
+/// Values written with save_data are plain strings, read them with this rather than get
+pub fn get_raw(table: &Table, key: &str) -> Result<Option<String>, DbError> {
+    match read_value(table, key)? {
+        Some(bytes) => String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|e| DbError::Corrupt {
+                table: table.name(),
+                key: key.to_owned(),
+                reason: e.to_string(),
+            }),
+        None => Ok(None),
+    }
+}
+
+/// Returns the json decoded value of the key, or None if the table does not contain it
+pub fn get<T: DeserializeOwned>(table: &Table, key: &str) -> Result<Option<T>, DbError> {
+    match get_raw(table, key)? {
+        Some(raw) => serde_json::from_str(&raw)
+            .map(Some)
+            .map_err(|e| DbError::Corrupt {
+                table: table.name(),
+                key: key.to_owned(),
+                reason: e.to_string(),
+            }),
+        None => Ok(None),
+    }
+}
+
+/// Returns true if the table contains the key
+pub fn contains(table: &Table, key: &str) -> Result<bool, DbError> {
+    Ok(read_value(table, key)?.is_some())
+}
+
+/// Saves the raw (string) value under the key
+pub fn put_raw(table: &Table, key: &str, value: &str) -> Result<(), DbError> {
+    write_value(table, key, value.as_bytes())
+}
+
+/// Saves the json encoded value under the key
+pub fn put<T: Serialize + ?Sized>(table: &Table, key: &str, value: &T) -> Result<(), DbError> {
+    put_raw(
+        table,
+        key,
+        &serde_json::to_string(value).map_err(DbError::Encode)?,
+    )
+}
+
+/// Removes the key from the table (does nothing if the table does not contain it)
+pub fn delete(table: &Table, key: &str) -> Result<(), DbError> {
+    remove_value(table, key)
+}
+
+/// # Atomic
+/// Runs f with every write it makes (on this thread) held in a batch, which is committed atomically if f returns Ok and discarded if it returns Err
+/// After a crash either every write of the batch is on disk or none are. Reads made by f see its writes, other threads do not until it is committed
+/// Nested calls join the outermost batch
+pub fn atomic<T, E: From<DbError>>(f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    if !begin_batch()? {
+        return f();
+    }
+    let _guard = BatchGuard; // discards the batch if f returns Err or panics
+    let value = f()?;
+    end_batch(true)?;
+    Ok(value)
+}
+
+struct BatchGuard;
+
+impl Drop for BatchGuard {
+    fn drop(&mut self) {
+        // does nothing if the batch was committed
+        let _ = end_batch(false);
+    }
+}
+
+/// # After commit
+/// Runs f once the current threads batch (see atomic) is committed, or now if the thread is not in one. f is dropped without running if the batch is discarded
+/// Use it for side effects of a write which must not happen unless the write reaches disk, eg announcing or acting on a new epoch
+pub fn after_commit(f: impl FnOnce() + Send + 'static) -> Result<(), DbError> {
+    queue_after_commit(Box::new(f))
+}
+
+/// Returns every (key, raw value) pair of the table, sorted by key
+pub fn entries(table: &Table) -> Result<Vec<(String, String)>, DbError> {
+    crate::entries(table)
+}
+
+/// Returns at most limit (key, raw value) pairs of the table with keys after after (from the first key if it is None), sorted by key
+/// Use it to walk a large table in bounded memory: pass the last key returned as after to read the next page, an empty page means the table has been read
+pub fn entries_page(
+    table: &Table,
+    after: Option<&str>,
+    limit: usize,
+) -> Result<Vec<(String, String)>, DbError> {
+    crate::entries_page(table, after, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::MemoryBackend, with_backend, with_current_backend};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Reads the key from the backend on another thread, which does not see this threads batch
+    fn read_elsewhere(backend: &Arc<MemoryBackend>, key: &'static str) -> Option<String> {
+        let backend = backend.clone();
+        std::thread::spawn(move || with_backend(backend, || get_raw(&Table::Meta, key).unwrap()))
+            .join()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_atomic_commit() {
+        let backend = Arc::new(MemoryBackend::new());
+        with_backend(backend.clone(), || {
+            atomic(|| -> Result<(), DbError> {
+                put_raw(&Table::Meta, "a", "1")?;
+                put(&Table::Meta, "b", &2)?;
+                delete(&Table::Meta, "b")?;
+                // the batch reads its own writes, other threads do not see them until it is committed
+                assert_eq!(get_raw(&Table::Meta, "a")?, Some("1".to_owned()));
+                assert!(!contains(&Table::Meta, "b")?);
+                assert_eq!(read_elsewhere(&backend, "a"), None);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(read_elsewhere(&backend, "a"), Some("1".to_owned()));
+            assert_eq!(read_elsewhere(&backend, "b"), None);
+        });
+    }
+
+    #[test]
+    fn test_atomic_discard() {
+        let backend = Arc::new(MemoryBackend::new());
+        with_backend(backend.clone(), || {
+            put_raw(&Table::Meta, "a", "1").unwrap();
+            let result = atomic(|| -> Result<(), DbError> {
+                put_raw(&Table::Meta, "a", "2")?;
+                put_raw(&Table::Meta, "b", "2")?;
+                Err(DbError::Poisoned)
+            });
+            assert!(result.is_err());
+            assert_eq!(get_raw(&Table::Meta, "a").unwrap(), Some("1".to_owned()));
+            assert_eq!(get_raw(&Table::Meta, "b").unwrap(), None);
+            // a write after the discarded batch is committed on its own
+            put_raw(&Table::Meta, "b", "3").unwrap();
+            assert_eq!(read_elsewhere(&backend, "b"), Some("3".to_owned()));
+        });
+    }
+
+    #[test]
+    fn test_atomic_nested() {
+        let backend = Arc::new(MemoryBackend::new());
+        with_backend(backend.clone(), || {
+            // an inner batch joins the outer one, its Err does not discard the outer batch
+            atomic(|| -> Result<(), DbError> {
+                put_raw(&Table::Meta, "outer", "1")?;
+                let inner = atomic(|| -> Result<(), DbError> {
+                    put_raw(&Table::Meta, "inner", "1")?;
+                    assert_eq!(read_elsewhere(&backend, "inner"), None);
+                    Err(DbError::Poisoned)
+                });
+                assert!(inner.is_err());
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(read_elsewhere(&backend, "outer"), Some("1".to_owned()));
+            assert_eq!(read_elsewhere(&backend, "inner"), Some("1".to_owned()));
+            // discarding the outer batch discards the writes of a committed inner one
+            let result = atomic(|| -> Result<(), DbError> {
+                atomic(|| put_raw(&Table::Meta, "inner", "2"))?;
+                Err(DbError::Poisoned)
+            });
+            assert!(result.is_err());
+            assert_eq!(get_raw(&Table::Meta, "inner").unwrap(), Some("1".to_owned()));
+        });
+    }
+
+    #[test]
+    fn test_after_commit() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            let ran = Arc::new(AtomicUsize::new(0));
+            let queued = ran.clone();
+            atomic(|| -> Result<(), DbError> {
+                let queued = queued.clone();
+                after_commit(move || {
+                    queued.fetch_add(1, Ordering::SeqCst);
+                })?;
+                assert_eq!(ran.load(Ordering::SeqCst), 0);
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(ran.load(Ordering::SeqCst), 1);
+            // dropped if the batch is discarded
+            let _ = atomic(|| -> Result<(), DbError> {
+                let queued = queued.clone();
+                after_commit(move || {
+                    queued.fetch_add(1, Ordering::SeqCst);
+                })?;
+                Err(DbError::Poisoned)
+            });
+            assert_eq!(ran.load(Ordering::SeqCst), 1);
+            // run now outside of a batch
+            after_commit(move || {
+                queued.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+            assert_eq!(ran.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    /// Reads the table page by page, limit entries at a time
+    fn read_paged(table: &Table, limit: usize) -> Vec<String> {
+        let mut keys = vec![];
+        loop {
+            let page = entries_page(table, keys.last().map(String::as_str), limit).unwrap();
+            if page.is_empty() {
+                return keys;
+            }
+            assert!(page.len() <= limit);
+            keys.extend(page.into_iter().map(|(key, _)| key));
+        }
+    }
+
+    #[test]
+    fn test_entries_page() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            for key in &["a", "b", "c", "d", "e"] {
+                put_raw(&Table::Meta, key, "1").unwrap();
+            }
+            put_raw(&Table::Snapshots, "a", "1").unwrap();
+            assert_eq!(read_paged(&Table::Meta, 2), vec!["a", "b", "c", "d", "e"]);
+            assert_eq!(
+                entries_page(&Table::Meta, Some("b"), 2).unwrap(),
+                vec![
+                    ("c".to_owned(), "1".to_owned()),
+                    ("d".to_owned(), "1".to_owned())
+                ]
+            );
+            // pages include the writes pending in the batch, a page whose stored keys were all deleted does not end the walk
+            atomic(|| -> Result<(), DbError> {
+                delete(&Table::Meta, "a")?;
+                delete(&Table::Meta, "b")?;
+                put_raw(&Table::Meta, "bb", "1")?;
+                put_raw(&Table::Meta, "f", "1")?;
+                assert_eq!(read_paged(&Table::Meta, 2), vec!["bb", "c", "d", "e", "f"]);
+                delete(&Table::Meta, "bb")?;
+                assert_eq!(read_paged(&Table::Meta, 2), vec!["c", "d", "e", "f"]);
+                Ok(())
+            })
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_with_current_backend() {
+        with_backend(Arc::new(MemoryBackend::new()), || {
+            put_raw(&Table::Meta, "a", "1").unwrap();
+            // a thread spawned with its function wrapped reads the same backend
+            let spawned =
+                std::thread::spawn(with_current_backend(|| get_raw(&Table::Meta, "a").unwrap()));
+            assert_eq!(spawned.join().unwrap(), Some("1".to_owned()));
+        });
+    }
+
+    #[test]
+    fn test_table_names() {
+        assert_eq!(
+            Table::ChainIndex("abc".to_owned()).name(),
+            "chains/abc-chainindex"
+        );
+        assert_eq!(Table::MasterChainIndex.name(), "chains/masterchainindex");
+        assert_eq!(Table::Certificates.name(), "fn-certificates");
+        assert_eq!(Table::Wallet("main".to_owned()).to_string(), "wallets/main");
+    }
+
+    #[test]
+    fn test_table_from_name() {
+        for name in &["chains/abc-chainindex", "chains/masterchainindex", "wallets/main", "fn-certificates", "keystore"] {
+            assert_eq!(Table::from_name(name).name(), *name);
+        }
+        assert_eq!(Table::from_name("/invites"), Table::Invites);
+        assert_eq!(Table::from_name("keystore"), Table::Other("keystore".to_owned()));
+        assert_eq!(Table::MasterChainIndex.column_family(), "masterchainindex");
+        assert_eq!(Table::ChainIndex("abc".to_owned()).key("5"), "abc/5");
+        assert_eq!(Table::Invites.key("5"), "5");
+        let column_families = Table::column_families();
+        let mut deduplicated = column_families.clone();
+        deduplicated.sort();
+        deduplicated.dedup();
+        assert_eq!(column_families.len(), deduplicated.len());
+    }
+}
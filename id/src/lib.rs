@@ -1,6 +1,13 @@
 // This lib deals with the generation of ID's based off the random strings provided by the consensius commitee at the end of the last round
 
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 extern crate hex;
 extern crate rand;
@@ -14,6 +21,18 @@ pub struct HashParams {
     pub memory: u32,
 }
 
+/// The params used to hash each nonce, every iteration is a full raw_hash round and validators recompute the hash of every certificate
+pub const ID_HASH_PARAMS: HashParams = HashParams {
+    memory: 262144,
+    iterations: 16,
+};
+
+/// The highest difficulty (the hash must be all zeros)
+pub const MAX_DIFFICULTY: u128 = 128;
+
+/// How many attempts (across all threads) between progress reports
+pub const PROGRESS_INTERVAL: u64 = 16;
+
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
 pub struct IdDetails {
     pub hash: String,
@@ -23,12 +42,79 @@ pub struct IdDetails {
     pub end_t: u64,
 }
 
+/// # Id search
+/// Controls a nonce search
+/// * threads: the number of threads to search with
+/// * cancel: set to true to stop the search (eg from another thread)
+/// * progress: called with the total number of attempts every PROGRESS_INTERVAL attempts
+#[derive(Clone)]
+pub struct IdSearch {
+    pub threads: usize,
+    pub cancel: Arc<AtomicBool>,
+    pub progress: Option<Arc<dyn Fn(u64) + Send + Sync>>,
+}
+
+impl Default for IdSearch {
+    fn default() -> Self {
+        IdSearch {
+            threads: thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            cancel: Arc::new(AtomicBool::new(false)),
+            progress: None,
+        }
+    }
+}
+
+impl IdSearch {
+    /// Stops the search, search_nonce will return None
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+}
+
+/// Returns the first 16 bytes of v as a big endian u128, missing bytes are treated as 0xff (so a short hash is never easier to find)
 pub fn difficulty_bytes_as_u128(v: &[u8]) -> u128 {
-    return v.iter().sum::<u8>() as u128;
+    let mut bytes = [0xff; 16];
+    for (i, byte) in v.iter().take(16).enumerate() {
+        bytes[i] = *byte;
+    }
+    u128::from_be_bytes(bytes)
+}
+
+/// Returns the highest value (see difficulty_bytes_as_u128) a hash can have and meet the difficulty
+/// The difficulty is the number of leading zero bits the hash needs, so each increment doubles the expected work
+pub fn difficulty_target(difficulty: u128) -> u128 {
+    if difficulty >= MAX_DIFFICULTY {
+        0
+    } else {
+        u128::MAX >> difficulty
+    }
 }
 
+/// Returns true if the (hex encoded) hash meets the difficulty
 pub fn check_difficulty(hash: &str, difficulty: u128) -> bool {
-    difficulty > difficulty_bytes_as_u128(&hash.as_bytes().to_vec())
+    match hex::decode(hash) {
+        Ok(bytes) => difficulty_bytes_as_u128(&bytes) <= difficulty_target(difficulty),
+        Err(_) => false,
+    }
+}
+
+/// # Retarget
+/// Returns the difficulty for the next epoch given the number of registrations this epoch and the target number of registrations
+/// More registrations than the target raises the difficulty by one bit, fewer than half the target lowers it by one bit (but not below minimum)
+pub fn retarget(difficulty: u128, registrations: u64, target: u64, minimum: u128) -> u128 {
+    if registrations > target {
+        (difficulty + 1).min(MAX_DIFFICULTY)
+    } else if registrations * 2 < target {
+        difficulty.saturating_sub(1).max(minimum)
+    } else {
+        difficulty
+    }
 }
 
 fn _calculate_hash_params(seed: String) -> HashParams {
@@ -54,50 +140,120 @@ fn hash_string(params: &HashParams, s: &String) -> String {
     return out;
 }
 
-pub fn generate_id(
+/// Returns the hash of the nonce for the key k and publickey
+pub fn id_hash(k: &str, public_key: &str, nonce: u64) -> String {
+    hash_string(
+        &ID_HASH_PARAMS,
+        &(k.to_owned() + public_key + &nonce.to_string()),
+    )
+}
+
+/// Returns true if the nonce meets the difficulty for the key k and publickey
+pub fn verify_id(k: &str, public_key: &str, nonce: u64, difficulty: u128) -> bool {
+    check_difficulty(&id_hash(k, public_key, nonce), difficulty)
+}
+
+/// # Search nonce
+/// Searches for a nonce which meets the difficulty, returns the (nonce, hash) or None if the search was cancelled
+/// Each thread tries every search.threads'th nonce and the search stops as soon as any thread finds one, so the nonce returned meets the difficulty
+/// but is not necessarily the lowest nonce which does (of the nonces found before the threads stopped, the lowest is returned)
+pub fn search_nonce(
+    k: &str,
+    public_key: &str,
+    difficulty: u128,
+    search: &IdSearch,
+) -> Option<(u64, String)> {
+    let threads = search.threads.max(1) as u64;
+    let found: Arc<Mutex<Option<(u64, String)>>> = Arc::new(Mutex::new(None));
+    let done = Arc::new(AtomicBool::new(false));
+    let attempts = Arc::new(AtomicU64::new(0));
+    let mut handles = vec![];
+    for offset in 0..threads {
+        let (k, public_key) = (k.to_owned(), public_key.to_owned());
+        let (found, done, attempts) = (found.clone(), done.clone(), attempts.clone());
+        let search = search.clone();
+        handles.push(thread::spawn(move || {
+            let mut nonce = offset + 1;
+            while !done.load(Ordering::SeqCst) && !search.cancelled() {
+                let hashed = id_hash(&k, &public_key, nonce);
+                if check_difficulty(&hashed, difficulty) {
+                    if let Ok(mut found) = found.lock() {
+                        if found.as_ref().map(|(lowest, _)| nonce < *lowest).unwrap_or(true) {
+                            *found = Some((nonce, hashed));
+                        }
+                    }
+                    done.store(true, Ordering::SeqCst);
+                    break;
+                }
+                let total = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                if total % PROGRESS_INTERVAL == 0 {
+                    if let Some(progress) = &search.progress {
+                        (progress)(total);
+                    }
+                }
+                nonce += threads;
+            }
+        }));
+    }
+    for handle in handles {
+        if handle.join().is_err() {
+            error!("ID search thread panicked");
+        }
+    }
+    if search.cancelled() {
+        debug!(
+            "ID search cancelled after {} attempts",
+            attempts.load(Ordering::SeqCst)
+        );
+        return None;
+    }
+    let found = found.lock().ok()?.take();
+    found
+}
+
+/// # Generate id with
+/// Searches for an ID meeting the difficulty using the search params, returns None if the search was cancelled
+pub fn generate_id_with(
     k: String,
     public_key: String,
     private_key: String,
     difficulty: u128,
-) -> IdDetails {
+    search: &IdSearch,
+) -> Option<IdDetails> {
     let mut struct_: IdDetails = IdDetails::default();
-    let params = HashParams {
-        memory: 262144,
-        iterations: 65536,
-    };
-    let mut nonce: u32 = 0;
-    let mut hashed: String;
-
     struct_.start_t = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .expect("Time went backwards")
         .as_millis() as u64;
 
-    loop {
-        nonce += 1;
-        hashed = hash_string(&params, &(k.clone() + &public_key + &nonce.to_string()));
-
-        // check difficulty
-        if check_difficulty(&hashed, difficulty) {
-            struct_.nonce = nonce as u64;
-            struct_.hash = hashed.clone();
-            struct_.end_t = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_millis() as u64;
-            info!(
-                "Found ID hash: {} with nonce: {} (in {} secconds)",
-                hashed,
-                nonce,
-                (struct_.end_t - struct_.start_t) / 1000
-            );
-            break;
-        }
-    }
+    let (nonce, hashed) = search_nonce(&k, &public_key, difficulty, search)?;
+    struct_.nonce = nonce;
+    struct_.hash = hashed.clone();
+    struct_.end_t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64;
+    info!(
+        "Found ID hash: {} with nonce: {} (in {} secconds)",
+        hashed,
+        nonce,
+        (struct_.end_t - struct_.start_t) / 1000
+    );
 
     struct_.signed = sign(hashed, private_key);
 
-    struct_
+    Some(struct_)
+}
+
+pub fn generate_id(
+    k: String,
+    public_key: String,
+    private_key: String,
+    difficulty: u128,
+) -> IdDetails {
+    // the default search is never cancelled
+    generate_id_with(k, public_key, private_key, difficulty, &IdSearch::default())
+        .unwrap_or_default()
 }
 
 fn sign(s: String, pk: String) -> String {
@@ -114,3 +270,22 @@ fn sign(s: String, pk: String) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_difficulty() {
+        assert!(check_difficulty("00ff", 8));
+        assert!(!check_difficulty("00ff", 9));
+        assert!(check_difficulty(&"00".repeat(32), MAX_DIFFICULTY));
+        assert!(check_difficulty("0fff", 4));
+        assert!(!check_difficulty("0fff", 5));
+        assert!(!check_difficulty("not hex", 0));
+        assert_eq!(retarget(10, 5, 2, 4), 11);
+        assert_eq!(retarget(10, 0, 2, 4), 9);
+        assert_eq!(retarget(4, 0, 2, 4), 4);
+        assert_eq!(retarget(10, 2, 2, 4), 10);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{
     io::{read, send},
-    peer::{add_peer, get_peers_addr, lock, remove_peer},
+    peer::{add_peer, get_peers_addr, lock, remove_peer, set_pruning},
 };
 extern crate rand;
 extern crate x25519_dalek;
@@ -83,7 +83,7 @@ pub fn new_connection(addr: &str) -> Result<std::net::TcpStream, Box<dyn std::er
     }
     let d_split = d.message.split('*').collect::<Vec<&str>>();
     if d_split.len() != 5 {
-        trace!("d_split: {}, expected 4", d_split.len());
+        trace!("d_split: {}, expected 5", d_split.len());
         Err("wrong return len: ".into())
     } else if hex::encode(config().network_id) != d_split[0] {
         Err("wrong network id".into())
@@ -121,7 +121,9 @@ pub fn new_connection(addr: &str) -> Result<std::net::TcpStream, Box<dyn std::er
                         e
                     );
                     return Err("failed to add peer to peer list".into());
-                } else if let Err(e) = crate::handle::launch_handle_client(rx, &mut a, true) {
+                }
+                let _ = set_pruning(&a.peer_addr()?, handshake_pruning(&d_split));
+                if let Err(e) = crate::handle::launch_handle_client(rx, &mut a, true) {
                     log::error!("Failed to launch peer handler stream, gave error: {}", e);
                 }
                 Ok(a)
@@ -136,17 +138,34 @@ pub fn new_connection(addr: &str) -> Result<std::net::TcpStream, Box<dyn std::er
     }
 }
 
+/// Forms our handshake: network id*identity*node type*p2p port*publickey
+/// Pruned nodes send their node type as node type:epochs of blocks kept. Nodes from before pruning check for exactly 5 fields and never read the node type,
+/// so the epochs kept go in it rather than a field of their own
 pub fn form_handshake(l_pub: &[u8; 32]) -> String {
+    let node_type = if config().prune_keep_epochs == 0 {
+        config().node_type.to_string()
+    } else {
+        format!("{}:{}", config().node_type, config().prune_keep_epochs)
+    };
     return format!(
         "{}*{}*{}*{}*{}",
         hex::encode(config().network_id),
         &config().identitiy,
-        &config().node_type,
+        node_type,
         &config().p2p_port,
         hex::encode(l_pub)
     );
 }
 
+/// Returns the number of epochs of blocks the peer keeps from their split handshake, peers which keep every block (including those from before pruning) send 0 or nothing
+pub fn handshake_pruning(d_split: &[&str]) -> u64 {
+    d_split
+        .get(2)
+        .and_then(|node_type| node_type.split(':').nth(1))
+        .and_then(|keep_epochs| keep_epochs.parse().ok())
+        .unwrap_or(0)
+}
+
 pub fn rec_server(address: &str) -> Result<(), Box<dyn std::error::Error>> {
     let mut p2p_server = crate::server::P2pServer::default();
     p2p_server.set_bind_addr(&address.parse()?)?;
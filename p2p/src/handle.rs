@@ -15,10 +15,12 @@ use avrio_config::config;
 use avrio_core::{mempool::{add_block, Caller, get_block as get_block_mempool}, validate::Verifiable};
 use avrio_core::{
     block::{from_compact, get_block, get_block_from_raw, Block},
-    epoch::get_top_epoch,
-    chunk::BlockChunk
+    epoch::{get_top_epoch, Epoch},
+    chunk::BlockChunk,
+    consensus::ConsensusSignatures,
+    snapshot::Snapshot,
 };
-use avrio_database::{get_data, open_database};
+use avrio_database::store::{self, Table};
 use avrio_rpc::block_announce;
 use lazy_static::lazy_static;
 use log::{debug, error, info, trace};
@@ -32,6 +34,110 @@ pub fn block_enacted_callback(rec_from: SocketAddr, block: Block) {
 
 lazy_static! {
     static ref SYNCING_PEERS: Mutex<(u64, Vec<String>)> = Mutex::new((0, vec![]));
+    /// Called when the consensus round leader asks us for our epoch salt seed, with the top epoch number. Returns our (publickey, VRF proof) seed
+    pub static ref EPOCH_SALT_SEED_HANDLER: Mutex<Option<Box<dyn Fn(u64) -> Result<(String, String), Box<dyn std::error::Error>> + Send>>> = Mutex::new(None);
+    /// Called when the consensus round leader proposes a consensus block for us to sign. Returns our (BLS publickey, BLS signature)
+    pub static ref CONSENSUS_BLOCK_SIGNER: Mutex<Option<Box<dyn Fn(Block) -> Result<(String, String), Box<dyn std::error::Error>> + Send>>> = Mutex::new(None);
+}
+
+/// Creates our epoch salt seed for the round leader and returns it json encoded, or an empty string if we cannot create one
+fn create_epoch_salt_seed(message: &str, stream: &TcpStream) -> String {
+    let top_epoch = match get_top_epoch() {
+        Ok(epoch) => epoch,
+        Err(e) => {
+            error!("Asked to create epoch salt but failed to get top epoch, error={}", e);
+            return String::default();
+        }
+    };
+    let peer = stream
+        .peer_addr()
+        .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0));
+    // check if we are in the consensus commtiee and in a valid position (not round leader) to produce a epoch salt seed VRF
+    if top_epoch.committees.is_empty()
+        || top_epoch.committees[0].get_round_leader().unwrap_or_default() == config().chain_key
+        || !top_epoch.committees[0].members.contains(&config().chain_key)
+    {
+        error!("Asked to create epoch salt by {} but not in valid position", peer);
+        return String::default();
+    }
+    if message != top_epoch.epoch_number.to_string() {
+        error!(
+            "Asked to create epoch salt for epoch {} by {}, but top epoch is {}",
+            message, peer, top_epoch.epoch_number
+        );
+        return String::default();
+    }
+    debug!("Creating epoch salt seed VRF");
+    match EPOCH_SALT_SEED_HANDLER.lock() {
+        Ok(lock) => {
+            if let Some(handler) = &*lock {
+                match (handler)(top_epoch.epoch_number) {
+                    Ok(seed) => return serde_json::to_string(&seed).unwrap_or_default(),
+                    Err(e) => error!("Failed to create epoch salt seed, error={}", e),
+                }
+            } else {
+                error!("Asked to create epoch salt but no seed handler registered");
+            }
+        }
+        Err(e) => error!(
+            "Failed to get mutex lock on EPOCH_SALT_SEED_HANDLER lazy static, error={}",
+            e
+        ),
+    }
+    String::default()
+}
+
+/// Signs the consensus block proposed by the round leader and returns our signature json encoded, or an empty string if we cannot sign it
+fn sign_consensus_block(message: &str) -> String {
+    let block: Block = match serde_json::from_str(message) {
+        Ok(block) => block,
+        Err(e) => {
+            error!("Failed to decode proposed consensus block, error={}", e);
+            return String::default();
+        }
+    };
+    match CONSENSUS_BLOCK_SIGNER.lock() {
+        Ok(lock) => {
+            if let Some(signer) = &*lock {
+                match (signer)(block.clone()) {
+                    Ok(signature) => return serde_json::to_string(&signature).unwrap_or_default(),
+                    Err(e) => error!(
+                        "Failed to sign proposed consensus block {}, error={}",
+                        block.hash, e
+                    ),
+                }
+            } else {
+                error!("Asked to sign consensus block but no signer registered");
+            }
+        }
+        Err(e) => error!(
+            "Failed to get mutex lock on CONSENSUS_BLOCK_SIGNER lazy static, error={}",
+            e
+        ),
+    }
+    String::default()
+}
+
+/// Validates and enacts the aggregated consensus committee signatures over a consensus block, which finalizes it
+/// The round leader sends these before the block, which we only accept once we have them
+fn handle_consensus_signatures(message: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let signatures: ConsensusSignatures = serde_json::from_str(message)?;
+    if ConsensusSignatures::get(signatures.block_hash.clone()).is_ok() {
+        trace!(
+            "Already have consensus signatures for block {}",
+            signatures.block_hash
+        );
+        return Ok(());
+    }
+    signatures.valid()?;
+    signatures.save()?;
+    signatures.enact()?;
+    debug!(
+        "Enacted consensus signatures for block {} ({} signers)",
+        signatures.block_hash,
+        signatures.signers.len()
+    );
+    Ok(())
 }
 
 fn get_syncing_peers_count() -> Result<u64, Box<dyn std::error::Error>> {
@@ -279,6 +385,43 @@ pub fn launch_handle_client(
     Ok(())
 }
 
+/// Handles a block containing a VRF lottery ticket relayed to us by a consensus committee member
+/// Only the consensus round leader processes these; if we do not already have the block we validate and enact it (which records the ticket)
+fn handle_relayed_vrf_ticket(message: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let top_epoch = get_top_epoch()?;
+    if top_epoch.committees.is_empty()
+        || top_epoch.committees[0].get_round_leader()? != config().chain_key
+    {
+        return Err("not consensus round leader".into());
+    }
+    let block: Block = serde_json::from_str(message)?;
+    if !block.txns.iter().any(|txn| txn.flag == 'v') {
+        return Err("relayed block contains no VRF lottery ticket".into());
+    }
+    if get_block_from_raw(block.hash.clone()) != Block::default() {
+        trace!("Already have relayed VRF lottery ticket block {}", block.hash);
+        return Ok(());
+    }
+    block.valid()?;
+    block.save()?;
+    block.enact()?;
+    debug!("Enacted relayed VRF lottery ticket block {}", block.hash);
+    Ok(())
+}
+
+/// Returns the json encoded (previous epoch, epoch, snapshot) of the epoch number in message, or an empty string if we do not have them
+fn encode_snapshot(message: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let epoch_number: u64 = message.parse()?;
+    match Snapshot::get(epoch_number)? {
+        Some(snapshot) if epoch_number != 0 => Ok(serde_json::to_string(&(
+            Epoch::get(epoch_number - 1)?,
+            Epoch::get(epoch_number)?,
+            snapshot,
+        ))?),
+        _ => Ok(String::from("")),
+    }
+}
+
 pub fn process_handle_msg(
     read_msg: P2pData,
     stream: &mut TcpStream,
@@ -291,16 +434,57 @@ pub fn process_handle_msg(
                 "Received a p2p message with type 0. Likeley corrupted"
             );
         }
-        // generate Epoch Salt seed
-        0x06 => {
+        // generate Epoch Salt seed (0x62 is sent by the round leader when retrying members who did not respond)
+        0x06 | 0x62 => {
             debug!("Asked to create epoch salt");
-            // check if we are in the consensus commtiee and in a valid position (not round leader) to produce a epoch salt seed VRF
-            let top_epoch = get_top_epoch().unwrap();
-            if top_epoch.committees[0].get_round_leader().unwrap_or_default() == config().chain_key || !top_epoch.committees[0].members.contains(&config().chain_key) {
-                error!("Asked to create epoch salt by {} but not in valid position", stream.peer_addr()
-                .unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)));
-            } else {
-                debug!("Creating epoch salt seed VRF");
+            let seed = create_epoch_salt_seed(&read_msg.message, stream);
+            // always respond, so the round leader is not left waiting for a read to time out
+            let response_type = if read_msg.message_type == 0x06 { 0x07 } else { 0x63 };
+            if let Err(e) = send(seed, stream, response_type, true, None) {
+                error!("Failed to send epoch salt seed to round leader, error={}", e);
+            }
+        }
+        // consensus block proposed by the consensus round leader
+        0x67 => {
+            debug!("Asked to sign consensus block");
+            let signature = sign_consensus_block(&read_msg.message);
+            // always respond, so the round leader is not left waiting for a read to time out
+            if let Err(e) = send(signature, stream, 0x68, true, None) {
+                error!("Failed to send consensus block signature to round leader, error={}", e);
+            }
+        }
+        // aggregated consensus committee signatures over a consensus block
+        0x69 => {
+            if let Err(e) = handle_consensus_signatures(&read_msg.message) {
+                debug!("Ignoring consensus signatures, reason={}", e);
+            }
+        }
+        // signatures of a consensus block requested by a node syncing the consensus chain
+        0x6c => {
+            let response = ConsensusSignatures::get(read_msg.message.clone())
+                .ok()
+                .and_then(|signatures| serde_json::to_string(&signatures).ok())
+                .unwrap_or_default();
+            // always respond, so the peer is not left waiting for a read to time out
+            if let Err(e) = send(response, stream, 0x6d, true, None) {
+                error!("Failed to send consensus block signatures to peer, error={}", e);
+            }
+        }
+        // state snapshot requested by a node bootstrapping from a checkpoint
+        0x6a => {
+            let response = encode_snapshot(&read_msg.message).unwrap_or_else(|e| {
+                debug!("Failed to get snapshot of epoch {}, error={}", read_msg.message, e);
+                String::from("")
+            });
+            // always respond, so the peer is not left waiting for a read to time out
+            if let Err(e) = send(response, stream, 0x6b, true, None) {
+                error!("Failed to send state snapshot to peer, error={}", e);
+            }
+        }
+        // VRF lottery ticket relayed by a consensus committee member
+        0x66 => {
+            if let Err(e) = handle_relayed_vrf_ticket(&read_msg.message) {
+                debug!("Ignoring relayed VRF lottery ticket, reason={}", e);
             }
         }
         // ping 
@@ -421,10 +605,10 @@ pub fn process_handle_msg(
         0x1b | 0x1c => {
             // send the peer our chain digest
             log::trace!("Sending chain digest to peer");
-            let chain_digest = avrio_database::get_data(
-                avrio_config::config().db_path + "/chaindigest",
-                "master",
-            );
+            let chain_digest = store::get_raw(&Table::ChainDigest, "master")
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| String::from("-1"));
             let _ = send(chain_digest, stream, 0xcd, true, None);
         }
         0x05 => {
@@ -523,12 +707,8 @@ pub fn process_handle_msg(
                 stream.peer_addr().expect("Could not get addr for peer")
             );
 
-            if let Ok(db) = open_database(config().db_path + &"/chainlist".to_owned()) {
-                let mut chains: Vec<String> = vec![];
-
-                for (key, _) in db.iter() {
-                    chains.push(key.to_owned());
-                }
+            if let Ok(entries) = store::entries(&Table::ChainList) {
+                let chains: Vec<String> = entries.into_iter().map(|(key, _)| key).collect();
 
                 log::trace!("Our chain list: {:#?}", chains);
                 let s = serde_json::to_string(&chains).unwrap_or_default();
@@ -542,35 +722,21 @@ pub fn process_handle_msg(
         }
         0x45 => {
             // send block count
-            let bc = get_data(
-                config().db_path
-                    + &"/chains/".to_owned()
-                    + &read_msg.message
-                    + &"-chainindex".to_owned(),
-                &"blockcount".to_owned(),
-            );
+            let bc = avrio_core::block::block_count(&read_msg.message);
             log::trace!("Blockcount={} for chain={}", bc, read_msg.message);
 
-            if bc == *"-1" {
-                let _ = send("0".into(), stream, 0x46, true, None);
-            } else {
-                let _ = send(bc, stream, 0x46, true, None);
-            }
+            let _ = send(bc.to_string(), stream, 0x46, true, None);
 
         }
         0x47 => {
             // send global block count
-            let gbc = get_data(
-                config().db_path + &"/globalindex".to_owned(),
-                "globalblockcount",
-            );
+            let gbc: u64 = store::get(&Table::GlobalIndex, "globalblockcount")
+                .ok()
+                .flatten()
+                .unwrap_or(0);
             log::trace!("Global blockcount={}", gbc);
 
-            if gbc == *"-1" {
-                let _ = send("0".into(), stream, 0x48, true, None);
-            } else {
-                let _ = send(gbc, stream, 0x48, true, None);
-            }
+            let _ = send(gbc.to_string(), stream, 0x48, true, None);
 
         }
         0x6f => {
@@ -640,9 +806,7 @@ pub fn process_handle_msg(
 
                 if hash == "0" || hash == "-1"  {
                     log::trace!("Getting blocks above network genesis (globally) ");
-                    let got_index = get_data(
-                        config().db_path + "/globalindex","1");
-                    if got_index != "-1"
+                    if let Ok(Some(got_index)) = store::get_raw(&Table::GlobalIndex, "1")
                     {
                     block_from = get_block_from_raw(got_index);
                     log::trace!("Block from: {:#?}", block_from);
@@ -667,9 +831,9 @@ pub fn process_handle_msg(
 
                         got += 1;
                         log::trace!("Sent block at height: {}", got);
-                        let got_index = get_data(
-                            config().db_path + "/globalindex",&got.to_string());
-                        if got_index != "-1" {
+                        if let Ok(Some(got_index)) =
+                            store::get_raw(&Table::GlobalIndex, &got.to_string())
+                        {
                             prev = get_block_from_raw(got_index);
                         } else {
                             break;
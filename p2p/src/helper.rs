@@ -1,16 +1,19 @@
 use crate::{
     format::P2pData,
     io::{read, send},
-    peer::{get_peers_addr, lock, locked, unlock_peer},
+    peer::{get_peers_addr, is_pruned, lock, locked, unlock_peer},
     utils::*,
 };
 use avrio_config::config;
 use avrio_core::{
-    block::{from_compact, get_block_from_raw, save_block, Block},
+    block::{from_compact, get_block_from_raw, save_block, Block, BlockType},
+    consensus::ConsensusSignatures,
+    epoch::{get_top_epoch, Epoch},
+    snapshot::{checkpoint, data_dir_empty, Snapshot},
     states::form_state_digest,
     validate::Verifiable,
 };
-use avrio_database::get_data;
+use avrio_database::store::{self, Table};
 
 //use bson;
 use log::*;
@@ -45,7 +48,10 @@ pub fn sync_needed() -> Result<bool, Box<dyn Error>> {
         // we got at least one chain digest
         // find the most common chain digest
         let mode: String = get_mode(chain_digests.clone());
-        let ours = get_data(config().db_path + &"/chaindigest".to_owned(), &"master");
+        let ours = store::get_raw(&Table::ChainDigest, "master")
+            .ok()
+            .flatten()
+            .unwrap_or_else(|| String::from("-1"));
         debug!(
             "Chain digests: {:#?}, mode: {}, ours: {}",
             chain_digests, mode, ours
@@ -60,6 +66,106 @@ pub fn sync_needed() -> Result<bool, Box<dyn Error>> {
     }
 }
 
+/// Gets, validates and enacts the signatures of the consensus send block from the peer we are syncing from, unless we have them already
+/// (consensus send blocks are only valid once we have their signatures)
+fn sync_consensus_signatures(block: &Block, peer: &mut TcpStream) -> Result<(), Box<dyn Error>> {
+    if block.header.chain_key != "0"
+        || block.block_type != BlockType::Send
+        || ConsensusSignatures::get(block.hash.clone()).is_ok()
+    {
+        return Ok(());
+    }
+    send(block.hash.clone(), peer, 0x6c, true, None)?;
+    let response = read(peer, Some(10000), None)?;
+    if response.message_type != 0x6d {
+        return Err(format!(
+            "Expected consensus block signatures, got message type {}",
+            response.message_type
+        )
+        .into());
+    }
+    if response.message.is_empty() {
+        return Err("Peer does not have the signatures".into());
+    }
+    let signatures: ConsensusSignatures = serde_json::from_str(&response.message)?;
+    if signatures.block_hash != block.hash {
+        return Err("Peer sent the signatures of another block".into());
+    }
+    signatures.valid()?;
+    signatures.save()?;
+    signatures.enact()?;
+    Ok(())
+}
+
+/// Asks the peer for the epoch before epoch_number, the epoch and its state snapshot, returns None if the peer does not have them
+pub fn get_snapshot_from_peer(
+    peer: &SocketAddr,
+    epoch_number: u64,
+) -> Result<Option<(Epoch, Epoch, Snapshot)>, Box<dyn Error>> {
+    let mut peer_lock = lock(peer, 1000)?;
+    send(epoch_number.to_string(), &mut peer_lock, 0x6a, true, None)?;
+    let response = read(&mut peer_lock, Some(60000), None); // snapshots are large, wait for 60 secs
+    unlock_peer(peer_lock)?;
+    let response = response?;
+    if response.message_type != 0x6b {
+        return Err(format!("Expected a state snapshot, got message type {}", response.message_type).into());
+    }
+    if response.message.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&response.message)?))
+}
+
+/// # Bootstrap from snapshot
+/// If a snapshot checkpoint is set in the config and we are behind it, downloads the snapshot of the checkpoint epoch from a peer,
+/// verifies it against the checkpoint and applies it, so we only need to sync the blocks after it
+/// Returns true if a snapshot was applied, false if there is no checkpoint or we are already past it
+/// Refuses (with an error) to apply a snapshot over a data directory which already holds state, which it would mix with the snapshot
+pub fn bootstrap_from_snapshot() -> Result<bool, Box<dyn Error>> {
+    let checkpoint = match checkpoint() {
+        Some(checkpoint) => checkpoint,
+        None => return Ok(false),
+    };
+    if get_top_epoch().map_or(false, |top| top.epoch_number >= checkpoint.0) {
+        debug!("Already at or past snapshot checkpoint epoch {}, not bootstrapping", checkpoint.0);
+        return Ok(false);
+    }
+    if !data_dir_empty()? {
+        return Err(format!(
+            "Data directory {} is not empty, only a new node can bootstrap from a snapshot",
+            config().db_path
+        )
+        .into());
+    }
+    info!("Bootstrapping from the state snapshot of epoch {}", checkpoint.0);
+    for peer in get_peers_addr().unwrap_or_default() {
+        let (previous, epoch, snapshot) = match get_snapshot_from_peer(&peer, checkpoint.0) {
+            Ok(Some(snapshot)) => snapshot,
+            Ok(None) => {
+                debug!("Peer {} does not have the snapshot of epoch {}", peer, checkpoint.0);
+                continue;
+            }
+            Err(e) => {
+                debug!("Failed to get snapshot from peer {}, error={}", peer, e);
+                continue;
+            }
+        };
+        if let Err(e) = snapshot.verify(&previous, &epoch, &checkpoint) {
+            warn!("Invalid snapshot from peer {}, error={}", peer, e);
+            continue;
+        }
+        snapshot.apply(previous, &epoch)?;
+        info!(
+            "Bootstrapped from the snapshot of epoch {} sent by {}, chains={}",
+            checkpoint.0,
+            peer,
+            snapshot.chain_tips.len()
+        );
+        return Ok(true);
+    }
+    Err(format!("No peer sent a valid snapshot of epoch {}", checkpoint.0).into())
+}
+
 /// # Prop_block
 /// Sends a block to all connected peers.
 /// # Returns
@@ -182,7 +288,11 @@ pub fn sync() -> Result<u64, String> {
     for i in &chain_digests {
         if *i.digest == mode_hash {
             if let Some(peer_) = &i.peer {
-                peer_to_use = Some(peer_.try_clone().unwrap());
+                // pruned peers can not serve old blocks, only sync from one if no other peer has the digest
+                let pruned = peer_.peer_addr().map_or(false, |addr| is_pruned(&addr));
+                if peer_to_use.is_none() || !pruned {
+                    peer_to_use = Some(peer_.try_clone().unwrap());
+                }
             }
         }
     }
@@ -265,7 +375,7 @@ pub fn sync() -> Result<u64, String> {
     }
 
     info!("Synced all chains, checking chain digest with peers");
-    let cd = form_state_digest(config().db_path + "/chaindigest").unwrap(); //  recalculate our state digest
+    let cd = form_state_digest().unwrap(); //  recalculate our state digest
     if cd != mode_hash {
         error!("Synced blocks do not result in mode block hash, if you have appended blocks (using send_txn or generate etc) then ignore this. If not please delete your data dir and resync");
         error!("Our CD: {}, expected: {}", cd, mode_hash);
@@ -332,7 +442,11 @@ pub fn sync_in_order() -> Result<u64, Box<dyn std::error::Error>> {
     for i in &chain_digests {
         if *i.digest == mode_hash {
             if let Some(peer_) = &i.peer {
-                peer_to_use = Some(peer_.try_clone().unwrap());
+                // pruned peers can not serve old blocks, only sync from one if no other peer has the digest
+                let pruned = peer_.peer_addr().map_or(false, |addr| is_pruned(&addr));
+                if peer_to_use.is_none() || !pruned {
+                    peer_to_use = Some(peer_.try_clone().unwrap());
+                }
             }
         }
     }
@@ -395,7 +509,7 @@ pub fn sync_in_order() -> Result<u64, Box<dyn std::error::Error>> {
                     let mut top_block_hash: String;
                     let peer = &mut peer_to_use_unwraped;
                     top_block_hash =
-                        get_data(config().db_path + "/globalindex", "globaltopblockhash");
+                        global_top_block_hash();
                     if top_block_hash == "-1" {
                         top_block_hash = "0".to_string();
                         if let Err(e) = send(serde_json::to_string(&0)?, peer, 0x7f, true, None) {
@@ -471,6 +585,12 @@ pub fn sync_in_order() -> Result<u64, Box<dyn std::error::Error>> {
                                 );
 
                                 for block in blocks {
+                                    if let Err(e) = sync_consensus_signatures(&block, peer) {
+                                        debug!(
+                                            "Failed to get the signatures of consensus block {} from peer, error={}",
+                                            block.hash, e
+                                        );
+                                    }
                                     if let Err(e) = block.valid() {
                                         error!("Recieved invalid block with hash: {} from peer, validation gave error: {:#?}. Invalid blocks from peer: {}", block.hash, e, invalid_blocks);
                                         invalid_blocks += 1;
@@ -506,7 +626,7 @@ pub fn sync_in_order() -> Result<u64, Box<dyn std::error::Error>> {
                         let top_block_hash: String;
 
                         top_block_hash =
-                            get_data(config().db_path + "/globalindex", "globaltopblockhash");
+                            global_top_block_hash();
 
                         trace!("Asking peer for blocks above hash: {}", top_block_hash);
 
@@ -543,7 +663,7 @@ pub fn sync_in_order() -> Result<u64, Box<dyn std::error::Error>> {
                         }
                     }
                     info!("Synced all blocks, checking chain digest with peers");
-                    let cd = form_state_digest(config().db_path + "/chaindigest").unwrap(); //  recalculate our state digest
+                    let cd = form_state_digest().unwrap(); //  recalculate our state digest
                     if cd != mode_hash {
                         error!("Synced blocks do not result in mode block hash, if you have appended blocks (using send_txn or register_username etc) then ignore this. If not please delete your data dir and resync");
                         error!("Our CD: {}, expected: {}", cd, mode_hash);
@@ -563,7 +683,7 @@ pub fn sync_in_order() -> Result<u64, Box<dyn std::error::Error>> {
     }
 
     info!("Synced all chains, checking chain digest with peers");
-    let cd = form_state_digest(config().db_path + "/chaindigest").unwrap(); //  recalculate our state digest
+    let cd = form_state_digest().unwrap(); //  recalculate our state digest
     if cd != mode_hash {
         error!("Synced blocks do not result in mode block hash, if you have appended blocks (using send_txn or generate etc) then ignore this. If not please delete your data dir and resync");
         error!("Our SD: {}, expected: {}", cd, mode_hash);
@@ -631,10 +751,8 @@ pub fn sync_chain(chain: String, peer: &mut TcpStream) -> Result<u64, Box<dyn st
 
     let top_block_hash: String;
     // let opened_db: rocksdb::DB;
-    top_block_hash = get_data(
-        config().db_path + "/chains/" + &chain + "-chainindex",
-        "topblockhash",
-    );
+    top_block_hash =
+        avrio_core::block::top_block_hash(&chain).unwrap_or_else(|| String::from("-1"));
 
     if top_block_hash == "-1" {
         if let Err(e) = send(
@@ -715,6 +833,12 @@ pub fn sync_chain(chain: String, peer: &mut TcpStream) -> Result<u64, Box<dyn st
                 );
 
                 for block in blocks {
+                    if let Err(e) = sync_consensus_signatures(&block, peer) {
+                        debug!(
+                            "Failed to get the signatures of consensus block {} from peer, error={}",
+                            block.hash, e
+                        );
+                    }
                     if let Err(e) = block.valid() {
                         error!("Recieved invalid block with hash: {} from peer, validation gave error: {:#?}. Invalid blocks from peer: {}", block.hash, e, invalid_blocks);
                         invalid_blocks += 1;
@@ -750,10 +874,8 @@ pub fn sync_chain(chain: String, peer: &mut TcpStream) -> Result<u64, Box<dyn st
 
         let top_block_hash: String;
 
-        top_block_hash = get_data(
-            config().db_path + "/chains/" + &chain + "-chainindex",
-            "topblockhash",
-        );
+        top_block_hash =
+            avrio_core::block::top_block_hash(&chain).unwrap_or_else(|| String::from("-1"));
 
         trace!("Asking peer for blocks above hash: {}", top_block_hash);
 
@@ -873,6 +995,14 @@ fn syncack_peer(peer: &mut TcpStream) -> Result<TcpStream, Box<dyn Error>> {
     }
 }
 
+/// Returns the hash of the last block we enacted (globally), or -1 if we have none
+fn global_top_block_hash() -> String {
+    store::get_raw(&Table::GlobalIndex, "globaltopblockhash")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| String::from("-1"))
+}
+
 fn get_chain_digest_string(peer: &mut TcpStream, _unlock: bool) -> String {
     let mut tries = 0;
     loop {
@@ -37,10 +37,18 @@ fn get_message_types() -> HashMap<u16, &'static str> {
     message_types.insert(0x52, "Get block chunk range (response)");
     message_types.insert(0x60, "Get Chain List (ask)");
     message_types.insert(0x61, "Get Chain List (response)");
-    message_types.insert(0x62, "Generate epoch salt seeds (ask)");
+    message_types.insert(0x62, "Generate epoch salt seeds (ask)"); // sent by consensus commitee round leader to retry members who did not respond to 0x06
     message_types.insert(0x63, "Generate epoch salt seeds (response)");
     message_types.insert(0x64, "Propose chunk for round");
     message_types.insert(0x65, "Proposed chunk signature"); 
+    message_types.insert(0x66, "Relay VRF lottery ticket"); // sent by consensus commitee members to the round leader
+    message_types.insert(0x67, "Sign consensus block (ask)"); // sent by consensus commitee round leader to the committee
+    message_types.insert(0x68, "Sign consensus block (response)");
+    message_types.insert(0x69, "Consensus block signatures"); // the aggregated signatures of the consensus commitee, finalizes the block
+    message_types.insert(0x6a, "Get state snapshot (ask)"); // sent by a new node bootstrapping from a checkpoint, with the epoch number
+    message_types.insert(0x6b, "State snapshot (response)"); // the epoch and its snapshot, empty if we do not have them
+    message_types.insert(0x6c, "Get consensus block signatures (ask)"); // sent while syncing the consensus chain, with the hash of the consensus send block
+    message_types.insert(0x6d, "Consensus block signatures (response)"); // the aggregated signatures, empty if we do not have them
     message_types.insert(0x6f, "Get Block Above Hash, chain concurrent"); // get the blocks above hash x, concurernt to chain c
     message_types.insert(0x7f, "Get Block Above Hash, global concurrent"); // get the blocks above hash x, but globally concurernt rather than chain concurrent
     message_types.insert(0x91, "Ping");
@@ -11,6 +11,8 @@ lazy_static! {
     pub static ref OUTGOING: Mutex<Vec<TcpStream>> = Mutex::new(vec![]);
     pub static ref PEERS: Mutex<HashMap<String, (String, bool, Option<std::sync::mpsc::Sender<String>>, u64)>> =
         Mutex::new(HashMap::new());
+    /// The number of epochs of blocks each pruned peer keeps, as sent in their handshake. Peers which keep every block are not in it
+    static ref PRUNED_PEERS: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
 }
 
 #[deprecated(
@@ -87,8 +89,26 @@ pub fn add_peer(
     Ok(())
 }
 
+/// Records the number of epochs of blocks the peer keeps, from their handshake (0 if they keep every block)
+pub fn set_pruning(peer: &SocketAddr, keep_epochs: u64) -> Result<(), Box<dyn Error>> {
+    if keep_epochs == 0 {
+        (*PRUNED_PEERS.lock()?).remove(&strip_port(peer));
+    } else {
+        (*PRUNED_PEERS.lock()?).insert(strip_port(peer), keep_epochs);
+    }
+    Ok(())
+}
+
+/// Returns true if the peer prunes old blocks, so can not serve them
+pub fn is_pruned(peer: &SocketAddr) -> bool {
+    PRUNED_PEERS
+        .lock()
+        .map_or(false, |pruned| pruned.contains_key(&strip_port(peer)))
+}
+
 pub fn remove_peer(peer: SocketAddr, is_incoming: bool) -> Result<(), Box<dyn Error>> {
     (*PEERS.lock()?).remove(&strip_port(&peer));
+    (*PRUNED_PEERS.lock()?).remove(&strip_port(&peer));
     if is_incoming {
         let mut new_incoming: Vec<TcpStream> = vec![];
         for incoming in &(*INCOMING.lock()?) {
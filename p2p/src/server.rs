@@ -10,7 +10,7 @@ use x25519_dalek::PublicKey;
 
 use crate::{
     io::{read, send},
-    peer::{add_peer, remove_peer},
+    peer::{add_peer, remove_peer, set_pruning},
 };
 
 use std::convert::TryInto;
@@ -228,6 +228,10 @@ impl P2pServer {
                                                     hex::encode(key.as_bytes()),
                                                     &tx,
                                                 );
+                                                let _ = set_pruning(
+                                                    &addr,
+                                                    crate::core::handshake_pruning(&d_split),
+                                                );
                                                 let _ = crate::handle::launch_handle_client(
                                                     rx,
                                                     &mut stream,
@@ -1,4 +1,7 @@
-use avrio_core::block::{Block, BlockType};
+use avrio_core::{
+    block::{Block, BlockType},
+    fork::ForkConflict,
+};
 use lazy_static::*;
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -95,6 +98,67 @@ pub fn username_announce(username: String) -> Result<(), Box<dyn std::error::Err
     Ok(())
 }
 
+/// Announces that a block became final (it was included in a quorum signed chunk), content is {"hash": block hash, "chunk": chunk hash}
+pub fn finalized_announce(
+    block_hash: String,
+    chunk_hash: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let announcement = Announcement {
+        m_type: "finalized".to_string(),
+        content: serde_json::json!({ "hash": block_hash, "chunk": chunk_hash }).to_string(),
+    };
+    let connections = &mut CONNECTIONS.lock().unwrap();
+    for (stream, subscriptions) in connections.iter_mut() {
+        if subscriptions.contains(&"finalized".to_string()) {
+            if let Err(e) = stream.write(
+                serde_json::to_string(&announcement)
+                    .unwrap_or_default()
+                    .as_bytes(),
+            ) {
+                trace!(
+                    "Failed to announce finalized block={} to peer, got error={}",
+                    block_hash,
+                    e,
+                );
+            }
+        }
+    }
+    let mut local_callbacks = LOCAL_CALLBACKS.lock().unwrap();
+    for callback in local_callbacks.iter_mut() {
+        callback.call(announcement.clone());
+    }
+    Ok(())
+}
+
+/// Announces a fork (two blocks at the same height of a chain), when it is detected and again when it is resolved, content is the json encoded fork::ForkConflict
+pub fn fork_announce(conflict: ForkConflict) -> Result<(), Box<dyn std::error::Error>> {
+    let announcement = Announcement {
+        m_type: "fork".to_string(),
+        content: serde_json::to_string(&conflict).unwrap_or_default(),
+    };
+    let connections = &mut CONNECTIONS.lock().unwrap();
+    for (stream, subscriptions) in connections.iter_mut() {
+        if subscriptions.contains(&"fork".to_string()) {
+            if let Err(e) = stream.write(
+                serde_json::to_string(&announcement)
+                    .unwrap_or_default()
+                    .as_bytes(),
+            ) {
+                trace!(
+                    "Failed to announce fork on chain={} to peer, got error={}",
+                    conflict.chain_key,
+                    e,
+                );
+            }
+        }
+    }
+    let mut local_callbacks = LOCAL_CALLBACKS.lock().unwrap();
+    for callback in local_callbacks.iter_mut() {
+        callback.call(announcement.clone());
+    }
+    Ok(())
+}
+
 pub fn peer_announce(peer: String) -> Result<(), Box<dyn std::error::Error>> {
     let connections = &mut CONNECTIONS.lock().unwrap();
     for (stream, subscriptions) in connections.iter_mut() {
@@ -255,7 +319,11 @@ pub fn launch(port: u64) {
                         let hi_string = String::from_utf8(hi_buffer[0..read_bytes].to_vec())
                             .unwrap_or_default();
                         if hi_string == "init" {
-                            let services_list = ["block".to_string()]; // TODO: move to config
+                            let services_list = [
+                                "block".to_string(),
+                                "finalized".to_string(),
+                                "fork".to_string(),
+                            ]; // TODO: move to config
                             let services_list_ser =
                                 serde_json::to_string(&services_list).unwrap_or_default();
                             if let Ok(_) = stream.write(services_list_ser.as_bytes()) {